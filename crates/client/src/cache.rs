@@ -0,0 +1,677 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use buildkit_rs_proto::moby::buildkit::v1::{frontend as gateway, CacheOptions, CacheOptionsEntry};
+use oci_spec::image::{Descriptor, ImageIndex, ANNOTATION_REF_NAME};
+use thiserror::Error;
+use tracing::warn;
+
+use crate::exporter::Compression;
+
+/// The exporter response with the descriptor of an exported cache manifest
+const CACHE_MANIFEST_KEY: &str = "cache.manifest";
+
+/// The frontend attribute with the cache imports, frontends solve with them
+/// in their own requests
+const FRONTEND_CACHE_IMPORTS_KEY: &str = "cache-imports";
+
+/// The tag of a local cache when none is set, like `buildctl`
+const DEFAULT_LOCAL_TAG: &str = "latest";
+
+/// The prefix of the content store ids of local caches in the session
+const LOCAL_STORE_PREFIX: &str = "local:";
+
+#[derive(Debug, Error)]
+pub enum CacheError {
+    #[error("the inline cache is part of an image, import it with a registry cache")]
+    InlineImport,
+    #[error("{path}: {source}")]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("invalid OCI index {path}: {source}")]
+    Index {
+        path: PathBuf,
+        source: oci_spec::OciSpecError,
+    },
+    #[error("invalid cache manifest descriptor: {0}")]
+    ManifestDescriptor(#[from] serde_json::Error),
+}
+
+/// Which layers of a build are exported to the cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Only the layers of the result
+    Min,
+    /// The layers of all intermediate steps too
+    Max,
+}
+
+impl CacheMode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            CacheMode::Min => "min",
+            CacheMode::Max => "max",
+        }
+    }
+}
+
+/// Where a cache is imported from or exported to
+#[derive(Debug, Clone)]
+pub enum CacheBackend {
+    /// A cache image in a registry
+    Registry(RegistryCache),
+    /// An OCI image layout on the client, transferred through the session
+    Local(LocalCache),
+    /// The cache metadata embedded in the exported image, it is imported
+    /// with [`CacheBackend::Registry`]
+    Inline,
+    /// The GitHub Actions cache
+    Gha(GhaCache),
+    /// An S3 bucket
+    S3(S3Cache),
+    /// An Azure Blob Storage container
+    AzBlob(AzBlobCache),
+}
+
+impl CacheBackend {
+    /// The name of the backend in the daemon
+    pub fn name(&self) -> &'static str {
+        match self {
+            CacheBackend::Registry(_) => "registry",
+            CacheBackend::Local(_) => "local",
+            CacheBackend::Inline => "inline",
+            CacheBackend::Gha(_) => "gha",
+            CacheBackend::S3(_) => "s3",
+            CacheBackend::AzBlob(_) => "azblob",
+        }
+    }
+
+    fn attrs(&self, export: bool) -> HashMap<String, String> {
+        let mut attrs = HashMap::new();
+        match self {
+            CacheBackend::Registry(registry) => {
+                attrs.insert("ref".into(), registry.reference.clone());
+                if registry.insecure {
+                    attrs.insert("registry.insecure".into(), "true".into());
+                }
+            }
+            CacheBackend::Local(local) => {
+                let key = if export { "dest" } else { "src" };
+                attrs.insert(key.into(), local.path.to_string_lossy().into_owned());
+                if let Some(digest) = &local.digest {
+                    attrs.insert("digest".into(), digest.clone());
+                }
+            }
+            CacheBackend::Inline => {}
+            CacheBackend::Gha(gha) => {
+                insert_some(&mut attrs, "url", &gha.url);
+                insert_some(&mut attrs, "token", &gha.token);
+                insert_some(&mut attrs, "scope", &gha.scope);
+            }
+            CacheBackend::S3(s3) => {
+                attrs.insert("bucket".into(), s3.bucket.clone());
+                attrs.insert("region".into(), s3.region.clone());
+                insert_some(&mut attrs, "name", &s3.name);
+                insert_some(&mut attrs, "prefix", &s3.prefix);
+                insert_some(&mut attrs, "endpoint_url", &s3.endpoint_url);
+                if s3.use_path_style {
+                    attrs.insert("use_path_style".into(), "true".into());
+                }
+                insert_some(&mut attrs, "access_key_id", &s3.access_key_id);
+                insert_some(&mut attrs, "secret_access_key", &s3.secret_access_key);
+                insert_some(&mut attrs, "session_token", &s3.session_token);
+            }
+            CacheBackend::AzBlob(azblob) => {
+                attrs.insert("account_url".into(), azblob.account_url.clone());
+                insert_some(&mut attrs, "name", &azblob.name);
+                insert_some(&mut attrs, "secret_access_key", &azblob.secret_access_key);
+            }
+        }
+        attrs
+    }
+}
+
+fn insert_some(attrs: &mut HashMap<String, String>, key: &str, value: &Option<String>) {
+    if let Some(value) = value {
+        attrs.insert(key.into(), value.clone());
+    }
+}
+
+/// Options of [`CacheBackend::Registry`]
+#[derive(Debug, Clone)]
+pub struct RegistryCache {
+    pub reference: String,
+    /// Use plain HTTP or an untrusted certificate for the registry
+    pub insecure: bool,
+}
+
+impl RegistryCache {
+    pub fn new(reference: impl Into<String>) -> Self {
+        Self {
+            reference: reference.into(),
+            insecure: false,
+        }
+    }
+
+    pub fn with_insecure(mut self, insecure: bool) -> Self {
+        self.insecure = insecure;
+        self
+    }
+}
+
+/// Options of [`CacheBackend::Local`]
+#[derive(Debug, Clone)]
+pub struct LocalCache {
+    pub path: PathBuf,
+    /// The tag in the `index.json` of the layout, `latest` if `None`
+    pub tag: Option<String>,
+    /// The cache manifest to import, looked up by `tag` if `None`
+    pub digest: Option<String>,
+}
+
+impl LocalCache {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            tag: None,
+            digest: None,
+        }
+    }
+
+    pub fn with_tag(mut self, tag: impl Into<String>) -> Self {
+        self.tag = Some(tag.into());
+        self
+    }
+
+    pub fn with_digest(mut self, digest: impl Into<String>) -> Self {
+        self.digest = Some(digest.into());
+        self
+    }
+
+    fn tag(&self) -> &str {
+        self.tag.as_deref().unwrap_or(DEFAULT_LOCAL_TAG)
+    }
+
+    fn store_id(&self) -> String {
+        format!("{LOCAL_STORE_PREFIX}{}", self.path.to_string_lossy())
+    }
+}
+
+/// Options of [`CacheBackend::Gha`]
+#[derive(Debug, Clone, Default)]
+pub struct GhaCache {
+    /// The cache service, `ACTIONS_CACHE_URL` in a workflow
+    pub url: Option<String>,
+    /// `ACTIONS_RUNTIME_TOKEN` in a workflow
+    pub token: Option<String>,
+    /// Separates the caches of different builds in a repository
+    pub scope: Option<String>,
+}
+
+impl GhaCache {
+    /// The cache of the workflow this runs in, from the `ACTIONS_*` variables
+    /// that are set for actions
+    pub fn from_env() -> Self {
+        Self {
+            url: std::env::var("ACTIONS_CACHE_URL").ok(),
+            token: std::env::var("ACTIONS_RUNTIME_TOKEN").ok(),
+            scope: None,
+        }
+    }
+
+    pub fn with_scope(mut self, scope: impl Into<String>) -> Self {
+        self.scope = Some(scope.into());
+        self
+    }
+}
+
+/// Options of [`CacheBackend::S3`], credentials that are not set are read by
+/// the daemon from its environment
+#[derive(Debug, Clone, Default)]
+pub struct S3Cache {
+    pub bucket: String,
+    pub region: String,
+    /// The name of the cache manifest in the bucket
+    pub name: Option<String>,
+    /// A prefix for all the objects of the cache
+    pub prefix: Option<String>,
+    /// An S3 compatible service instead of AWS
+    pub endpoint_url: Option<String>,
+    pub use_path_style: bool,
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+    pub session_token: Option<String>,
+}
+
+impl S3Cache {
+    pub fn new(bucket: impl Into<String>, region: impl Into<String>) -> Self {
+        Self {
+            bucket: bucket.into(),
+            region: region.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = Some(prefix.into());
+        self
+    }
+
+    pub fn with_endpoint_url(mut self, endpoint_url: impl Into<String>) -> Self {
+        self.endpoint_url = Some(endpoint_url.into());
+        self
+    }
+
+    pub fn with_path_style(mut self, use_path_style: bool) -> Self {
+        self.use_path_style = use_path_style;
+        self
+    }
+
+    pub fn with_credentials(
+        mut self,
+        access_key_id: impl Into<String>,
+        secret_access_key: impl Into<String>,
+    ) -> Self {
+        self.access_key_id = Some(access_key_id.into());
+        self.secret_access_key = Some(secret_access_key.into());
+        self
+    }
+
+    pub fn with_session_token(mut self, session_token: impl Into<String>) -> Self {
+        self.session_token = Some(session_token.into());
+        self
+    }
+}
+
+/// Options of [`CacheBackend::AzBlob`]
+#[derive(Debug, Clone, Default)]
+pub struct AzBlobCache {
+    /// e.g. `https://myaccount.blob.core.windows.net`
+    pub account_url: String,
+    /// The name of the cache manifest in the container
+    pub name: Option<String>,
+    /// The account key, the daemon uses its own credentials if `None`
+    pub secret_access_key: Option<String>,
+}
+
+impl AzBlobCache {
+    pub fn new(account_url: impl Into<String>) -> Self {
+        Self {
+            account_url: account_url.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn with_secret_access_key(mut self, secret_access_key: impl Into<String>) -> Self {
+        self.secret_access_key = Some(secret_access_key.into());
+        self
+    }
+}
+
+/// A cache the result of a solve is exported to, like `buildctl --export-cache`
+#[derive(Debug, Clone)]
+pub struct CacheExport {
+    pub backend: CacheBackend,
+    /// The daemon's default, `min`, if `None`
+    pub mode: Option<CacheMode>,
+    /// Don't fail the build if the cache can't be exported
+    pub ignore_error: bool,
+    pub compression: Option<Compression>,
+    pub oci_mediatypes: Option<bool>,
+    /// Export the cache as an image manifest instead of an index, for
+    /// registries that don't support indexes
+    pub image_manifest: bool,
+}
+
+impl CacheExport {
+    pub fn new(backend: CacheBackend) -> Self {
+        Self {
+            backend,
+            mode: None,
+            ignore_error: false,
+            compression: None,
+            oci_mediatypes: None,
+            image_manifest: false,
+        }
+    }
+
+    pub fn with_mode(mut self, mode: CacheMode) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    pub fn with_ignore_error(mut self, ignore_error: bool) -> Self {
+        self.ignore_error = ignore_error;
+        self
+    }
+
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    pub fn with_image_manifest(mut self, image_manifest: bool) -> Self {
+        self.image_manifest = image_manifest;
+        self
+    }
+
+    fn attrs(&self) -> HashMap<String, String> {
+        let mut attrs = self.backend.attrs(true);
+        if let Some(mode) = self.mode {
+            attrs.insert("mode".into(), mode.as_str().into());
+        }
+        if self.ignore_error {
+            attrs.insert("ignore-error".into(), "true".into());
+        }
+        if let Some(compression) = self.compression {
+            attrs.insert("compression".into(), compression.as_str().into());
+        }
+        if let Some(oci_mediatypes) = self.oci_mediatypes {
+            attrs.insert("oci-mediatypes".into(), oci_mediatypes.to_string());
+        }
+        if self.image_manifest {
+            attrs.insert("image-manifest".into(), "true".into());
+        }
+        attrs
+    }
+}
+
+/// The cache options of a solve request and the local caches it uses
+#[derive(Debug, Default)]
+pub(crate) struct CacheRequest {
+    pub options: CacheOptions,
+    /// The content stores the session serves for local caches, by id
+    pub content_stores: HashMap<String, PathBuf>,
+    /// The local caches whose `index.json` is updated after the solve
+    indices: Vec<(PathBuf, String)>,
+}
+
+impl CacheRequest {
+    pub fn new(exports: Vec<CacheExport>, imports: Vec<CacheBackend>) -> Result<Self, CacheError> {
+        let mut request = CacheRequest::default();
+
+        for export in exports {
+            if let CacheBackend::Local(local) = &export.backend {
+                std::fs::create_dir_all(&local.path).map_err(io_error(&local.path))?;
+                request
+                    .content_stores
+                    .insert(local.store_id(), local.path.clone());
+                request
+                    .indices
+                    .push((local.path.join("index.json"), local.tag().to_owned()));
+            }
+            request.options.exports.push(CacheOptionsEntry {
+                r#type: export.backend.name().into(),
+                attrs: export.attrs(),
+            });
+        }
+
+        for import in imports {
+            let import = match import {
+                CacheBackend::Inline => return Err(CacheError::InlineImport),
+                CacheBackend::Local(local) => match local_import(local)? {
+                    Some(local) => {
+                        request
+                            .content_stores
+                            .insert(local.store_id(), local.path.clone());
+                        CacheBackend::Local(local)
+                    }
+                    None => continue,
+                },
+                import => import,
+            };
+            request.options.imports.push(CacheOptionsEntry {
+                r#type: import.name().into(),
+                attrs: import.attrs(false),
+            });
+        }
+
+        Ok(request)
+    }
+
+    /// The attributes passing the imports to a frontend
+    pub fn frontend_attrs(&self) -> HashMap<String, String> {
+        if self.options.imports.is_empty() {
+            return HashMap::new();
+        }
+        let imports: Vec<_> = self
+            .options
+            .imports
+            .iter()
+            .map(|import| serde_json::json!({ "Type": import.r#type, "Attrs": import.attrs }))
+            .collect();
+        HashMap::from([(
+            FRONTEND_CACHE_IMPORTS_KEY.into(),
+            serde_json::Value::from(imports).to_string(),
+        )])
+    }
+
+    /// The imports of the solves made through the LLB bridge of a build
+    pub fn gateway_imports(&self) -> Vec<gateway::CacheOptionsEntry> {
+        self.options
+            .imports
+            .iter()
+            .map(|import| gateway::CacheOptionsEntry {
+                r#type: import.r#type.clone(),
+                attrs: import.attrs.clone(),
+            })
+            .collect()
+    }
+
+    /// Tag the exported cache manifest in the `index.json` of local caches
+    pub fn update_indices(
+        &self,
+        exporter_response: &HashMap<String, String>,
+    ) -> Result<(), CacheError> {
+        let Some(manifest) = exporter_response.get(CACHE_MANIFEST_KEY) else {
+            return Ok(());
+        };
+        let manifest: Descriptor = serde_json::from_str(manifest)?;
+
+        for (path, tag) in &self.indices {
+            let mut index = match path.exists() {
+                true => ImageIndex::from_file(path).map_err(|source| CacheError::Index {
+                    path: path.clone(),
+                    source,
+                })?,
+                false => ImageIndex::default(),
+            };
+
+            let mut manifest = manifest.clone();
+            let mut annotations = manifest.annotations().clone().unwrap_or_default();
+            annotations.insert(ANNOTATION_REF_NAME.into(), tag.clone());
+            manifest.set_annotations(Some(annotations));
+
+            let mut manifests: Vec<_> = index
+                .manifests()
+                .iter()
+                .filter(|desc| ref_name(desc) != Some(tag))
+                .cloned()
+                .collect();
+            manifests.push(manifest);
+            index.set_manifests(manifests);
+
+            index.to_file(path).map_err(|source| CacheError::Index {
+                path: path.clone(),
+                source,
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Resolve the digest of a local cache from its tag, `None` if there is no
+/// cache to import yet, which is not an error like with `buildctl`
+fn local_import(mut local: LocalCache) -> Result<Option<LocalCache>, CacheError> {
+    if local.digest.is_some() {
+        return Ok(Some(local));
+    }
+
+    let path = local.path.join("index.json");
+    if !path.exists() {
+        warn!(?path, "Local cache not found");
+        return Ok(None);
+    }
+    let index = ImageIndex::from_file(&path).map_err(|source| CacheError::Index {
+        path: path.clone(),
+        source,
+    })?;
+
+    let tag = local.tag();
+    match index
+        .manifests()
+        .iter()
+        .find(|desc| ref_name(desc) == Some(tag))
+    {
+        Some(desc) => {
+            local.digest = Some(desc.digest().clone());
+            Ok(Some(local))
+        }
+        None => {
+            warn!(?path, tag, "Local cache tag not found");
+            Ok(None)
+        }
+    }
+}
+
+fn ref_name(desc: &Descriptor) -> Option<&str> {
+    desc.annotations()
+        .as_ref()
+        .and_then(|annotations| annotations.get(ANNOTATION_REF_NAME))
+        .map(String::as_str)
+}
+
+fn io_error(path: &Path) -> impl FnOnce(std::io::Error) -> CacheError + '_ {
+    move |source| CacheError::Io {
+        path: path.to_owned(),
+        source,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_attrs() {
+        let request = CacheRequest::new(
+            vec![
+                CacheExport::new(CacheBackend::Registry(RegistryCache::new(
+                    "registry.example.com/app:cache",
+                )))
+                .with_mode(CacheMode::Max),
+                CacheExport::new(CacheBackend::Inline),
+            ],
+            vec![
+                CacheBackend::Registry(RegistryCache::new("registry.example.com/app:cache")),
+                CacheBackend::S3(
+                    S3Cache::new("cache", "eu-west-1")
+                        .with_endpoint_url("http://minio:9000")
+                        .with_path_style(true),
+                ),
+            ],
+        )
+        .unwrap();
+        assert!(request.content_stores.is_empty());
+
+        let exports = &request.options.exports;
+        assert_eq!(exports[0].r#type, "registry");
+        assert_eq!(
+            exports[0].attrs,
+            HashMap::from([
+                ("ref".into(), "registry.example.com/app:cache".into()),
+                ("mode".into(), "max".into()),
+            ])
+        );
+        assert_eq!(exports[1].r#type, "inline");
+        assert!(exports[1].attrs.is_empty());
+
+        let imports = &request.options.imports;
+        assert_eq!(imports[0].r#type, "registry");
+        assert_eq!(imports[1].r#type, "s3");
+        assert_eq!(
+            imports[1].attrs,
+            HashMap::from([
+                ("bucket".into(), "cache".into()),
+                ("region".into(), "eu-west-1".into()),
+                ("endpoint_url".into(), "http://minio:9000".into()),
+                ("use_path_style".into(), "true".into()),
+            ])
+        );
+
+        let attrs = request.frontend_attrs();
+        let imports: serde_json::Value = serde_json::from_str(&attrs["cache-imports"]).unwrap();
+        assert_eq!(
+            imports[0],
+            serde_json::json!({
+                "Type": "registry",
+                "Attrs": { "ref": "registry.example.com/app:cache" },
+            })
+        );
+
+        let imports = request.gateway_imports();
+        assert_eq!(imports.len(), 2);
+        assert_eq!(imports[1].r#type, "s3");
+        assert_eq!(imports[1].attrs, request.options.imports[1].attrs);
+
+        let err = CacheRequest::new(vec![], vec![CacheBackend::Inline]).unwrap_err();
+        assert!(matches!(err, CacheError::InlineImport));
+    }
+
+    #[test]
+    fn local_cache_index() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path().join("cache");
+        let local = LocalCache::new(&dir).with_tag("main");
+
+        // Nothing to import before the first export
+        let request = CacheRequest::new(vec![], vec![CacheBackend::Local(local.clone())]).unwrap();
+        assert!(request.options.imports.is_empty());
+
+        let request = CacheRequest::new(
+            vec![CacheExport::new(CacheBackend::Local(local.clone()))],
+            vec![],
+        )
+        .unwrap();
+        let store_id = format!("local:{}", dir.display());
+        assert_eq!(request.content_stores[&store_id], dir);
+        assert_eq!(
+            request.options.exports[0].attrs["dest"],
+            dir.to_string_lossy()
+        );
+
+        let digest = format!("sha256:{}", "a".repeat(64));
+        let manifest = serde_json::json!({
+            "mediaType": "application/vnd.oci.image.index.v1+json",
+            "digest": digest,
+            "size": 1234,
+        });
+        let response = HashMap::from([(CACHE_MANIFEST_KEY.into(), manifest.to_string())]);
+        request.update_indices(&response).unwrap();
+        // Exporting again replaces the tag
+        request.update_indices(&response).unwrap();
+
+        let index = ImageIndex::from_file(dir.join("index.json")).unwrap();
+        assert_eq!(index.manifests().len(), 1);
+        assert_eq!(ref_name(&index.manifests()[0]), Some("main"));
+
+        let request = CacheRequest::new(vec![], vec![CacheBackend::Local(local)]).unwrap();
+        let import = &request.options.imports[0];
+        assert_eq!(import.r#type, "local");
+        assert_eq!(import.attrs["src"], dir.to_string_lossy());
+        assert_eq!(import.attrs["digest"], digest);
+        assert_eq!(request.content_stores[&store_id], dir);
+    }
+}
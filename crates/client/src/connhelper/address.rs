@@ -0,0 +1,361 @@
+use std::{fmt, path::PathBuf, str::FromStr};
+
+use thiserror::Error;
+
+use super::{docker_engine::check_docker_host, kube::KubePod, ssh::SshHost};
+
+/// The environment variable `buildctl` reads the daemon address from
+pub const BUILDKIT_HOST: &str = "BUILDKIT_HOST";
+
+/// The address `buildctl` uses when none is given
+pub const DEFAULT_ADDRESS: &str = "unix:///run/buildkit/buildkitd.sock";
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum AddressError {
+    #[error("invalid address {0:?}, expected <scheme>://<address>")]
+    Invalid(String),
+    #[error("unsupported scheme {0:?}")]
+    UnsupportedScheme(String),
+    #[error("missing port in {0:?}")]
+    MissingPort(String),
+    #[error("invalid port in {0:?}")]
+    InvalidPort(String),
+    #[error("missing {what} in {address:?}")]
+    Empty { what: &'static str, address: String },
+    #[error("unsupported docker host {0:?}, expected unix:// or tcp://")]
+    UnsupportedDockerHost(String),
+    #[error("docker host {0:?} uses TLS, which is not supported")]
+    DockerHostTls(String),
+    #[error("unknown parameter {name:?} in {address:?}")]
+    UnknownParameter { name: String, address: String },
+}
+
+/// The address of a buildkitd daemon, in the format of `buildctl --addr` and
+/// `BUILDKIT_HOST`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    /// `unix:///run/buildkit/buildkitd.sock`
+    Unix(PathBuf),
+    /// `tcp://host:port`
+    Tcp { host: String, port: u16 },
+    /// `docker-container://name`, runs `buildctl dial-stdio` in the container
+    DockerContainer(String),
+    /// `podman-container://name`, runs `buildctl dial-stdio` in the container
+    PodmanContainer(String),
+    /// `docker://` or `docker://unix:///var/run/docker.sock`, the BuildKit
+    /// embedded in Docker Engine. Without an engine address `DOCKER_HOST` is
+    /// used. Sessions and engines reached over TLS are not supported.
+    DockerEngine(Option<Box<Address>>),
+    /// `ssh://user@host:port`, runs `buildctl dial-stdio` on the host. The
+    /// identity and options can only be set with [`SshHost`]
+    Ssh(SshHost),
+    /// `kube-pod://name?namespace=ns&context=ctx&container=c&kubeconfig=path`,
+    /// runs `buildctl dial-stdio` in the pod
+    KubePod(KubePod),
+}
+
+impl Address {
+    /// The address from `BUILDKIT_HOST`, or [`DEFAULT_ADDRESS`] if it is not set
+    pub fn from_env() -> Result<Self, AddressError> {
+        match std::env::var(BUILDKIT_HOST) {
+            Ok(address) if !address.is_empty() => address.parse(),
+            _ => DEFAULT_ADDRESS.parse(),
+        }
+    }
+}
+
+impl FromStr for Address {
+    type Err = AddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (scheme, rest) = s
+            .split_once("://")
+            .ok_or_else(|| AddressError::Invalid(s.into()))?;
+
+        let non_empty = |what| {
+            if rest.is_empty() {
+                Err(AddressError::Empty {
+                    what,
+                    address: s.into(),
+                })
+            } else {
+                Ok(rest.to_owned())
+            }
+        };
+
+        match scheme {
+            "unix" => Ok(Self::Unix(non_empty("socket path")?.into())),
+            "tcp" => {
+                let (host, port) = rest
+                    .rsplit_once(':')
+                    .ok_or_else(|| AddressError::MissingPort(s.into()))?;
+                if host.is_empty() {
+                    return Err(AddressError::Empty {
+                        what: "host",
+                        address: s.into(),
+                    });
+                }
+                let port = port
+                    .parse()
+                    .map_err(|_| AddressError::InvalidPort(s.into()))?;
+                Ok(Self::Tcp {
+                    host: host.into(),
+                    port,
+                })
+            }
+            "docker-container" => Ok(Self::DockerContainer(non_empty("container name")?)),
+            "podman-container" => Ok(Self::PodmanContainer(non_empty("container name")?)),
+            "docker" if rest.is_empty() => Ok(Self::DockerEngine(None)),
+            "docker" => Ok(Self::DockerEngine(Some(Box::new(check_docker_host(
+                rest.parse()?,
+            )?)))),
+            "ssh" => {
+                let (user, host) = match rest.rsplit_once('@') {
+                    Some((user, host)) => (Some(user), host),
+                    None => (None, rest),
+                };
+                // The port is optional, and IPv6 hosts are in brackets
+                let (host, port) = match host.rsplit_once(':') {
+                    Some((host, port)) if !port.contains(']') => {
+                        let port = port
+                            .parse()
+                            .map_err(|_| AddressError::InvalidPort(s.into()))?;
+                        (host, Some(port))
+                    }
+                    _ => (host, None),
+                };
+
+                for (what, value) in [("user", user.unwrap_or("-")), ("host", host)] {
+                    if value.is_empty() {
+                        return Err(AddressError::Empty {
+                            what,
+                            address: s.into(),
+                        });
+                    }
+                }
+
+                let mut ssh_host = SshHost::new(host);
+                ssh_host.user = user.map(Into::into);
+                ssh_host.port = port;
+                Ok(Self::Ssh(ssh_host))
+            }
+            "kube-pod" => {
+                let (pod, query) = rest.split_once('?').unwrap_or((rest, ""));
+                if pod.is_empty() {
+                    return Err(AddressError::Empty {
+                        what: "pod name",
+                        address: s.into(),
+                    });
+                }
+
+                let mut kube_pod = KubePod::new(pod);
+                for param in query.split('&').filter(|param| !param.is_empty()) {
+                    let (name, value) = param.split_once('=').unwrap_or((param, ""));
+                    kube_pod = match name {
+                        "namespace" => kube_pod.with_namespace(value),
+                        "context" => kube_pod.with_context(value),
+                        "container" => kube_pod.with_container(value),
+                        "kubeconfig" => kube_pod.with_kubeconfig(value),
+                        _ => {
+                            return Err(AddressError::UnknownParameter {
+                                name: name.into(),
+                                address: s.into(),
+                            })
+                        }
+                    };
+                }
+                Ok(Self::KubePod(kube_pod))
+            }
+            _ => Err(AddressError::UnsupportedScheme(scheme.into())),
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unix(path) => write!(f, "unix://{}", path.display()),
+            Self::Tcp { host, port } => write!(f, "tcp://{host}:{port}"),
+            Self::DockerContainer(name) => write!(f, "docker-container://{name}"),
+            Self::PodmanContainer(name) => write!(f, "podman-container://{name}"),
+            Self::DockerEngine(None) => write!(f, "docker://"),
+            Self::DockerEngine(Some(host)) => write!(f, "docker://{host}"),
+            Self::Ssh(ssh_host) => {
+                write!(f, "ssh://")?;
+                if let Some(user) = &ssh_host.user {
+                    write!(f, "{user}@")?;
+                }
+                write!(f, "{}", ssh_host.host)?;
+                if let Some(port) = ssh_host.port {
+                    write!(f, ":{port}")?;
+                }
+                Ok(())
+            }
+            Self::KubePod(kube_pod) => {
+                write!(f, "kube-pod://{}", kube_pod.pod)?;
+                let params = [
+                    ("namespace", kube_pod.namespace.clone()),
+                    ("context", kube_pod.context.clone()),
+                    ("container", kube_pod.container.clone()),
+                    (
+                        "kubeconfig",
+                        kube_pod
+                            .kubeconfig
+                            .as_ref()
+                            .map(|path| path.display().to_string()),
+                    ),
+                ];
+                let mut separator = '?';
+                for (name, value) in params {
+                    if let Some(value) = value {
+                        write!(f, "{separator}{name}={value}")?;
+                        separator = '&';
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            DEFAULT_ADDRESS.parse(),
+            Ok(Address::Unix("/run/buildkit/buildkitd.sock".into()))
+        );
+        assert_eq!(
+            "tcp://buildkitd:1234".parse(),
+            Ok(Address::Tcp {
+                host: "buildkitd".into(),
+                port: 1234
+            })
+        );
+        assert_eq!(
+            "tcp://[::1]:1234".parse(),
+            Ok(Address::Tcp {
+                host: "[::1]".into(),
+                port: 1234
+            })
+        );
+        assert_eq!(
+            "docker-container://buildkitd".parse(),
+            Ok(Address::DockerContainer("buildkitd".into()))
+        );
+        assert_eq!(
+            "podman-container://buildkitd".parse(),
+            Ok(Address::PodmanContainer("buildkitd".into()))
+        );
+
+        assert_eq!("docker://".parse(), Ok(Address::DockerEngine(None)));
+        assert_eq!(
+            "docker://tcp://127.0.0.1:2375".parse(),
+            Ok(Address::DockerEngine(Some(Box::new(Address::Tcp {
+                host: "127.0.0.1".into(),
+                port: 2375
+            }))))
+        );
+        assert_eq!(
+            "ssh://builder@buildkitd:2222".parse(),
+            Ok(Address::Ssh(
+                SshHost::new("buildkitd")
+                    .with_user("builder")
+                    .with_port(2222)
+            ))
+        );
+        assert_eq!(
+            "ssh://[::1]".parse(),
+            Ok(Address::Ssh(SshHost::new("[::1]")))
+        );
+        assert_eq!(
+            "kube-pod://buildkitd-0".parse(),
+            Ok(Address::KubePod(KubePod::new("buildkitd-0")))
+        );
+        assert_eq!(
+            "kube-pod://buildkitd-0?context=staging&namespace=ci&container=buildkitd&kubeconfig=/etc/kube/config"
+                .parse(),
+            Ok(Address::KubePod(
+                KubePod::new("buildkitd-0")
+                    .with_namespace("ci")
+                    .with_context("staging")
+                    .with_container("buildkitd")
+                    .with_kubeconfig("/etc/kube/config")
+            ))
+        );
+
+        for address in [
+            DEFAULT_ADDRESS,
+            "tcp://127.0.0.1:1234",
+            "docker-container://buildkitd",
+            "kube-pod://buildkitd-0?namespace=ci&container=buildkitd",
+            "ssh://buildkitd",
+            "ssh://builder@[::1]:2222",
+            "docker://",
+            "docker://unix:///var/run/docker.sock",
+        ] {
+            assert_eq!(address.parse::<Address>().unwrap().to_string(), address);
+        }
+    }
+
+    #[test]
+    fn parse_errors() {
+        let err = |s: &str| s.parse::<Address>().unwrap_err();
+
+        assert_eq!(
+            err("/run/buildkit/buildkitd.sock"),
+            AddressError::Invalid("/run/buildkit/buildkitd.sock".into())
+        );
+        assert_eq!(
+            err("http://localhost:1234"),
+            AddressError::UnsupportedScheme("http".into())
+        );
+        assert_eq!(
+            err("tcp://localhost"),
+            AddressError::MissingPort("tcp://localhost".into())
+        );
+        assert_eq!(
+            err("tcp://localhost:http"),
+            AddressError::InvalidPort("tcp://localhost:http".into())
+        );
+        assert_eq!(
+            err("docker-container://"),
+            AddressError::Empty {
+                what: "container name",
+                address: "docker-container://".into()
+            }
+        );
+        assert_eq!(
+            err("docker://docker-container://buildkitd"),
+            AddressError::UnsupportedDockerHost("docker-container://buildkitd".into())
+        );
+        assert_eq!(
+            err("ssh://builder@buildkitd:ssh"),
+            AddressError::InvalidPort("ssh://builder@buildkitd:ssh".into())
+        );
+        assert_eq!(
+            err("ssh://@buildkitd"),
+            AddressError::Empty {
+                what: "user",
+                address: "ssh://@buildkitd".into()
+            }
+        );
+        assert_eq!(
+            err("kube-pod://?namespace=ci"),
+            AddressError::Empty {
+                what: "pod name",
+                address: "kube-pod://?namespace=ci".into()
+            }
+        );
+        assert_eq!(
+            err("kube-pod://buildkitd-0?replicas=2"),
+            AddressError::UnknownParameter {
+                name: "replicas".into(),
+                address: "kube-pod://buildkitd-0?replicas=2".into()
+            }
+        );
+    }
+}
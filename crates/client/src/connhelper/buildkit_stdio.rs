@@ -1,12 +1,17 @@
+use futures::future::BoxFuture;
 use pin_project::pin_project;
 use std::pin::Pin;
-use std::task::{Context, Poll};
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf, Result};
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf, Result};
 use tokio::process::{Child, ChildStdin, ChildStdout};
+use tokio::task::JoinHandle;
 
 #[pin_project]
 pub(crate) struct BuildkitStdio {
-    child: Child,
+    /// Taken when stdout is closed to wait for the exit status
+    child: Option<Child>,
+    stderr: Option<JoinHandle<Vec<u8>>>,
+    exit: Option<BoxFuture<'static, Result<()>>>,
     #[pin]
     stdin: ChildStdin,
     #[pin]
@@ -19,12 +24,24 @@ impl BuildkitStdio {
     /// ## Panics
     ///
     /// This function will panic if [child] does not have a stdin or stdout.
+    ///
+    /// If stderr is piped it is collected, and returned in the error of the
+    /// last read when the child exits with a failure.
     pub fn new(mut child: Child) -> BuildkitStdio {
         let stdin = child.stdin.take().unwrap();
         let stdout = child.stdout.take().unwrap();
+        let stderr = child.stderr.take().map(|mut stderr| {
+            tokio::spawn(async move {
+                let mut buf = Vec::new();
+                let _ = stderr.read_to_end(&mut buf).await;
+                buf
+            })
+        });
 
         Self {
-            child,
+            child: Some(child),
+            stderr,
+            exit: None,
             stdin,
             stdout,
         }
@@ -32,18 +49,59 @@ impl BuildkitStdio {
 
     async fn kill(&mut self) -> Result<()> {
         self.stdin.shutdown().await?;
-        self.child.kill().await?;
+        if let Some(child) = &mut self.child {
+            child.kill().await?;
+        }
         Ok(())
     }
 }
 
+/// Wait for the child after its stdout was closed, failing if it did not
+/// exit successfully
+async fn wait(mut child: Child, stderr: Option<JoinHandle<Vec<u8>>>) -> Result<()> {
+    let status = child.wait().await?;
+    if status.success() {
+        return Ok(());
+    }
+
+    let stderr = match stderr {
+        Some(stderr) => stderr.await.unwrap_or_default(),
+        None => Vec::new(),
+    };
+    let stderr = String::from_utf8_lossy(&stderr);
+    let message = match stderr.trim() {
+        "" => format!("connection helper exited with {status}"),
+        stderr => format!("connection helper exited with {status}: {stderr}"),
+    };
+    Err(std::io::Error::new(std::io::ErrorKind::BrokenPipe, message))
+}
+
 impl AsyncRead for BuildkitStdio {
     fn poll_read(
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<Result<()>> {
-        self.project().stdout.poll_read(cx, buf)
+        let this = self.project();
+
+        if this.exit.is_none() {
+            let filled = buf.filled().len();
+            ready!(this.stdout.poll_read(cx, buf))?;
+            if buf.filled().len() > filled || buf.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+
+            // End of stream, check why the child closed it
+            match this.child.take() {
+                Some(child) => *this.exit = Some(Box::pin(wait(child, this.stderr.take()))),
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+
+        let exit = this.exit.as_mut().expect("exit is set");
+        let result = ready!(exit.as_mut().poll(cx));
+        *this.exit = None;
+        Poll::Ready(result)
     }
 }
 
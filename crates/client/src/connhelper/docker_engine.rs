@@ -0,0 +1,195 @@
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::{Address, AddressError};
+
+/// The environment variable docker reads the engine address from
+pub const DOCKER_HOST: &str = "DOCKER_HOST";
+
+/// The address docker uses when `DOCKER_HOST` is not set
+pub const DEFAULT_DOCKER_HOST: &str = "unix:///var/run/docker.sock";
+
+/// The environment variables that make docker use TLS for a `tcp://` host
+const DOCKER_TLS_VARS: &[&str] = &["DOCKER_TLS_VERIFY", "DOCKER_TLS", "DOCKER_CERT_PATH"];
+
+/// The largest response to the upgrade request that is accepted
+const MAX_RESPONSE_LEN: usize = 16 * 1024;
+
+pub(crate) trait Io: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Io for T {}
+
+/// The docker engine from `DOCKER_HOST`, or [`DEFAULT_DOCKER_HOST`] if it is not set
+pub(crate) fn docker_host_from_env() -> Result<Address, AddressError> {
+    let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+    let tls = DOCKER_TLS_VARS.iter().any(|name| var(name).is_some());
+    docker_host(var(DOCKER_HOST).as_deref(), tls)
+}
+
+/// The docker engine at `host`, TLS is not supported so a `tcp://` host
+/// that docker would reach over TLS is refused instead of used in plaintext
+fn docker_host(host: Option<&str>, tls: bool) -> Result<Address, AddressError> {
+    let host = check_docker_host(host.unwrap_or(DEFAULT_DOCKER_HOST).parse()?)?;
+    match host {
+        Address::Tcp { .. } if tls => Err(AddressError::DockerHostTls(host.to_string())),
+        host => Ok(host),
+    }
+}
+
+/// Docker engines can only be reached over `unix://` and `tcp://`
+pub(crate) fn check_docker_host(host: Address) -> Result<Address, AddressError> {
+    match host {
+        Address::Unix(_) | Address::Tcp { .. } => Ok(host),
+        other => Err(AddressError::UnsupportedDockerHost(other.to_string())),
+    }
+}
+
+/// Connect to the BuildKit embedded in the docker engine at `host`, by
+/// upgrading a request to `/grpc` to a HTTP/2 connection.
+///
+/// Docker serves sessions on a separate `/session` endpoint, which is not
+/// supported, so builds that need a session for local sources, secrets or
+/// registry credentials fail against the docker engine.
+pub(crate) async fn docker_engine_connect(host: Address) -> io::Result<Box<dyn Io>> {
+    let stream: Box<dyn Io> = match host {
+        #[cfg(unix)]
+        Address::Unix(path) => Box::new(tokio::net::UnixStream::connect(path).await?),
+        Address::Tcp { host, port } => {
+            Box::new(tokio::net::TcpStream::connect(format!("{host}:{port}")).await?)
+        }
+        other => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("cannot connect to a docker engine at {other}"),
+            ))
+        }
+    };
+
+    upgrade(stream, "/grpc").await
+}
+
+async fn upgrade<S: Io>(mut stream: S, path: &str) -> io::Result<S> {
+    let request = format!(
+        "POST {path} HTTP/1.1\r\n\
+         Host: docker\r\n\
+         Connection: Upgrade\r\n\
+         Upgrade: h2c\r\n\
+         Content-Length: 0\r\n\
+         \r\n"
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read one byte at a time, the HTTP/2 connection starts right after the
+    // headers and must be left in the stream
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_RESPONSE_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "docker engine response is too long",
+            ));
+        }
+        response.push(stream.read_u8().await?);
+    }
+
+    let response = String::from_utf8_lossy(&response);
+    let status = response.lines().next().unwrap_or_default();
+    match status.split_whitespace().nth(1) {
+        Some("101") => Ok(stream),
+        _ => Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("docker engine did not upgrade {path}: {status}"),
+        )),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use tokio::net::{UnixListener, UnixStream};
+
+    use super::*;
+    use crate::{util::test_util, Client};
+
+    /// Accept one connection and answer its upgrade request with `response`
+    async fn fake_engine(response: &'static str) -> (Address, tokio::task::JoinHandle<UnixStream>) {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("docker.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+
+        let engine = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                request.push(stream.read_u8().await.unwrap());
+            }
+            let request = String::from_utf8(request).unwrap();
+            assert!(request.starts_with("POST /grpc HTTP/1.1\r\n"));
+            assert!(request.contains("\r\nUpgrade: h2c\r\n"));
+
+            stream.write_all(response.as_bytes()).await.unwrap();
+            drop(dir);
+            stream
+        });
+
+        (Address::Unix(socket), engine)
+    }
+
+    #[tokio::test]
+    async fn grpc_upgrade() {
+        let (host, engine) =
+            fake_engine("HTTP/1.1 101 UPGRADED\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n")
+                .await;
+
+        tokio::spawn(async move {
+            let stream = engine.await.unwrap();
+            test_util::no_control()
+                .serve_with_incoming(futures::stream::iter([Ok::<_, io::Error>(stream)]))
+                .await
+        });
+
+        let address = Address::DockerEngine(Some(Box::new(host)));
+        let mut client = Client::connect_address(&address).await.unwrap();
+        let status = client.info().await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unimplemented);
+    }
+
+    #[test]
+    fn tls_docker_host() {
+        assert_eq!(
+            docker_host(None, true),
+            Ok(Address::Unix("/var/run/docker.sock".into()))
+        );
+        assert_eq!(
+            docker_host(Some("tcp://docker:2375"), false),
+            Ok(Address::Tcp {
+                host: "docker".into(),
+                port: 2375
+            })
+        );
+        assert_eq!(
+            docker_host(Some("tcp://docker:2376"), true),
+            Err(AddressError::DockerHostTls("tcp://docker:2376".into()))
+        );
+        assert!(matches!(
+            docker_host(Some("ssh://docker"), false),
+            Err(AddressError::UnsupportedDockerHost(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn upgrade_refused() {
+        let (host, _engine) = fake_engine(
+            "HTTP/1.1 404 Not Found\r\nContent-Type: text/plain\r\nContent-Length: 0\r\n\r\n",
+        )
+        .await;
+
+        let err = match docker_engine_connect(host).await {
+            Ok(_) => panic!("the upgrade should fail"),
+            Err(err) => err,
+        };
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(
+            err.to_string(),
+            "docker engine did not upgrade /grpc: HTTP/1.1 404 Not Found"
+        );
+    }
+}
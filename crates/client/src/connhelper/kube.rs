@@ -0,0 +1,141 @@
+use std::ffi::OsStr;
+use std::path::PathBuf;
+
+use tokio::{io, process::Command};
+
+use super::buildkit_stdio::BuildkitStdio;
+
+/// A pod running buildkitd, reached with `kubectl exec`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KubePod {
+    pub pod: String,
+    pub namespace: Option<String>,
+    pub context: Option<String>,
+    pub container: Option<String>,
+    pub kubeconfig: Option<PathBuf>,
+}
+
+impl KubePod {
+    pub fn new(pod: impl Into<String>) -> Self {
+        Self {
+            pod: pod.into(),
+            namespace: None,
+            context: None,
+            container: None,
+            kubeconfig: None,
+        }
+    }
+
+    pub fn with_namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.namespace = Some(namespace.into());
+        self
+    }
+
+    pub fn with_context(mut self, context: impl Into<String>) -> Self {
+        self.context = Some(context.into());
+        self
+    }
+
+    /// The container of the pod to exec in, required if the pod has more than one
+    pub fn with_container(mut self, container: impl Into<String>) -> Self {
+        self.container = Some(container.into());
+        self
+    }
+
+    pub fn with_kubeconfig(mut self, kubeconfig: impl Into<PathBuf>) -> Self {
+        self.kubeconfig = Some(kubeconfig.into());
+        self
+    }
+
+    /// The command to connect with, running `program` as `kubectl`
+    fn command(&self, program: impl AsRef<OsStr>) -> Command {
+        let mut command = Command::new(program);
+        if let Some(kubeconfig) = &self.kubeconfig {
+            command.arg("--kubeconfig").arg(kubeconfig);
+        }
+        if let Some(context) = &self.context {
+            command.arg("--context").arg(context);
+        }
+        if let Some(namespace) = &self.namespace {
+            command.arg("--namespace").arg(namespace);
+        }
+        command.arg("exec");
+        if let Some(container) = &self.container {
+            command.arg("--container").arg(container);
+        }
+        command
+            .arg("-i")
+            .arg(&self.pod)
+            .arg("--")
+            .arg("buildctl")
+            .arg("dial-stdio");
+        command
+    }
+}
+
+pub(crate) async fn kube_connect(pod: KubePod) -> io::Result<BuildkitStdio> {
+    spawn(pod.command("kubectl"))
+}
+
+/// Start a kubectl command, its stdio is the connection to the daemon
+fn spawn(mut command: Command) -> io::Result<BuildkitStdio> {
+    let child = command
+        .stdout(std::process::Stdio::piped())
+        .stdin(std::process::Stdio::piped())
+        .spawn()?;
+
+    Ok(BuildkitStdio::new(child))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::connhelper::tests::fake_bin;
+
+    #[tokio::test]
+    async fn fake_kubectl() {
+        // Records its arguments and echoes stdin like `buildctl dial-stdio` would
+        // forward it to the daemon
+        let dir = tempfile::tempdir().unwrap();
+        let kubectl = fake_bin(
+            dir.path(),
+            "kubectl",
+            "#!/bin/sh\nprintf '%s\\n' \"$@\" > \"$(dirname \"$0\")/kubectl.args\"\nexec cat\n",
+        );
+
+        let pod = KubePod::new("buildkitd-0")
+            .with_namespace("ci")
+            .with_context("staging")
+            .with_container("buildkitd")
+            .with_kubeconfig("/etc/kube/config");
+        let mut stdio = spawn(pod.command(kubectl)).unwrap();
+
+        stdio.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        stdio.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        let args = std::fs::read_to_string(dir.path().join("kubectl.args")).unwrap();
+        assert_eq!(
+            args.lines().collect::<Vec<_>>(),
+            [
+                "--kubeconfig",
+                "/etc/kube/config",
+                "--context",
+                "staging",
+                "--namespace",
+                "ci",
+                "exec",
+                "--container",
+                "buildkitd",
+                "-i",
+                "buildkitd-0",
+                "--",
+                "buildctl",
+                "dial-stdio",
+            ]
+        );
+    }
+}
@@ -1,3 +1,113 @@
+mod address;
 mod buildkit_stdio;
 pub(crate) mod docker;
+mod docker_engine;
+mod kube;
 pub(crate) mod podman;
+mod ssh;
+mod tls;
+
+pub use address::{Address, AddressError, BUILDKIT_HOST, DEFAULT_ADDRESS};
+pub use docker_engine::{DEFAULT_DOCKER_HOST, DOCKER_HOST};
+pub use kube::KubePod;
+pub use ssh::SshHost;
+pub use tls::{TlsError, TlsOptions};
+use tonic::transport::{Channel, Endpoint, Uri};
+use tower::service_fn;
+
+use crate::Error;
+
+/// Open a channel to the daemon at `address`, TLS is only supported for
+/// [`Address::Tcp`]
+pub(crate) async fn channel(address: &Address, tls: Option<&TlsOptions>) -> Result<Channel, Error> {
+    if tls.is_some() && !matches!(address, Address::Tcp { .. }) {
+        return Err(TlsError::UnsupportedAddress(address.to_string()).into());
+    }
+
+    // The URI is only used for the `:authority` header when there is a connector
+    let endpoint = Endpoint::from_static("http://[::1]:50051");
+
+    let channel = match address.clone() {
+        #[cfg(unix)]
+        Address::Unix(path) => {
+            endpoint
+                .connect_with_connector(service_fn(move |_: Uri| {
+                    tokio::net::UnixStream::connect(path.clone())
+                }))
+                .await?
+        }
+        #[cfg(not(unix))]
+        Address::Unix(_) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "unix sockets are not supported on this platform",
+            )
+            .into())
+        }
+        Address::Tcp { host, port } => match tls {
+            Some(tls) => {
+                Endpoint::from_shared(format!("https://{host}:{port}"))?
+                    .tls_config(tls.client_config()?)?
+                    .connect()
+                    .await?
+            }
+            None => {
+                Endpoint::from_shared(format!("http://{host}:{port}"))?
+                    .connect()
+                    .await?
+            }
+        },
+        Address::DockerContainer(container) => {
+            endpoint
+                .connect_with_connector(service_fn(move |_: Uri| {
+                    docker::docker_connect(container.clone())
+                }))
+                .await?
+        }
+        Address::PodmanContainer(container) => {
+            endpoint
+                .connect_with_connector(service_fn(move |_: Uri| {
+                    podman::podman_connect(container.clone())
+                }))
+                .await?
+        }
+        Address::DockerEngine(host) => {
+            let host = match host {
+                Some(host) => *host,
+                None => docker_engine::docker_host_from_env()?,
+            };
+            endpoint
+                .connect_with_connector(service_fn(move |_: Uri| {
+                    docker_engine::docker_engine_connect(host.clone())
+                }))
+                .await?
+        }
+        Address::Ssh(host) => {
+            endpoint
+                .connect_with_connector(service_fn(move |_: Uri| ssh::ssh_connect(host.clone())))
+                .await?
+        }
+        Address::KubePod(pod) => {
+            endpoint
+                .connect_with_connector(service_fn(move |_: Uri| kube::kube_connect(pod.clone())))
+                .await?
+        }
+    };
+
+    Ok(channel)
+}
+
+#[cfg(all(test, unix))]
+pub(crate) mod tests {
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::{Path, PathBuf};
+
+    /// Write an executable script `name` to `dir` to run in place of a
+    /// connection helper binary
+    pub(crate) fn fake_bin(dir: &Path, name: &str, script: &str) -> PathBuf {
+        let bin = dir.join(name);
+        std::fs::write(&bin, script).unwrap();
+        std::fs::set_permissions(&bin, std::fs::Permissions::from_mode(0o755)).unwrap();
+        bin
+    }
+}
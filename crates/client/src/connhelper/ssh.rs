@@ -0,0 +1,163 @@
+use std::ffi::OsStr;
+use std::path::PathBuf;
+
+use tokio::{io, process::Command};
+
+use super::buildkit_stdio::BuildkitStdio;
+
+/// A host running buildkitd, reached with `ssh`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SshHost {
+    pub host: String,
+    pub user: Option<String>,
+    pub port: Option<u16>,
+    pub identity: Option<PathBuf>,
+    /// Passed to `ssh` as `-o <option>`, e.g. `StrictHostKeyChecking=no`
+    pub options: Vec<String>,
+}
+
+impl SshHost {
+    pub fn new(host: impl Into<String>) -> Self {
+        Self {
+            host: host.into(),
+            user: None,
+            port: None,
+            identity: None,
+            options: Vec::new(),
+        }
+    }
+
+    pub fn with_user(mut self, user: impl Into<String>) -> Self {
+        self.user = Some(user.into());
+        self
+    }
+
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = Some(port);
+        self
+    }
+
+    /// The private key to authenticate with, like `ssh -i`
+    pub fn with_identity(mut self, identity: impl Into<PathBuf>) -> Self {
+        self.identity = Some(identity.into());
+        self
+    }
+
+    /// Add an option in the format of the ssh config file, like `ssh -o`
+    pub fn with_option(mut self, option: impl Into<String>) -> Self {
+        self.options.push(option.into());
+        self
+    }
+
+    /// The command to connect with, running `program` as `ssh`
+    fn command(&self, program: impl AsRef<OsStr>) -> Command {
+        let mut command = Command::new(program);
+        if let Some(user) = &self.user {
+            command.arg("-l").arg(user);
+        }
+        if let Some(port) = self.port {
+            command.arg("-p").arg(port.to_string());
+        }
+        if let Some(identity) = &self.identity {
+            command.arg("-i").arg(identity);
+        }
+        for option in &self.options {
+            command.arg("-o").arg(option);
+        }
+        command
+            .arg("--")
+            .arg(self.host.trim_start_matches('[').trim_end_matches(']'))
+            .arg("buildctl")
+            .arg("dial-stdio");
+        command
+    }
+}
+
+pub(crate) async fn ssh_connect(host: SshHost) -> io::Result<BuildkitStdio> {
+    spawn(host.command("ssh"))
+}
+
+/// Start an ssh command, its stdio is the connection to the daemon
+fn spawn(mut command: Command) -> io::Result<BuildkitStdio> {
+    let child = command
+        .stdout(std::process::Stdio::piped())
+        .stdin(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()?;
+
+    Ok(BuildkitStdio::new(child))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::connhelper::tests::fake_bin;
+
+    const FAKE_SSH: &str = r#"#!/bin/sh
+args=$(printf '%s\n' "$@")
+for arg; do shift; [ "$arg" = "--" ] && break; done
+if [ "$1" = "unreachable" ]; then
+    echo "ssh: connect to host unreachable port 22: Connection refused" >&2
+    exit 255
+fi
+echo "$args" > "$(dirname "$0")/ssh.args"
+exec head -c 4
+"#;
+
+    #[tokio::test]
+    async fn fake_ssh() {
+        let dir = tempfile::tempdir().unwrap();
+        let ssh = fake_bin(dir.path(), "ssh", FAKE_SSH);
+
+        let host = SshHost::new("[::1]")
+            .with_user("builder")
+            .with_port(2222)
+            .with_identity("/home/builder/.ssh/id_ed25519")
+            .with_option("StrictHostKeyChecking=no");
+        let mut stdio = spawn(host.command(ssh)).unwrap();
+
+        stdio.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        stdio.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        let args = std::fs::read_to_string(dir.path().join("ssh.args")).unwrap();
+        assert_eq!(
+            args.lines().collect::<Vec<_>>(),
+            [
+                "-l",
+                "builder",
+                "-p",
+                "2222",
+                "-i",
+                "/home/builder/.ssh/id_ed25519",
+                "-o",
+                "StrictHostKeyChecking=no",
+                "--",
+                "::1",
+                "buildctl",
+                "dial-stdio",
+            ]
+        );
+
+        // The fake exits after echoing, a clean exit is the end of the stream
+        assert_eq!(stdio.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn exit_with_stderr() {
+        let dir = tempfile::tempdir().unwrap();
+        let ssh = fake_bin(dir.path(), "ssh", FAKE_SSH);
+
+        let host = SshHost::new("unreachable");
+        let mut stdio = spawn(host.command(ssh)).unwrap();
+        let err = stdio.read(&mut [0; 4]).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "connection helper exited with exit status: 255: \
+             ssh: connect to host unreachable port 22: Connection refused"
+        );
+    }
+}
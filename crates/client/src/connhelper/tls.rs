@@ -0,0 +1,204 @@
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+use tonic::transport::{Certificate, ClientTlsConfig, Identity};
+
+#[derive(Debug, Error)]
+pub enum TlsError {
+    #[error("failed to read {path}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("no {expected} found in {path}")]
+    InvalidPem {
+        path: PathBuf,
+        expected: &'static str,
+    },
+    #[error("a client certificate requires a key")]
+    MissingKey,
+    #[error("a client key requires a certificate")]
+    MissingCert,
+    #[error("TLS is only supported for tcp:// addresses, not {0}")]
+    UnsupportedAddress(String),
+}
+
+/// TLS options for `tcp://` addresses, matching the `--tlscacert`,
+/// `--tlscert`, `--tlskey` and `--tlsservername` flags of `buildctl`.
+///
+/// Without a CA certificate the server is verified with the system roots,
+/// with a certificate and key the client authenticates itself (mTLS).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TlsOptions {
+    ca_cert: Option<PathBuf>,
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+    server_name: Option<String>,
+}
+
+impl TlsOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use the `ca.pem`, `cert.pem` and `key.pem` files of a directory, like
+    /// `buildctl --tlsdir`
+    pub fn from_dir(dir: impl AsRef<Path>) -> Self {
+        let dir = dir.as_ref();
+        Self::new()
+            .with_ca_cert(dir.join("ca.pem"))
+            .with_cert(dir.join("cert.pem"))
+            .with_key(dir.join("key.pem"))
+    }
+
+    /// The CA certificate to verify the server with
+    pub fn with_ca_cert(mut self, path: impl Into<PathBuf>) -> Self {
+        self.ca_cert = Some(path.into());
+        self
+    }
+
+    /// The client certificate, requires [`TlsOptions::with_key`]
+    pub fn with_cert(mut self, path: impl Into<PathBuf>) -> Self {
+        self.cert = Some(path.into());
+        self
+    }
+
+    /// The key of the client certificate
+    pub fn with_key(mut self, path: impl Into<PathBuf>) -> Self {
+        self.key = Some(path.into());
+        self
+    }
+
+    /// The name to verify the server certificate against, defaults to the
+    /// host of the address
+    pub fn with_server_name(mut self, server_name: impl Into<String>) -> Self {
+        self.server_name = Some(server_name.into());
+        self
+    }
+
+    /// Read the certificates and build the tonic config
+    pub(crate) fn client_config(&self) -> Result<ClientTlsConfig, TlsError> {
+        let mut config = ClientTlsConfig::new();
+
+        if let Some(path) = &self.ca_cert {
+            let pem = read_pem(path, "certificate", has_certs)?;
+            config = config.ca_certificate(Certificate::from_pem(pem));
+        }
+
+        match (&self.cert, &self.key) {
+            (Some(cert), Some(key)) => {
+                let cert = read_pem(cert, "certificate", has_certs)?;
+                let key = read_pem(key, "private key", has_key)?;
+                config = config.identity(Identity::from_pem(cert, key));
+            }
+            (Some(_), None) => return Err(TlsError::MissingKey),
+            (None, Some(_)) => return Err(TlsError::MissingCert),
+            (None, None) => {}
+        }
+
+        if let Some(server_name) = &self.server_name {
+            config = config.domain_name(server_name);
+        }
+
+        Ok(config)
+    }
+}
+
+fn read_pem(
+    path: &Path,
+    expected: &'static str,
+    check: fn(&[u8]) -> bool,
+) -> Result<Vec<u8>, TlsError> {
+    let pem = std::fs::read(path).map_err(|source| TlsError::Read {
+        path: path.into(),
+        source,
+    })?;
+
+    if !check(&pem) {
+        return Err(TlsError::InvalidPem {
+            path: path.into(),
+            expected,
+        });
+    }
+    Ok(pem)
+}
+
+fn has_certs(pem: &[u8]) -> bool {
+    rustls_pemfile::certs(&mut &pem[..]).is_ok_and(|certs| !certs.is_empty())
+}
+
+fn has_key(pem: &[u8]) -> bool {
+    use rustls_pemfile::Item;
+
+    rustls_pemfile::read_all(&mut &pem[..]).is_ok_and(|items| {
+        items
+            .iter()
+            .any(|item| matches!(item, Item::PKCS8Key(_) | Item::RSAKey(_) | Item::ECKey(_)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn testdata() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/tls")
+    }
+
+    #[test]
+    fn valid() {
+        assert!(TlsOptions::from_dir(testdata()).client_config().is_ok());
+        assert!(TlsOptions::new()
+            .with_ca_cert(testdata().join("ca.pem"))
+            .with_server_name("buildkitd")
+            .client_config()
+            .is_ok());
+    }
+
+    #[test]
+    fn errors() {
+        let err = TlsOptions::new()
+            .with_cert(testdata().join("cert.pem"))
+            .client_config()
+            .unwrap_err();
+        assert!(matches!(err, TlsError::MissingKey));
+
+        let err = TlsOptions::new()
+            .with_key(testdata().join("key.pem"))
+            .client_config()
+            .unwrap_err();
+        assert!(matches!(err, TlsError::MissingCert));
+
+        let err = TlsOptions::new()
+            .with_ca_cert(testdata().join("missing.pem"))
+            .client_config()
+            .unwrap_err();
+        assert!(matches!(err, TlsError::Read { .. }));
+
+        // A key where the certificate should be
+        let err = TlsOptions::new()
+            .with_ca_cert(testdata().join("key.pem"))
+            .client_config()
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            TlsError::InvalidPem {
+                expected: "certificate",
+                ..
+            }
+        ));
+
+        let err = TlsOptions::new()
+            .with_cert(testdata().join("cert.pem"))
+            .with_key(testdata().join("ca.pem"))
+            .client_config()
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            TlsError::InvalidPem {
+                expected: "private key",
+                ..
+            }
+        ));
+    }
+}
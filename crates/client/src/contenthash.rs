@@ -0,0 +1,370 @@
+//! Content checksums of a local context, computed the same way as BuildKit's
+//! `cache/contenthash` does for the files it receives from the session.
+//!
+//! The checksum only depends on what is sent to BuildKit, so it can be used to
+//! tell whether a `Local` source changed since the last build without solving.
+//!
+//! - Every file gets a header digest, a tarsum v1 header of its metadata. The
+//!   name and modification time are not part of it, and the owner is always
+//!   root as the session sends every file as owned by root.
+//! - The content of a regular file is hashed after its header.
+//! - A directory hashes its own header followed by the name and digest of each
+//!   of its children, sorted by name.
+//! - The root of the context has no header, as its metadata is not sent.
+//!
+//! Extended attributes are not read, so a context that relies on them will
+//! not match the checksum BuildKit computes.
+
+use std::fs::{self, File, Metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use crate::session::filesync::PathFilter;
+
+/// Computes the checksum of a local context
+#[derive(Debug, Clone)]
+pub struct ContentHasher {
+    root: PathBuf,
+    includes: Vec<String>,
+    excludes: Vec<String>,
+}
+
+impl ContentHasher {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            includes: Vec::new(),
+            excludes: Vec::new(),
+        }
+    }
+
+    /// Use the include patterns of the `Local` source
+    pub fn with_includes<I, S>(mut self, include: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.includes = include.into_iter().map(|s| s.as_ref().into()).collect();
+        self
+    }
+
+    /// Use the exclude patterns of the `Local` source
+    pub fn with_excludes<I, S>(mut self, exclude: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.excludes = exclude.into_iter().map(|s| s.as_ref().into()).collect();
+        self
+    }
+
+    /// The checksum of the whole context
+    pub fn checksum(&self) -> io::Result<String> {
+        let filter = self.filter();
+        let mut hasher = Sha256::new();
+        self.hash_children(&filter, Path::new(""), &mut hasher)?;
+        Ok(format!("sha256:{:x}", hasher.finalize()))
+    }
+
+    /// The checksum of a path inside of the context, like the one used for
+    /// the source of a copy
+    pub fn checksum_path(&self, path: impl AsRef<Path>) -> io::Result<String> {
+        let path = path_clean::clean(path.as_ref().strip_prefix("/").unwrap_or(path.as_ref()));
+        if path == Path::new(".") {
+            return self.checksum();
+        }
+
+        let filter = self.filter();
+        let is_dir = fs::symlink_metadata(self.root.join(&path)).is_ok_and(|m| m.is_dir());
+        if !filter.is_walked(&path, is_dir) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} is not part of the context", path.display()),
+            ));
+        }
+        self.digest(&filter, &path)
+    }
+
+    fn filter(&self) -> PathFilter {
+        PathFilter::new(self.includes.clone(), self.excludes.clone())
+    }
+
+    fn digest(&self, filter: &PathFilter, path: &Path) -> io::Result<String> {
+        let full_path = self.root.join(path);
+        let metadata = fs::symlink_metadata(&full_path)?;
+
+        let mut hasher = Sha256::new();
+        if metadata.is_dir() {
+            hasher.update(b"\0");
+            hasher.update(header_digest(&full_path, &metadata)?);
+            self.hash_children(filter, path, &mut hasher)?;
+        } else {
+            hasher.update(header(&full_path, &metadata)?);
+            if metadata.is_file() {
+                io::copy(&mut File::open(&full_path)?, &mut hasher)?;
+            }
+        }
+
+        Ok(format!("sha256:{:x}", hasher.finalize()))
+    }
+
+    fn hash_children(
+        &self,
+        filter: &PathFilter,
+        path: &Path,
+        hasher: &mut Sha256,
+    ) -> io::Result<()> {
+        let mut entries = fs::read_dir(self.root.join(path))?
+            .map(|entry| entry.and_then(|entry| Ok((entry.file_name(), entry.file_type()?))))
+            .collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by(|(a, _), (b, _)| a.as_encoded_bytes().cmp(b.as_encoded_bytes()));
+
+        for (name, file_type) in entries {
+            // Directories are part of the context if a path in them is
+            let child = path.join(&name);
+            if !filter.is_walked(&child, file_type.is_dir()) {
+                continue;
+            }
+
+            hasher.update(b"\0");
+            hasher.update(name.as_encoded_bytes());
+            hasher.update(self.digest(filter, &child)?);
+        }
+
+        Ok(())
+    }
+}
+
+fn header_digest(path: &Path, metadata: &Metadata) -> io::Result<String> {
+    Ok(format!(
+        "sha256:{:x}",
+        Sha256::digest(header(path, metadata)?)
+    ))
+}
+
+/// The tarsum v1 header of a file, the fields are written as key and value
+/// without any separator
+///
+/// <https://github.com/moby/buildkit/blob/master/cache/contenthash/tarsum.go>
+fn header(path: &Path, metadata: &Metadata) -> io::Result<Vec<u8>> {
+    let file_type = metadata.file_type();
+    let (mode, dev) = mode_and_dev(metadata);
+
+    let typeflag = if file_type.is_dir() {
+        '5'
+    } else if file_type.is_symlink() {
+        '2'
+    } else {
+        special_typeflag(metadata).unwrap_or('0')
+    };
+    let size = if typeflag == '0' { metadata.len() } else { 0 };
+    let linkname = if file_type.is_symlink() {
+        fs::read_link(path)?.to_string_lossy().into_owned()
+    } else {
+        String::new()
+    };
+    let (devmajor, devminor) = match typeflag {
+        '3' | '4' => (major(dev), minor(dev)),
+        _ => (0, 0),
+    };
+
+    let fields = [
+        ("name", String::new()),
+        ("mode", mode.to_string()),
+        ("uid", "0".into()),
+        ("gid", "0".into()),
+        ("size", size.to_string()),
+        ("typeflag", typeflag.to_string()),
+        ("linkname", linkname),
+        ("uname", String::new()),
+        ("gname", String::new()),
+        ("devmajor", devmajor.to_string()),
+        ("devminor", devminor.to_string()),
+    ];
+
+    Ok(fields
+        .into_iter()
+        .flat_map(|(key, value)| [key.as_bytes(), value.as_bytes()].concat())
+        .collect())
+}
+
+/// The permission, setuid, setgid and sticky bits, and the raw device number
+#[cfg(unix)]
+fn mode_and_dev(metadata: &Metadata) -> (u32, u64) {
+    use std::os::unix::fs::MetadataExt;
+
+    (metadata.mode() & 0o7777, metadata.rdev())
+}
+
+#[cfg(windows)]
+fn mode_and_dev(metadata: &Metadata) -> (u32, u64) {
+    use crate::util::file_mode::FileMode;
+
+    let mode = FileMode::from_metadata(metadata) & FileMode::MODE_PERM_MASK;
+    (mode.bits(), 0)
+}
+
+/// Devices and named pipes, sockets are hashed as empty regular files
+#[cfg(unix)]
+fn special_typeflag(metadata: &Metadata) -> Option<char> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let file_type = metadata.file_type();
+    if file_type.is_char_device() {
+        Some('3')
+    } else if file_type.is_block_device() {
+        Some('4')
+    } else if file_type.is_fifo() {
+        Some('6')
+    } else {
+        None
+    }
+}
+
+#[cfg(windows)]
+fn special_typeflag(_metadata: &Metadata) -> Option<char> {
+    None
+}
+
+fn major(dev: u64) -> u64 {
+    ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff)
+}
+
+fn minor(dev: u64) -> u64 {
+    (dev & 0xff) | ((dev >> 12) & !0xff)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("src/nested")).unwrap();
+        fs::create_dir_all(root.join("target")).unwrap();
+        fs::write(root.join("Cargo.toml"), "[package]\n").unwrap();
+        fs::write(root.join("src/main.rs"), "fn main() {}\n").unwrap();
+        fs::write(root.join("src/nested/mod.rs"), "").unwrap();
+        fs::write(root.join("target/out"), "binary").unwrap();
+        dir
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn file_header() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = context();
+        let root = dir.path();
+        let path = root.join("Cargo.toml");
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        let metadata = fs::symlink_metadata(&path).unwrap();
+        assert_eq!(
+            String::from_utf8(header(&path, &metadata).unwrap()).unwrap(),
+            "namemode420uid0gid0size10typeflag0linknameunamegnamedevmajor0devminor0"
+        );
+
+        let mut expected = header(&path, &metadata).unwrap();
+        expected.extend_from_slice(b"[package]\n");
+        assert_eq!(
+            ContentHasher::new(root)
+                .checksum_path("Cargo.toml")
+                .unwrap(),
+            format!("sha256:{:x}", Sha256::digest(expected))
+        );
+    }
+
+    #[test]
+    fn directory() {
+        let dir = context();
+        let root = dir.path();
+        let hasher = ContentHasher::new(root);
+
+        let path = root.join("src/nested");
+        let metadata = fs::symlink_metadata(&path).unwrap();
+        let mut expected = b"\0".to_vec();
+        expected.extend_from_slice(header_digest(&path, &metadata).unwrap().as_bytes());
+        expected.extend_from_slice(b"\0mod.rs");
+        expected.extend_from_slice(
+            hasher
+                .checksum_path("/src/nested/mod.rs")
+                .unwrap()
+                .as_bytes(),
+        );
+        assert_eq!(
+            hasher.checksum_path("src/nested").unwrap(),
+            format!("sha256:{:x}", Sha256::digest(expected))
+        );
+    }
+
+    /// The digests in `TestChecksumBasicFile` of BuildKit's
+    /// `cache/contenthash/checksum_test.go`, files are created with mode 0644
+    /// and directories with 0755
+    #[cfg(unix)]
+    #[test]
+    fn buildkit_digests() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("d0")).unwrap();
+        for (name, data) in [("foo", "data0"), ("bar", "data1"), ("d0/abc", "data0")] {
+            fs::write(root.join(name), data).unwrap();
+            fs::set_permissions(root.join(name), fs::Permissions::from_mode(0o644)).unwrap();
+        }
+
+        let hasher = ContentHasher::new(root);
+        let data0 = "sha256:cd8e75bca50f2d695f220d0cb0997d8ead387e4f926e8669a92d7f104cc9885b";
+        assert_eq!(hasher.checksum_path("foo").unwrap(), data0);
+        assert_eq!(
+            hasher.checksum_path("bar").unwrap(),
+            "sha256:c2b5e234f5f38fc5864da7def04782f82501a40d46192e4207d5b3f0c3c4732b"
+        );
+        // The name of a file is not part of its digest
+        assert_eq!(hasher.checksum_path("d0/abc").unwrap(), data0);
+
+        // `dgstDirD0`, with the symlinks of the test
+        std::os::unix::fs::symlink("abc", root.join("d0/def")).unwrap();
+        std::os::unix::fs::symlink("nosuchfile", root.join("d0/ghi")).unwrap();
+        fs::set_permissions(root.join("d0"), fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(
+            hasher.checksum_path("d0").unwrap(),
+            "sha256:d47454417d2c554067fbefe5f5719edc49f3cfe969c36b62e34a187a4da0cc9a"
+        );
+    }
+
+    #[test]
+    fn filtering() {
+        let dir = context();
+        let root = dir.path();
+        let all = ContentHasher::new(root);
+        let excluded = ContentHasher::new(root).with_excludes(["target"]);
+        let included = ContentHasher::new(root).with_includes(["src/main.rs"]);
+
+        let before = (
+            all.checksum().unwrap(),
+            excluded.checksum().unwrap(),
+            included.checksum().unwrap(),
+        );
+        assert_eq!(before.0, all.checksum_path("/").unwrap());
+        assert_ne!(before.0, before.1);
+        assert!(excluded.checksum_path("target").is_err());
+
+        // Changes to filtered files don't change the checksum
+        fs::write(root.join("target/out"), "rebuilt").unwrap();
+        assert_ne!(all.checksum().unwrap(), before.0);
+        assert_eq!(excluded.checksum().unwrap(), before.1);
+        assert_eq!(included.checksum().unwrap(), before.2);
+
+        fs::write(root.join("src/nested/mod.rs"), "mod a;").unwrap();
+        assert_ne!(excluded.checksum().unwrap(), before.1);
+        assert_eq!(included.checksum().unwrap(), before.2);
+
+        fs::write(root.join("src/main.rs"), "fn main() { }\n").unwrap();
+        assert_ne!(included.checksum().unwrap(), before.2);
+    }
+}
@@ -0,0 +1,165 @@
+use std::fmt;
+
+use buildkit_rs_proto::pb::{self, op::Op as OpEnum, NetMode, SecurityMode};
+use prost::Message;
+use thiserror::Error;
+
+/// A privilege a solve has to request, which the daemon must also allow with
+/// `--allow-insecure-entitlement`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Entitlement {
+    /// Execs using the network of the host
+    NetworkHost,
+    /// Execs running privileged
+    SecurityInsecure,
+}
+
+impl Entitlement {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Entitlement::NetworkHost => "network.host",
+            Entitlement::SecurityInsecure => "security.insecure",
+        }
+    }
+}
+
+impl fmt::Display for Entitlement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// What to do with entitlements a definition needs but the solve does not request
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EntitlementPolicy {
+    /// Fail before the solve is sent
+    #[default]
+    Strict,
+    /// Request them
+    Permissive,
+}
+
+#[derive(Debug, Error)]
+pub enum EntitlementError {
+    #[error("{op} needs the {entitlement} entitlement, which the solve does not request")]
+    Missing {
+        entitlement: Entitlement,
+        op: String,
+    },
+    #[error("failed to decode op at position {position}: {source}")]
+    Decode {
+        position: usize,
+        #[source]
+        source: prost::DecodeError,
+    },
+}
+
+/// The entitlements of a solve, checked against what its definitions need.
+///
+/// Only definitions sent with the solve are checked, not the ones a frontend
+/// creates in the daemon.
+pub(crate) fn resolve<'a>(
+    requested: &[Entitlement],
+    policy: EntitlementPolicy,
+    definitions: impl IntoIterator<Item = &'a pb::Definition>,
+) -> Result<Vec<String>, EntitlementError> {
+    let mut entitlements = requested.to_vec();
+
+    for definition in definitions {
+        for (entitlement, op) in required(definition)? {
+            if entitlements.contains(&entitlement) {
+                continue;
+            }
+            match policy {
+                EntitlementPolicy::Strict => {
+                    return Err(EntitlementError::Missing { entitlement, op })
+                }
+                EntitlementPolicy::Permissive => entitlements.push(entitlement),
+            }
+        }
+    }
+
+    entitlements.sort();
+    entitlements.dedup();
+    Ok(entitlements
+        .iter()
+        .map(|entitlement| entitlement.as_str().to_owned())
+        .collect())
+}
+
+/// The entitlements the execs of a definition need, with a description of the exec
+fn required(definition: &pb::Definition) -> Result<Vec<(Entitlement, String)>, EntitlementError> {
+    let mut required = Vec::new();
+
+    for (position, bytes) in definition.def.iter().enumerate() {
+        let op = pb::Op::decode(bytes.as_slice())
+            .map_err(|source| EntitlementError::Decode { position, source })?;
+        let Some(OpEnum::Exec(exec)) = op.op else {
+            continue;
+        };
+
+        let describe = || match &exec.meta {
+            Some(meta) => format!("exec {:?}", meta.args),
+            None => format!("exec at position {position}"),
+        };
+        if exec.network() == NetMode::Host {
+            required.push((Entitlement::NetworkHost, describe()));
+        }
+        if exec.security() == SecurityMode::Insecure {
+            required.push((Entitlement::SecurityInsecure, describe()));
+        }
+    }
+
+    Ok(required)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use buildkit_rs_llb::{self as llb, Definition, Exec, Image, Mount, SingleOutput};
+
+    use super::*;
+
+    /// A definition with an exec using the network of the host
+    fn host_network() -> pb::Definition {
+        let image = Arc::new(Image::new("alpine:latest"));
+        let exec = Arc::new(
+            Exec::shlex("wget http://localhost:8080")
+                .with_network(llb::NetMode::Host)
+                .with_mount(Mount::layer(image.output(), "/")),
+        );
+        Definition::new(exec.root().unwrap()).into_pb()
+    }
+
+    #[test]
+    fn strict() {
+        let def = host_network();
+
+        let err = resolve(&[], EntitlementPolicy::Strict, [&def]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"exec ["wget", "http://localhost:8080"] needs the network.host entitlement, which the solve does not request"#
+        );
+
+        let entitlements = resolve(
+            &[Entitlement::SecurityInsecure, Entitlement::NetworkHost],
+            EntitlementPolicy::Strict,
+            [&def],
+        )
+        .unwrap();
+        assert_eq!(entitlements, ["network.host", "security.insecure"]);
+    }
+
+    #[test]
+    fn permissive() {
+        let def = host_network();
+        let entitlements = resolve(&[], EntitlementPolicy::Permissive, [&def]).unwrap();
+        assert_eq!(entitlements, ["network.host"]);
+
+        let image = Arc::new(Image::new("alpine:latest"));
+        let def = Definition::new(image.output()).into_pb();
+        let entitlements = resolve(&[], EntitlementPolicy::Permissive, [&def]).unwrap();
+        assert!(entitlements.is_empty());
+    }
+}
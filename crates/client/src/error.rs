@@ -6,4 +6,28 @@ pub enum Error {
     TonicTransport(#[from] tonic::transport::Error),
     #[error(transparent)]
     Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Status(#[from] tonic::Status),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Join(#[from] tokio::task::JoinError),
+    #[error(transparent)]
+    Reference(#[from] buildkit_rs_reference::Error),
+    #[error(transparent)]
+    SourcePolicy(#[from] buildkit_rs_llb::SourcePolicyError),
+    #[error(transparent)]
+    Address(#[from] crate::connhelper::AddressError),
+    #[error("session {0} was not started by this client and cannot transfer exports or caches")]
+    UnknownSession(String),
+    #[error("session {0} is already exporting locally for another solve")]
+    ExportInProgress(String),
+    #[error(transparent)]
+    Tls(#[from] crate::connhelper::TlsError),
+    #[error(transparent)]
+    Cache(#[from] crate::cache::CacheError),
+    #[error("a solve cannot have both a definition and a frontend")]
+    DefinitionAndFrontend,
+    #[error(transparent)]
+    Entitlement(#[from] crate::entitlements::EntitlementError),
 }
@@ -0,0 +1,280 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+
+use oci_spec::image::ImageConfiguration;
+use tokio::io::AsyncWrite;
+
+use crate::EXPORTER_IMAGE_CONFIG_KEY;
+
+/// Where the result of a solve is exported to
+#[derive(Debug)]
+pub enum Exporter {
+    /// An image in the image store of the daemon, optionally pushed to a registry
+    Image(ImageExport),
+    /// An OCI image layout tarball
+    Oci(ImageTarball),
+    /// A tarball that can be loaded with `docker load`
+    Docker(ImageTarball),
+    /// The files of the result, written to a directory
+    Local(PathBuf),
+    /// The files of the result as a tarball
+    Tar(ExportOutput),
+}
+
+/// The file or writer a tarball is exported to
+pub enum ExportOutput {
+    File(PathBuf),
+    Writer(Box<dyn AsyncWrite + Send + Unpin>),
+}
+
+impl fmt::Debug for ExportOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File(path) => f.debug_tuple("File").field(path).finish(),
+            Self::Writer(_) => f.debug_tuple("Writer").finish_non_exhaustive(),
+        }
+    }
+}
+
+/// The layer compression of an exported image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Uncompressed,
+    Gzip,
+    Estargz,
+    Zstd,
+}
+
+impl Compression {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Compression::Uncompressed => "uncompressed",
+            Compression::Gzip => "gzip",
+            Compression::Estargz => "estargz",
+            Compression::Zstd => "zstd",
+        }
+    }
+}
+
+/// The options shared by the image exporters
+#[derive(Debug, Clone, Default)]
+pub struct ImageOptions {
+    pub names: Vec<String>,
+    pub compression: Option<Compression>,
+    pub compression_level: Option<u32>,
+    /// Recompress layers that are already compressed differently
+    pub force_compression: bool,
+    pub oci_mediatypes: Option<bool>,
+    /// The image config, exported as `containerimage.config`
+    pub config: Option<ImageConfiguration>,
+}
+
+impl ImageOptions {
+    fn attrs(&self) -> Result<HashMap<String, String>, serde_json::Error> {
+        let mut attrs = HashMap::new();
+        if !self.names.is_empty() {
+            attrs.insert("name".into(), self.names.join(","));
+        }
+        if let Some(compression) = self.compression {
+            attrs.insert("compression".into(), compression.as_str().into());
+        }
+        if let Some(level) = self.compression_level {
+            attrs.insert("compression-level".into(), level.to_string());
+        }
+        if self.force_compression {
+            attrs.insert("force-compression".into(), "true".into());
+        }
+        if let Some(oci_mediatypes) = self.oci_mediatypes {
+            attrs.insert("oci-mediatypes".into(), oci_mediatypes.to_string());
+        }
+        if let Some(config) = &self.config {
+            attrs.insert(
+                EXPORTER_IMAGE_CONFIG_KEY.into(),
+                serde_json::to_string(config)?,
+            );
+        }
+        Ok(attrs)
+    }
+}
+
+/// Options of [`Exporter::Image`]
+#[derive(Debug, Clone, Default)]
+pub struct ImageExport {
+    pub options: ImageOptions,
+    pub push: bool,
+    /// Push to a registry over plain HTTP or with an untrusted certificate
+    pub insecure_registry: bool,
+}
+
+impl ImageExport {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self::default().with_name(name)
+    }
+
+    /// Add a name to tag the image with
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.options.names.push(name.into());
+        self
+    }
+
+    pub fn with_push(mut self, push: bool) -> Self {
+        self.push = push;
+        self
+    }
+
+    pub fn with_insecure_registry(mut self, insecure: bool) -> Self {
+        self.insecure_registry = insecure;
+        self
+    }
+
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.options.compression = Some(compression);
+        self
+    }
+
+    pub fn with_config(mut self, config: ImageConfiguration) -> Self {
+        self.options.config = Some(config);
+        self
+    }
+}
+
+/// Options of [`Exporter::Oci`] and [`Exporter::Docker`]
+#[derive(Debug)]
+pub struct ImageTarball {
+    pub options: ImageOptions,
+    pub output: ExportOutput,
+}
+
+impl ImageTarball {
+    pub fn new(output: ExportOutput) -> Self {
+        Self {
+            options: ImageOptions::default(),
+            output,
+        }
+    }
+
+    /// Add a name to tag the image with
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.options.names.push(name.into());
+        self
+    }
+
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.options.compression = Some(compression);
+        self
+    }
+
+    pub fn with_config(mut self, config: ImageConfiguration) -> Self {
+        self.options.config = Some(config);
+        self
+    }
+}
+
+/// What the daemon sends to the FileSend service of the session
+#[derive(Debug)]
+pub(crate) enum ExportTarget {
+    Output(ExportOutput),
+    Directory(PathBuf),
+}
+
+impl Exporter {
+    /// The name of the exporter in the daemon
+    pub fn name(&self) -> &'static str {
+        match self {
+            Exporter::Image(_) => "image",
+            Exporter::Oci(_) => "oci",
+            Exporter::Docker(_) => "docker",
+            Exporter::Local(_) => "local",
+            Exporter::Tar(_) => "tar",
+        }
+    }
+
+    /// The exporter attributes of the solve request
+    pub fn attrs(&self) -> Result<HashMap<String, String>, serde_json::Error> {
+        match self {
+            Exporter::Image(image) => {
+                let mut attrs = image.options.attrs()?;
+                if image.push {
+                    attrs.insert("push".into(), "true".into());
+                }
+                if image.insecure_registry {
+                    attrs.insert("registry.insecure".into(), "true".into());
+                }
+                Ok(attrs)
+            }
+            Exporter::Oci(tarball) | Exporter::Docker(tarball) => tarball.options.attrs(),
+            Exporter::Local(_) | Exporter::Tar(_) => Ok(HashMap::new()),
+        }
+    }
+
+    /// The local output of the exporter, if it has one
+    pub(crate) fn into_target(self) -> Option<ExportTarget> {
+        match self {
+            Exporter::Image(_) => None,
+            Exporter::Oci(tarball) | Exporter::Docker(tarball) => {
+                Some(ExportTarget::Output(tarball.output))
+            }
+            Exporter::Local(dest) => Some(ExportTarget::Directory(dest)),
+            Exporter::Tar(output) => Some(ExportTarget::Output(output)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_attrs() {
+        let exporter = Exporter::Image(
+            ImageExport::new("registry.example.com/app:latest")
+                .with_name("registry.example.com/app:v1")
+                .with_push(true)
+                .with_compression(Compression::Zstd),
+        );
+        assert_eq!(exporter.name(), "image");
+
+        let attrs = exporter.attrs().unwrap();
+        assert_eq!(
+            attrs,
+            HashMap::from([
+                (
+                    "name".into(),
+                    "registry.example.com/app:latest,registry.example.com/app:v1".into()
+                ),
+                ("push".into(), "true".into()),
+                ("compression".into(), "zstd".into()),
+            ])
+        );
+        assert!(exporter.into_target().is_none());
+    }
+
+    #[test]
+    fn tarball_targets() {
+        let config = oci_spec::image::ImageConfigurationBuilder::default()
+            .build()
+            .unwrap();
+        let exporter = Exporter::Docker(
+            ImageTarball::new(ExportOutput::File("image.tar".into()))
+                .with_name("app")
+                .with_config(config),
+        );
+
+        let attrs = exporter.attrs().unwrap();
+        assert_eq!(attrs["name"], "app");
+        assert!(attrs[EXPORTER_IMAGE_CONFIG_KEY].starts_with('{'));
+        assert!(matches!(
+            exporter.into_target(),
+            Some(ExportTarget::Output(ExportOutput::File(path))) if path == std::path::Path::new("image.tar")
+        ));
+
+        let exporter = Exporter::Tar(ExportOutput::Writer(Box::new(tokio::io::sink())));
+        assert_eq!(exporter.name(), "tar");
+        assert!(exporter.attrs().unwrap().is_empty());
+        assert!(matches!(
+            exporter.into_target(),
+            Some(ExportTarget::Output(ExportOutput::Writer(_)))
+        ));
+    }
+}
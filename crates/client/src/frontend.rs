@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+
+use buildkit_rs_llb::{Definition, Platform};
+
+/// The frontend built into the daemon that builds Dockerfiles
+pub const DOCKERFILE_FRONTEND: &str = "dockerfile.v0";
+
+/// The frontend that runs a frontend image, set with the `source` attribute
+pub const GATEWAY_FRONTEND: &str = "gateway.v0";
+
+/// A frontend that creates the definition of a solve in the daemon, like
+/// `buildctl build --frontend`
+#[derive(Debug)]
+pub struct Frontend {
+    pub name: String,
+    pub attrs: HashMap<String, String>,
+    /// Definitions the frontend reads by name, e.g. as a named context
+    pub inputs: HashMap<String, Definition>,
+}
+
+impl Frontend {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            attrs: HashMap::new(),
+            inputs: HashMap::new(),
+        }
+    }
+
+    /// Build a Dockerfile with the frontend built into the daemon
+    pub fn dockerfile(options: DockerfileOptions) -> Self {
+        options.apply(Self::new(DOCKERFILE_FRONTEND))
+    }
+
+    /// Build a Dockerfile with a frontend image such as `docker/dockerfile:1`,
+    /// like a `# syntax=` directive
+    pub fn dockerfile_image(image: impl Into<String>, options: DockerfileOptions) -> Self {
+        options.apply(Self::new(GATEWAY_FRONTEND).with_attr("source", image))
+    }
+
+    pub fn with_attr(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.attrs.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn with_input(mut self, name: impl Into<String>, definition: Definition) -> Self {
+        self.inputs.insert(name.into(), definition);
+        self
+    }
+}
+
+/// A named context of a Dockerfile, used by `FROM <name>` and `COPY --from=<name>`
+#[derive(Debug)]
+pub enum NamedContext {
+    /// An image from a registry
+    Image(String),
+    /// A local source of the session by its name
+    Local(String),
+    /// A definition, sent as a frontend input
+    Input(Definition),
+}
+
+/// Options of the Dockerfile frontend.
+///
+/// Without `context` and `dockerfile`, they are read from the `context` and
+/// `dockerfile` local sources of the session.
+#[derive(Debug, Default)]
+pub struct DockerfileOptions {
+    /// The name of the Dockerfile in its directory, `Dockerfile` if `None`
+    pub filename: Option<String>,
+    /// The stage to build, the last one if `None`
+    pub target: Option<String>,
+    pub build_args: HashMap<String, String>,
+    pub labels: HashMap<String, String>,
+    /// Build for these platforms instead of the daemon's
+    pub platforms: Vec<Platform>,
+    pub no_cache: NoCache,
+    pub contexts: HashMap<String, NamedContext>,
+    /// The build context as a definition instead of a local source
+    pub context: Option<Definition>,
+    /// The directory of the Dockerfile as a definition instead of a local source
+    pub dockerfile: Option<Definition>,
+}
+
+/// Which stages of a Dockerfile are built without the cache
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum NoCache {
+    #[default]
+    None,
+    All,
+    Stages(Vec<String>),
+}
+
+impl DockerfileOptions {
+    pub fn with_filename(mut self, filename: impl Into<String>) -> Self {
+        self.filename = Some(filename.into());
+        self
+    }
+
+    pub fn with_target(mut self, target: impl Into<String>) -> Self {
+        self.target = Some(target.into());
+        self
+    }
+
+    pub fn with_build_arg(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.build_args.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn with_label(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.labels.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn with_platform(mut self, platform: Platform) -> Self {
+        self.platforms.push(platform);
+        self
+    }
+
+    pub fn with_no_cache(mut self, no_cache: NoCache) -> Self {
+        self.no_cache = no_cache;
+        self
+    }
+
+    pub fn with_context(mut self, name: impl Into<String>, context: NamedContext) -> Self {
+        self.contexts.insert(name.into(), context);
+        self
+    }
+
+    pub fn with_context_definition(mut self, context: Definition) -> Self {
+        self.context = Some(context);
+        self
+    }
+
+    pub fn with_dockerfile_definition(mut self, dockerfile: Definition) -> Self {
+        self.dockerfile = Some(dockerfile);
+        self
+    }
+
+    fn apply(self, mut frontend: Frontend) -> Frontend {
+        let attrs = &mut frontend.attrs;
+        if let Some(filename) = self.filename {
+            attrs.insert("filename".into(), filename);
+        }
+        if let Some(target) = self.target {
+            attrs.insert("target".into(), target);
+        }
+        for (key, value) in self.build_args {
+            attrs.insert(format!("build-arg:{key}"), value);
+        }
+        for (key, value) in self.labels {
+            attrs.insert(format!("label:{key}"), value);
+        }
+        if !self.platforms.is_empty() {
+            let platforms: Vec<_> = self.platforms.iter().map(Platform::to_string).collect();
+            attrs.insert("platform".into(), platforms.join(","));
+        }
+        match self.no_cache {
+            NoCache::None => {}
+            NoCache::All => {
+                attrs.insert("no-cache".into(), String::new());
+            }
+            NoCache::Stages(stages) => {
+                attrs.insert("no-cache".into(), stages.join(","));
+            }
+        }
+
+        for (name, context) in self.contexts {
+            let value = match context {
+                NamedContext::Image(image) => format!("docker-image://{image}"),
+                NamedContext::Local(local) => format!("local:{local}"),
+                NamedContext::Input(definition) => {
+                    frontend.inputs.insert(name.clone(), definition);
+                    format!("input:{name}")
+                }
+            };
+            frontend.attrs.insert(format!("context:{name}"), value);
+        }
+
+        if let Some(context) = self.context {
+            frontend.inputs.insert("context".into(), context);
+        }
+        if let Some(dockerfile) = self.dockerfile {
+            frontend.inputs.insert("dockerfile".into(), dockerfile);
+        }
+        frontend
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use buildkit_rs_llb::{Image, SingleOutput};
+
+    use super::*;
+
+    #[test]
+    fn dockerfile_attrs() {
+        let frontend = Frontend::dockerfile(
+            DockerfileOptions::default()
+                .with_filename("Dockerfile.release")
+                .with_target("release")
+                .with_build_arg("VERSION", "1.2.3")
+                .with_platform(Platform::LINUX_ARM64)
+                .with_no_cache(NoCache::Stages(vec!["deps".into()]))
+                .with_context("alpine", NamedContext::Image("alpine:3.18".into()))
+                .with_context("assets", NamedContext::Local("assets".into()))
+                .with_context(
+                    "base",
+                    NamedContext::Input(Definition::new(
+                        Arc::new(Image::new("debian:bookworm")).output(),
+                    )),
+                ),
+        );
+
+        assert_eq!(frontend.name, "dockerfile.v0");
+        assert_eq!(
+            frontend.attrs,
+            HashMap::from([
+                ("filename".into(), "Dockerfile.release".into()),
+                ("target".into(), "release".into()),
+                ("build-arg:VERSION".into(), "1.2.3".into()),
+                ("platform".into(), "linux/arm64".into()),
+                ("no-cache".into(), "deps".into()),
+                ("context:alpine".into(), "docker-image://alpine:3.18".into()),
+                ("context:assets".into(), "local:assets".into()),
+                ("context:base".into(), "input:base".into()),
+            ])
+        );
+        assert_eq!(frontend.inputs.keys().collect::<Vec<_>>(), ["base"]);
+    }
+
+    #[test]
+    fn dockerfile_image() {
+        let frontend = Frontend::dockerfile_image(
+            "docker/dockerfile:1.5",
+            DockerfileOptions::default().with_no_cache(NoCache::All),
+        );
+        assert_eq!(frontend.name, "gateway.v0");
+        assert_eq!(frontend.attrs["source"], "docker/dockerfile:1.5");
+        assert_eq!(frontend.attrs["no-cache"], "");
+    }
+}
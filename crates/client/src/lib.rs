@@ -1,40 +1,61 @@
+pub mod cache;
 pub mod connhelper;
+pub mod contenthash;
+pub mod entitlements;
 pub(crate) mod error;
+pub mod exporter;
+pub mod frontend;
 pub mod session;
 pub(crate) mod util;
 
 use std::collections::HashMap;
 use std::fmt::Debug;
+use std::future::Future;
 use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Weak};
 
-use buildkit_rs_llb::Definition;
+use buildkit_rs_llb::{
+    Definition, ImageConfig, ImageMetaResolver, Local, MultiPlatformDefinition, Platform,
+    ResolveMode, ResolvedImageConfig, SourcePolicy,
+};
+use buildkit_rs_proto::containerd::services::content::v1::content_server::ContentServer;
+use buildkit_rs_proto::google::rpc;
 use buildkit_rs_proto::moby::buildkit::secrets::v1::secrets_server::SecretsServer;
+use buildkit_rs_proto::moby::buildkit::v1::frontend::{
+    self as gateway, llb_bridge_client::LlbBridgeClient, result, Ref, RefMap,
+    ResolveImageConfigRequest, ReturnRequest,
+};
 use buildkit_rs_proto::moby::buildkit::v1::BytesMessage;
 use buildkit_rs_proto::moby::buildkit::v1::{
     control_client::ControlClient, DiskUsageRequest, DiskUsageResponse, InfoRequest, InfoResponse,
-    ListWorkersRequest, ListWorkersResponse, SolveResponse,
+    ListWorkersRequest, ListWorkersResponse, SolveRequest, SolveResponse,
 };
 use buildkit_rs_proto::moby::buildkit::v1::{StatusRequest, StatusResponse};
 use buildkit_rs_proto::moby::filesync::v1::auth_server::AuthServer;
 use buildkit_rs_proto::moby::filesync::v1::file_sync_server::FileSyncServer;
+use buildkit_rs_proto::pb;
+use buildkit_rs_reference::Reference;
 use buildkit_rs_util::oci::OciBackend;
-use connhelper::{docker::docker_connect, podman::podman_connect};
-use error::Error;
+use cache::{CacheBackend, CacheExport, CacheRequest};
+use connhelper::{Address, TlsOptions};
+use entitlements::{Entitlement, EntitlementPolicy};
+use exporter::Exporter;
+use frontend::Frontend;
 use futures::stream::StreamExt;
-use session::filesend::FileSendService;
+use session::content::{ContentStoreService, ContentStores};
+use session::filesend::{ExportGuard, ExportSlot, FileSendService};
 use session::secret::SecretSource;
 use session::{auth::AuthService, filesync::FileSyncService};
 use tokio::io::AsyncWriteExt;
 use tokio_util::io::ReaderStream;
-use tonic::{
-    transport::{Channel, Uri},
-    Request, Response,
-};
+use tonic::{transport::Channel, Request, Response};
 use tonic::{Status, Streaming};
-use tower::{service_fn, ServiceBuilder};
+use tower::ServiceBuilder;
 use tower_http::ServiceBuilderExt;
 use tracing::{debug, info};
 
+pub use crate::error::Error;
 use crate::session::secret::SecretService;
 pub use crate::util::id::random_id;
 
@@ -42,12 +63,58 @@ const HEADER_SESSION_ID: &str = "x-docker-expose-session-uuid";
 const HEADER_SESSION_NAME: &str = "x-docker-expose-session-name";
 const HEADER_SESSION_SHARED_KEY: &str = "x-docker-expose-session-sharedkey";
 const HEADER_SESSION_METHOD: &str = "x-docker-expose-session-grpc-method";
+const HEADER_BUILD_ID: &str = "buildkit-controlapi-buildid";
+
+pub(crate) const EXPORTER_IMAGE_CONFIG_KEY: &str = "containerimage.config";
+const EXPORTER_PLATFORMS_KEY: &str = "refs.platforms";
+
+#[derive(Debug)]
+pub struct SolveOptions {
+    pub id: String,
+    pub session: String,
+    /// The definition to solve, a solve has either a definition or a frontend
+    pub definition: Option<Definition>,
+    pub frontend: Option<Frontend>,
+    /// Applied to the definition and frontend inputs before they are
+    /// submitted and enforced by buildkit during the solve
+    pub source_policy: Option<SourcePolicy>,
+    /// Where the result is exported to, it is only kept in the cache if `None`.
+    /// Exporters with a local output need a session started by this client,
+    /// which exports locally for one solve at a time.
+    pub exporter: Option<Exporter>,
+    /// Caches the build is exported to, local caches need a session started
+    /// by this client like local exporters
+    pub cache_exports: Vec<CacheExport>,
+    /// Caches the build can reuse steps from
+    pub cache_imports: Vec<CacheBackend>,
+    /// Privileges the solve requests, which the daemon must also allow
+    pub entitlements: Vec<Entitlement>,
+    /// Whether entitlements the definition needs are added to `entitlements`
+    /// or fail the solve before it is sent
+    pub entitlement_policy: EntitlementPolicy,
+}
 
 #[derive(Debug)]
-pub struct SolveOptions<'a> {
+pub struct MultiPlatformSolveOptions {
     pub id: String,
     pub session: String,
-    pub definition: Definition<'a>,
+    pub definition: MultiPlatformDefinition,
+    /// The image config of each platform, exported as `containerimage.config`
+    pub image_configs: HashMap<Platform, oci_spec::image::ImageConfiguration>,
+    /// Where the result is exported to, see [`SolveOptions::exporter`]
+    pub exporter: Option<Exporter>,
+    /// Caches the build is exported to, see [`SolveOptions::cache_exports`]
+    pub cache_exports: Vec<CacheExport>,
+    /// Caches the solve of each platform can reuse steps from
+    pub cache_imports: Vec<CacheBackend>,
+    /// Applied to each platform's definition before it is submitted and
+    /// enforced by buildkit during the solve
+    pub source_policy: Option<SourcePolicy>,
+    /// Privileges the solve requests, which the daemon must also allow
+    pub entitlements: Vec<Entitlement>,
+    /// Whether entitlements the definition of any platform needs are added
+    /// to `entitlements` or fail the solve before it is sent
+    pub entitlement_policy: EntitlementPolicy,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -61,102 +128,243 @@ pub struct Session {
     pub id: String,
 }
 
+impl Session {
+    /// Create a local source that is only read from this session, so
+    /// concurrent builds on one daemon don't read each other's files
+    pub fn local(&self, name: impl Into<String>) -> Local {
+        self.bind_local(Local::new(name.into()))
+    }
+
+    /// Bind an existing local source to this session
+    pub fn bind_local(&self, local: Local) -> Local {
+        local.with_session_id(&self.id)
+    }
+}
+
+/// The exporter of a solve as it is sent to the daemon
+#[derive(Debug, Default)]
+struct SolveExporter {
+    name: String,
+    attrs: HashMap<String, String>,
+    /// Keeps the export target of a local output until the solve returns
+    guard: Option<ExportGuard>,
+}
+
+/// What the solves using a session started by this client pass to its services
 #[derive(Debug)]
-pub struct Client(ControlClient<Channel>);
+struct SessionSlots {
+    export: Weak<ExportSlot>,
+    content_stores: Weak<Mutex<HashMap<String, PathBuf>>>,
+}
+
+#[derive(Debug)]
+pub struct Client {
+    control: ControlClient<Channel>,
+    bridge: LlbBridgeClient<Channel>,
+    /// The sessions started by this client, by id
+    sessions: HashMap<String, SessionSlots>,
+}
 
 impl Client {
+    /// Connect to buildkitd in a container by running `buildctl dial-stdio` in it
     pub async fn connect(backend: OciBackend, container_name: String) -> Result<Client, Error> {
-        let channel = Channel::from_static("http://[::1]:50051")
-            .connect_with_connector(service_fn(move |_: Uri| {
-                let container_name = container_name.clone();
-                async move {
-                    match backend {
-                        OciBackend::Docker => docker_connect(container_name).await,
-                        OciBackend::Podman => podman_connect(container_name).await,
-                    }
-                }
-            }))
-            .await?;
+        let address = match backend {
+            OciBackend::Docker => Address::DockerContainer(container_name),
+            OciBackend::Podman => Address::PodmanContainer(container_name),
+        };
+        Self::connect_address(&address).await
+    }
 
-        Ok(Client(ControlClient::new(channel)))
+    /// Connect to a `buildctl` style address such as `unix:///run/buildkit/buildkitd.sock`
+    /// or `tcp://buildkitd:1234`, defaulting to `BUILDKIT_HOST` when it is `None`
+    pub async fn connect_url(address: Option<&str>) -> Result<Client, Error> {
+        let address = match address {
+            Some(address) => address.parse()?,
+            None => Address::from_env()?,
+        };
+        Self::connect_address(&address).await
+    }
+
+    pub async fn connect_address(address: &Address) -> Result<Client, Error> {
+        Ok(Self::from_channel(
+            connhelper::channel(address, None).await?,
+        ))
+    }
+
+    /// Connect to a `tcp://` address over TLS, like `buildctl --tlscacert`
+    pub async fn connect_tls(address: &Address, tls: &TlsOptions) -> Result<Client, Error> {
+        Ok(Self::from_channel(
+            connhelper::channel(address, Some(tls)).await?,
+        ))
+    }
+
+    fn from_channel(channel: Channel) -> Client {
+        Client {
+            control: ControlClient::new(channel.clone()),
+            bridge: LlbBridgeClient::new(channel),
+            sessions: HashMap::new(),
+        }
     }
 
     pub async fn info(&mut self) -> Result<InfoResponse, tonic::Status> {
-        self.0.info(InfoRequest {}).await.map(Response::into_inner)
+        self.control
+            .info(InfoRequest {})
+            .await
+            .map(Response::into_inner)
     }
 
     pub async fn disk_usage(&mut self) -> Result<DiskUsageResponse, tonic::Status> {
-        self.0
+        self.control
             .disk_usage(DiskUsageRequest { filter: vec![] })
             .await
             .map(Response::into_inner)
     }
 
     pub async fn list_workers(&mut self) -> Result<ListWorkersResponse, tonic::Status> {
-        self.0
+        self.control
             .list_workers(ListWorkersRequest { filter: vec![] })
             .await
             .map(Response::into_inner)
     }
 
-    pub async fn solve(
-        &mut self,
-        options: SolveOptions<'_>,
-    ) -> Result<SolveResponse, tonic::Status> {
-        let config = oci_spec::image::ConfigBuilder::default()
-            .user("root".to_string())
-            // .working_dir(job.working_directory.clone())
-            .env(["ABC=123".to_owned()])
-            .cmd(["/bin/bash".to_owned()])
-            // .entrypoint(["/app/hello-world".to_owned()])
-            .build()
-            .unwrap();
-
-        let image_config = oci_spec::image::ImageConfigurationBuilder::default()
-            .config(config)
-            .build()
-            .unwrap();
-
-        let json = serde_json::to_string(&image_config).unwrap();
-
-        self.0
+    pub async fn solve(&mut self, options: SolveOptions) -> Result<SolveResponse, Error> {
+        let source_policy = options.source_policy.as_ref();
+        let to_pb = |definition: &Definition| match source_policy {
+            Some(policy) => policy.evaluate(&definition.into_pb()),
+            None => Ok(definition.into_pb()),
+        };
+        let definition = options.definition.as_ref().map(to_pb).transpose()?;
+
+        let SolveExporter {
+            name: exporter,
+            attrs: exporter_attrs,
+            guard: _export,
+        } = self.exporter(&options.session, options.exporter)?;
+
+        let cache = self.cache(
+            &options.session,
+            options.cache_exports,
+            options.cache_imports,
+        )?;
+
+        let (frontend, mut frontend_attrs, frontend_inputs) = match options.frontend {
+            Some(_) if definition.is_some() => return Err(Error::DefinitionAndFrontend),
+            Some(frontend) => {
+                let mut inputs = HashMap::new();
+                for (name, input) in &frontend.inputs {
+                    inputs.insert(name.clone(), to_pb(input)?);
+                }
+                (frontend.name, frontend.attrs, inputs)
+            }
+            None => Default::default(),
+        };
+        if !frontend.is_empty() {
+            frontend_attrs.extend(cache.frontend_attrs());
+        }
+
+        let entitlements = entitlements::resolve(
+            &options.entitlements,
+            options.entitlement_policy,
+            definition.iter().chain(frontend_inputs.values()),
+        )?;
+
+        let res = self
+            .control
             .solve(Request::new(
                 buildkit_rs_proto::moby::buildkit::v1::SolveRequest {
                     r#ref: options.id,
-                    definition: Some(options.definition.into_pb()),
-                    frontend_attrs: [("no-cache".to_owned(), "".to_owned())]
-                        .into_iter()
-                        .collect(),
+                    definition,
+                    frontend,
+                    frontend_attrs,
+                    frontend_inputs,
                     session: options.session,
-                    exporter: "docker".to_owned(),
-                    exporter_attrs: [
-                        ("name".into(), "test".into()),
-                        ("containerimage.config".into(), json),
-                    ]
-                    .into_iter()
-                    .collect(),
-                    // frontend: todo!(),
-                    // cache: todo!(),
-                    // entitlements: todo!(),
-                    // frontend_inputs: todo!(),
+                    exporter,
+                    exporter_attrs,
+                    cache: Some(cache.options.clone()),
+                    entitlements,
                     // internal: todo!(),
-                    // source_policy: todo!(),
+                    source_policy: options.source_policy.as_ref().map(SourcePolicy::to_pb),
                     ..Default::default()
                 },
             ))
-            .await
-            .map(|res| res.into_inner())
+            .await?
+            .into_inner();
+
+        cache.update_indices(&res.exporter_response)?;
+        Ok(res)
+    }
+
+    /// The exporter of a solve, an exporter with a local output is set as the
+    /// export target of the session
+    #[allow(clippy::result_large_err)]
+    fn exporter(&self, session: &str, exporter: Option<Exporter>) -> Result<SolveExporter, Error> {
+        let exporter = match exporter {
+            Some(exporter) => exporter,
+            None => return Ok(Default::default()),
+        };
+
+        let name = exporter.name().to_owned();
+        let attrs = exporter.attrs()?;
+        let guard = match exporter.into_target() {
+            Some(target) => {
+                let slot = self
+                    .sessions
+                    .get(session)
+                    .and_then(|slots| slots.export.upgrade())
+                    .ok_or_else(|| Error::UnknownSession(session.into()))?;
+                let guard = slot
+                    .set(target)
+                    .ok_or_else(|| Error::ExportInProgress(session.into()))?;
+                Some(guard)
+            }
+            None => None,
+        };
+        Ok(SolveExporter { name, attrs, guard })
+    }
+
+    /// The caches of a solve, local caches are served by the session
+    #[allow(clippy::result_large_err)]
+    fn cache(
+        &self,
+        session: &str,
+        exports: Vec<CacheExport>,
+        imports: Vec<CacheBackend>,
+    ) -> Result<CacheRequest, Error> {
+        let cache = CacheRequest::new(exports, imports)?;
+        if !cache.content_stores.is_empty() {
+            let stores = self
+                .sessions
+                .get(session)
+                .and_then(|slots| slots.content_stores.upgrade())
+                .ok_or_else(|| Error::UnknownSession(session.into()))?;
+            stores.lock().unwrap().extend(cache.content_stores.clone());
+        }
+        Ok(cache)
     }
 
     pub async fn session(&mut self, options: SessionOptions) -> Result<Session, tonic::Status> {
         let (server_stream, client_stream) = tokio::io::duplex(4096);
+        let id = random_id();
 
         let (mut health_reporter, health_server) = tonic_health::server::health_reporter();
 
+        let export_slot = Arc::<ExportSlot>::default();
+        let content_stores = ContentStores::default();
+        self.sessions
+            .retain(|_, slots| slots.export.strong_count() > 0);
+        self.sessions.insert(
+            id.clone(),
+            SessionSlots {
+                export: Arc::downgrade(&export_slot),
+                content_stores: Arc::downgrade(&content_stores),
+            },
+        );
+
         let auth = AuthService::new().into_server();
         let file_sync = FileSyncService::new(options.local).into_server();
-        let file_send = FileSendService::new().into_server();
+        let file_send = FileSendService::new(export_slot).into_server();
         let secret = SecretService::new(options.secrets).into_server();
+        let content = ContentStoreService::new(content_stores).into_server();
 
         health_reporter
             .set_serving::<AuthServer<AuthService>>()
@@ -174,6 +382,10 @@ impl Client {
             .set_serving::<SecretsServer<SecretService>>()
             .await;
 
+        health_reporter
+            .set_serving::<ContentServer<ContentStoreService>>()
+            .await;
+
         let layer = ServiceBuilder::new().trace_for_grpc().into_inner();
 
         tokio::spawn(async move {
@@ -185,6 +397,7 @@ impl Client {
                 .add_service(file_sync)
                 .add_service(file_send)
                 .add_service(secret)
+                .add_service(content)
                 .serve_with_incoming(futures::stream::iter(vec![Ok::<_, std::io::Error>(
                     server_stream,
                 )]))
@@ -237,8 +450,6 @@ impl Client {
             data: bytes.unwrap().to_vec(),
         }));
 
-        let id = random_id();
-
         request
             .metadata_mut()
             .append(HEADER_SESSION_ID, id.parse().expect("valid header value"));
@@ -318,7 +529,26 @@ impl Client {
                 .expect("valid header value"),
         );
 
-        let res = self.0.session(request).await?;
+        for method in [
+            "Info",
+            "Update",
+            "List",
+            "Delete",
+            "Read",
+            "Status",
+            "ListStatuses",
+            "Write",
+            "Abort",
+        ] {
+            request.metadata_mut().append(
+                HEADER_SESSION_METHOD,
+                format!("/containerd.services.content.v1.Content/{method}")
+                    .parse()
+                    .expect("valid header value"),
+            );
+        }
+
+        let res = self.control.session(request).await?;
 
         tokio::spawn(async move {
             let mut inner = res.into_inner();
@@ -349,17 +579,412 @@ impl Client {
     }
 
     pub async fn status(&mut self, id: String) -> Result<Streaming<StatusResponse>, Status> {
-        self.0
+        self.control
             .status(StatusRequest { r#ref: id })
             .await
             .map(Response::into_inner)
     }
+
+    /// Resolve the config of an image with the gateway `ResolveImageConfig` API
+    pub async fn resolve_image_config(
+        &mut self,
+        reference: &Reference,
+        platform: Option<&Platform>,
+        resolve_mode: ResolveMode,
+    ) -> Result<ResolvedImageConfig, Error> {
+        let id = random_id();
+
+        // A solve without a definition or a frontend is registered with the
+        // gateway forwarder, which serves the LLB bridge for the build id
+        let mut control = self.control.clone();
+        let build = tokio::spawn({
+            let id = id.clone();
+            async move {
+                control
+                    .solve(SolveRequest {
+                        r#ref: id,
+                        ..Default::default()
+                    })
+                    .await
+            }
+        });
+
+        let res = self
+            .bridge
+            .resolve_image_config(build_request(
+                &id,
+                ResolveImageConfigRequest {
+                    r#ref: reference.to_string(),
+                    platform: platform.map(Platform::to_pb),
+                    resolve_mode: resolve_mode.as_str().into(),
+                    ..Default::default()
+                },
+            ))
+            .await;
+
+        // Always return so the build finishes, even if resolving failed
+        self.bridge
+            .r#return(build_request(
+                &id,
+                ReturnRequest {
+                    result: Some(Default::default()),
+                    error: None,
+                },
+            ))
+            .await?;
+
+        match build.await {
+            Ok(Ok(_)) => {}
+            Ok(Err(err)) => debug!(?err, "Resolve image config build failed"),
+            Err(err) => debug!(?err, "Resolve image config build panicked"),
+        }
+
+        let res = res?.into_inner();
+
+        Ok(ResolvedImageConfig {
+            digest: res.digest,
+            config: ImageConfig::from_json(&res.config)?,
+        })
+    }
+
+    /// Solve a definition for each platform in a single build, so the
+    /// exporter produces one multi-platform image
+    pub async fn solve_multi_platform(
+        &mut self,
+        options: MultiPlatformSolveOptions,
+    ) -> Result<SolveResponse, Error> {
+        let MultiPlatformSolveOptions {
+            id,
+            session,
+            definition,
+            image_configs,
+            exporter,
+            cache_exports,
+            cache_imports,
+            source_policy,
+            entitlements,
+            entitlement_policy,
+        } = options;
+
+        let mut definitions = vec![];
+        for (platform, def) in definition.into_definitions() {
+            let def = match &source_policy {
+                Some(policy) => policy.evaluate(&def)?,
+                None => def,
+            };
+            definitions.push((platform, def));
+        }
+        let entitlements = entitlements::resolve(
+            &entitlements,
+            entitlement_policy,
+            definitions.iter().map(|(_, def)| def),
+        )?;
+
+        let SolveExporter {
+            name: exporter,
+            attrs: exporter_attrs,
+            guard: _export,
+        } = self.exporter(&session, exporter)?;
+        let cache = self.cache(&session, cache_exports, cache_imports)?;
+
+        let policy = source_policy.as_ref().map(SourcePolicy::to_pb);
+        let mut control = self.control.clone();
+        let build = tokio::spawn({
+            let id = id.clone();
+            let cache_options = cache.options.clone();
+            async move {
+                control
+                    .solve(SolveRequest {
+                        r#ref: id,
+                        session,
+                        exporter,
+                        exporter_attrs,
+                        cache: Some(cache_options),
+                        entitlements,
+                        source_policy: policy,
+                        ..Default::default()
+                    })
+                    .await
+            }
+        });
+
+        let (result, error) = match self
+            .solve_platforms(&id, definitions, image_configs, cache.gateway_imports())
+            .await
+        {
+            Ok(result) => (Some(result), None),
+            Err(err) => (
+                None,
+                Some(rpc::Status {
+                    code: match &err {
+                        Error::Status(status) => status.code() as i32,
+                        _ => tonic::Code::Unknown as i32,
+                    },
+                    message: err.to_string(),
+                    details: vec![],
+                }),
+            ),
+        };
+
+        self.bridge
+            .r#return(build_request(&id, ReturnRequest { result, error }))
+            .await?;
+
+        let res = build.await??.into_inner();
+        cache.update_indices(&res.exporter_response)?;
+        Ok(res)
+    }
+
+    /// Solve each definition through the LLB bridge of the build with `id`
+    /// and collect the refs into a multi-platform result
+    async fn solve_platforms(
+        &mut self,
+        id: &str,
+        definitions: Vec<(Platform, pb::Definition)>,
+        image_configs: HashMap<Platform, oci_spec::image::ImageConfiguration>,
+        cache_imports: Vec<gateway::CacheOptionsEntry>,
+    ) -> Result<gateway::Result, Error> {
+        let mut refs = vec![];
+
+        for (platform, def) in definitions {
+            let res = self
+                .bridge
+                .solve(build_request(
+                    id,
+                    gateway::SolveRequest {
+                        definition: Some(def),
+                        allow_result_return: true,
+                        allow_result_array_ref: true,
+                        cache_imports: cache_imports.clone(),
+                        ..Default::default()
+                    },
+                ))
+                .await?
+                .into_inner();
+
+            let platform_ref = match res.result.and_then(|res| res.result) {
+                Some(result::Result::Ref(platform_ref)) => platform_ref,
+                Some(result::Result::RefDeprecated(ref_id)) => Ref {
+                    id: ref_id,
+                    def: None,
+                },
+                _ => {
+                    return Err(Status::internal(format!(
+                        "solve for {platform} did not return a single ref"
+                    ))
+                    .into())
+                }
+            };
+            refs.push((platform, platform_ref));
+        }
+
+        Ok(multi_platform_result(refs, &image_configs)?)
+    }
+}
+
+/// The result of a multi-platform build in the format of BuildKit's exporters,
+/// a ref and an image config per platform id and the platforms in `refs.platforms`
+fn multi_platform_result(
+    refs: Vec<(Platform, Ref)>,
+    image_configs: &HashMap<Platform, oci_spec::image::ImageConfiguration>,
+) -> Result<gateway::Result, serde_json::Error> {
+    let mut ref_map = HashMap::new();
+    let mut metadata = HashMap::new();
+    let mut platforms = vec![];
+
+    for (platform, platform_ref) in refs {
+        let platform_id = platform.to_string();
+        if let Some(config) = image_configs.get(&platform) {
+            metadata.insert(
+                format!("{EXPORTER_IMAGE_CONFIG_KEY}/{platform_id}"),
+                serde_json::to_vec(config)?,
+            );
+        }
+
+        // Empty fields are omitted like the OCI platform of Go
+        let mut spec = serde_json::json!({
+            "architecture": platform.architecture,
+            "os": platform.os,
+        });
+        if let Some(variant) = &platform.variant {
+            spec["variant"] = variant.as_ref().into();
+        }
+        if let Some(os_version) = &platform.os_version {
+            spec["os.version"] = os_version.as_ref().into();
+        }
+        if !platform.os_features.is_empty() {
+            spec["os.features"] = platform.os_features.clone().into();
+        }
+        platforms.push(serde_json::json!({ "ID": platform_id, "Platform": spec }));
+        ref_map.insert(platform_id, platform_ref);
+    }
+
+    metadata.insert(
+        EXPORTER_PLATFORMS_KEY.into(),
+        serde_json::to_vec(&serde_json::json!({ "Platforms": platforms }))?,
+    );
+
+    Ok(gateway::Result {
+        result: Some(result::Result::Refs(RefMap { refs: ref_map })),
+        metadata,
+        ..Default::default()
+    })
+}
+
+impl ImageMetaResolver for Client {
+    type Error = Error;
+
+    fn resolve_image_config<'a>(
+        &'a mut self,
+        reference: &'a Reference,
+        platform: Option<&'a Platform>,
+        resolve_mode: ResolveMode,
+    ) -> Pin<Box<dyn Future<Output = Result<ResolvedImageConfig, Self::Error>> + Send + 'a>> {
+        Box::pin(Client::resolve_image_config(
+            self,
+            reference,
+            platform,
+            resolve_mode,
+        ))
+    }
+}
+
+/// Create a request to the LLB bridge of the build with `id`
+fn build_request<T>(id: &str, message: T) -> Request<T> {
+    let mut request = Request::new(message);
+    request
+        .metadata_mut()
+        .append(HEADER_BUILD_ID, id.parse().expect("valid header value"));
+    request
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn connect_unix_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("buildkitd.sock");
+        util::test_util::serve_unix(util::test_util::no_control(), &socket);
+
+        let address = format!("unix://{}", socket.display());
+        let mut client = Client::connect_url(Some(&address)).await.unwrap();
+        let status = client.info().await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unimplemented);
+    }
+
+    #[tokio::test]
+    async fn connect_mtls() {
+        use tonic::transport::{Certificate, Identity, ServerTlsConfig};
+
+        let testdata = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/tls");
+        let read = |name: &str| std::fs::read(testdata.join(name)).unwrap();
+
+        let tls = ServerTlsConfig::new()
+            .identity(Identity::from_pem(
+                read("server-cert.pem"),
+                read("server-key.pem"),
+            ))
+            .client_ca_root(Certificate::from_pem(read("ca.pem")));
+        let router = tonic::transport::Server::builder()
+            .tls_config(tls)
+            .unwrap()
+            .add_service(util::test_util::health_service());
+        let port = util::test_util::serve_tcp(router).await;
+
+        let address = Address::Tcp {
+            host: "127.0.0.1".into(),
+            port,
+        };
+        let tls = TlsOptions::from_dir(&testdata).with_server_name("buildkitd");
+        let mut client = Client::connect_tls(&address, &tls).await.unwrap();
+        let status = client.info().await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unimplemented);
+
+        // The server requires a client certificate
+        let tls = TlsOptions::new()
+            .with_ca_cert(testdata.join("ca.pem"))
+            .with_server_name("buildkitd");
+        let rejected = match Client::connect_tls(&address, &tls).await {
+            Ok(mut client) => client.info().await.unwrap_err().code() != tonic::Code::Unimplemented,
+            Err(_) => true,
+        };
+        assert!(rejected);
+    }
+
+    #[tokio::test]
+    async fn tls_requires_tcp() {
+        let address = Address::Unix("/run/buildkit/buildkitd.sock".into());
+        let err = Client::connect_tls(&address, &TlsOptions::new())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Tls(connhelper::TlsError::UnsupportedAddress(_))
+        ));
+    }
+
+    #[test]
+    fn multi_platform_metadata() {
+        let arm = Platform::LINUX_ARMHF;
+        let config = oci_spec::image::ImageConfiguration::default();
+        let refs = vec![
+            (
+                Platform::LINUX_AMD64,
+                Ref {
+                    id: "amd64-ref".into(),
+                    def: None,
+                },
+            ),
+            (
+                arm.clone(),
+                Ref {
+                    id: "arm-ref".into(),
+                    def: None,
+                },
+            ),
+        ];
+        let image_configs = HashMap::from([(arm, config)]);
+
+        let res = multi_platform_result(refs, &image_configs).unwrap();
+        let Some(result::Result::Refs(RefMap { refs })) = res.result else {
+            panic!("expected a ref map");
+        };
+        assert_eq!(refs["linux/amd64"].id, "amd64-ref");
+        assert_eq!(refs["linux/arm/v7"].id, "arm-ref");
+
+        let mut keys: Vec<_> = res.metadata.keys().collect();
+        keys.sort();
+        assert_eq!(
+            keys,
+            ["containerimage.config/linux/arm/v7", "refs.platforms"]
+        );
+
+        let exported: oci_spec::image::ImageConfiguration =
+            serde_json::from_slice(&res.metadata["containerimage.config/linux/arm/v7"]).unwrap();
+        assert_eq!(exported, image_configs[&Platform::LINUX_ARMHF]);
+
+        let platforms: serde_json::Value =
+            serde_json::from_slice(&res.metadata["refs.platforms"]).unwrap();
+        assert_eq!(
+            platforms,
+            serde_json::json!({
+                "Platforms": [
+                    {
+                        "ID": "linux/amd64",
+                        "Platform": { "architecture": "amd64", "os": "linux" },
+                    },
+                    {
+                        "ID": "linux/arm/v7",
+                        "Platform": { "architecture": "arm", "os": "linux", "variant": "v7" },
+                    },
+                ]
+            })
+        );
+    }
+
     #[tokio::test]
     async fn test_connect() {
         std::env::set_var("RUST_LOG", "debug");
@@ -0,0 +1,758 @@
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use buildkit_rs_proto::containerd::services::content::v1::{
+    content_server::{Content, ContentServer},
+    AbortRequest, DeleteContentRequest, Info, InfoRequest, InfoResponse, ListContentRequest,
+    ListContentResponse, ListStatusesRequest, ListStatusesResponse, ReadContentRequest,
+    ReadContentResponse, Status as IngestStatus, StatusRequest, StatusResponse, UpdateRequest,
+    UpdateResponse, WriteAction, WriteContentRequest, WriteContentResponse,
+};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::mpsc::Sender;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::metadata::MetadataMap;
+use tonic::{Request, Response, Status, Streaming};
+use tracing::error;
+
+/// The metadata with the id of the store a request is for
+const STORE_ID_HEADER: &str = "buildkit-attachable-store-id";
+
+const READ_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// The content stores served by a session, by id, set by the solves using it
+pub(crate) type ContentStores = Arc<Mutex<HashMap<String, PathBuf>>>;
+
+/// A write in progress, its data is in the ingest directory of the store
+#[derive(Debug, Clone)]
+struct Ingest {
+    started_at: SystemTime,
+    updated_at: SystemTime,
+    total: i64,
+    expected: String,
+}
+
+type Ingests = Arc<Mutex<HashMap<(PathBuf, String), Ingest>>>;
+
+/// Serves OCI image layouts on the client as containerd content stores, which
+/// is how the daemon reads and writes local caches.
+///
+/// Blobs are stored in `blobs/sha256/<hex>` of the layout like containerd's
+/// local store, so the layouts can be used by other tools.
+#[derive(Debug)]
+pub(crate) struct ContentStoreService {
+    stores: ContentStores,
+    ingests: Ingests,
+}
+
+impl ContentStoreService {
+    pub fn new(stores: ContentStores) -> Self {
+        Self {
+            stores,
+            ingests: Default::default(),
+        }
+    }
+
+    pub fn into_server(self) -> ContentServer<Self> {
+        ContentServer::new(self)
+    }
+
+    /// The root of the store a request is for
+    #[allow(clippy::result_large_err)]
+    fn store(&self, metadata: &MetadataMap) -> Result<PathBuf, Status> {
+        let id = metadata
+            .get(STORE_ID_HEADER)
+            .and_then(|id| id.to_str().ok())
+            .ok_or_else(|| Status::invalid_argument(format!("missing {STORE_ID_HEADER}")))?;
+        self.stores
+            .lock()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .ok_or_else(|| Status::not_found(format!("content store {id} not found")))
+    }
+
+    async fn ingest_status(&self, root: &Path, reference: &str) -> Option<IngestStatus> {
+        let key = (root.to_owned(), reference.to_owned());
+        let ingest = self.ingests.lock().unwrap().get(&key).cloned()?;
+        let offset = tokio::fs::metadata(ingest_dir(root, reference).join("data"))
+            .await
+            .map(|metadata| metadata.len() as i64)
+            .unwrap_or_default();
+
+        Some(IngestStatus {
+            started_at: Some(ingest.started_at.into()),
+            updated_at: Some(ingest.updated_at.into()),
+            r#ref: reference.into(),
+            offset,
+            total: ingest.total,
+            expected: ingest.expected,
+        })
+    }
+}
+
+#[tonic::async_trait]
+impl Content for ContentStoreService {
+    type ListStream =
+        futures::stream::Once<futures::future::Ready<Result<ListContentResponse, Status>>>;
+    type ReadStream = ReceiverStream<Result<ReadContentResponse, Status>>;
+    type WriteStream = ReceiverStream<Result<WriteContentResponse, Status>>;
+
+    #[tracing::instrument(skip_all)]
+    async fn info(&self, request: Request<InfoRequest>) -> Result<Response<InfoResponse>, Status> {
+        let root = self.store(request.metadata())?;
+        let info = blob_info(&root, &request.into_inner().digest).await?;
+        Ok(Response::new(InfoResponse { info: Some(info) }))
+    }
+
+    async fn update(
+        &self,
+        _request: Request<UpdateRequest>,
+    ) -> Result<Response<UpdateResponse>, Status> {
+        Err(Status::failed_precondition(
+            "update not supported on immutable content store",
+        ))
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn list(
+        &self,
+        request: Request<ListContentRequest>,
+    ) -> Result<Response<Self::ListStream>, Status> {
+        let root = self.store(request.metadata())?;
+        // The blobs have no labels, which most filters are about
+        if !request.into_inner().filters.is_empty() {
+            return Err(Status::unimplemented("filters are not supported"));
+        }
+
+        let mut info = Vec::new();
+        let dir = root.join("blobs/sha256");
+        match tokio::fs::read_dir(&dir).await {
+            Ok(mut entries) => {
+                while let Some(entry) = entries.next_entry().await.map_err(io_error(&dir))? {
+                    let digest = format!("sha256:{}", entry.file_name().to_string_lossy());
+                    if let Ok(blob) = blob_info(&root, &digest).await {
+                        info.push(blob);
+                    }
+                }
+            }
+            // Nothing was written to the store yet
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(io_error(&dir)(err)),
+        }
+
+        Ok(Response::new(futures::stream::once(
+            futures::future::ready(Ok(ListContentResponse { info })),
+        )))
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn delete(&self, request: Request<DeleteContentRequest>) -> Result<Response<()>, Status> {
+        let root = self.store(request.metadata())?;
+        let digest = request.into_inner().digest;
+        let path = blob_path(&root, &digest)?;
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => Ok(Response::new(())),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Err(not_found(&digest)),
+            Err(err) => Err(io_error(&path)(err)),
+        }
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn read(
+        &self,
+        request: Request<ReadContentRequest>,
+    ) -> Result<Response<Self::ReadStream>, Status> {
+        let root = self.store(request.metadata())?;
+        let ReadContentRequest {
+            digest,
+            offset,
+            size,
+        } = request.into_inner();
+
+        let path = blob_path(&root, &digest)?;
+        let mut file = match tokio::fs::File::open(&path).await {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Err(not_found(&digest))
+            }
+            Err(err) => return Err(io_error(&path)(err)),
+        };
+
+        let offset = offset.max(0) as u64;
+        file.seek(SeekFrom::Start(offset))
+            .await
+            .map_err(io_error(&path))?;
+        // A size of zero or less reads the rest of the blob
+        let mut remaining = match size {
+            size if size > 0 => size as u64,
+            _ => u64::MAX,
+        };
+
+        let (tx, rx) = tokio::sync::mpsc::channel(4);
+        tokio::spawn(async move {
+            let mut offset = offset as i64;
+            while remaining > 0 {
+                let mut data = vec![0; READ_CHUNK_SIZE.min(remaining as usize)];
+                let n = match file.read(&mut data).await {
+                    Ok(0) => break,
+                    Ok(n) => n,
+                    Err(err) => {
+                        let _ = tx.send(Err(io_error(&path)(err))).await;
+                        break;
+                    }
+                };
+                data.truncate(n);
+
+                let response = ReadContentResponse { offset, data };
+                if tx.send(Ok(response)).await.is_err() {
+                    break;
+                }
+                offset += n as i64;
+                remaining -= n as u64;
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn status(
+        &self,
+        request: Request<StatusRequest>,
+    ) -> Result<Response<StatusResponse>, Status> {
+        let root = self.store(request.metadata())?;
+        let reference = request.into_inner().r#ref;
+        match self.ingest_status(&root, &reference).await {
+            Some(status) => Ok(Response::new(StatusResponse {
+                status: Some(status),
+            })),
+            None => Err(Status::not_found(format!(
+                "no write in progress for {reference}"
+            ))),
+        }
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn list_statuses(
+        &self,
+        request: Request<ListStatusesRequest>,
+    ) -> Result<Response<ListStatusesResponse>, Status> {
+        let root = self.store(request.metadata())?;
+        if !request.into_inner().filters.is_empty() {
+            return Err(Status::unimplemented("filters are not supported"));
+        }
+        let references: Vec<_> = self
+            .ingests
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|(store, _)| *store == root)
+            .map(|(_, reference)| reference.clone())
+            .collect();
+
+        let mut statuses = Vec::new();
+        for reference in &references {
+            statuses.extend(self.ingest_status(&root, reference).await);
+        }
+        Ok(Response::new(ListStatusesResponse { statuses }))
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn write(
+        &self,
+        request: Request<Streaming<WriteContentRequest>>,
+    ) -> Result<Response<Self::WriteStream>, Status> {
+        let root = self.store(request.metadata())?;
+        let stream = request.into_inner();
+        let ingests = self.ingests.clone();
+
+        let (tx, rx) = tokio::sync::mpsc::channel(4);
+        tokio::spawn(async move {
+            if let Err(err) = write(&root, &ingests, stream, &tx).await {
+                error!(?err, "Error writing content");
+                let _ = tx.send(Err(err)).await;
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn abort(&self, request: Request<AbortRequest>) -> Result<Response<()>, Status> {
+        let root = self.store(request.metadata())?;
+        let reference = request.into_inner().r#ref;
+
+        let key = (root.clone(), reference.clone());
+        if self.ingests.lock().unwrap().remove(&key).is_none() {
+            return Err(Status::not_found(format!(
+                "no write in progress for {reference}"
+            )));
+        }
+        let dir = ingest_dir(&root, &reference);
+        tokio::fs::remove_dir_all(&dir)
+            .await
+            .map_err(io_error(&dir))?;
+        Ok(Response::new(()))
+    }
+}
+
+/// The open data file of a write
+struct Writer {
+    reference: String,
+    file: tokio::fs::File,
+    offset: i64,
+}
+
+/// Handle the requests of one write, which is for a single ref and ends with
+/// its commit
+async fn write(
+    root: &Path,
+    ingests: &Ingests,
+    mut stream: Streaming<WriteContentRequest>,
+    tx: &Sender<Result<WriteContentResponse, Status>>,
+) -> Result<(), Status> {
+    let mut writer: Option<Writer> = None;
+
+    while let Some(request) = stream.message().await? {
+        if writer.is_none() {
+            writer = Some(open_writer(root, ingests, &request).await?);
+        }
+        let writer = writer.as_mut().expect("the writer is open");
+        let key = (root.to_owned(), writer.reference.clone());
+
+        let ingest = {
+            let mut ingests = ingests.lock().unwrap();
+            let ingest = ingests
+                .get_mut(&key)
+                .ok_or_else(|| Status::aborted("the write was aborted"))?;
+            if request.total > 0 {
+                ingest.total = request.total;
+            }
+            if !request.expected.is_empty() {
+                ingest.expected = request.expected.clone();
+            }
+            ingest.clone()
+        };
+
+        let action = request.action();
+        let mut response = WriteContentResponse {
+            action: action.into(),
+            offset: writer.offset,
+            ..Default::default()
+        };
+
+        match action {
+            WriteAction::Stat => {
+                response.started_at = Some(ingest.started_at.into());
+                response.updated_at = Some(ingest.updated_at.into());
+                response.total = ingest.total;
+            }
+            WriteAction::Write | WriteAction::Commit => {
+                let path = ingest_dir(root, &writer.reference).join("data");
+                if request.offset > 0 && request.offset != writer.offset {
+                    return Err(Status::out_of_range(format!(
+                        "write @{} must occur at current offset {}",
+                        request.offset, writer.offset
+                    )));
+                }
+                // Writing at zero again starts over
+                if request.offset == 0 && writer.offset > 0 {
+                    writer.file.set_len(0).await.map_err(io_error(&path))?;
+                    writer
+                        .file
+                        .seek(SeekFrom::Start(0))
+                        .await
+                        .map_err(io_error(&path))?;
+                    writer.offset = 0;
+                }
+                if !request.data.is_empty() {
+                    writer
+                        .file
+                        .write_all(&request.data)
+                        .await
+                        .map_err(io_error(&path))?;
+                    writer.offset += request.data.len() as i64;
+                    if let Some(ingest) = ingests.lock().unwrap().get_mut(&key) {
+                        ingest.updated_at = SystemTime::now();
+                    }
+                }
+                response.offset = writer.offset;
+
+                if action == WriteAction::Commit {
+                    writer.file.flush().await.map_err(io_error(&path))?;
+                    let result = commit(root, &path, writer.offset, &ingest).await;
+
+                    ingests.lock().unwrap().remove(&key);
+                    let dir = ingest_dir(root, &writer.reference);
+                    let _ = tokio::fs::remove_dir_all(&dir).await;
+
+                    response.digest = result?;
+                    response.started_at = Some(ingest.started_at.into());
+                    response.updated_at = Some(SystemTime::now().into());
+                    response.total = writer.offset;
+                    let _ = tx.send(Ok(response)).await;
+                    return Ok(());
+                }
+            }
+        }
+
+        tx.send(Ok(response))
+            .await
+            .map_err(|_| Status::cancelled("the writer is gone"))?;
+    }
+
+    Ok(())
+}
+
+/// Start or resume the write of the first request of a stream
+async fn open_writer(
+    root: &Path,
+    ingests: &Ingests,
+    request: &WriteContentRequest,
+) -> Result<Writer, Status> {
+    let reference = request.r#ref.clone();
+    if reference.is_empty() {
+        return Err(Status::invalid_argument(
+            "the first write request must have a ref",
+        ));
+    }
+    if !request.expected.is_empty() && exists(&blob_path(root, &request.expected)?).await? {
+        return Err(Status::already_exists(format!(
+            "content {} already exists",
+            request.expected
+        )));
+    }
+
+    let dir = ingest_dir(root, &reference);
+    tokio::fs::create_dir_all(&dir)
+        .await
+        .map_err(io_error(&dir))?;
+    let path = dir.join("data");
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(&path)
+        .await
+        .map_err(io_error(&path))?;
+    let offset = file.seek(SeekFrom::End(0)).await.map_err(io_error(&path))? as i64;
+
+    let now = SystemTime::now();
+    ingests
+        .lock()
+        .unwrap()
+        .entry((root.to_owned(), reference.clone()))
+        .or_insert_with(|| Ingest {
+            started_at: now,
+            updated_at: now,
+            total: request.total,
+            expected: request.expected.clone(),
+        });
+
+    Ok(Writer {
+        reference,
+        file,
+        offset,
+    })
+}
+
+/// Verify the written data and move it to the blobs of the store
+async fn commit(root: &Path, data: &Path, size: i64, ingest: &Ingest) -> Result<String, Status> {
+    if ingest.total > 0 && ingest.total != size {
+        return Err(Status::failed_precondition(format!(
+            "unexpected commit size {size}, expected {}",
+            ingest.total
+        )));
+    }
+
+    let mut file = tokio::fs::File::open(data).await.map_err(io_error(data))?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await.map_err(io_error(data))?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    let digest = format!("sha256:{:x}", hasher.finalize());
+
+    if !ingest.expected.is_empty() && ingest.expected != digest {
+        return Err(Status::failed_precondition(format!(
+            "unexpected commit digest {digest}, expected {}",
+            ingest.expected
+        )));
+    }
+
+    let path = blob_path(root, &digest)?;
+    if exists(&path).await? {
+        return Err(Status::already_exists(format!(
+            "content {digest} already exists"
+        )));
+    }
+    let parent = path.parent().expect("blobs have a parent");
+    tokio::fs::create_dir_all(parent)
+        .await
+        .map_err(io_error(parent))?;
+    tokio::fs::rename(data, &path)
+        .await
+        .map_err(io_error(&path))?;
+
+    Ok(digest)
+}
+
+/// The path of a blob in the store, only sha256 digests are supported
+#[allow(clippy::result_large_err)]
+fn blob_path(root: &Path, digest: &str) -> Result<PathBuf, Status> {
+    match digest.split_once(':') {
+        Some(("sha256", hex))
+            if hex.len() == 64 && hex.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) =>
+        {
+            Ok(root.join("blobs/sha256").join(hex))
+        }
+        _ => Err(Status::invalid_argument(format!(
+            "unsupported digest {digest:?}"
+        ))),
+    }
+}
+
+async fn blob_info(root: &Path, digest: &str) -> Result<Info, Status> {
+    let path = blob_path(root, digest)?;
+    let metadata = match tokio::fs::metadata(&path).await {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Err(not_found(digest)),
+        Err(err) => return Err(io_error(&path)(err)),
+    };
+    let modified = metadata.modified().map_err(io_error(&path))?;
+
+    Ok(Info {
+        digest: digest.into(),
+        size: metadata.len() as i64,
+        created_at: Some(modified.into()),
+        updated_at: Some(modified.into()),
+        labels: HashMap::new(),
+    })
+}
+
+async fn exists(path: &Path) -> Result<bool, Status> {
+    tokio::fs::try_exists(path).await.map_err(io_error(path))
+}
+
+/// Where the data of a write is kept until it is committed, refs can contain
+/// any characters so they are hashed
+fn ingest_dir(root: &Path, reference: &str) -> PathBuf {
+    root.join("ingest")
+        .join(format!("{:x}", Sha256::digest(reference)))
+}
+
+fn not_found(digest: &str) -> Status {
+    Status::not_found(format!("content {digest} not found"))
+}
+
+fn io_error(path: &Path) -> impl FnOnce(std::io::Error) -> Status + '_ {
+    move |err| Status::internal(format!("{}: {err}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use tonic::codec::ProstCodec;
+    use tonic::codegen::http::uri::PathAndQuery;
+    use tonic::transport::{Channel, Server};
+
+    use super::*;
+    use crate::util::test_util;
+
+    const STORE_ID: &str = "local:cache";
+
+    async fn serve(root: &Path) -> tonic::client::Grpc<Channel> {
+        let stores = ContentStores::default();
+        stores
+            .lock()
+            .unwrap()
+            .insert(STORE_ID.into(), root.to_owned());
+
+        let router = Server::builder().add_service(ContentStoreService::new(stores).into_server());
+        let port = test_util::serve_tcp(router).await;
+        tonic::client::Grpc::new(test_util::connect(port).await)
+    }
+
+    fn request<T>(message: T) -> tonic::Request<T> {
+        let mut request = tonic::Request::new(message);
+        request
+            .metadata_mut()
+            .insert(STORE_ID_HEADER, STORE_ID.parse().unwrap());
+        request
+    }
+
+    #[tokio::test]
+    async fn write_and_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let mut grpc = serve(root).await;
+
+        let data = b"cache blob";
+        let digest = format!("sha256:{:x}", Sha256::digest(data));
+        let write = |action: WriteAction, offset: i64, data: &[u8]| WriteContentRequest {
+            action: action.into(),
+            r#ref: "layer-1".into(),
+            total: 10,
+            expected: digest.clone(),
+            offset,
+            data: data.to_vec(),
+            ..Default::default()
+        };
+
+        grpc.ready().await.unwrap();
+        let responses: Vec<WriteContentResponse> = grpc
+            .streaming(
+                request(futures::stream::iter([
+                    write(WriteAction::Stat, 0, b""),
+                    write(WriteAction::Write, 0, &data[..5]),
+                    write(WriteAction::Commit, 5, &data[5..]),
+                ])),
+                PathAndQuery::from_static("/containerd.services.content.v1.Content/Write"),
+                ProstCodec::default(),
+            )
+            .await
+            .unwrap()
+            .into_inner()
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        let offsets: Vec<_> = responses.iter().map(|r| (r.action(), r.offset)).collect();
+        assert_eq!(
+            offsets,
+            [
+                (WriteAction::Stat, 0),
+                (WriteAction::Write, 5),
+                (WriteAction::Commit, 10)
+            ]
+        );
+        assert_eq!(responses[2].digest, digest);
+        assert!(!root.join("ingest/").read_dir().unwrap().any(|_| true));
+
+        grpc.ready().await.unwrap();
+        let info: InfoResponse = grpc
+            .unary(
+                request(InfoRequest {
+                    digest: digest.clone(),
+                }),
+                PathAndQuery::from_static("/containerd.services.content.v1.Content/Info"),
+                ProstCodec::default(),
+            )
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(info.info.unwrap().size, 10);
+
+        grpc.ready().await.unwrap();
+        let read: Vec<ReadContentResponse> = grpc
+            .server_streaming(
+                request(ReadContentRequest {
+                    digest: digest.clone(),
+                    offset: 6,
+                    size: 0,
+                }),
+                PathAndQuery::from_static("/containerd.services.content.v1.Content/Read"),
+                ProstCodec::default(),
+            )
+            .await
+            .unwrap()
+            .into_inner()
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        assert_eq!(read.len(), 1);
+        assert_eq!((read[0].offset, read[0].data.as_slice()), (6, &b"blob"[..]));
+
+        let list = |filters: Vec<String>| {
+            let mut grpc = grpc.clone();
+            async move {
+                grpc.ready().await.unwrap();
+                let mut response = grpc
+                    .server_streaming::<_, ListContentResponse, _>(
+                        request(ListContentRequest { filters }),
+                        PathAndQuery::from_static("/containerd.services.content.v1.Content/List"),
+                        ProstCodec::default(),
+                    )
+                    .await?
+                    .into_inner();
+                let mut info = Vec::new();
+                while let Some(message) = response.message().await? {
+                    info.extend(message.info);
+                }
+                Ok::<_, Status>(info)
+            }
+        };
+        let info = list(vec![]).await.unwrap();
+        assert_eq!(
+            info.iter().map(|info| &info.digest).collect::<Vec<_>>(),
+            [&digest]
+        );
+        let status = list(vec!["labels.cache".into()]).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unimplemented);
+
+        // A blob is only written once
+        grpc.ready().await.unwrap();
+        let status = grpc
+            .streaming::<_, _, WriteContentResponse, _>(
+                request(futures::stream::iter([write(WriteAction::Stat, 0, b"")])),
+                PathAndQuery::from_static("/containerd.services.content.v1.Content/Write"),
+                ProstCodec::default(),
+            )
+            .await
+            .unwrap()
+            .into_inner()
+            .message()
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::AlreadyExists);
+    }
+
+    #[tokio::test]
+    async fn commit_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let mut grpc = serve(root).await;
+
+        grpc.ready().await.unwrap();
+        let status = grpc
+            .streaming::<_, _, WriteContentResponse, _>(
+                request(futures::stream::iter([WriteContentRequest {
+                    action: WriteAction::Commit.into(),
+                    r#ref: "layer-1".into(),
+                    expected: format!("sha256:{}", "0".repeat(64)),
+                    data: b"data".to_vec(),
+                    ..Default::default()
+                }])),
+                PathAndQuery::from_static("/containerd.services.content.v1.Content/Write"),
+                ProstCodec::default(),
+            )
+            .await
+            .unwrap()
+            .into_inner()
+            .message()
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+        assert!(!root.join("blobs").exists());
+
+        // Requests for stores the session does not serve fail
+        grpc.ready().await.unwrap();
+        let status = grpc
+            .unary::<_, InfoResponse, _>(
+                tonic::Request::new(InfoRequest::default()),
+                PathAndQuery::from_static("/containerd.services.content.v1.Content/Info"),
+                ProstCodec::default(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+}
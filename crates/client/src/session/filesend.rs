@@ -1,54 +1,367 @@
-use buildkit_rs_proto::moby::filesync::v1::{
-    file_send_server::{FileSend, FileSendServer},
-    BytesMessage,
-};
-use tokio::io::AsyncWriteExt;
+use std::convert::Infallible;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use buildkit_rs_proto::fsutil::types::Packet;
+use buildkit_rs_proto::moby::filesync::v1::BytesMessage;
+use bytes::{Buf, BufMut};
+use futures::{Stream, StreamExt};
+use prost::Message;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 use tokio_stream::wrappers::ReceiverStream;
-use tonic::{Response, Status};
-use tracing::info;
+use tonic::codec::{Codec, DecodeBuf, Decoder, EncodeBuf, Encoder};
+use tonic::codegen::{http, Body, BoxFuture, Context, Poll, Service, StdError};
+use tonic::server::{Grpc, NamedService, StreamingService};
+use tonic::{Request, Response, Status, Streaming};
+use tracing::{error, info};
 
-pub(crate) struct FileSendService {}
+use super::receive::receive;
+use crate::exporter::{ExportOutput, ExportTarget};
 
-impl FileSendService {
-    pub fn new() -> Self {
-        Self {}
+const DIFF_COPY_PATH: &str = "/moby.filesync.v1.FileSend/DiffCopy";
+
+/// The target of the next export of a session, set by the solve using it.
+///
+/// The daemon does not say which solve an export belongs to, so a session
+/// only exports locally for one solve at a time.
+#[derive(Debug, Default)]
+pub(crate) struct ExportSlot {
+    /// Set while a solve of the session has a local export
+    busy: AtomicBool,
+    target: Mutex<Option<ExportTarget>>,
+}
+
+impl ExportSlot {
+    /// Set the target of the session's next export until the guard is
+    /// dropped, `None` if another solve of the session has a local export
+    pub fn set(self: &Arc<Self>, target: ExportTarget) -> Option<ExportGuard> {
+        if self.busy.swap(true, Ordering::AcqRel) {
+            return None;
+        }
+        *self.target.lock().unwrap() = Some(target);
+        Some(ExportGuard(self.clone()))
     }
 
-    pub fn into_server(self) -> FileSendServer<Self> {
-        FileSendServer::new(self)
+    fn take(&self) -> Option<ExportTarget> {
+        self.target.lock().unwrap().take()
     }
 }
 
-#[tonic::async_trait]
-impl FileSend for FileSendService {
-    type DiffCopyStream = ReceiverStream<Result<BytesMessage, Status>>;
+/// Clears the export target when the solve that set it returns, so a failed
+/// solve does not leave its target to the next one
+#[derive(Debug)]
+pub(crate) struct ExportGuard(Arc<ExportSlot>);
+
+impl Drop for ExportGuard {
+    fn drop(&mut self) {
+        self.0.target.lock().unwrap().take();
+        self.0.busy.store(false, Ordering::Release);
+    }
+}
+
+type DiffCopyStream = Pin<Box<dyn Stream<Item = Result<Vec<u8>, Status>> + Send>>;
+
+/// Receives exports from the daemon.
+///
+/// `DiffCopy` is declared with `BytesMessage`s, which is what tarballs are sent
+/// as, but directories are sent as fsutil `Packet`s on the same method. The
+/// messages are decoded depending on the target, so this is not a generated
+/// server.
+#[derive(Clone)]
+pub(crate) struct FileSendService {
+    target: Arc<ExportSlot>,
+}
+
+impl FileSendService {
+    pub fn new(target: Arc<ExportSlot>) -> Self {
+        Self { target }
+    }
+
+    pub fn into_server(self) -> Self {
+        self
+    }
 
     async fn diff_copy(
         &self,
-        request: tonic::Request<tonic::Streaming<BytesMessage>>,
-    ) -> Result<Response<Self::DiffCopyStream>, Status> {
+        request: Request<Streaming<Vec<u8>>>,
+    ) -> Result<Response<DiffCopyStream>, Status> {
         info!(?request);
 
+        let target = self
+            .target
+            .take()
+            .ok_or_else(|| Status::failed_precondition("no export output set for the session"))?;
+
+        let writer: Box<dyn AsyncWrite + Send + Unpin> = match target {
+            ExportTarget::Output(ExportOutput::File(path)) => Box::new(
+                tokio::fs::File::create(&path)
+                    .await
+                    .map_err(|err| Status::internal(format!("{}: {err}", path.display())))?,
+            ),
+            ExportTarget::Output(ExportOutput::Writer(writer)) => writer,
+            ExportTarget::Directory(dest) => return Ok(receive_dir(dest, request.into_inner())),
+        };
+
         let (tx, rx) = tokio::sync::mpsc::channel(1);
         let stream = request.into_inner();
 
+        // The response stream is closed once everything is written, which is
+        // when the daemon considers the export done
         tokio::spawn(async move {
-            let mut docker_load = tokio::process::Command::new("docker")
-                .arg("load")
-                .stdin(std::process::Stdio::piped())
-                .spawn()
-                .unwrap();
-
-            let mut docker_load_stdin = docker_load.stdin.take().unwrap();
-            
-            let _tx = tx;
-            let mut stream = stream;
-            while let Some(message) = stream.message().await.unwrap() {
-                let data = message.data;
-                docker_load_stdin.write_all(&data).await.unwrap();
+            if let Err(err) = write_stream(stream, writer).await {
+                error!(?err, "Error writing export");
+                let _ = tx.send(Err(err)).await;
             }
         });
 
-        Ok(Response::new(ReceiverStream::new(rx)))
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+}
+
+/// Receive the fsutil packets of a directory export into `dest`
+#[allow(clippy::result_large_err)]
+fn receive_dir(dest: PathBuf, stream: Streaming<Vec<u8>>) -> Response<DiffCopyStream> {
+    let (tx, rx) = tokio::sync::mpsc::channel(16);
+
+    tokio::spawn(async move {
+        let packets = stream.map(|message| {
+            Packet::decode(message?.as_slice())
+                .map_err(|err| Status::invalid_argument(err.to_string()))
+        });
+        if let Err(err) = receive(&dest, packets, &tx).await {
+            error!(?err, "Error receiving export");
+            let _ = tx.send(Err(err)).await;
+        }
+    });
+
+    let responses =
+        ReceiverStream::new(rx).map(|packet| packet.map(|packet| packet.encode_to_vec()));
+    Response::new(Box::pin(responses))
+}
+
+async fn write_stream(
+    mut stream: Streaming<Vec<u8>>,
+    mut writer: Box<dyn AsyncWrite + Send + Unpin>,
+) -> Result<(), Status> {
+    let io_error = |err: std::io::Error| Status::internal(err.to_string());
+
+    while let Some(message) = stream.message().await? {
+        let message = BytesMessage::decode(message.as_slice())
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        writer.write_all(&message.data).await.map_err(io_error)?;
+    }
+    writer.shutdown().await.map_err(io_error)
+}
+
+impl NamedService for FileSendService {
+    const NAME: &'static str = "moby.filesync.v1.FileSend";
+}
+
+impl<B> Service<http::Request<B>> for FileSendService
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<tonic::body::BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let service = self.clone();
+        Box::pin(async move {
+            if request.uri().path() != DIFF_COPY_PATH {
+                return Ok(Status::unimplemented(request.uri().path()).to_http());
+            }
+            Ok(Grpc::new(RawCodec).streaming(service, request).await)
+        })
+    }
+}
+
+impl StreamingService<Vec<u8>> for FileSendService {
+    type Response = Vec<u8>;
+    type ResponseStream = DiffCopyStream;
+    type Future = BoxFuture<Response<Self::ResponseStream>, Status>;
+
+    fn call(&mut self, request: Request<Streaming<Vec<u8>>>) -> Self::Future {
+        let service = self.clone();
+        Box::pin(async move { service.diff_copy(request).await })
+    }
+}
+
+/// Passes the encoded messages through, to be decoded by the service
+#[derive(Debug, Clone, Copy, Default)]
+struct RawCodec;
+
+impl Codec for RawCodec {
+    type Encode = Vec<u8>;
+    type Decode = Vec<u8>;
+    type Encoder = Self;
+    type Decoder = Self;
+
+    fn encoder(&mut self) -> Self::Encoder {
+        *self
+    }
+
+    fn decoder(&mut self) -> Self::Decoder {
+        *self
+    }
+}
+
+impl Encoder for RawCodec {
+    type Item = Vec<u8>;
+    type Error = Status;
+
+    fn encode(&mut self, item: Self::Item, dst: &mut EncodeBuf<'_>) -> Result<(), Self::Error> {
+        dst.put_slice(&item);
+        Ok(())
+    }
+}
+
+impl Decoder for RawCodec {
+    type Item = Vec<u8>;
+    type Error = Status;
+
+    fn decode(&mut self, src: &mut DecodeBuf<'_>) -> Result<Option<Self::Item>, Self::Error> {
+        Ok(Some(src.copy_to_bytes(src.remaining()).to_vec()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use buildkit_rs_proto::fsutil::types::{packet::PacketType, Stat};
+    use tonic::codec::ProstCodec;
+    use tonic::codegen::http::uri::PathAndQuery;
+    use tonic::transport::Server;
+
+    use super::*;
+    use crate::util::file_mode::FileMode;
+    use crate::util::test_util;
+
+    /// Send `messages` to the FileSend service like the daemon does for an
+    /// export, and collect the responses
+    async fn diff_copy<M>(target: Arc<ExportSlot>, messages: Vec<M>) -> Result<Vec<M>, Status>
+    where
+        M: Message + Default + Send + Sync + 'static,
+    {
+        let router = Server::builder().add_service(FileSendService::new(target).into_server());
+        let port = test_util::serve_tcp(router).await;
+        let mut grpc = tonic::client::Grpc::new(test_util::connect(port).await);
+        grpc.ready().await.unwrap();
+
+        let mut response = grpc
+            .streaming(
+                tonic::Request::new(futures::stream::iter(messages)),
+                PathAndQuery::from_static(DIFF_COPY_PATH),
+                ProstCodec::<M, M>::default(),
+            )
+            .await?
+            .into_inner();
+        let mut responses = Vec::new();
+        while let Some(message) = response.message().await? {
+            responses.push(message);
+        }
+        Ok(responses)
+    }
+
+    fn bytes(data: &[u8]) -> BytesMessage {
+        BytesMessage { data: data.into() }
+    }
+
+    #[tokio::test]
+    async fn export_to_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("export.tar");
+        let target = Arc::<ExportSlot>::default();
+        let guard = target
+            .set(ExportTarget::Output(ExportOutput::File(path.clone())))
+            .unwrap();
+
+        // Only one solve of a session can export locally at a time
+        assert!(target
+            .set(ExportTarget::Output(ExportOutput::File(path.clone())))
+            .is_none());
+
+        diff_copy(target.clone(), vec![bytes(b"first "), bytes(b"second")])
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"first second");
+        std::fs::remove_file(&path).unwrap();
+
+        // The target is used by one export only
+        let status = diff_copy(target.clone(), vec![bytes(b"data")])
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+
+        // The target of a solve that did not export is cleared when it returns
+        drop(guard);
+        let guard = target
+            .set(ExportTarget::Output(ExportOutput::File(path.clone())))
+            .unwrap();
+        drop(guard);
+        let status = diff_copy(target, vec![bytes(b"data")]).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn export_to_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = Arc::<ExportSlot>::default();
+        let _guard = target
+            .set(ExportTarget::Directory(dir.path().to_owned()))
+            .unwrap();
+
+        let stat = |path: &str, mode: FileMode| Packet {
+            r#type: PacketType::PacketStat.into(),
+            stat: Some(Stat {
+                path: path.into(),
+                mode: mode.bits(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let packet = |packet_type: PacketType, id: u32, data: &[u8]| Packet {
+            r#type: packet_type.into(),
+            id,
+            data: data.into(),
+            ..Default::default()
+        };
+
+        let responses = diff_copy(
+            target,
+            vec![
+                stat(
+                    "bin",
+                    FileMode::MODE_DIR | FileMode::from_bits_retain(0o755),
+                ),
+                stat("bin/app", FileMode::from_bits_retain(0o755)),
+                packet(PacketType::PacketStat, 0, b""),
+                packet(PacketType::PacketData, 1, b"#!/bin/sh\n"),
+                packet(PacketType::PacketData, 1, b""),
+                packet(PacketType::PacketFin, 0, b""),
+            ],
+        )
+        .await
+        .unwrap();
+
+        let responses: Vec<_> = responses
+            .iter()
+            .map(|packet| (packet.r#type(), packet.id))
+            .collect();
+        assert_eq!(
+            responses,
+            [(PacketType::PacketReq, 1), (PacketType::PacketFin, 0)]
+        );
+        assert_eq!(
+            std::fs::read(dir.path().join("bin/app")).unwrap(),
+            b"#!/bin/sh\n"
+        );
     }
 }
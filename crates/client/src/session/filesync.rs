@@ -11,11 +11,14 @@ use tokio_stream::wrappers::ReceiverStream;
 use tonic::{Request, Response, Status, Streaming};
 use tracing::{debug, error, info, trace, warn};
 
-use crate::util::file_mode::FileMode;
+use crate::util::{
+    file_mode::FileMode,
+    pattern::{self, Pattern},
+};
 
 const KEY_INCLUDE_PATTERNS: &str = "include-patterns";
 const KEY_EXCLUDE_PATTERNS: &str = "exclude-patterns";
-// const KEY_FOLLOW_PATHS: &str = "followpaths";
+const KEY_FOLLOW_PATHS: &str = "followpaths";
 const KEY_DIR_NAME: &str = "dir-name";
 
 const MAX_PACKET_SIZE: usize = 1024 * 1024 * 4;
@@ -77,23 +80,30 @@ impl FileSync for FileSyncService {
             .map(Into::into)
             .collect();
 
-        // let follow_paths: Vec<String> = request
-        //     .metadata()
-        //     .get_all(KEY_FOLLOW_PATHS)
-        //     .iter()
-        //     .map(|v| v.to_str().ok())
-        //     .flatten()
-        //     .map(Into::into)
-        //     .collect();
+        let follow_paths: Vec<String> = request
+            .metadata()
+            .get_all(KEY_FOLLOW_PATHS)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .map(Into::into)
+            .collect();
 
         tokio::spawn(async move {
-            let files = walk(
-                &context_path,
-                tx.clone(),
-                exclude_patterns,
-                include_patterns,
-            )
+            // Following symlinks reads the context
+            let root = context_path.clone();
+            let filter = tokio::task::spawn_blocking(move || {
+                PathFilter::new(include_patterns, exclude_patterns)
+                    .with_follow_paths(&root, &follow_paths)
+            })
             .await;
+            let filter = match filter {
+                Ok(filter) => filter,
+                Err(err) => {
+                    error!(?err, "Error resolving the follow paths");
+                    return;
+                }
+            };
+            let files = walk(&context_path, tx.clone(), filter).await;
 
             let mut inner = request.into_inner();
             while let Ok(Some(packet)) = inner.message().await {
@@ -188,9 +198,7 @@ impl FileSync for FileSyncService {
 async fn walk(
     root: impl AsRef<Path>,
     tx: Sender<Result<Packet, Status>>,
-
-    exclude_patterns: Vec<String>,
-    _include_patterns: Vec<String>,
+    filter: PathFilter,
 ) -> Vec<String> {
     macro_rules! send_data_packet {
         ($t:ident, $data:expr) => {
@@ -206,19 +214,16 @@ async fn walk(
 
     let root = root.as_ref();
     let mut files = vec![];
+    // Directories that are not sent themselves but are walked as a path in
+    // them may be, they are sent before the first such path
+    let mut parents: Vec<Stat> = vec![];
 
     for entry in walkdir::WalkDir::new(root)
         .sort_by_file_name()
         .into_iter()
         .filter_entry(|entry| {
-            // Very primitive filtering
-            // TODO: replace with real matching based on https://github.com/moby/patternmatcher/blob/main/patternmatcher.go
             let trimmed_path = entry.path().strip_prefix(root).unwrap();
-            let clean_path = path_clean::clean(trimmed_path);
-
-            !exclude_patterns
-                .iter()
-                .any(|pattern| clean_path.starts_with(pattern))
+            entry.depth() == 0 || filter.is_walked(trimmed_path, entry.file_type().is_dir())
         })
     {
         let entry = match entry {
@@ -240,36 +245,14 @@ async fn walk(
         let trimmed_path = entry.path().strip_prefix(root).unwrap();
         let clean_path = path_clean::clean(trimmed_path);
 
-        #[cfg(unix)]
-        let (uid, gid, size) = {
-            use std::os::unix::prelude::MetadataExt;
-
-            let uid = metadata.uid();
-            let gid = metadata.gid();
-            let size = metadata.size() as i64;
-
-            (uid, gid, size)
-        };
-
-        #[cfg(windows)]
-        let (uid, gid, size) = {
-            use std::os::windows::prelude::MetadataExt;
-
-            // TODO: this seems wrong, not sure what to do here for uid/gid, maybe default to 1000?
-            let uid = 0;
-            let gid = 0;
-
-            let size = metadata.file_size() as i64;
-
-            (uid, gid, size)
-        };
-
         let stat = Stat {
             path: clean_path.to_string_lossy().into_owned(),
             mode: FileMode::from_metadata(&metadata).bits(),
-            uid,
-            gid,
-            size,
+            // Like buildctl's `resetUIDAndGID`, files are sent as owned by
+            // root as the owner on this host means nothing in the build
+            uid: 0,
+            gid: 0,
+            size: metadata.len() as i64,
             mod_time: metadata.modified().map_or(0, |t| {
                 t.duration_since(std::time::UNIX_EPOCH)
                     .unwrap_or_default()
@@ -278,17 +261,25 @@ async fn walk(
             ..Default::default()
         };
 
-        files.push(stat.path.clone());
+        parents.retain(|parent| clean_path.starts_with(&parent.path));
+        if entry.depth() > 0 && !filter.matches(trimmed_path) {
+            parents.push(stat);
+            continue;
+        }
 
-        if let Err(err) = tx
-            .send(Ok(Packet {
-                r#type: PacketType::PacketStat.into(),
-                stat: Some(stat),
-                ..Default::default()
-            }))
-            .await
-        {
-            error!(?err);
+        for stat in parents.drain(..).chain(std::iter::once(stat)) {
+            files.push(stat.path.clone());
+
+            if let Err(err) = tx
+                .send(Ok(Packet {
+                    r#type: PacketType::PacketStat.into(),
+                    stat: Some(stat),
+                    ..Default::default()
+                }))
+                .await
+            {
+                error!(?err);
+            }
         }
     }
 
@@ -305,3 +296,187 @@ async fn walk(
 
     files
 }
+
+/// The include and exclude patterns of a local source
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PathFilter {
+    includes: Vec<Pattern>,
+    excludes: Vec<Pattern>,
+    follow_paths: Vec<PathBuf>,
+}
+
+impl PathFilter {
+    pub(crate) fn new(includes: Vec<String>, excludes: Vec<String>) -> Self {
+        Self {
+            includes: includes.iter().map(|p| Pattern::new(p)).collect(),
+            excludes: excludes.iter().map(|p| Pattern::new(p)).collect(),
+            follow_paths: Vec::new(),
+        }
+    }
+
+    /// Always send these paths of the context under `root` and the targets of
+    /// the symlinks on them, like `local.followpaths`
+    pub(crate) fn with_follow_paths(mut self, root: &Path, follow_paths: &[String]) -> Self {
+        for path in follow_paths {
+            let mut path = path_clean::clean(path.trim_start_matches('/'));
+            // Bounded like the symlink resolution of fsutil, in case of a loop
+            for _ in 0..255 {
+                let target = resolve_link(root, &path);
+                if !self.follow_paths.contains(&path) {
+                    self.follow_paths.push(path);
+                }
+                match target {
+                    Some(target) => path = target,
+                    None => break,
+                }
+            }
+        }
+        self
+    }
+
+    /// Whether a path relative to the root of the context is sent, a path
+    /// is sent if it or a parent matches an include pattern and it is not
+    /// excluded, or if it is followed
+    pub(crate) fn matches(&self, path: &Path) -> bool {
+        let path = path_clean::clean(path);
+        if self
+            .follow_paths
+            .iter()
+            .any(|follow| path.starts_with(follow))
+        {
+            return true;
+        }
+
+        let included = self.includes.is_empty() || self.includes.iter().any(|p| p.matches(&path));
+        included && !pattern::excluded(&self.excludes, &path)
+    }
+
+    /// Whether a path inside the directory `dir` can be sent, even if `dir`
+    /// is not, so that the walk has to go into it
+    pub(crate) fn matches_below(&self, dir: &Path) -> bool {
+        let dir = path_clean::clean(dir);
+        if self
+            .follow_paths
+            .iter()
+            .any(|follow| follow.starts_with(&dir))
+        {
+            return true;
+        }
+
+        let included = self.includes.is_empty()
+            || self
+                .includes
+                .iter()
+                .any(|p| p.matches(&dir) || p.matches_below(&dir));
+        let excluded = pattern::excluded(&self.excludes, &dir)
+            && !self
+                .excludes
+                .iter()
+                .any(|p| p.is_exception() && p.matches_below(&dir));
+        included && !excluded
+    }
+
+    /// Whether a path is sent or is a directory that has to be walked
+    pub(crate) fn is_walked(&self, path: &Path, is_dir: bool) -> bool {
+        self.matches(path) || (is_dir && self.matches_below(path))
+    }
+}
+
+/// The path a symlink on `path` points to, with the rest of `path` appended,
+/// or `None` if there is no symlink on it inside `root`
+fn resolve_link(root: &Path, path: &Path) -> Option<PathBuf> {
+    let mut prefix = PathBuf::new();
+    let mut components = path.components();
+
+    while let Some(component) = components.next() {
+        prefix.push(component);
+        let metadata = std::fs::symlink_metadata(root.join(&prefix)).ok()?;
+        if !metadata.file_type().is_symlink() {
+            continue;
+        }
+
+        let link = std::fs::read_link(root.join(&prefix)).ok()?;
+        // Absolute targets are relative to the root of the context
+        let target = match link.strip_prefix("/") {
+            Ok(link) => link.to_path_buf(),
+            Err(_) => prefix.parent().unwrap_or(Path::new("")).join(link),
+        };
+        let target = path_clean::clean(target.join(components.as_path()));
+        if target.starts_with("..") {
+            return None;
+        }
+        return Some(target);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn follow_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("build/docker")).unwrap();
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        std::fs::write(root.join("build/docker/Dockerfile"), "FROM scratch").unwrap();
+        std::os::unix::fs::symlink("build/docker/Dockerfile", root.join("Dockerfile")).unwrap();
+
+        let filter = PathFilter::new(vec![], vec!["build".into()])
+            .with_follow_paths(root, &["Dockerfile".into()]);
+        assert!(filter.matches(Path::new("Dockerfile")));
+        assert!(!filter.matches(Path::new("build")));
+        assert!(filter.matches_below(Path::new("build")));
+        assert!(filter.matches(Path::new("build/docker/Dockerfile")));
+        assert!(!filter.matches(Path::new("build/other")));
+        assert!(filter.matches(Path::new("src")));
+
+        // Followed paths are sent next to the included ones
+        let filter = PathFilter::new(vec!["src".into()], vec![])
+            .with_follow_paths(root, &["/Dockerfile".into()]);
+        assert!(filter.matches(Path::new("src")));
+        assert!(filter.matches(Path::new("build/docker/Dockerfile")));
+        assert!(!filter.matches(Path::new("README.md")));
+    }
+
+    #[tokio::test]
+    async fn walk_patterns() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        for dir in ["src", "target/debug", "target/keep", "docs"] {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for file in [
+            "README.md",
+            "src/main.rs",
+            "target/debug/app",
+            "target/keep/a",
+            "docs/guide.md",
+        ] {
+            std::fs::write(root.join(file), file).unwrap();
+        }
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel(32);
+        let filter = PathFilter::new(
+            vec!["src".into(), "target".into(), "*.md".into()],
+            vec!["target".into(), "!target/keep".into()],
+        );
+        let files = walk(root, tx, filter).await;
+        while rx.recv().await.is_some() {}
+
+        // `target` is excluded, but sent as the parent of an exception
+        assert_eq!(
+            files[1..],
+            [
+                "README.md",
+                "src",
+                "src/main.rs",
+                "target",
+                "target/keep",
+                "target/keep/a",
+            ]
+        );
+    }
+}
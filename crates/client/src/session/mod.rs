@@ -1,4 +1,6 @@
 pub mod auth;
+pub mod content;
 pub mod filesend;
 pub mod filesync;
+mod receive;
 pub mod secret;
@@ -0,0 +1,506 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use buildkit_rs_proto::fsutil::types::{packet::PacketType, Packet, Stat};
+use futures::{Stream, StreamExt};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc::Sender;
+use tonic::Status;
+use tracing::{debug, warn};
+
+use crate::util::file_mode::FileMode;
+
+/// A regular file whose data was requested from the sender
+struct PendingFile {
+    path: PathBuf,
+    stat: Stat,
+    /// Opened when the first data arrives
+    file: Option<tokio::fs::File>,
+}
+
+/// Receive the files of a fsutil DiffCopy stream into `dest`, the other side
+/// of what [`super::filesync`] sends.
+///
+/// Entries of `dest` that are not in the stream are kept. Like with
+/// `buildctl --output type=local`, the entries belong to the current user
+/// whatever their owner in the stream.
+pub(crate) async fn receive<S>(
+    dest: &Path,
+    mut packets: S,
+    tx: &Sender<Result<Packet, Status>>,
+) -> Result<(), Status>
+where
+    S: Stream<Item = Result<Packet, Status>> + Unpin,
+{
+    tokio::fs::create_dir_all(dest)
+        .await
+        .map_err(io_error(dest))?;
+    let root = tokio::fs::canonicalize(dest)
+        .await
+        .map_err(io_error(dest))?;
+
+    // The sender numbers the entries in the order of their stat packets
+    let mut next_id = 0;
+    let mut pending = HashMap::new();
+    let mut dirs = Vec::new();
+    let mut listed = false;
+
+    while let Some(packet) = packets.next().await {
+        let packet = packet?;
+        match packet.r#type() {
+            PacketType::PacketStat => {
+                let Some(stat) = packet.stat else {
+                    listed = true;
+                    if pending.is_empty() {
+                        send(tx, PacketType::PacketFin, 0).await?;
+                    }
+                    continue;
+                };
+                let id = next_id;
+                next_id += 1;
+
+                let path = entry_path(&root, &stat.path).await?;
+                let mode = FileMode::from_bits_retain(stat.mode);
+                debug!(?path, ?mode, "Receive");
+
+                if mode.contains(FileMode::MODE_DIR) {
+                    match tokio::fs::symlink_metadata(&path).await {
+                        Ok(metadata) if metadata.is_dir() => {}
+                        Ok(_) => {
+                            tokio::fs::remove_file(&path)
+                                .await
+                                .map_err(io_error(&path))?;
+                            tokio::fs::create_dir(&path)
+                                .await
+                                .map_err(io_error(&path))?;
+                        }
+                        Err(_) => tokio::fs::create_dir_all(&path)
+                            .await
+                            .map_err(io_error(&path))?,
+                    }
+                    dirs.push((path, stat));
+                } else if mode.contains(FileMode::MODE_SYMLINK) {
+                    remove_existing(&path).await?;
+                    blocking(path, move |path| {
+                        symlink(&stat.linkname, path)?;
+                        chown(path)
+                    })
+                    .await?;
+                } else if mode.intersects(FileMode::MODE_TYPE_MASK) {
+                    warn!(?path, ?mode, "Skipping special file");
+                } else if !stat.linkname.is_empty() {
+                    let target = entry_path(&root, &stat.linkname).await?;
+                    remove_existing(&path).await?;
+                    tokio::fs::hard_link(target, &path)
+                        .await
+                        .map_err(io_error(&path))?;
+                } else {
+                    // Created right away so hardlinks to it can be made before
+                    // its data arrives
+                    remove_existing(&path).await?;
+                    tokio::fs::File::create(&path)
+                        .await
+                        .map_err(io_error(&path))?;
+                    pending.insert(
+                        id,
+                        PendingFile {
+                            path,
+                            stat,
+                            file: None,
+                        },
+                    );
+                    send(tx, PacketType::PacketReq, id).await?;
+                }
+            }
+            PacketType::PacketData => {
+                let Some(pending_file) = pending.get_mut(&packet.id) else {
+                    return Err(Status::invalid_argument(format!(
+                        "data for unknown file {}",
+                        packet.id
+                    )));
+                };
+
+                // An empty data packet ends the file
+                if packet.data.is_empty() {
+                    let pending_file = pending.remove(&packet.id).expect("file is pending");
+                    finish_file(pending_file).await?;
+                    if listed && pending.is_empty() {
+                        send(tx, PacketType::PacketFin, 0).await?;
+                    }
+                    continue;
+                }
+
+                let path = &pending_file.path;
+                let file = match &mut pending_file.file {
+                    Some(file) => file,
+                    None => pending_file.file.insert(
+                        tokio::fs::OpenOptions::new()
+                            .write(true)
+                            .open(path)
+                            .await
+                            .map_err(io_error(path))?,
+                    ),
+                };
+                file.write_all(&packet.data).await.map_err(io_error(path))?;
+            }
+            // The sender acknowledges our FIN and closes the stream
+            PacketType::PacketFin => break,
+            PacketType::PacketErr => {
+                return Err(Status::aborted(format!(
+                    "error from sender: {}",
+                    String::from_utf8_lossy(&packet.data)
+                )))
+            }
+            PacketType::PacketReq => {
+                return Err(Status::invalid_argument("unexpected request packet"))
+            }
+        }
+    }
+
+    if !listed || !pending.is_empty() {
+        return Err(Status::aborted(
+            "the stream ended before all files were received",
+        ));
+    }
+
+    // Last and deepest first, so writing the children doesn't change the
+    // mtime and read-only directories can still be written to
+    for (path, stat) in dirs.into_iter().rev() {
+        blocking(path, move |path| set_metadata(path, &stat)).await?;
+    }
+
+    Ok(())
+}
+
+async fn send(
+    tx: &Sender<Result<Packet, Status>>,
+    packet_type: PacketType,
+    id: u32,
+) -> Result<(), Status> {
+    tx.send(Ok(Packet {
+        r#type: packet_type.into(),
+        id,
+        ..Default::default()
+    }))
+    .await
+    .map_err(|_| Status::cancelled("the sender is gone"))
+}
+
+async fn finish_file(pending_file: PendingFile) -> Result<(), Status> {
+    let PendingFile { path, stat, file } = pending_file;
+
+    if let Some(mut file) = file {
+        file.flush().await.map_err(io_error(&path))?;
+    }
+
+    blocking(path, move |path| set_metadata(path, &stat)).await
+}
+
+/// Run blocking filesystem calls on `path` off the async runtime
+async fn blocking<T, F>(path: PathBuf, f: F) -> Result<T, Status>
+where
+    F: FnOnce(&Path) -> io::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    let (path, result) = tokio::task::spawn_blocking(move || {
+        let result = f(&path);
+        (path, result)
+    })
+    .await
+    .map_err(|err| Status::internal(err.to_string()))?;
+    result.map_err(io_error(&path))
+}
+
+/// The path of an entry in `root`, which must stay inside it even if the
+/// stream created symlinks on the way
+async fn entry_path(root: &Path, path: &str) -> Result<PathBuf, Status> {
+    let invalid = || Status::invalid_argument(format!("invalid path {path:?}"));
+
+    let relative = Path::new(path);
+    let lexically_inside = relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    if path.is_empty() || !lexically_inside {
+        return Err(invalid());
+    }
+
+    let path = root.join(relative);
+    let parent = path.parent().unwrap_or(root);
+    let parent = tokio::fs::canonicalize(parent)
+        .await
+        .map_err(io_error(parent))?;
+    if !parent.starts_with(root) {
+        return Err(invalid());
+    }
+    Ok(path)
+}
+
+/// Remove what is in the way of a new entry
+async fn remove_existing(path: &Path) -> Result<(), Status> {
+    let result = match tokio::fs::symlink_metadata(path).await {
+        Ok(metadata) if metadata.is_dir() => tokio::fs::remove_dir_all(path).await,
+        Ok(_) => tokio::fs::remove_file(path).await,
+        Err(_) => Ok(()),
+    };
+    result.map_err(io_error(path))
+}
+
+/// Apply the mtime and mode of `stat` and give the entry to the current user,
+/// the mode last as changing the owner clears setuid and a read-only mode
+/// prevents opening the file
+fn set_metadata(path: &Path, stat: &Stat) -> io::Result<()> {
+    chown(path)?;
+
+    // fsutil sends the nanoseconds since the epoch
+    let mod_time = UNIX_EPOCH + Duration::from_nanos(stat.mod_time.max(0) as u64);
+    std::fs::File::open(path)?.set_modified(mod_time)?;
+
+    set_mode(path, FileMode::from_bits_retain(stat.mode))
+}
+
+fn io_error(path: &Path) -> impl FnOnce(io::Error) -> Status + '_ {
+    move |err| Status::internal(format!("{}: {err}", path.display()))
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: FileMode) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    std::fs::set_permissions(
+        path,
+        std::fs::Permissions::from_mode(mode.unix_permissions()),
+    )
+}
+
+#[cfg(not(unix))]
+fn set_mode(path: &Path, mode: FileMode) -> io::Result<()> {
+    let mut permissions = std::fs::metadata(path)?.permissions();
+    permissions.set_readonly(!mode.contains(FileMode::USER_WRITE));
+    std::fs::set_permissions(path, permissions)
+}
+
+#[cfg(unix)]
+fn symlink(target: &str, path: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(not(unix))]
+fn symlink(_target: &str, _path: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "symlinks are not supported on this platform",
+    ))
+}
+
+/// Give `path` to the current user, the owner in the stream is the one in
+/// the build which means nothing on this host
+#[cfg(unix)]
+fn chown(path: &Path) -> io::Result<()> {
+    // SAFETY: getuid and getgid are always successful
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    std::os::unix::fs::lchown(path, Some(uid), Some(gid))
+}
+
+#[cfg(not(unix))]
+fn chown(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    use super::*;
+
+    const MOD_TIME: i64 = 1_700_000_000_123_456_789;
+
+    fn stat(path: &str, mode: FileMode, linkname: &str) -> Packet {
+        Packet {
+            r#type: PacketType::PacketStat.into(),
+            stat: Some(Stat {
+                path: path.into(),
+                mode: mode.bits(),
+                uid: 1000,
+                gid: 1000,
+                mod_time: MOD_TIME,
+                linkname: linkname.into(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn data(id: u32, data: &[u8]) -> Packet {
+        Packet {
+            r#type: PacketType::PacketData.into(),
+            id,
+            data: data.into(),
+            ..Default::default()
+        }
+    }
+
+    fn packet(packet_type: PacketType) -> Packet {
+        Packet {
+            r#type: packet_type.into(),
+            ..Default::default()
+        }
+    }
+
+    async fn receive_packets(
+        dest: &Path,
+        packets: Vec<Packet>,
+    ) -> (Result<(), Status>, Vec<Packet>) {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+        let result = receive(
+            dest,
+            futures::stream::iter(packets.into_iter().map(Ok)),
+            &tx,
+        )
+        .await;
+        drop(tx);
+
+        let mut sent = Vec::new();
+        while let Some(packet) = rx.recv().await {
+            sent.push(packet.unwrap());
+        }
+        (result, sent)
+    }
+
+    #[tokio::test]
+    async fn receive_tree() {
+        let mode = |bits| FileMode::from_bits_retain(bits);
+        let dir = FileMode::MODE_DIR;
+
+        let packets = vec![
+            stat("bin", dir | mode(0o755), ""),
+            stat("bin/run.sh", mode(0o755), ""),
+            stat("empty", mode(0o600), ""),
+            stat("link", FileMode::MODE_SYMLINK | mode(0o777), "bin/run.sh"),
+            stat("readonly", dir | mode(0o555), ""),
+            stat("readonly/data.txt", mode(0o444), ""),
+            stat("run", mode(0o755), "bin/run.sh"),
+            packet(PacketType::PacketStat),
+            data(1, b"#!/bin/sh\n"),
+            data(1, b"echo hello\n"),
+            data(1, b""),
+            data(2, b""),
+            data(5, b"data"),
+            data(5, b""),
+            packet(PacketType::PacketFin),
+        ];
+
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path();
+        let (result, sent) = receive_packets(dest, packets).await;
+        result.unwrap();
+
+        let sent: Vec<_> = sent.iter().map(|p| (p.r#type(), p.id)).collect();
+        assert_eq!(
+            sent,
+            [
+                (PacketType::PacketReq, 1),
+                (PacketType::PacketReq, 2),
+                (PacketType::PacketReq, 5),
+                (PacketType::PacketFin, 0),
+            ]
+        );
+
+        let script = dest.join("bin/run.sh");
+        assert_eq!(
+            std::fs::read_to_string(&script).unwrap(),
+            "#!/bin/sh\necho hello\n"
+        );
+        assert_eq!(std::fs::read(dest.join("empty")).unwrap(), b"");
+        assert_eq!(
+            std::fs::read_to_string(dest.join("readonly/data.txt")).unwrap(),
+            "data"
+        );
+        assert_eq!(
+            std::fs::read_link(dest.join("link")).unwrap(),
+            Path::new("bin/run.sh")
+        );
+
+        let perms = |path: &str| std::fs::metadata(dest.join(path)).unwrap().mode() & 0o7777;
+        assert_eq!(perms("bin/run.sh"), 0o755);
+        assert_eq!(perms("empty"), 0o600);
+        assert_eq!(perms("readonly"), 0o555);
+        assert_eq!(perms("readonly/data.txt"), 0o444);
+
+        let metadata = std::fs::metadata(&script).unwrap();
+        assert_eq!(
+            std::fs::metadata(dest.join("run")).unwrap().ino(),
+            metadata.ino()
+        );
+        let mod_time = UNIX_EPOCH + Duration::from_nanos(MOD_TIME as u64);
+        assert_eq!(metadata.modified().unwrap(), mod_time);
+        assert_eq!(
+            std::fs::metadata(dest.join("readonly"))
+                .unwrap()
+                .modified()
+                .unwrap(),
+            mod_time
+        );
+
+        // The owners in the stream are replaced by the current user
+        let owner = unsafe { (libc::getuid(), libc::getgid()) };
+        assert_eq!((metadata.uid(), metadata.gid()), owner);
+        let link = std::fs::symlink_metadata(dest.join("link")).unwrap();
+        assert_eq!((link.uid(), link.gid()), owner);
+
+        // Let the temporary directory remove the read-only directory
+        std::fs::set_permissions(
+            dest.join("readonly"),
+            std::fs::Permissions::from_mode(0o755),
+        )
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn receive_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path();
+
+        let (result, _) = receive_packets(
+            dest,
+            vec![stat("../escape", FileMode::from_bits_retain(0o644), "")],
+        )
+        .await;
+        assert_eq!(result.unwrap_err().code(), tonic::Code::InvalidArgument);
+
+        let mut error = packet(PacketType::PacketErr);
+        error.data = b"permission denied".to_vec();
+        let (result, _) = receive_packets(dest, vec![error]).await;
+        assert_eq!(
+            result.unwrap_err().message(),
+            "error from sender: permission denied"
+        );
+
+        // Writing through a symlink of the stream
+        let (result, _) = receive_packets(
+            dest,
+            vec![
+                stat(
+                    "parent",
+                    FileMode::MODE_SYMLINK | FileMode::from_bits_retain(0o777),
+                    "..",
+                ),
+                stat("parent/escape", FileMode::from_bits_retain(0o644), ""),
+            ],
+        )
+        .await;
+        assert_eq!(result.unwrap_err().code(), tonic::Code::InvalidArgument);
+
+        // Data was requested but never sent
+        let (result, _) = receive_packets(
+            dest,
+            vec![
+                stat("file", FileMode::from_bits_retain(0o644), ""),
+                packet(PacketType::PacketStat),
+            ],
+        )
+        .await;
+        assert_eq!(result.unwrap_err().code(), tonic::Code::Aborted);
+    }
+}
@@ -86,4 +86,19 @@ impl FileMode {
 
         mode
     }
+
+    /// The permission bits of a unix mode, including setuid, setgid and sticky
+    pub(crate) fn unix_permissions(self) -> u32 {
+        let mut mode = (self & Self::MODE_PERM_MASK).bits();
+        if self.contains(Self::MODE_SETUID) {
+            mode |= 0o4000;
+        }
+        if self.contains(Self::MODE_SETGID) {
+            mode |= 0o2000;
+        }
+        if self.contains(Self::MODE_STICKY) {
+            mode |= 0o1000;
+        }
+        mode
+    }
 }
@@ -1,2 +1,5 @@
-pub(crate) mod id;
 pub(crate) mod file_mode;
+pub(crate) mod id;
+pub(crate) mod pattern;
+#[cfg(test)]
+pub(crate) mod test_util;
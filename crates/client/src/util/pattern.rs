@@ -0,0 +1,222 @@
+use std::path::{Component, Path};
+
+/// A pattern of the include or exclude patterns of a local source, with the
+/// semantics of moby's `patternmatcher`.
+///
+/// `*` and `?` match within a path component, `**` matches any number of
+/// components and a leading `!` makes an exclude pattern an exception.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Pattern {
+    segments: Vec<String>,
+    exception: bool,
+}
+
+impl Pattern {
+    pub(crate) fn new(pattern: &str) -> Self {
+        let (pattern, exception) = match pattern.strip_prefix('!') {
+            Some(pattern) => (pattern.trim(), true),
+            None => (pattern, false),
+        };
+        let segments = pattern
+            .split('/')
+            .filter(|segment| !segment.is_empty() && *segment != ".")
+            .map(Into::into)
+            .collect();
+        Self {
+            segments,
+            exception,
+        }
+    }
+
+    pub(crate) fn is_exception(&self) -> bool {
+        self.exception
+    }
+
+    /// Whether the pattern matches `path` or one of its parents
+    pub(crate) fn matches(&self, path: &Path) -> bool {
+        let components = components(path);
+        (1..=components.len()).any(|len| match_segments(&self.segments, &components[..len]))
+    }
+
+    /// Whether the pattern can match a path inside the directory `dir`
+    pub(crate) fn matches_below(&self, dir: &Path) -> bool {
+        could_match_below(&self.segments, &components(dir))
+    }
+}
+
+/// Whether a path is excluded by `patterns`, the last matching pattern wins
+pub(crate) fn excluded(patterns: &[Pattern], path: &Path) -> bool {
+    patterns.iter().fold(false, |excluded, pattern| {
+        // Only exceptions can change the result once a path is excluded
+        match excluded == pattern.exception && pattern.matches(path) {
+            true => !pattern.exception,
+            false => excluded,
+        }
+    })
+}
+
+fn components(path: &Path) -> Vec<&str> {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => name.to_str(),
+            _ => None,
+        })
+        .collect()
+}
+
+fn match_segments(pattern: &[String], path: &[&str]) -> bool {
+    match (pattern.split_first(), path.split_first()) {
+        (None, None) => true,
+        // A trailing `**` only matches inside the directory before it
+        (Some((first, [])), _) if first == "**" => !path.is_empty(),
+        (Some((first, rest)), _) if first == "**" => {
+            (0..=path.len()).any(|skip| match_segments(rest, &path[skip..]))
+        }
+        (Some((first, rest)), Some((name, path))) => {
+            match_segment(first, name) && match_segments(rest, path)
+        }
+        _ => false,
+    }
+}
+
+fn could_match_below(pattern: &[String], dir: &[&str]) -> bool {
+    match (pattern.split_first(), dir.split_first()) {
+        (Some((first, _)), _) if first == "**" => true,
+        // Anything below the directory has at least one more component
+        (Some(_), None) => true,
+        (Some((first, rest)), Some((name, dir))) => {
+            match_segment(first, name) && could_match_below(rest, dir)
+        }
+        (None, _) => false,
+    }
+}
+
+/// Match a single path component, like Go's `filepath.Match`
+fn match_segment(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+    match_chars(&pattern, &name)
+}
+
+fn match_chars(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|skip| match_chars(rest, &name[skip..])),
+        Some(('?', rest)) => !name.is_empty() && match_chars(rest, &name[1..]),
+        Some(('[', rest)) => match (name.split_first(), match_class(rest)) {
+            (Some((c, name)), Some((class, negated, rest))) => {
+                class_contains(class, *c) != negated && match_chars(rest, name)
+            }
+            _ => false,
+        },
+        Some(('\\', [escaped, rest @ ..])) => {
+            name.first() == Some(escaped) && match_chars(rest, &name[1..])
+        }
+        Some((c, rest)) => name.first() == Some(c) && match_chars(rest, &name[1..]),
+    }
+}
+
+/// Split a character class after its `[` into its contents, whether it is
+/// negated and the rest of the pattern, `None` if it is not closed
+fn match_class(pattern: &[char]) -> Option<(&[char], bool, &[char])> {
+    let (negated, pattern) = match pattern.split_first() {
+        Some(('^' | '!', rest)) => (true, rest),
+        _ => (false, pattern),
+    };
+    // A `]` right after the `[` is part of the class
+    let end = pattern
+        .iter()
+        .skip(1)
+        .position(|c| *c == ']')
+        .map(|end| end + 1)?;
+    Some((&pattern[..end], negated, &pattern[end + 1..]))
+}
+
+fn class_contains(class: &[char], c: char) -> bool {
+    let mut idx = 0;
+    while idx < class.len() {
+        match class[idx..] {
+            [lo, '-', hi, ..] => {
+                if lo <= c && c <= hi {
+                    return true;
+                }
+                idx += 3;
+            }
+            [member, ..] => {
+                if member == c {
+                    return true;
+                }
+                idx += 1;
+            }
+            [] => break,
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, path: &str) -> bool {
+        Pattern::new(pattern).matches(Path::new(path))
+    }
+
+    #[test]
+    fn glob() {
+        assert!(matches("*.rs", "main.rs"));
+        assert!(!matches("*.rs", "src/main.rs"));
+        assert!(matches("src/*.rs", "src/main.rs"));
+        assert!(matches("src/ma?n.rs", "src/main.rs"));
+        assert!(matches("src/[lm]*.rs", "src/main.rs"));
+        assert!(!matches("src/[^m]*.rs", "src/main.rs"));
+        assert!(matches("src/[a-z]ain.rs", "src/main.rs"));
+        assert!(matches("a\\*b", "a*b"));
+        assert!(!matches("a\\*b", "axb"));
+    }
+
+    #[test]
+    fn parents() {
+        // A pattern matching a directory matches everything in it
+        assert!(matches("target", "target/debug/app"));
+        assert!(matches("/target/", "target/debug"));
+        assert!(!matches("target", "src/target"));
+        assert!(!matches("src/main.rs", "src"));
+    }
+
+    #[test]
+    fn double_star() {
+        assert!(matches("**/*.rs", "main.rs"));
+        assert!(matches("**/*.rs", "src/bin/main.rs"));
+        assert!(matches("src/**/mod.rs", "src/mod.rs"));
+        assert!(matches("src/**/mod.rs", "src/a/b/mod.rs"));
+        assert!(matches("target/**", "target/debug"));
+        assert!(!matches("target/**", "target"));
+        assert!(matches("**", "anything/at/all"));
+    }
+
+    #[test]
+    fn exceptions() {
+        let patterns = ["target", "*.md", "!README.md", "!target/keep"].map(Pattern::new);
+        assert!(excluded(&patterns, Path::new("target/debug")));
+        assert!(!excluded(&patterns, Path::new("target/keep/file")));
+        assert!(excluded(&patterns, Path::new("CHANGELOG.md")));
+        assert!(!excluded(&patterns, Path::new("README.md")));
+        assert!(!excluded(&patterns, Path::new("src")));
+
+        // The last matching pattern wins
+        let patterns = ["!README.md", "*.md"].map(Pattern::new);
+        assert!(excluded(&patterns, Path::new("README.md")));
+    }
+
+    #[test]
+    fn below() {
+        let below = |pattern: &str, dir: &str| Pattern::new(pattern).matches_below(Path::new(dir));
+        assert!(below("src/bin/*.rs", "src"));
+        assert!(below("src/bin/*.rs", "src/bin"));
+        assert!(!below("src/bin/*.rs", "src/bin/main.rs"));
+        assert!(!below("src/bin/*.rs", "docs"));
+        assert!(below("*/bin", "src"));
+        assert!(below("**/*.rs", "docs/deep"));
+    }
+}
@@ -0,0 +1,41 @@
+use tonic::transport::{server::Router, Channel, Endpoint, Server};
+use tonic_health::pb::health_server::{Health, HealthServer};
+
+/// A server without the control service, for tests where reaching the
+/// daemon is enough: every control call fails with `Unimplemented`
+pub(crate) fn no_control() -> Router {
+    Server::builder().add_service(health_service())
+}
+
+/// The only service of [`no_control`], for servers that need a configured
+/// builder
+pub(crate) fn health_service() -> HealthServer<impl Health> {
+    let (_, health_server) = tonic_health::server::health_reporter();
+    health_server
+}
+
+/// Serve `router` on a free local TCP port and return the port
+pub(crate) async fn serve_tcp(router: Router) -> u16 {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let incoming = tokio_stream::wrappers::TcpListenerStream::new(listener);
+    tokio::spawn(router.serve_with_incoming(incoming));
+    port
+}
+
+/// Serve `router` on a new unix socket at `path`
+#[cfg(unix)]
+pub(crate) fn serve_unix(router: Router, path: &std::path::Path) {
+    let listener = tokio::net::UnixListener::bind(path).unwrap();
+    let incoming = tokio_stream::wrappers::UnixListenerStream::new(listener);
+    tokio::spawn(router.serve_with_incoming(incoming));
+}
+
+/// Connect to a server of [`serve_tcp`] without TLS
+pub(crate) async fn connect(port: u16) -> Channel {
+    Endpoint::from_shared(format!("http://127.0.0.1:{port}"))
+        .unwrap()
+        .connect()
+        .await
+        .unwrap()
+}
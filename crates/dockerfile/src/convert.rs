@@ -0,0 +1,1264 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+
+use buildkit_rs_llb::{
+    Copy, Definition, EnvError, EnvMap, Exec, FileActions, Image, ImageConfig, ImageMetaResolver,
+    Local, MkFile, Mount, NetMode, Output, OutputError, Owner, Platform, PlatformError,
+    ResolveMode, SecurityMode, SingleOutput,
+};
+use buildkit_rs_reference::Reference;
+use buildkit_rs_util::shell::{Lex, ShellError};
+use thiserror::Error;
+
+use crate::parser::{
+    CmdLine, CopyCommand, Dockerfile, FromCommand, Heredoc, Instruction, MountKind, RunCommand,
+    RunMount,
+};
+
+/// The error type for converting a Dockerfile to LLB
+#[derive(Debug, Error)]
+pub enum ConvertError {
+    /// The Dockerfile does not contain a `FROM` instruction
+    #[error("the Dockerfile does not contain any stages")]
+    NoStages,
+
+    /// An instruction other than `ARG` is used before the first `FROM`
+    #[error("line {line}: only ARG is allowed before the first FROM")]
+    InstructionBeforeFrom { line: usize },
+
+    /// The target stage does not exist
+    #[error("target stage {0:?} not found")]
+    UnknownTarget(String),
+
+    /// The target stage does not produce a filesystem, such as `FROM scratch`
+    /// without any other instructions
+    #[error("the target stage is empty")]
+    EmptyTarget,
+
+    /// The instruction uses a feature that the converter does not support
+    #[error("line {line}: {feature} is not supported")]
+    Unsupported { line: usize, feature: String },
+
+    #[error("line {line}: {source}")]
+    Reference {
+        line: usize,
+        #[source]
+        source: buildkit_rs_reference::Error,
+    },
+
+    #[error("line {line}: {source}")]
+    Platform {
+        line: usize,
+        #[source]
+        source: PlatformError,
+    },
+
+    #[error("line {line}: {source}")]
+    Shell {
+        line: usize,
+        #[source]
+        source: ShellError,
+    },
+
+    #[error("line {line}: {source}")]
+    Env {
+        line: usize,
+        #[source]
+        source: EnvError,
+    },
+
+    #[error("line {line}: {source}")]
+    Output {
+        line: usize,
+        #[source]
+        source: OutputError,
+    },
+
+    /// The config of a base image could not be resolved
+    #[error("line {line}: failed to resolve {image}: {source}")]
+    Resolve {
+        line: usize,
+        image: String,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+}
+
+/// Options for [`convert`]
+#[derive(Debug, Clone)]
+pub struct ConvertOptions {
+    target: Option<String>,
+    build_args: BTreeMap<String, String>,
+    platform: Option<Platform>,
+    build_platform: Option<Platform>,
+    context_name: String,
+}
+
+impl Default for ConvertOptions {
+    fn default() -> Self {
+        Self {
+            target: None,
+            build_args: BTreeMap::new(),
+            platform: None,
+            build_platform: None,
+            context_name: "context".into(),
+        }
+    }
+}
+
+impl ConvertOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Build the stage with this name instead of the last stage
+    pub fn with_target(mut self, target: impl Into<String>) -> Self {
+        self.target = Some(target.into());
+        self
+    }
+
+    pub fn with_build_arg(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.build_args.insert(name.into(), value.into());
+        self
+    }
+
+    /// The target platform, defaults to the platform of the definition
+    pub fn with_platform(mut self, platform: Platform) -> Self {
+        self.platform = Some(platform);
+        self
+    }
+
+    /// The platform of the worker, used for `BUILDPLATFORM` and as the
+    /// default `TARGETPLATFORM`. Defaults to linux on the architecture of
+    /// the host, a client on another OS should use a platform of the worker
+    /// as listed by `ListWorkers`.
+    pub fn with_build_platform(mut self, platform: Platform) -> Self {
+        self.build_platform = Some(platform);
+        self
+    }
+
+    /// The name of the local source used for the build context
+    pub fn with_context_name(mut self, name: impl Into<String>) -> Self {
+        self.context_name = name.into();
+        self
+    }
+
+    fn build_platform(&self) -> Platform {
+        self.build_platform.clone().unwrap_or_else(|| Platform {
+            os: "linux".into(),
+            ..Platform::host()
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Stage {
+    name: Option<String>,
+    /// `None` for scratch
+    root: Option<Output>,
+    platform: Option<Platform>,
+    /// The args declared in the stage, they are only visible to `RUN`
+    args: EnvMap,
+    env: EnvMap,
+    /// Only set by `WORKDIR` or the base image, execs default to `/`
+    cwd: Option<String>,
+    user: Option<String>,
+    shell: Vec<String>,
+}
+
+impl Stage {
+    /// The variables used for expansion, the env takes precedence over args
+    fn vars(&self) -> EnvMap {
+        let mut vars = self.args.clone();
+        vars.merge(&self.env);
+        vars
+    }
+
+    fn cwd(&self) -> &str {
+        self.cwd.as_deref().unwrap_or("/")
+    }
+}
+
+/// The configs of base images, by reference and platform
+type ImageConfigs = HashMap<(String, Option<Platform>), ImageConfig>;
+
+struct Converter<'a> {
+    options: &'a ConvertOptions,
+    lex: Lex,
+    global_args: EnvMap,
+    stages: Vec<Stage>,
+    context: Option<Output>,
+    configs: &'a ImageConfigs,
+    /// Base images that are not in `configs`, with the line of their `FROM`
+    unresolved: Vec<(usize, Reference, Option<Platform>)>,
+}
+
+/// Convert a Dockerfile to LLB.
+///
+/// This does not resolve image configs, so the env, working directory and
+/// user of the base images are not known to the build, see
+/// [`convert_with_resolver`]. `ADD` does not support remote sources.
+pub fn convert(
+    dockerfile: &Dockerfile,
+    options: &ConvertOptions,
+) -> Result<Definition, ConvertError> {
+    Converter::new(dockerfile, options, &HashMap::new()).convert(dockerfile)
+}
+
+/// Convert a Dockerfile to LLB, with the env, working directory and user of
+/// the base images resolved with `resolver`
+pub async fn convert_with_resolver<R>(
+    dockerfile: &Dockerfile,
+    options: &ConvertOptions,
+    resolver: &mut R,
+) -> Result<Definition, ConvertError>
+where
+    R: ImageMetaResolver,
+    R::Error: std::error::Error + Send + Sync + 'static,
+{
+    // The base images are only known once the args of each `FROM` are
+    // expanded, so they are collected by converting without configs first
+    let mut configs = ImageConfigs::new();
+    let mut converter = Converter::new(dockerfile, options, &configs);
+    converter.convert(dockerfile)?;
+
+    for (line, reference, platform) in converter.unresolved {
+        let resolved = resolver
+            .resolve_image_config(&reference, platform.as_ref(), ResolveMode::Default)
+            .await
+            .map_err(|source| ConvertError::Resolve {
+                line,
+                image: reference.to_string(),
+                source: Box::new(source),
+            })?;
+        configs.insert((reference.to_string(), platform), resolved.config);
+    }
+
+    Converter::new(dockerfile, options, &configs).convert(dockerfile)
+}
+
+impl<'a> Converter<'a> {
+    fn new(
+        dockerfile: &Dockerfile,
+        options: &'a ConvertOptions,
+        configs: &'a ImageConfigs,
+    ) -> Self {
+        Self {
+            options,
+            lex: Lex::new(dockerfile.directives.escape),
+            global_args: EnvMap::new(),
+            stages: Vec::new(),
+            context: None,
+            configs,
+            unresolved: Vec::new(),
+        }
+    }
+
+    fn convert(&mut self, dockerfile: &Dockerfile) -> Result<Definition, ConvertError> {
+        let options = self.options;
+        self.set_platform_args(0)?;
+
+        let mut target = None;
+        for statement in &dockerfile.statements {
+            let line = statement.line;
+            match (&statement.instruction, self.stages.is_empty()) {
+                (Instruction::From(from), _) => {
+                    // All stages after the target are not needed
+                    if target.is_some() {
+                        break;
+                    }
+
+                    let stage = self.from(line, from)?;
+                    let is_target = |target: &String| {
+                        stage
+                            .name
+                            .as_ref()
+                            .is_some_and(|name| name.eq_ignore_ascii_case(target))
+                    };
+                    if options.target.as_ref().is_some_and(is_target) {
+                        target = Some(self.stages.len());
+                    }
+                    self.stages.push(stage);
+                }
+                (Instruction::Arg(args), true) => {
+                    for (name, default) in args {
+                        let value = match options.build_args.get(name) {
+                            Some(value) => value.clone(),
+                            None => match default {
+                                Some(default) => self.expand(line, default, &self.global_args)?,
+                                None => continue,
+                            },
+                        };
+                        self.global_args
+                            .set(name, value)
+                            .map_err(|source| ConvertError::Env { line, source })?;
+                    }
+                }
+                (_, true) => return Err(ConvertError::InstructionBeforeFrom { line }),
+                (instruction, false) => self.instruction(line, instruction)?,
+            }
+        }
+
+        let stage = match (&options.target, target) {
+            (Some(_), Some(idx)) => &self.stages[idx],
+            (Some(name), None) => return Err(ConvertError::UnknownTarget(name.clone())),
+            (None, _) => self.stages.last().ok_or(ConvertError::NoStages)?,
+        };
+        let root = stage.root.clone().ok_or(ConvertError::EmptyTarget)?;
+
+        let definition = Definition::new(root);
+        Ok(match &options.platform {
+            Some(platform) => definition.with_platform(platform.clone()),
+            None => definition,
+        })
+    }
+}
+
+impl Converter<'_> {
+    /// Set the automatic `BUILDPLATFORM` and `TARGETPLATFORM` args, based on
+    /// the options
+    fn set_platform_args(&mut self, line: usize) -> Result<(), ConvertError> {
+        let build = self.options.build_platform();
+        let target = self
+            .options
+            .platform
+            .clone()
+            .unwrap_or_else(|| build.clone());
+
+        for (prefix, platform) in [("BUILD", build), ("TARGET", target)] {
+            let vars = [
+                ("PLATFORM", platform.to_string()),
+                ("OS", platform.os.to_string()),
+                ("ARCH", platform.architecture.to_string()),
+                ("VARIANT", platform.variant.unwrap_or_default().to_string()),
+            ];
+            for (suffix, value) in vars {
+                self.global_args
+                    .set(format!("{prefix}{suffix}"), value)
+                    .map_err(|source| ConvertError::Env { line, source })?;
+            }
+        }
+        Ok(())
+    }
+
+    fn expand(&self, line: usize, word: &str, vars: &EnvMap) -> Result<String, ConvertError> {
+        self.lex
+            .process_word(word, vars)
+            .map_err(|source| ConvertError::Shell { line, source })
+    }
+
+    fn find_stage(&self, name: &str) -> Option<&Stage> {
+        match name.parse::<usize>() {
+            Ok(idx) => self.stages.get(idx),
+            Err(_) => {
+                let name = name.to_ascii_lowercase();
+                self.stages
+                    .iter()
+                    .rev()
+                    .find(|stage| stage.name.as_deref() == Some(name.as_str()))
+            }
+        }
+    }
+
+    fn reference(&self, line: usize, name: &str) -> Result<Reference, ConvertError> {
+        let parse = |name: &str| {
+            Reference::parse_normalized_named(name)
+                .map_err(|source| ConvertError::Reference { line, source })
+        };
+
+        // Images without a tag or digest use the latest tag, like `docker pull`
+        let reference = parse(name)?;
+        match reference.tag().is_none() && reference.digest().is_none() {
+            true => parse(&format!("{name}:latest")),
+            false => Ok(reference),
+        }
+    }
+
+    fn image(
+        &self,
+        line: usize,
+        name: &str,
+        platform: Option<&Platform>,
+    ) -> Result<Output, ConvertError> {
+        let reference = self.reference(line, name)?;
+        Ok(image_output(reference, platform))
+    }
+
+    /// The output of a stage or image used by `--from`, `None` for scratch
+    fn source(
+        &self,
+        line: usize,
+        from: &str,
+        platform: Option<&Platform>,
+    ) -> Result<Option<Output>, ConvertError> {
+        let from = self.expand(line, from, &self.global_args)?;
+        match self.find_stage(&from) {
+            Some(stage) => Ok(stage.root.clone()),
+            None if from == "scratch" => Ok(None),
+            None => self.image(line, &from, platform).map(Some),
+        }
+    }
+
+    fn context(&mut self) -> Output {
+        let name = &self.options.context_name;
+        self.context
+            .get_or_insert_with(|| Arc::new(Local::new(name.clone())).output())
+            .clone()
+    }
+
+    fn from(&mut self, line: usize, from: &FromCommand) -> Result<Stage, ConvertError> {
+        let platform = match &from.platform {
+            Some(platform) => Some(
+                self.expand(line, platform, &self.global_args)?
+                    .parse::<Platform>()
+                    .map_err(|source| ConvertError::Platform { line, source })?,
+            ),
+            None => self.options.platform.clone(),
+        };
+
+        let image = self.expand(line, &from.image, &self.global_args)?;
+        let base = match self.find_stage(&image) {
+            // Only stages can be referenced by name, not by index
+            Some(stage) if image.parse::<usize>().is_err() => Stage {
+                args: EnvMap::new(),
+                ..stage.clone()
+            },
+            _ if image == "scratch" => Stage {
+                name: None,
+                root: None,
+                platform: platform.clone(),
+                args: EnvMap::new(),
+                env: EnvMap::new(),
+                cwd: None,
+                user: None,
+                shell: vec!["/bin/sh".into(), "-c".into()],
+            },
+            _ => {
+                let reference = self.reference(line, &image)?;
+                let key = (reference.to_string(), platform.clone());
+                let config = self.configs.get(&key).cloned().unwrap_or_else(|| {
+                    self.unresolved
+                        .push((line, reference.clone(), platform.clone()));
+                    ImageConfig::default()
+                });
+
+                Stage {
+                    name: None,
+                    root: Some(image_output(reference, platform.as_ref())),
+                    platform: platform.clone(),
+                    args: EnvMap::new(),
+                    env: EnvMap::parse(&config.env)
+                        .map_err(|source| ConvertError::Env { line, source })?,
+                    cwd: config.working_dir,
+                    user: config.user,
+                    shell: vec!["/bin/sh".into(), "-c".into()],
+                }
+            }
+        };
+
+        Ok(Stage {
+            name: from.name.clone(),
+            platform: platform.or(base.platform.clone()),
+            ..base
+        })
+    }
+
+    fn instruction(&mut self, line: usize, instruction: &Instruction) -> Result<(), ConvertError> {
+        let env_error = |source| ConvertError::Env { line, source };
+
+        match instruction {
+            Instruction::Run(run) => self.run(line, run),
+            Instruction::Copy(copy) => self.copy(line, copy, false),
+            Instruction::Add(copy) => self.copy(line, copy, true),
+            Instruction::Arg(args) => {
+                let stage = self.stages.last().unwrap();
+                let mut stage_args = stage.args.clone();
+                for (name, default) in args {
+                    let value = match self.options.build_args.get(name) {
+                        Some(value) => value.clone(),
+                        None => match (self.global_args.get(name), default) {
+                            (Some(value), None) => value.into(),
+                            (_, Some(default)) => self.expand(line, default, &stage.vars())?,
+                            // The arg is declared without a value
+                            (None, None) => continue,
+                        },
+                    };
+                    stage_args.set(name, value).map_err(env_error)?;
+                }
+                self.stages.last_mut().unwrap().args = stage_args;
+                Ok(())
+            }
+            Instruction::Env(vars) => {
+                let stage = self.stages.last().unwrap();
+                // Values are expanded with the env from before the instruction,
+                // so `ENV abc=bye def=$abc` sets def to the previous abc
+                let expand_vars = stage.vars();
+                let mut env = stage.env.clone();
+                for (key, value) in vars {
+                    let value = self.expand(line, value, &expand_vars)?;
+                    env.set(self.expand(line, key, &expand_vars)?, value)
+                        .map_err(env_error)?;
+                }
+                self.stages.last_mut().unwrap().env = env;
+                Ok(())
+            }
+            Instruction::Workdir(dir) => {
+                let stage = self.stages.last().unwrap();
+                let cwd = resolve_path(stage.cwd(), &self.expand(line, dir, &stage.vars())?);
+                self.stages.last_mut().unwrap().cwd = Some(cwd);
+                Ok(())
+            }
+            Instruction::User(user) => {
+                let stage = self.stages.last().unwrap();
+                let user = self.expand(line, user, &stage.vars())?;
+                self.stages.last_mut().unwrap().user = Some(user);
+                Ok(())
+            }
+            Instruction::Shell(shell) => {
+                self.stages.last_mut().unwrap().shell = shell.clone();
+                Ok(())
+            }
+            // The rest only changes the image config, which is not part of
+            // the definition
+            _ => Ok(()),
+        }
+    }
+
+    fn run(&mut self, line: usize, run: &RunCommand) -> Result<(), ConvertError> {
+        let unsupported = |feature: String| ConvertError::Unsupported { line, feature };
+        let network = match run.network.as_deref() {
+            None | Some("default") => NetMode::Sandbox,
+            Some("host") => NetMode::Host,
+            Some("none") => NetMode::None,
+            Some(network) => return Err(unsupported(format!("RUN --network={network}"))),
+        };
+        let security = match run.security.as_deref() {
+            None | Some("sandbox") => SecurityMode::Sandbox,
+            Some("insecure") => SecurityMode::Insecure,
+            Some(security) => return Err(unsupported(format!("RUN --security={security}"))),
+        };
+
+        let stage = self.stages.last().unwrap();
+        let args = match &run.command {
+            CmdLine::Exec(args) => args.clone(),
+            CmdLine::Shell(command) => {
+                let mut args = stage.shell.clone();
+                args.push(shell_script(command, &run.heredocs));
+                args
+            }
+        };
+
+        let mut exec = Exec::new(args)
+            .with_env(stage.vars())
+            .with_network(network)
+            .with_security(security)
+            .with_mount(match &stage.root {
+                Some(root) => Mount::layer(root.clone(), "/"),
+                None => Mount::scratch("/"),
+            });
+        if let Some(cwd) = &stage.cwd {
+            exec = exec.with_cwd(cwd.clone());
+        }
+        if let Some(user) = &stage.user {
+            exec = exec.with_user(user.clone());
+        }
+        if let Some(platform) = &stage.platform {
+            exec = exec.with_platform(platform.clone());
+        }
+
+        for mount in &run.mounts {
+            exec = exec.with_mount(self.run_mount(line, mount)?);
+        }
+
+        let root = Arc::new(exec)
+            .root()
+            .map_err(|source| ConvertError::Output { line, source })?;
+        self.stages.last_mut().unwrap().root = Some(root);
+        Ok(())
+    }
+
+    fn run_mount(&mut self, line: usize, mount: &RunMount) -> Result<Mount, ConvertError> {
+        let stage = self.stages.last().unwrap();
+        let platform = stage.platform.clone();
+        let target = mount
+            .target
+            .as_ref()
+            .map(|target| {
+                let target = self.expand(line, target, &stage.vars())?;
+                Ok(resolve_path(stage.cwd(), &target))
+            })
+            .transpose()?;
+
+        Ok(match mount.kind {
+            MountKind::Bind => {
+                let target = target.unwrap_or_default();
+                let input = match &mount.from {
+                    Some(from) => self.source(line, from, platform.as_ref())?.ok_or_else(|| {
+                        ConvertError::Unsupported {
+                            line,
+                            feature: "bind mounting scratch".into(),
+                        }
+                    })?,
+                    None => self.context(),
+                };
+                if !mount.readonly {
+                    return Err(ConvertError::Unsupported {
+                        line,
+                        feature: "writable bind mounts".into(),
+                    });
+                }
+
+                let bind = Mount::layer_readonly(input, target);
+                match mount
+                    .source
+                    .as_deref()
+                    .map(|source| source.trim_start_matches('/'))
+                {
+                    Some(source) if !source.is_empty() => bind.with_selector(format!("/{source}")),
+                    _ => bind,
+                }
+            }
+            MountKind::Cache => {
+                let target = target.unwrap_or_default();
+                let id = mount.id.clone().unwrap_or_else(|| target.clone());
+                Mount::cache(target, id, mount.sharing)
+            }
+            MountKind::Tmpfs => Mount::tmpfs(target.unwrap_or_default(), mount.size.unwrap_or(0)),
+            MountKind::Secret => {
+                let id = mount.id.clone().unwrap_or_else(|| {
+                    let target = target.as_deref().unwrap_or_default();
+                    target.rsplit('/').next().unwrap_or_default().into()
+                });
+                Mount::secret(
+                    target.unwrap_or_else(|| format!("/run/secrets/{id}")),
+                    id,
+                    mount.uid.unwrap_or(0),
+                    mount.gid.unwrap_or(0),
+                    mount.mode.unwrap_or(0o400),
+                    !mount.required,
+                )
+            }
+            MountKind::Ssh => Mount::ssh(
+                target.unwrap_or_else(|| "/run/buildkit/ssh_agent.0".into()),
+                mount.id.clone().unwrap_or_else(|| "default".into()),
+                mount.uid.unwrap_or(0),
+                mount.gid.unwrap_or(0),
+                mount.mode.unwrap_or(0o600),
+                !mount.required,
+            ),
+        })
+    }
+}
+
+impl Converter<'_> {
+    /// `COPY` and `ADD` are file ops on the stage, with the same options as
+    /// dockerfile2llb
+    fn copy(&mut self, line: usize, copy: &CopyCommand, add: bool) -> Result<(), ConvertError> {
+        let stage = self.stages.last().unwrap();
+        let vars = stage.vars();
+        let platform = stage.platform.clone();
+
+        let sources = copy
+            .sources
+            .iter()
+            .filter(|source| !copy.heredocs.iter().any(|h| is_heredoc_marker(source, h)))
+            .map(|source| self.expand(line, source, &vars))
+            .collect::<Result<Vec<_>, _>>()?;
+        if add {
+            if let Some(source) = sources.iter().find(|source| source.contains("://")) {
+                return Err(ConvertError::Unsupported {
+                    line,
+                    feature: format!("ADD from {source}"),
+                });
+            }
+        }
+
+        let expanded_dest = self.expand(line, &copy.dest, &vars)?;
+        // Names are looked up in the stage, a user without a group uses its
+        // id as the group id, like Docker does
+        let owner = copy
+            .chown
+            .as_ref()
+            .map(|chown| Ok(Owner::parse(&self.expand(line, chown, &vars)?)))
+            .transpose()?;
+        let mode = copy
+            .chmod
+            .as_ref()
+            .map(|chmod| {
+                let chmod = self.expand(line, chmod, &vars)?;
+                u32::from_str_radix(&chmod, 8).map_err(|_| ConvertError::Unsupported {
+                    line,
+                    feature: format!("--chmod={chmod}"),
+                })
+            })
+            .transpose()?;
+
+        // A destination ending with `/` is a directory to copy into
+        let mut dest = resolve_path(stage.cwd(), &expanded_dest);
+        if sources.len() + copy.heredocs.len() > 1 && !dest.ends_with('/') {
+            dest.push('/');
+        }
+
+        let options = |copy: Copy| {
+            let copy = copy
+                .with_follow_symlink(true)
+                .with_dir_copy_contents(true)
+                .with_create_dest_path(true)
+                .with_allow_wildcard(true, true);
+            let copy = match &owner {
+                Some(owner) => copy.with_owner(owner.clone()),
+                None => copy,
+            };
+            match mode {
+                Some(mode) => copy.with_mode(mode),
+                None => copy,
+            }
+        };
+
+        let mut file = FileActions::new(stage.root.clone());
+        if !sources.is_empty() {
+            let source = match &copy.from {
+                Some(from) => self.source(line, from, platform.as_ref())?.ok_or_else(|| {
+                    ConvertError::Unsupported {
+                        line,
+                        feature: "copying from scratch".into(),
+                    }
+                })?,
+                None => self.context(),
+            };
+            for path in &sources {
+                let path = resolve_path("/", path);
+                let action = Copy::new(source.clone(), path, dest.clone()).with_attempt_unpack(add);
+                file = file.with_action(options(action));
+            }
+        }
+
+        for heredoc in &copy.heredocs {
+            let content = match heredoc.expand {
+                // Quotes and escapes are kept, like in a shell heredoc
+                true => self
+                    .lex
+                    .with_raw_quotes(true)
+                    .with_raw_escapes(true)
+                    .process_word(&heredoc.content, &vars)
+                    .map_err(|source| ConvertError::Shell { line, source })?,
+                false => heredoc.content.clone(),
+            };
+            // The file is created on its own and copied like any other source
+            let path = format!("/{}", heredoc.name);
+            let created = FileActions::new(None).with_action(MkFile::new(&path, content));
+            let action = Copy::new(Arc::new(created).output(), path, dest.clone());
+            file = file.with_action(options(action));
+        }
+
+        if let Some(platform) = platform {
+            file = file.with_platform(platform);
+        }
+        self.stages.last_mut().unwrap().root = Some(Arc::new(file).output());
+        Ok(())
+    }
+}
+
+fn image_output(reference: Reference, platform: Option<&Platform>) -> Output {
+    let mut image = Image::from_reference(reference);
+    if let Some(platform) = platform {
+        image = image.with_platform(platform.clone());
+    }
+    Arc::new(image).output()
+}
+
+fn is_heredoc_marker(word: &str, heredoc: &Heredoc) -> bool {
+    let marker = word.trim_start_matches("<<").trim_start_matches('-');
+    word.starts_with("<<") && marker.trim_matches(['"', '\'']) == heredoc.name
+}
+
+/// The script for a shell form `RUN`, heredocs are passed to the shell as
+/// they were written unless the command is a single heredoc, which is run as
+/// the script itself
+fn shell_script(command: &str, heredocs: &[Heredoc]) -> String {
+    match heredocs {
+        [] => command.into(),
+        [heredoc] if is_heredoc_marker(command.trim(), heredoc) => heredoc.content.clone(),
+        heredocs => {
+            let mut script = format!("{command}\n");
+            for heredoc in heredocs {
+                // Leading tabs were already removed, so the delimiter is
+                // found by the shell either way
+                script.push_str(&heredoc.content);
+                script.push_str(&heredoc.name);
+                script.push('\n');
+            }
+            script
+        }
+    }
+}
+
+/// Resolve `path` relative to the absolute directory `cwd`
+fn resolve_path(cwd: &str, path: &str) -> String {
+    let joined = match path.starts_with('/') {
+        true => path.to_owned(),
+        false => format!("{}/{path}", cwd.trim_end_matches('/')),
+    };
+
+    let mut parts = Vec::new();
+    for part in joined.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+
+    let mut resolved = format!("/{}", parts.join("/"));
+    if path.ends_with('/') && resolved != "/" {
+        resolved.push('/');
+    }
+    resolved
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{future::Future, pin::Pin};
+
+    use buildkit_rs_llb::ResolvedImageConfig;
+    use buildkit_rs_util::test_util::block_on;
+    use serde_json::Value;
+
+    use super::*;
+
+    fn convert_str(input: &str, options: &ConvertOptions) -> Result<Definition, ConvertError> {
+        convert(&Dockerfile::parse(input).unwrap(), options)
+    }
+
+    /// The ops of the definition in the JSON form
+    fn ops(definition: &Definition) -> Vec<Value> {
+        let value = serde_json::to_value(definition).unwrap();
+        value
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|op| op["Op"]["op"].clone())
+            .collect()
+    }
+
+    fn execs(definition: &Definition) -> Vec<Value> {
+        ops(definition)
+            .into_iter()
+            .filter_map(|op| op.get("exec").cloned())
+            .collect()
+    }
+
+    /// The copy actions of all file ops
+    fn copies(definition: &Definition) -> Vec<Value> {
+        ops(definition)
+            .into_iter()
+            .filter_map(|op| op["file"]["actions"].as_array().cloned())
+            .flatten()
+            .filter_map(|action| action["action"].get("copy").cloned())
+            .collect()
+    }
+
+    #[test]
+    fn multi_stage() {
+        let definition = convert_str(
+            "FROM golang:1.21 AS build\n\
+             WORKDIR /src\n\
+             COPY . .\n\
+             RUN --mount=type=cache,target=/root/.cache/go-build go build -o /out/app\n\
+             \n\
+             FROM alpine\n\
+             COPY --from=build /out/app /usr/bin/\n\
+             USER nobody\n\
+             ENTRYPOINT [\"app\"]\n",
+            &ConvertOptions::new(),
+        )
+        .unwrap();
+        assert_eq!(definition.validate(), Ok(()));
+
+        let sources = ops(&definition)
+            .into_iter()
+            .filter_map(|op| op["source"]["identifier"].as_str().map(String::from))
+            .collect::<Vec<_>>();
+        assert!(sources.contains(&"docker-image://docker.io/library/golang:1.21".into()));
+        assert!(sources.contains(&"docker-image://docker.io/library/alpine:latest".into()));
+        assert!(sources.contains(&"local://context".into()));
+
+        let copies = copies(&definition);
+        assert_eq!(copies.len(), 2);
+        assert!(copies.iter().any(|copy| copy["dest"] == "/src"));
+        assert!(copies
+            .iter()
+            .any(|copy| copy["src"] == "/out/app" && copy["dest"] == "/usr/bin/"));
+
+        let execs = execs(&definition);
+        assert_eq!(execs.len(), 1);
+        let build = execs
+            .iter()
+            .find(|exec| exec["meta"]["args"][2] == "go build -o /out/app")
+            .unwrap();
+        assert_eq!(build["meta"]["cwd"], "/src");
+        assert_eq!(build["mounts"][1]["dest"], "/root/.cache/go-build");
+        assert_eq!(
+            build["mounts"][1]["cacheOpt"]["id"],
+            "/root/.cache/go-build"
+        );
+    }
+
+    #[test]
+    fn args_and_env() {
+        let definition = convert_str(
+            "ARG BASE=alpine\n\
+             FROM $BASE\n\
+             ARG VERSION=1\n\
+             ENV APP_VERSION=v$VERSION DIR=\"/opt/$VERSION\" LITERAL='$VERSION'\n\
+             ENV MODE=${MODE:-release} MAJOR=${APP_VERSION%%.*}\n\
+             WORKDIR $DIR\n\
+             WORKDIR bin\n\
+             RUN [\"./app\"]\n",
+            &ConvertOptions::new().with_build_arg("VERSION", "2.1"),
+        )
+        .unwrap();
+
+        let execs = execs(&definition);
+        let meta = &execs[0]["meta"];
+        assert_eq!(meta["args"], serde_json::json!(["./app"]));
+        assert_eq!(meta["cwd"], "/opt/2.1/bin");
+
+        let env = meta["env"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| v.as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert!(env.contains(&"VERSION=2.1"));
+        assert!(env.contains(&"APP_VERSION=v2.1"));
+        assert!(env.contains(&"LITERAL=$VERSION"));
+        assert!(env.contains(&"MODE=release"));
+        assert!(env.contains(&"MAJOR=v2"));
+    }
+
+    #[test]
+    fn env_expansion() {
+        let definition = convert_str(
+            "FROM alpine\n\
+             ENV abc=hello\n\
+             ENV abc=bye def=$abc\n\
+             RUN env\n",
+            &ConvertOptions::new(),
+        )
+        .unwrap();
+
+        let execs = execs(&definition);
+        let env = execs[0]["meta"]["env"].as_array().unwrap();
+        assert!(env.contains(&"abc=bye".into()));
+        assert!(env.contains(&"def=hello".into()));
+    }
+
+    struct StaticResolver;
+
+    impl ImageMetaResolver for StaticResolver {
+        type Error = std::io::Error;
+
+        fn resolve_image_config<'a>(
+            &'a mut self,
+            reference: &'a Reference,
+            _platform: Option<&'a Platform>,
+            _resolve_mode: ResolveMode,
+        ) -> Pin<Box<dyn Future<Output = Result<ResolvedImageConfig, Self::Error>> + Send + 'a>>
+        {
+            Box::pin(async move {
+                if reference.to_string() != "docker.io/library/golang:1.21" {
+                    return Err(std::io::Error::other("not found"));
+                }
+                Ok(ResolvedImageConfig {
+                    digest: String::new(),
+                    config: ImageConfig {
+                        env: vec![
+                            "PATH=/usr/local/go/bin:/usr/bin".into(),
+                            "GOPATH=/go".into(),
+                        ],
+                        working_dir: Some("/go".into()),
+                        user: Some("gopher".into()),
+                        platform: None,
+                    },
+                })
+            })
+        }
+    }
+
+    #[test]
+    fn resolved_base_image() {
+        let dockerfile = Dockerfile::parse(
+            "FROM golang:1.21\n\
+             RUN go build -o /out/app\n\
+             WORKDIR src\n\
+             RUN go test\n",
+        )
+        .unwrap();
+        let options = ConvertOptions::new();
+
+        let definition = block_on(convert_with_resolver(
+            &dockerfile,
+            &options,
+            &mut StaticResolver,
+        ))
+        .unwrap();
+        let execs = execs(&definition);
+        let meta = |args: &str| {
+            execs
+                .iter()
+                .find(|exec| exec["meta"]["args"][2] == args)
+                .map(|exec| exec["meta"].clone())
+                .unwrap()
+        };
+        let build = meta("go build -o /out/app");
+        assert_eq!(
+            build["env"],
+            serde_json::json!(["PATH=/usr/local/go/bin:/usr/bin", "GOPATH=/go"])
+        );
+        assert_eq!(build["cwd"], "/go");
+        assert_eq!(build["user"], "gopher");
+        assert_eq!(meta("go test")["cwd"], "/go/src");
+
+        let err = block_on(convert_with_resolver(
+            &Dockerfile::parse("FROM alpine\nRUN true\n").unwrap(),
+            &options,
+            &mut StaticResolver,
+        ))
+        .unwrap_err();
+        assert!(matches!(err, ConvertError::Resolve { line: 1, .. }));
+    }
+
+    #[test]
+    fn heredoc_run() {
+        let definition = convert_str(
+            "FROM alpine\n\
+             RUN <<EOF\n\
+             echo hello\n\
+             EOF\n\
+             RUN cat <<A > /a\n\
+             a\n\
+             A\n",
+            &ConvertOptions::new(),
+        )
+        .unwrap();
+
+        let args = execs(&definition)
+            .into_iter()
+            .map(|exec| exec["meta"]["args"][2].as_str().unwrap().to_owned())
+            .collect::<Vec<_>>();
+        assert!(args.contains(&"echo hello\n".into()));
+        assert!(args.contains(&"cat <<A > /a\na\nA\n".into()));
+    }
+
+    #[test]
+    fn copy_flags() {
+        let definition = convert_str(
+            "FROM alpine\n\
+             ARG UID=1000 MODE=0755\n\
+             COPY --chown=$UID:$UID --chmod=$MODE app /usr/bin/\n",
+            &ConvertOptions::new(),
+        )
+        .unwrap();
+
+        let copies = copies(&definition);
+        assert_eq!(copies[0]["src"], "/app");
+        assert_eq!(copies[0]["dest"], "/usr/bin/");
+        assert_eq!(copies[0]["mode"], 0o755);
+        assert_eq!(copies[0]["owner"]["user"]["user"]["byId"], 1000);
+        assert_eq!(copies[0]["owner"]["group"]["user"]["byId"], 1000);
+        assert_eq!(copies[0]["createDestPath"], true);
+        assert_eq!(copies[0]["allowWildcard"], true);
+        assert!(copies[0].get("attemptUnpackDockerCompatibility").is_none());
+
+        assert!(matches!(
+            convert_str(
+                "FROM alpine\nCOPY --chmod=u+x app /\n",
+                &ConvertOptions::new()
+            ),
+            Err(ConvertError::Unsupported { line: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn copy_ownership() {
+        let definition = convert_str(
+            "FROM alpine\nCOPY --chown=app src/ /usr/\n",
+            &ConvertOptions::new(),
+        )
+        .unwrap();
+
+        // Names are looked up in the stage, which is the input of the action
+        let value = serde_json::to_value(&definition).unwrap();
+        let file = value
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|op| op["Op"]["op"].get("file").is_some())
+            .unwrap();
+        let stage = &file["Op"]["inputs"][0]["digest"];
+        assert!(value
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|op| op["Digest"] == *stage
+                && op["Op"]["op"]["source"]["identifier"]
+                    == "docker-image://docker.io/library/alpine:latest"));
+
+        let action = &file["Op"]["op"]["file"]["actions"][0];
+        assert_eq!(action["input"], 0);
+        let owner = &action["action"]["copy"]["owner"];
+        assert_eq!(owner["user"]["user"]["byName"]["name"], "app");
+        assert_eq!(owner["user"]["user"]["byName"]["input"], 0);
+        assert!(owner.get("group").is_none());
+    }
+
+    #[test]
+    fn add_and_heredocs() {
+        let definition = convert_str(
+            "FROM alpine\n\
+             WORKDIR /app\n\
+             ADD app.tar.gz .\n\
+             COPY <<EOF config.toml ./\n\
+             name = \"app\"\n\
+             EOF\n",
+            &ConvertOptions::new(),
+        )
+        .unwrap();
+        assert_eq!(definition.validate(), Ok(()));
+
+        // Local archives are extracted by the daemon, like Docker does
+        let copies = copies(&definition);
+        assert_eq!(copies.len(), 3);
+        assert_eq!(copies[0]["src"], "/app.tar.gz");
+        assert_eq!(copies[0]["dest"], "/app");
+        assert_eq!(copies[0]["attemptUnpackDockerCompatibility"], true);
+
+        assert_eq!(copies[1]["src"], "/config.toml");
+        assert_eq!(copies[2]["src"], "/EOF");
+        assert_eq!(copies[2]["dest"], "/app/");
+
+        let mkfile = ops(&definition)
+            .into_iter()
+            .filter_map(|op| op["file"]["actions"][0]["action"].get("mkfile").cloned())
+            .next()
+            .unwrap();
+        assert_eq!(mkfile["path"], "/EOF");
+        assert_eq!(mkfile["mode"], 0o644);
+    }
+
+    #[test]
+    fn run_network_and_security() {
+        let definition = convert_str(
+            "FROM alpine\n\
+             RUN --network=host --security=insecure true\n\
+             RUN --network=none false\n",
+            &ConvertOptions::new(),
+        )
+        .unwrap();
+
+        let execs = execs(&definition);
+        let exec = |args: &str| {
+            execs
+                .iter()
+                .find(|exec| exec["meta"]["args"][2] == args)
+                .unwrap()
+        };
+        // Enums are numbers in the JSON form, `HOST` is 1 and `NONE` is 2
+        assert_eq!(exec("true")["network"], 1);
+        assert_eq!(exec("true")["security"], 1);
+        assert_eq!(exec("false")["network"], 2);
+        assert_eq!(exec("false")["security"], 0);
+
+        assert!(matches!(
+            convert_str(
+                "FROM alpine\nRUN --network=bridge true\n",
+                &ConvertOptions::new()
+            ),
+            Err(ConvertError::Unsupported { line: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn build_platform() {
+        let input = "FROM alpine\n\
+                     ARG BUILDPLATFORM TARGETPLATFORM\n\
+                     RUN echo $BUILDPLATFORM $TARGETPLATFORM\n";
+        let env = |options: &ConvertOptions| {
+            let definition = convert_str(input, options).unwrap();
+            execs(&definition)[0]["meta"]["env"].clone()
+        };
+        let platforms = |build: &str, target: &str| {
+            serde_json::json!([
+                format!("BUILDPLATFORM={build}"),
+                format!("TARGETPLATFORM={target}"),
+                "PATH=/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin",
+            ])
+        };
+
+        let options = ConvertOptions::new().with_build_platform(Platform::LINUX_ARM64);
+        assert_eq!(env(&options), platforms("linux/arm64", "linux/arm64"));
+        let options = options.with_platform(Platform::LINUX_S390X);
+        assert_eq!(env(&options), platforms("linux/arm64", "linux/s390x"));
+
+        // The worker runs linux, whatever the OS of the client
+        let host = format!("linux/{}", Platform::host().architecture);
+        assert_eq!(env(&ConvertOptions::new()), platforms(&host, &host));
+    }
+
+    #[test]
+    fn target() {
+        let input = "FROM alpine AS base\n\
+                     RUN touch /base\n\
+                     FROM base AS test\n\
+                     RUN touch /test\n\
+                     FROM busybox\n";
+
+        let definition = convert_str(input, &ConvertOptions::new().with_target("TEST")).unwrap();
+        assert_eq!(execs(&definition).len(), 2);
+
+        let definition = convert_str(input, &ConvertOptions::new()).unwrap();
+        assert_eq!(execs(&definition).len(), 0);
+
+        assert!(matches!(
+            convert_str(input, &ConvertOptions::new().with_target("missing")),
+            Err(ConvertError::UnknownTarget(_))
+        ));
+    }
+
+    #[test]
+    fn errors() {
+        let options = ConvertOptions::new();
+        assert!(matches!(
+            convert_str("ENV A=1\nFROM alpine\n", &options),
+            Err(ConvertError::InstructionBeforeFrom { line: 1 })
+        ));
+        assert!(matches!(
+            convert_str("FROM scratch\n", &options),
+            Err(ConvertError::EmptyTarget)
+        ));
+        assert!(matches!(
+            convert_str("FROM alpine\nADD https://example.com/a /a\n", &options),
+            Err(ConvertError::Unsupported { line: 2, .. })
+        ));
+        assert!(matches!(
+            convert_str("FROM alpine\nWORKDIR ${DIR:?}\n", &options),
+            Err(ConvertError::Shell { line: 2, .. })
+        ));
+        assert!(matches!(
+            convert_str("FROM Alpine\n", &options),
+            Err(ConvertError::Reference { line: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn paths() {
+        assert_eq!(resolve_path("/src", "bin"), "/src/bin");
+        assert_eq!(resolve_path("/src", "../etc/"), "/etc/");
+        assert_eq!(resolve_path("/src", "/opt/./app"), "/opt/app");
+    }
+}
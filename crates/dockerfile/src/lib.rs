@@ -0,0 +1,20 @@
+//! Parse Dockerfiles and convert them to LLB.
+//!
+//! This is mostly meant for validating Dockerfiles and for tests, it is not
+//! a replacement for the dockerfile frontend.
+//!
+//! ```no_run
+//! use buildkit_rs_dockerfile::{convert, ConvertOptions, Dockerfile};
+//!
+//! let dockerfile = Dockerfile::parse("FROM alpine\nRUN echo hello\n").unwrap();
+//! let definition = convert(&dockerfile, &ConvertOptions::new()).unwrap();
+//! ```
+
+mod convert;
+mod parser;
+
+pub use convert::{convert, convert_with_resolver, ConvertError, ConvertOptions};
+pub use parser::{
+    CmdLine, CopyCommand, Directives, Dockerfile, FromCommand, Heredoc, Instruction, MountKind,
+    ParseError, RunCommand, RunMount, Statement,
+};
@@ -0,0 +1,909 @@
+use std::str::FromStr;
+
+use buildkit_rs_llb::CacheSharingMode;
+use thiserror::Error;
+
+/// The error type for parsing a Dockerfile, lines are 1-based
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ParseError {
+    /// The instruction keyword is not known
+    #[error("line {line}: unknown instruction {instruction:?}")]
+    UnknownInstruction { line: usize, instruction: String },
+
+    /// The arguments of an instruction are missing or malformed
+    #[error("line {line}: {instruction} requires {expected}")]
+    InvalidArguments {
+        line: usize,
+        instruction: &'static str,
+        expected: &'static str,
+    },
+
+    /// The flag is not supported by the instruction
+    #[error("line {line}: unknown flag --{flag} for {instruction}")]
+    UnknownFlag {
+        line: usize,
+        instruction: &'static str,
+        flag: String,
+    },
+
+    /// The value of a flag is not valid
+    #[error("line {line}: invalid value {value:?} for flag --{flag}")]
+    InvalidFlag {
+        line: usize,
+        flag: String,
+        value: String,
+    },
+
+    /// A `RUN --mount` option is not valid
+    #[error("line {line}: invalid mount {mount:?}: {reason}")]
+    InvalidMount {
+        line: usize,
+        mount: String,
+        reason: &'static str,
+    },
+
+    /// The end of the file was reached before the heredoc delimiter
+    #[error("line {line}: unterminated heredoc {delimiter:?}")]
+    UnterminatedHeredoc { line: usize, delimiter: String },
+
+    /// The escape directive is neither `\` nor `` ` ``
+    #[error("invalid escape directive {0:?}, must be ` or \\")]
+    InvalidEscape(String),
+}
+
+/// The parser directives at the top of a Dockerfile, such as
+/// `# syntax=docker/dockerfile:1`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Directives {
+    pub syntax: Option<String>,
+    /// The escape character, `\` unless set to `` ` ``
+    pub escape: char,
+    pub check: Option<String>,
+}
+
+impl Default for Directives {
+    fn default() -> Self {
+        Self {
+            syntax: None,
+            escape: '\\',
+            check: None,
+        }
+    }
+}
+
+/// A parsed Dockerfile
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Dockerfile {
+    pub directives: Directives,
+    pub statements: Vec<Statement>,
+}
+
+/// An instruction and the line it starts on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Statement {
+    pub line: usize,
+    pub instruction: Instruction,
+}
+
+/// A Dockerfile instruction.
+///
+/// Words are kept as written, including quotes, they are unquoted and
+/// expanded during the conversion as the values depend on the build args
+/// and env.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Instruction {
+    From(FromCommand),
+    Run(RunCommand),
+    Cmd(CmdLine),
+    Entrypoint(CmdLine),
+    Shell(Vec<String>),
+    Copy(CopyCommand),
+    Add(CopyCommand),
+    /// Names with an optional default value
+    Arg(Vec<(String, Option<String>)>),
+    Env(Vec<(String, String)>),
+    Label(Vec<(String, String)>),
+    Workdir(String),
+    User(String),
+    Expose(Vec<String>),
+    Volume(Vec<String>),
+    StopSignal(String),
+    Healthcheck(String),
+    Onbuild(String),
+    Maintainer(String),
+}
+
+/// A command in the shell form, run with the current shell, or the exec
+/// (JSON) form
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CmdLine {
+    Shell(String),
+    Exec(Vec<String>),
+}
+
+/// The body of a heredoc, such as `<<EOF`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Heredoc {
+    pub name: String,
+    /// The lines of the heredoc, each terminated by a newline
+    pub content: String,
+    /// Variables are expanded unless the delimiter is quoted
+    pub expand: bool,
+    /// Leading tabs are removed, `<<-EOF`
+    pub chomp: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FromCommand {
+    pub image: String,
+    /// The stage name, lowercased
+    pub name: Option<String>,
+    pub platform: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunCommand {
+    pub command: CmdLine,
+    pub heredocs: Vec<Heredoc>,
+    pub mounts: Vec<RunMount>,
+    pub network: Option<String>,
+    pub security: Option<String>,
+}
+
+/// The `COPY` and `ADD` instructions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CopyCommand {
+    pub sources: Vec<String>,
+    pub dest: String,
+    pub heredocs: Vec<Heredoc>,
+    pub from: Option<String>,
+    pub chown: Option<String>,
+    pub chmod: Option<String>,
+    pub link: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MountKind {
+    #[default]
+    Bind,
+    Cache,
+    Tmpfs,
+    Secret,
+    Ssh,
+}
+
+/// A `RUN --mount` option
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct RunMount {
+    pub kind: MountKind,
+    pub target: Option<String>,
+    pub source: Option<String>,
+    /// A stage or image to mount from, the build context if not set
+    pub from: Option<String>,
+    pub id: Option<String>,
+    pub sharing: CacheSharingMode,
+    pub readonly: bool,
+    pub required: bool,
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub size: Option<i64>,
+}
+
+impl FromStr for Dockerfile {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl Dockerfile {
+    /// Parse a Dockerfile, based on the `parser` package of buildkit
+    pub fn parse(input: &str) -> Result<Self, ParseError> {
+        let input = input.strip_prefix('\u{feff}').unwrap_or(input);
+        let lines = input.lines().collect::<Vec<_>>();
+
+        let mut directives = Directives::default();
+        let mut idx = 0;
+        while let Some((key, value)) = lines.get(idx).and_then(|line| parse_directive(line)) {
+            match key.as_str() {
+                "syntax" => directives.syntax = Some(value),
+                "escape" => {
+                    directives.escape = match value.as_str() {
+                        "\\" => '\\',
+                        "`" => '`',
+                        _ => return Err(ParseError::InvalidEscape(value)),
+                    }
+                }
+                "check" => directives.check = Some(value),
+                _ => break,
+            }
+            idx += 1;
+        }
+
+        let mut statements = Vec::new();
+        while let Some(start) = skip_comments(&lines, idx) {
+            let line = start + 1;
+            idx = start;
+
+            // Join the continuation lines, comments and empty lines in between
+            // are skipped
+            let mut logical = String::new();
+            loop {
+                let current = lines[idx];
+                idx += 1;
+                match current.trim_end().strip_suffix(directives.escape) {
+                    Some(part) => {
+                        logical.push_str(part);
+                        match skip_comments(&lines, idx) {
+                            Some(next) => idx = next,
+                            None => break,
+                        }
+                    }
+                    None => {
+                        logical.push_str(current);
+                        break;
+                    }
+                }
+            }
+
+            let (keyword, rest) = split_first_word(logical.trim());
+            let keyword = keyword.to_ascii_lowercase();
+
+            let mut heredocs = Vec::new();
+            if matches!(keyword.as_str(), "run" | "copy" | "add") {
+                for (name, expand, chomp) in heredoc_markers(rest) {
+                    let mut content = String::new();
+                    loop {
+                        let Some(current) = lines.get(idx) else {
+                            return Err(ParseError::UnterminatedHeredoc {
+                                line,
+                                delimiter: name,
+                            });
+                        };
+                        idx += 1;
+
+                        let current = if chomp {
+                            current.trim_start_matches('\t')
+                        } else {
+                            current
+                        };
+                        if current == name {
+                            break;
+                        }
+                        content.push_str(current);
+                        content.push('\n');
+                    }
+                    heredocs.push(Heredoc {
+                        name,
+                        content,
+                        expand,
+                        chomp,
+                    });
+                }
+            }
+
+            let instruction = parse_instruction(line, &keyword, rest, heredocs, directives.escape)?;
+            statements.push(Statement { line, instruction });
+        }
+
+        Ok(Self {
+            directives,
+            statements,
+        })
+    }
+
+    /// The instructions in order
+    pub fn instructions(&self) -> impl Iterator<Item = &Instruction> {
+        self.statements
+            .iter()
+            .map(|statement| &statement.instruction)
+    }
+}
+
+/// A `# key=value` line
+fn parse_directive(line: &str) -> Option<(String, String)> {
+    let (key, value) = line.trim().strip_prefix('#')?.split_once('=')?;
+    let key = key.trim().to_ascii_lowercase();
+    if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return None;
+    }
+    Some((key, value.trim().into()))
+}
+
+/// The index of the next line that is not empty or a comment
+fn skip_comments(lines: &[&str], from: usize) -> Option<usize> {
+    (from..lines.len()).find(|&idx| {
+        let line = lines[idx].trim_start();
+        !line.is_empty() && !line.starts_with('#')
+    })
+}
+
+fn split_first_word(s: &str) -> (&str, &str) {
+    match s.split_once(char::is_whitespace) {
+        Some((first, rest)) => (first, rest.trim_start()),
+        None => (s, ""),
+    }
+}
+
+/// The heredoc markers in order, as `(name, expand, chomp)`
+fn heredoc_markers(s: &str) -> Vec<(String, bool, bool)> {
+    let mut markers = Vec::new();
+    let mut rest = s;
+    while let Some(idx) = rest.find("<<") {
+        let after = &rest[idx + 2..];
+        rest = after;
+        if after.starts_with('<') {
+            rest = after.trim_start_matches('<');
+            continue;
+        }
+
+        let (chomp, after) = match after.strip_prefix('-') {
+            Some(after) => (true, after),
+            None => (false, after),
+        };
+        let (quote, after) = match after.chars().next() {
+            Some(quote @ ('"' | '\'')) => (Some(quote), &after[1..]),
+            _ => (None, after),
+        };
+
+        let end = after
+            .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+            .unwrap_or(after.len());
+        let name = &after[..end];
+        if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+            continue;
+        }
+        if let Some(quote) = quote {
+            if !after[end..].starts_with(quote) {
+                continue;
+            }
+        }
+
+        markers.push((name.to_owned(), quote.is_none(), chomp));
+        rest = &after[end..];
+    }
+    markers
+}
+
+/// Split the leading `--name=value` flags from the arguments
+fn take_flags(mut rest: &str) -> (Vec<(String, String)>, &str) {
+    let mut flags = Vec::new();
+    while let Some(flag) = rest.strip_prefix("--") {
+        let (word, remainder) = split_first_word(flag);
+        let (name, value) = word.split_once('=').unwrap_or((word, ""));
+        flags.push((name.to_ascii_lowercase(), value.to_owned()));
+        rest = remainder;
+    }
+    (flags, rest)
+}
+
+/// Split on whitespace outside of quotes, the words are kept as written
+pub(crate) fn split_words(s: &str, escape: char) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quote = None;
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        match (c, quote) {
+            (c, None) if c.is_whitespace() => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+                continue;
+            }
+            (c, Some(q)) if c == q => quote = None,
+            ('"' | '\'', None) => quote = Some(c),
+            (c, q) if c == escape && q != Some('\'') => {
+                word.push(c);
+                if let Some(next) = chars.next() {
+                    word.push(next);
+                }
+                continue;
+            }
+            _ => {}
+        }
+        word.push(c);
+    }
+
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+fn parse_json_array(s: &str) -> Option<Vec<String>> {
+    let s = s.trim();
+    if !s.starts_with('[') {
+        return None;
+    }
+    serde_json::from_str(s).ok()
+}
+
+fn parse_cmd_line(rest: &str) -> CmdLine {
+    match parse_json_array(rest) {
+        Some(args) => CmdLine::Exec(args),
+        None => CmdLine::Shell(rest.trim().into()),
+    }
+}
+
+fn parse_instruction(
+    line: usize,
+    keyword: &str,
+    rest: &str,
+    heredocs: Vec<Heredoc>,
+    escape: char,
+) -> Result<Instruction, ParseError> {
+    let instruction = match keyword {
+        "from" => "FROM",
+        "run" => "RUN",
+        "cmd" => "CMD",
+        "entrypoint" => "ENTRYPOINT",
+        "shell" => "SHELL",
+        "copy" => "COPY",
+        "add" => "ADD",
+        "arg" => "ARG",
+        "env" => "ENV",
+        "label" => "LABEL",
+        "workdir" => "WORKDIR",
+        "user" => "USER",
+        "expose" => "EXPOSE",
+        "volume" => "VOLUME",
+        "stopsignal" => "STOPSIGNAL",
+        "healthcheck" => "HEALTHCHECK",
+        "onbuild" => "ONBUILD",
+        "maintainer" => "MAINTAINER",
+        _ => {
+            return Err(ParseError::UnknownInstruction {
+                line,
+                instruction: keyword.into(),
+            })
+        }
+    };
+
+    let invalid = |expected| ParseError::InvalidArguments {
+        line,
+        instruction,
+        expected,
+    };
+    let unknown_flag = |flag: String| ParseError::UnknownFlag {
+        line,
+        instruction,
+        flag,
+    };
+
+    let (flags, rest) = match keyword {
+        "from" | "run" | "copy" | "add" => take_flags(rest),
+        _ => (Vec::new(), rest),
+    };
+    let rest = rest.trim();
+    if rest.is_empty() && !matches!(keyword, "cmd" | "entrypoint") {
+        return Err(invalid("at least one argument"));
+    }
+
+    Ok(match keyword {
+        "from" => {
+            let mut platform = None;
+            for (flag, value) in flags {
+                match flag.as_str() {
+                    "platform" => platform = Some(value),
+                    _ => return Err(unknown_flag(flag)),
+                }
+            }
+
+            let words = rest.split_whitespace().collect::<Vec<_>>();
+            let name = match words.as_slice() {
+                [_] => None,
+                [_, as_, name] if as_.eq_ignore_ascii_case("as") => Some(name.to_ascii_lowercase()),
+                _ => return Err(invalid("an image and an optional `AS name`")),
+            };
+
+            Instruction::From(FromCommand {
+                image: words[0].into(),
+                name,
+                platform,
+            })
+        }
+        "run" => {
+            let mut mounts = Vec::new();
+            let mut network = None;
+            let mut security = None;
+            for (flag, value) in flags {
+                match flag.as_str() {
+                    "mount" => mounts.push(parse_mount(line, &value)?),
+                    "network" => network = Some(value),
+                    "security" => security = Some(value),
+                    _ => return Err(unknown_flag(flag)),
+                }
+            }
+
+            Instruction::Run(RunCommand {
+                command: parse_cmd_line(rest),
+                heredocs,
+                mounts,
+                network,
+                security,
+            })
+        }
+        "cmd" => Instruction::Cmd(parse_cmd_line(rest)),
+        "entrypoint" => Instruction::Entrypoint(parse_cmd_line(rest)),
+        "shell" => Instruction::Shell(
+            parse_json_array(rest)
+                .filter(|shell| !shell.is_empty())
+                .ok_or_else(|| invalid("a JSON array"))?,
+        ),
+        "copy" | "add" => {
+            let mut command = CopyCommand {
+                sources: Vec::new(),
+                dest: String::new(),
+                heredocs,
+                from: None,
+                chown: None,
+                chmod: None,
+                link: false,
+            };
+            for (flag, value) in flags {
+                match flag.as_str() {
+                    "from" if keyword == "copy" => command.from = Some(value),
+                    "chown" => command.chown = Some(value),
+                    "chmod" => command.chmod = Some(value),
+                    "link" => {
+                        command.link = match value.as_str() {
+                            "" | "true" => true,
+                            "false" => false,
+                            _ => return Err(ParseError::InvalidFlag { line, flag, value }),
+                        }
+                    }
+                    _ => return Err(unknown_flag(flag)),
+                }
+            }
+
+            let mut words = parse_json_array(rest).unwrap_or_else(|| split_words(rest, escape));
+            if words.len() < 2 {
+                return Err(invalid("at least one source and a destination"));
+            }
+            command.dest = words.pop().unwrap();
+            command.sources = words;
+
+            match keyword {
+                "copy" => Instruction::Copy(command),
+                _ => Instruction::Add(command),
+            }
+        }
+        "arg" => Instruction::Arg(
+            split_words(rest, escape)
+                .into_iter()
+                .map(|word| match word.split_once('=') {
+                    Some((name, default)) => (name.into(), Some(default.into())),
+                    None => (word, None),
+                })
+                .collect(),
+        ),
+        "env" | "label" => {
+            let words = split_words(rest, escape);
+            let pairs = if words[0].contains('=') {
+                words
+                    .into_iter()
+                    .map(|word| {
+                        word.split_once('=')
+                            .map(|(key, value)| (key.to_owned(), value.to_owned()))
+                    })
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or_else(|| invalid("arguments in the KEY=VALUE form"))?
+            } else {
+                // The legacy `ENV KEY some value` form
+                let (key, value) = split_first_word(rest);
+                if value.is_empty() {
+                    return Err(invalid("a value"));
+                }
+                vec![(key.to_owned(), value.to_owned())]
+            };
+
+            match keyword {
+                "env" => Instruction::Env(pairs),
+                _ => Instruction::Label(pairs),
+            }
+        }
+        "workdir" => Instruction::Workdir(rest.into()),
+        "user" => Instruction::User(rest.into()),
+        "expose" => Instruction::Expose(split_words(rest, escape)),
+        "volume" => {
+            Instruction::Volume(parse_json_array(rest).unwrap_or_else(|| split_words(rest, escape)))
+        }
+        "stopsignal" => Instruction::StopSignal(rest.into()),
+        "healthcheck" => Instruction::Healthcheck(rest.into()),
+        "onbuild" => Instruction::Onbuild(rest.into()),
+        _ => Instruction::Maintainer(rest.into()),
+    })
+}
+
+/// Parse a `RUN --mount` value such as `type=cache,target=/root/.cache`
+fn parse_mount(line: usize, value: &str) -> Result<RunMount, ParseError> {
+    let invalid = |reason| ParseError::InvalidMount {
+        line,
+        mount: value.into(),
+        reason,
+    };
+    let number =
+        |v: &str, radix| u32::from_str_radix(v, radix).map_err(|_| invalid("invalid number"));
+    let boolean = |v: &str| match v {
+        "" | "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(invalid("invalid boolean")),
+    };
+
+    let mut mount = RunMount::default();
+    let mut readwrite = None;
+    for field in value.split(',') {
+        let (key, v) = field.split_once('=').unwrap_or((field, ""));
+        match key.to_ascii_lowercase().as_str() {
+            "type" => {
+                mount.kind = match v {
+                    "bind" => MountKind::Bind,
+                    "cache" => MountKind::Cache,
+                    "tmpfs" => MountKind::Tmpfs,
+                    "secret" => MountKind::Secret,
+                    "ssh" => MountKind::Ssh,
+                    _ => return Err(invalid("unknown mount type")),
+                }
+            }
+            "target" | "dst" | "destination" => mount.target = Some(v.into()),
+            "source" | "src" => mount.source = Some(v.into()),
+            "from" => mount.from = Some(v.into()),
+            "id" => mount.id = Some(v.into()),
+            "sharing" => {
+                mount.sharing = match v {
+                    "shared" => CacheSharingMode::Shared,
+                    "private" => CacheSharingMode::Private,
+                    "locked" => CacheSharingMode::Locked,
+                    _ => return Err(invalid("unknown sharing mode")),
+                }
+            }
+            "readonly" | "ro" => readwrite = Some(!boolean(v)?),
+            "readwrite" | "rw" => readwrite = Some(boolean(v)?),
+            "required" => mount.required = boolean(v)?,
+            "mode" => mount.mode = Some(number(v, 8)?),
+            "uid" => mount.uid = Some(number(v, 10)?),
+            "gid" => mount.gid = Some(number(v, 10)?),
+            "size" => mount.size = Some(v.parse().map_err(|_| invalid("invalid size"))?),
+            _ => return Err(invalid("unknown option")),
+        }
+    }
+
+    // Bind mounts are read-only by default, everything else is writable
+    mount.readonly = match readwrite {
+        Some(readwrite) => !readwrite,
+        None => mount.kind == MountKind::Bind,
+    };
+
+    match mount.kind {
+        MountKind::Secret | MountKind::Ssh => {}
+        _ if mount.target.is_none() => return Err(invalid("missing target")),
+        _ => {}
+    }
+    if mount.kind == MountKind::Secret && mount.id.is_none() && mount.target.is_none() {
+        return Err(invalid("missing id or target"));
+    }
+
+    Ok(mount)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &str) -> Vec<Instruction> {
+        Dockerfile::parse(input)
+            .unwrap()
+            .statements
+            .into_iter()
+            .map(|statement| statement.instruction)
+            .collect()
+    }
+
+    #[test]
+    fn directives_and_continuations() {
+        let dockerfile = Dockerfile::parse(
+            "# syntax=docker/dockerfile:1\n\
+             # escape=`\n\
+             \n\
+             FROM alpine AS Build\n\
+             # comment\n\
+             RUN echo a && `\n\
+             \x20   # comment inside\n\
+             \n\
+             \x20   echo b\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            dockerfile.directives.syntax.as_deref(),
+            Some("docker/dockerfile:1")
+        );
+        assert_eq!(dockerfile.directives.escape, '`');
+        assert_eq!(dockerfile.statements[1].line, 6);
+        assert_eq!(
+            dockerfile.statements[0].instruction,
+            Instruction::From(FromCommand {
+                image: "alpine".into(),
+                name: Some("build".into()),
+                platform: None,
+            })
+        );
+        assert!(matches!(
+            &dockerfile.statements[1].instruction,
+            Instruction::Run(RunCommand { command: CmdLine::Shell(cmd), .. })
+                if cmd == "echo a &&     echo b"
+        ));
+    }
+
+    #[test]
+    fn json_and_shell_form() {
+        let instructions = parse(
+            "FROM scratch\n\
+             CMD [\"echo\", \"hi\"]\n\
+             ENTRYPOINT [not json\n\
+             SHELL [\"/bin/bash\", \"-c\"]\n\
+             COPY [\"a b\", \"/dest/\"]\n\
+             COPY --from=build --chown=1000:1000 \"a b\" c /dest/\n",
+        );
+
+        assert_eq!(
+            instructions[1],
+            Instruction::Cmd(CmdLine::Exec(vec!["echo".into(), "hi".into()]))
+        );
+        assert_eq!(
+            instructions[2],
+            Instruction::Entrypoint(CmdLine::Shell("[not json".into()))
+        );
+        assert_eq!(
+            instructions[3],
+            Instruction::Shell(vec!["/bin/bash".into(), "-c".into()])
+        );
+        let Instruction::Copy(copy) = &instructions[4] else {
+            panic!("expected COPY")
+        };
+        assert_eq!(copy.sources, ["a b"]);
+        let Instruction::Copy(copy) = &instructions[5] else {
+            panic!("expected COPY")
+        };
+        assert_eq!(copy.sources, ["\"a b\"", "c"]);
+        assert_eq!(copy.dest, "/dest/");
+        assert_eq!(copy.from.as_deref(), Some("build"));
+        assert_eq!(copy.chown.as_deref(), Some("1000:1000"));
+    }
+
+    #[test]
+    fn env_arg_label() {
+        let instructions = parse(
+            "ARG BASE=alpine VERSION\n\
+             FROM $BASE\n\
+             ENV A=1 B=\"two words\"\n\
+             ENV LEGACY some value\n\
+             LABEL \"com.example\"=yes\n",
+        );
+
+        assert_eq!(
+            instructions[0],
+            Instruction::Arg(vec![
+                ("BASE".into(), Some("alpine".into())),
+                ("VERSION".into(), None)
+            ])
+        );
+        assert_eq!(
+            instructions[2],
+            Instruction::Env(vec![
+                ("A".into(), "1".into()),
+                ("B".into(), "\"two words\"".into())
+            ])
+        );
+        assert_eq!(
+            instructions[3],
+            Instruction::Env(vec![("LEGACY".into(), "some value".into())])
+        );
+        assert_eq!(
+            instructions[4],
+            Instruction::Label(vec![("\"com.example\"".into(), "yes".into())])
+        );
+    }
+
+    #[test]
+    fn heredocs() {
+        let instructions = parse(
+            "FROM alpine\n\
+             RUN <<EOF\n\
+             echo $HOME\n\
+             EOF\n\
+             COPY <<-'A' <<B /dest/\n\
+             \tliteral\n\
+             \tA\n\
+             b\n\
+             B\n\
+             RUN echo done\n",
+        );
+
+        let Instruction::Run(run) = &instructions[1] else {
+            panic!("expected RUN")
+        };
+        assert_eq!(run.command, CmdLine::Shell("<<EOF".into()));
+        assert_eq!(
+            run.heredocs,
+            [Heredoc {
+                name: "EOF".into(),
+                content: "echo $HOME\n".into(),
+                expand: true,
+                chomp: false,
+            }]
+        );
+
+        let Instruction::Copy(copy) = &instructions[2] else {
+            panic!("expected COPY")
+        };
+        assert_eq!(copy.heredocs.len(), 2);
+        assert_eq!(copy.heredocs[0].content, "literal\n");
+        assert!(!copy.heredocs[0].expand && copy.heredocs[0].chomp);
+        assert_eq!(copy.heredocs[1].content, "b\n");
+        assert!(matches!(instructions[3], Instruction::Run(_)));
+
+        assert_eq!(
+            Dockerfile::parse("FROM alpine\nRUN <<EOF\necho\n"),
+            Err(ParseError::UnterminatedHeredoc {
+                line: 2,
+                delimiter: "EOF".into()
+            })
+        );
+    }
+
+    #[test]
+    fn run_mounts() {
+        let instructions = parse(
+            "FROM alpine\n\
+             RUN --mount=type=cache,target=/root/.cache,sharing=locked \\\n\
+             \x20   --mount=type=secret,id=token,required \\\n\
+             \x20   --mount=from=build,source=/out,target=/in,rw \\\n\
+             \x20   make\n",
+        );
+
+        let Instruction::Run(run) = &instructions[1] else {
+            panic!("expected RUN")
+        };
+        assert_eq!(run.command, CmdLine::Shell("make".into()));
+        assert_eq!(run.mounts[0].kind, MountKind::Cache);
+        assert_eq!(run.mounts[0].sharing, CacheSharingMode::Locked);
+        assert!(!run.mounts[0].readonly);
+        assert_eq!(run.mounts[1].kind, MountKind::Secret);
+        assert!(run.mounts[1].required);
+        assert_eq!(run.mounts[2].kind, MountKind::Bind);
+        assert_eq!(run.mounts[2].from.as_deref(), Some("build"));
+        assert!(!run.mounts[2].readonly);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            Dockerfile::parse("FROM alpine\nFOO bar\n"),
+            Err(ParseError::UnknownInstruction {
+                line: 2,
+                instruction: "foo".into()
+            })
+        );
+        assert!(matches!(
+            Dockerfile::parse("FROM alpine AS\n"),
+            Err(ParseError::InvalidArguments { line: 1, .. })
+        ));
+        assert!(matches!(
+            Dockerfile::parse("FROM alpine\nCOPY --nope a b\n"),
+            Err(ParseError::UnknownFlag { line: 2, .. })
+        ));
+        assert!(matches!(
+            Dockerfile::parse("FROM alpine\nRUN --mount=type=nfs,target=/x ls\n"),
+            Err(ParseError::InvalidMount { line: 2, .. })
+        ));
+        assert_eq!(
+            Dockerfile::parse("# escape=x\nFROM alpine\n"),
+            Err(ParseError::InvalidEscape("x".into()))
+        );
+    }
+}
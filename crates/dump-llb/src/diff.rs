@@ -0,0 +1,195 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+use serde_json::Value;
+
+use crate::vertex::Vertex;
+
+/// A vertex whose digest differs between two definitions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub old_digest: String,
+    pub new_digest: String,
+    pub summary: String,
+    /// Why the digest differs, either a changed field or a changed input
+    pub reasons: Vec<String>,
+}
+
+/// Compare two definitions starting from their terminal ops.
+///
+/// Vertices are paired by their position in the inputs of the vertices that
+/// use them, the changes are ordered like the vertices of the new definition.
+pub fn diff(old: &[Vertex], new: &[Vertex]) -> Vec<Change> {
+    let old_by_digest: HashMap<_, _> = old.iter().map(|v| (v.digest.as_str(), v)).collect();
+    let new_by_digest: HashMap<_, _> = new.iter().map(|v| (v.digest.as_str(), v)).collect();
+
+    let mut changes = Vec::new();
+    let mut visited = HashSet::new();
+    let mut queue: Vec<_> = old.last().zip(new.last()).into_iter().collect();
+
+    while let Some((old, new)) = queue.pop() {
+        if old.digest == new.digest || !visited.insert((&old.digest, &new.digest)) {
+            continue;
+        }
+
+        let mut reasons = Vec::new();
+        diff_values("", &content(old), &content(new), &mut reasons);
+
+        for (i, (old_input, new_input)) in old.op.inputs.iter().zip(&new.op.inputs).enumerate() {
+            if old_input.digest == new_input.digest {
+                continue;
+            }
+            reasons.push(format!("input {i} changed"));
+
+            let old_vertex = old_by_digest.get(old_input.digest.as_str());
+            let new_vertex = new_by_digest.get(new_input.digest.as_str());
+            if let Some((old_vertex, new_vertex)) = old_vertex.zip(new_vertex) {
+                queue.push((old_vertex, new_vertex));
+            }
+        }
+
+        changes.push(Change {
+            old_digest: old.digest.clone(),
+            new_digest: new.digest.clone(),
+            summary: new.summary(),
+            reasons,
+        });
+    }
+
+    let position: HashMap<_, _> = new
+        .iter()
+        .enumerate()
+        .map(|(i, v)| (v.digest.as_str(), i))
+        .collect();
+    changes.sort_by_key(|change| position[change.new_digest.as_str()]);
+    changes
+}
+
+/// Print the changes, one vertex per paragraph
+pub fn text(changes: &[Change]) -> String {
+    let mut out = String::new();
+    for change in changes {
+        writeln!(
+            out,
+            "{} -> {}  {}",
+            change.old_digest, change.new_digest, change.summary
+        )
+        .unwrap();
+        for reason in &change.reasons {
+            writeln!(out, "    {reason}").unwrap();
+        }
+    }
+    out
+}
+
+/// The op without the digests of its inputs, which are compared on their own
+fn content(vertex: &Vertex) -> Value {
+    let mut op = vertex.json["Op"].clone();
+    if let Some(inputs) = op.get_mut("inputs").and_then(Value::as_array_mut) {
+        for input in inputs {
+            if let Some(input) = input.as_object_mut() {
+                input.remove("digest");
+            }
+        }
+    }
+    op
+}
+
+fn diff_values(path: &str, old: &Value, new: &Value, out: &mut Vec<String>) {
+    match (old, new) {
+        (Value::Object(old_fields), Value::Object(new_fields)) => {
+            let mut keys: Vec<_> = old_fields.keys().chain(new_fields.keys()).collect();
+            keys.sort();
+            keys.dedup();
+
+            for key in keys {
+                let key_path = match (path.is_empty(), key.contains('.')) {
+                    (true, _) => key.clone(),
+                    (false, true) => format!("{path}.{key:?}"),
+                    (false, false) => format!("{path}.{key}"),
+                };
+                let old = old_fields.get(key).unwrap_or(&Value::Null);
+                let new = new_fields.get(key).unwrap_or(&Value::Null);
+                diff_values(&key_path, old, new, out);
+            }
+        }
+        (Value::Array(old_values), Value::Array(new_values))
+            if old_values.len() == new_values.len() =>
+        {
+            for (i, (old, new)) in old_values.iter().zip(new_values).enumerate() {
+                diff_values(&format!("{path}[{i}]"), old, new, out);
+            }
+        }
+        _ if old != new => out.push(format!("{path}: {old} -> {new}")),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use buildkit_rs_llb::{Definition, Exec, Image, Local, Mount, SingleOutput};
+
+    use super::*;
+
+    fn vertices(image: &str, excludes: &[&str]) -> Vec<Vertex> {
+        let image = Arc::new(Image::new(image));
+        let local = Arc::new(Local::new("context".into()).with_excludes(excludes));
+        let exec = Arc::new(
+            Exec::shlex("make")
+                .with_mount(Mount::layer(image.output(), "/"))
+                .with_mount(Mount::layer_readonly(local.output(), "/src")),
+        );
+        Vertex::all(&Definition::new(exec.root().unwrap()).into_pb()).unwrap()
+    }
+
+    #[test]
+    fn unchanged() {
+        let old = vertices("alpine:3.18", &[]);
+        let new = vertices("alpine:3.18", &[]);
+        assert_eq!(diff(&old, &new), []);
+    }
+
+    #[test]
+    fn changed_source() {
+        let old = vertices("alpine:3.18", &["target"]);
+        let new = vertices("alpine:3.19", &["target"]);
+        let changes = diff(&old, &new);
+
+        let summaries: Vec<_> = changes.iter().map(|c| c.summary.as_str()).collect();
+        assert_eq!(
+            summaries,
+            [
+                "source docker-image://docker.io/library/alpine:3.19",
+                "exec make",
+                "result"
+            ]
+        );
+        assert_eq!(
+            changes[0].reasons,
+            [
+                "op.source.identifier: \"docker-image://docker.io/library/alpine:3.18\" -> \
+                 \"docker-image://docker.io/library/alpine:3.19\""
+            ]
+        );
+        assert_eq!(changes[1].reasons, ["input 0 changed"]);
+        assert_eq!(changes[2].reasons, ["input 0 changed"]);
+
+        let text = text(&changes);
+        assert!(text.starts_with(&format!("{} -> {}  source", old[0].digest, new[0].digest)));
+    }
+
+    #[test]
+    fn changed_attrs() {
+        let old = vertices("alpine:3.18", &["target"]);
+        let new = vertices("alpine:3.18", &["target", "node_modules"]);
+        let changes = diff(&old, &new);
+
+        assert_eq!(changes.len(), 3);
+        assert_eq!(changes[0].summary, "source local://context");
+        assert_eq!(changes[0].reasons.len(), 1);
+        assert!(changes[0].reasons[0].starts_with("op.source.attrs.\"local.excludepatterns\": "));
+        assert_eq!(changes[1].reasons, ["input 1 changed"]);
+    }
+}
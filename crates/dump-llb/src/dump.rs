@@ -0,0 +1,132 @@
+use std::fmt::Write;
+
+use serde_json::json;
+
+use crate::vertex::Vertex;
+
+/// The JSON form of [`Definition::to_json`] with the source locations of each
+/// op, it can be read back with [`Definition::from_json`]
+///
+/// [`Definition::to_json`]: buildkit_rs_llb::Definition::to_json
+/// [`Definition::from_json`]: buildkit_rs_llb::Definition::from_json
+pub fn json(vertices: &[Vertex]) -> String {
+    let ops: Vec<_> = vertices
+        .iter()
+        .map(|vertex| {
+            let mut value = vertex.json.clone();
+            if !vertex.locations.is_empty() {
+                value["SourceLocations"] = json!(vertex.locations);
+            }
+            value
+        })
+        .collect();
+
+    let mut out = serde_json::to_string_pretty(&ops).unwrap();
+    out.push('\n');
+    out
+}
+
+/// A summary of each op followed by its inputs and metadata
+pub fn text(vertices: &[Vertex]) -> String {
+    let mut out = String::new();
+    for vertex in vertices {
+        writeln!(out, "{}  {}", vertex.digest, vertex.summary()).unwrap();
+
+        for (i, input) in vertex.op.inputs.iter().enumerate() {
+            writeln!(out, "    input {i}: {}[{}]", input.digest, input.index).unwrap();
+        }
+
+        let metadata = &vertex.metadata;
+        let mut description: Vec<_> = metadata.description.iter().collect();
+        description.sort();
+        for (key, value) in description {
+            writeln!(out, "    {key}: {value}").unwrap();
+        }
+
+        let mut caps: Vec<_> = metadata
+            .caps
+            .iter()
+            .filter(|(_, enabled)| **enabled)
+            .map(|(cap, _)| cap.as_str())
+            .collect();
+        if !caps.is_empty() {
+            caps.sort();
+            writeln!(out, "    caps: {}", caps.join(", ")).unwrap();
+        }
+
+        if metadata.ignore_cache {
+            writeln!(out, "    ignore cache").unwrap();
+        }
+
+        for location in &vertex.locations {
+            writeln!(out, "    location: {location}").unwrap();
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use buildkit_rs_llb::{Definition, Exec, Image, Mount, OpMetadataBuilder, SingleOutput};
+    use serde_json::Value;
+
+    use super::*;
+
+    fn vertices() -> Vec<Vertex> {
+        let image = Arc::new(Image::new("alpine:latest").with_custom_name("base"));
+        let exec = Arc::new(
+            Exec::shlex("sh -c 'echo hello'").with_mount(Mount::layer(image.output(), "/")),
+        );
+        Vertex::all(&Definition::new(exec.root().unwrap()).into_pb()).unwrap()
+    }
+
+    #[test]
+    fn text_format() {
+        let vertices = vertices();
+        let text = text(&vertices);
+        let lines: Vec<_> = text.lines().collect();
+
+        assert_eq!(
+            lines[0],
+            format!(
+                "{}  source docker-image://docker.io/library/alpine:latest",
+                vertices[0].digest
+            )
+        );
+        assert!(lines.contains(&"    llb.customname: base"));
+        assert!(lines.contains(&"    caps: source.image"));
+        assert!(text.contains(&format!(
+            "{}  exec sh -c \"echo hello\"\n    input 0: {}[0]\n",
+            vertices[1].digest, vertices[0].digest
+        )));
+        assert_eq!(
+            &lines[lines.len() - 2..],
+            [
+                format!("{}  result", vertices[2].digest),
+                format!("    input 0: {}[0]", vertices[1].digest),
+            ]
+        );
+    }
+
+    #[test]
+    fn json_format() {
+        let vertices = vertices();
+        let json = json(&vertices);
+        let values: Vec<Value> = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(values.len(), 3);
+        assert_eq!(values[0]["Digest"], vertices[0].digest);
+        assert_eq!(values[0]["OpMetadata"]["caps"]["source.image"], true);
+        assert_eq!(
+            values[1]["Op"]["op"]["exec"]["meta"]["args"][2],
+            "echo hello"
+        );
+        assert_eq!(values[2]["Op"]["inputs"][0]["digest"], vertices[1].digest);
+        assert!(values[2].get("SourceLocations").is_none());
+
+        let def = Definition::from_json(&json).unwrap().into_pb();
+        assert_eq!(Vertex::all(&def).unwrap()[1].digest, vertices[1].digest);
+    }
+}
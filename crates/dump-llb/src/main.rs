@@ -0,0 +1,155 @@
+//! Print the ops of an LLB definition, or compare two definitions.
+//!
+//! ```shell
+//! cargo run --example test --package buildkit-rs-llb | dump-llb --format text
+//! dump-llb --diff old.llb new.llb
+//! ```
+
+mod diff;
+mod dump;
+mod vertex;
+
+use std::io::{Read, Write};
+use std::process::ExitCode;
+
+use buildkit_rs_proto::pb;
+use prost::Message;
+use thiserror::Error;
+
+use crate::vertex::Vertex;
+
+const USAGE: &str = "\
+Usage: dump-llb [--format json|text] [FILE]
+       dump-llb --diff OLD NEW
+
+Reads a protobuf encoded definition from FILE, or from stdin if FILE is
+missing or `-`.";
+
+#[derive(Debug, Error)]
+enum Error {
+    #[error("{0}\n\n{USAGE}")]
+    Usage(String),
+    #[error("failed to read {path}: {source}")]
+    Read {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("failed to decode {path}: {source}")]
+    Decode {
+        path: String,
+        source: prost::DecodeError,
+    },
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+enum Command {
+    Help,
+    Dump { format: Format, path: String },
+    Diff { old: String, new: String },
+}
+
+enum Format {
+    Json,
+    Text,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Command, Error> {
+    let mut format = Format::Json;
+    let mut paths = Vec::new();
+    let mut is_diff = false;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "--diff" => is_diff = true,
+            "--format" => {
+                format = match args.next().as_deref() {
+                    Some("json") => Format::Json,
+                    Some("text") => Format::Text,
+                    Some(other) => return Err(Error::Usage(format!("unknown format `{other}`"))),
+                    None => return Err(Error::Usage("missing value for --format".into())),
+                }
+            }
+            flag if flag.starts_with("--") => {
+                return Err(Error::Usage(format!("unknown flag `{flag}`")))
+            }
+            _ => paths.push(arg),
+        }
+    }
+
+    match (is_diff, paths.len()) {
+        (true, 2) => {
+            let new = paths.pop().unwrap();
+            let old = paths.pop().unwrap();
+            Ok(Command::Diff { old, new })
+        }
+        (true, _) => Err(Error::Usage("--diff takes two files".into())),
+        (false, 0 | 1) => Ok(Command::Dump {
+            format,
+            path: paths.pop().unwrap_or_else(|| "-".into()),
+        }),
+        (false, _) => Err(Error::Usage("too many files".into())),
+    }
+}
+
+fn load(path: &str) -> Result<Vec<Vertex>, Error> {
+    let read_error = |source| Error::Read {
+        path: path.into(),
+        source,
+    };
+
+    let bytes = if path == "-" {
+        let mut bytes = Vec::new();
+        std::io::stdin()
+            .read_to_end(&mut bytes)
+            .map_err(read_error)?;
+        bytes
+    } else {
+        std::fs::read(path).map_err(read_error)?
+    };
+
+    pb::Definition::decode(bytes.as_slice())
+        .and_then(|def| Vertex::all(&def))
+        .map_err(|source| Error::Decode {
+            path: path.into(),
+            source,
+        })
+}
+
+fn run() -> Result<ExitCode, Error> {
+    let mut stdout = std::io::stdout().lock();
+
+    match parse_args(std::env::args().skip(1))? {
+        Command::Help => writeln!(stdout, "{USAGE}")?,
+        Command::Dump { format, path } => {
+            let vertices = load(&path)?;
+            let out = match format {
+                Format::Json => dump::json(&vertices),
+                Format::Text => dump::text(&vertices),
+            };
+            stdout.write_all(out.as_bytes())?;
+        }
+        Command::Diff { old, new } => {
+            let changes = diff::diff(&load(&old)?, &load(&new)?);
+            stdout.write_all(diff::text(&changes).as_bytes())?;
+
+            // Like diff(1), differences are reported with a status of 1
+            if !changes.is_empty() {
+                return Ok(ExitCode::from(1));
+            }
+        }
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("dump-llb: {err}");
+            ExitCode::from(2)
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use buildkit_rs_llb::{digest, Definition};
+use buildkit_rs_proto::pb::{self, file_action::Action, op::Op};
+use prost::Message;
+use serde_json::Value;
+
+/// A decoded op of a definition
+#[derive(Debug, Clone)]
+pub struct Vertex {
+    pub digest: String,
+    pub op: pb::Op,
+    pub metadata: pb::OpMetadata,
+    /// Source locations as `file:line` or `file:start-end`
+    pub locations: Vec<String>,
+    /// The op in the JSON form of [`Definition::to_json`]
+    pub json: Value,
+}
+
+impl Vertex {
+    /// The vertices of a definition in the order they are stored, the last one
+    /// is the terminal op
+    pub fn all(def: &pb::Definition) -> Result<Vec<Self>, prost::DecodeError> {
+        let json = Definition::json_ops(def)?;
+        def.def
+            .iter()
+            .zip(json)
+            .map(|(bytes, json)| {
+                let digest = digest(bytes);
+                Ok(Self {
+                    op: pb::Op::decode(bytes.as_slice())?,
+                    metadata: def.metadata.get(&digest).cloned().unwrap_or_default(),
+                    locations: locations(def, &digest),
+                    digest,
+                    json,
+                })
+            })
+            .collect()
+    }
+
+    /// A one line description of the op
+    pub fn summary(&self) -> String {
+        match &self.op.op {
+            Some(Op::Source(source)) => format!("source {}", source.identifier),
+            Some(Op::Exec(exec)) => {
+                let args = exec.meta.as_ref().map(|meta| &meta.args[..]).unwrap_or(&[]);
+                let args: Vec<_> = args.iter().map(|arg| quote(arg)).collect();
+                format!("exec {}", args.join(" "))
+            }
+            Some(Op::File(file)) => {
+                let actions: Vec<_> = file
+                    .actions
+                    .iter()
+                    .map(|action| match &action.action {
+                        Some(Action::Copy(copy)) => format!("copy {} {}", copy.src, copy.dest),
+                        Some(Action::Mkfile(mkfile)) => format!("mkfile {}", mkfile.path),
+                        Some(Action::Mkdir(mkdir)) => format!("mkdir {}", mkdir.path),
+                        Some(Action::Rm(rm)) => format!("rm {}", rm.path),
+                        None => "none".into(),
+                    })
+                    .collect();
+                format!("file {}", actions.join(", "))
+            }
+            Some(Op::Build(_)) => "build".into(),
+            Some(Op::Merge(_)) => "merge".into(),
+            Some(Op::Diff(_)) => "diff".into(),
+            None => "result".into(),
+        }
+    }
+}
+
+fn quote(arg: &str) -> String {
+    if arg.is_empty() || arg.contains(|c: char| c.is_whitespace() || c == '"' || c == '\'') {
+        format!("{arg:?}")
+    } else {
+        arg.into()
+    }
+}
+
+fn locations(def: &pb::Definition, digest: &str) -> Vec<String> {
+    let Some(source) = &def.source else {
+        return Vec::new();
+    };
+
+    source
+        .locations
+        .get(digest)
+        .into_iter()
+        .flat_map(|locations| &locations.locations)
+        .flat_map(|location| {
+            let filename = source
+                .infos
+                .get(location.source_index as usize)
+                .map_or("<unknown>", |info| info.filename.as_str());
+
+            location.ranges.iter().map(move |range| {
+                let start = range.start.as_ref().map_or(0, |pos| pos.line);
+                let end = range.end.as_ref().map_or(start, |pos| pos.line);
+                if end > start {
+                    format!("{filename}:{start}-{end}")
+                } else {
+                    format!("{filename}:{start}")
+                }
+            })
+        })
+        .collect()
+}
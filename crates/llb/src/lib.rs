@@ -2,18 +2,26 @@ mod ops;
 mod platform;
 mod serialize;
 mod sourcemap;
+mod sourcepolicy;
 pub mod utils;
+mod validate;
 
+pub use ops::exec::env::{EnvError, EnvMap};
 pub use ops::exec::mount::CacheSharingMode;
 pub use ops::exec::mount::Mount;
-pub use ops::exec::Exec;
+pub use ops::exec::{Exec, NetMode, SecurityMode};
+pub use ops::file::{Copy, FileAction, FileActions, MkFile, Mkdir, Owner, OwnerId};
 pub use ops::metadata::OpMetadataBuilder;
-pub use ops::output::{
-    MultiBorrowedLastOutput, MultiBorrowedOutput, MultiOwnedLastOutput, MultiOwnedOutput,
-    SingleBorrowedOutput, SingleOwnedOutput,
-};
-pub use ops::source::image::Image;
+pub use ops::output::{MultiOutput, Output, OutputError, SingleOutput};
 pub use ops::source::image::ResolveMode;
-pub use ops::source::local::Local;
-pub use platform::Platform;
-pub use serialize::Definition;
+pub use ops::source::image::{
+    Image, ImageConfig, ImageMetaResolver, RecordType, ResolvedImageConfig,
+};
+pub use ops::source::local::{Local, LocalDiffer};
+pub use platform::{Platform, PlatformError, PlatformMatcher};
+pub use serialize::node::digest;
+pub use serialize::{Definition, MultiPlatformDefinition};
+pub use sourcepolicy::{
+    AttrConstraint, MatchType, PolicyViolation, Selector, SourcePolicy, SourcePolicyError, Update,
+};
+pub use validate::{validate, ValidationError};
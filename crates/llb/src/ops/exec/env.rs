@@ -0,0 +1,217 @@
+use buildkit_rs_util::shell::EnvGetter;
+use thiserror::Error;
+
+/// The error type for invalid environment variables
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum EnvError {
+    /// The key is empty or contains `=` or a NUL byte
+    #[error("invalid environment variable name {0:?}")]
+    InvalidKey(String),
+}
+
+/// An ordered map of environment variables.
+///
+/// Setting a variable that already exists keeps its position, and an unset
+/// variable is remembered so it also removes the variable from the env it is
+/// merged on top of, such as the env of the root image.
+///
+/// Based on the `EnvList` type in the Go client.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EnvMap {
+    vars: Vec<(String, Option<String>)>,
+}
+
+impl EnvMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parse variables in the `KEY=VALUE` form, a variable without `=` is
+    /// set to an empty value
+    pub fn parse<I, S>(vars: I) -> Result<Self, EnvError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut env = Self::new();
+        for var in vars {
+            let (key, value) = var.as_ref().split_once('=').unwrap_or((var.as_ref(), ""));
+            env.set(key, value)?;
+        }
+        Ok(env)
+    }
+
+    /// Set a variable, replacing the value of an existing variable in place
+    pub fn set(
+        &mut self,
+        key: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Result<(), EnvError> {
+        let key = key.into();
+        if key.is_empty() || key.contains(['=', '\0']) {
+            return Err(EnvError::InvalidKey(key));
+        }
+
+        self.insert(key, Some(value.into()));
+        Ok(())
+    }
+
+    /// Set a variable only if it is neither set nor explicitly unset
+    pub fn set_default(
+        &mut self,
+        key: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Result<(), EnvError> {
+        let key = key.into();
+        if self.vars.iter().any(|(k, _)| *k == key) {
+            return Ok(());
+        }
+
+        self.set(key, value)
+    }
+
+    /// Remove a variable, this also removes it from the env this map is
+    /// merged on top of
+    pub fn unset(&mut self, key: impl Into<String>) {
+        self.insert(key.into(), None);
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.vars
+            .iter()
+            .find(|(k, _)| k == key)
+            .and_then(|(_, v)| v.as_deref())
+    }
+
+    /// Expand `$VAR` and `${VAR}` in `s` with the variables in the map,
+    /// variables that are not set expand to an empty string.
+    ///
+    /// Quotes and other shell syntax are left as they are, use
+    /// [`Lex`](buildkit_rs_util::shell::Lex) for full shell-word expansion.
+    pub fn expand(&self, s: &str) -> String {
+        let is_name = |c: char| c.is_ascii_alphanumeric() || c == '_';
+
+        let mut expanded = String::with_capacity(s.len());
+        let mut rest = s;
+        while let Some(idx) = rest.find('$') {
+            expanded.push_str(&rest[..idx]);
+            let after = &rest[idx + 1..];
+
+            let (name, remainder) = match after.strip_prefix('{') {
+                Some(braced) => match braced.find('}') {
+                    Some(end) => (&braced[..end], &braced[end + 1..]),
+                    None => ("", after),
+                },
+                None => {
+                    let end = after.find(|c| !is_name(c)).unwrap_or(after.len());
+                    (&after[..end], &after[end..])
+                }
+            };
+
+            if name.is_empty() {
+                expanded.push('$');
+            } else {
+                expanded.push_str(self.get(name).unwrap_or_default());
+            }
+            rest = remainder;
+        }
+        expanded.push_str(rest);
+        expanded
+    }
+
+    /// Apply the variables and unsets of `other` on top of this map
+    pub fn merge(&mut self, other: &EnvMap) {
+        for (key, value) in &other.vars {
+            self.insert(key.clone(), value.clone());
+        }
+    }
+
+    /// Iterate over the variables that are set, in order
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.vars
+            .iter()
+            .filter_map(|(k, v)| v.as_deref().map(|v| (k.as_str(), v)))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    /// The variables in the `KEY=VALUE` form
+    pub fn to_vec(&self) -> Vec<String> {
+        self.iter().map(|(k, v)| format!("{k}={v}")).collect()
+    }
+
+    fn insert(&mut self, key: String, value: Option<String>) {
+        match self.vars.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, v)) => *v = value,
+            None => self.vars.push((key, value)),
+        }
+    }
+}
+
+impl EnvGetter for EnvMap {
+    fn get_env(&self, key: &str) -> Option<&str> {
+        self.get(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_get_unset() {
+        let mut env = EnvMap::parse(["PATH=/usr/bin", "HOME=/root", "EMPTY"]).unwrap();
+        assert_eq!(env.get("PATH"), Some("/usr/bin"));
+        assert_eq!(env.get("EMPTY"), Some(""));
+
+        env.set("PATH", "/bin").unwrap();
+        env.unset("HOME");
+        env.set_default("PATH", "/sbin").unwrap();
+        env.set_default("USER", "root").unwrap();
+        env.set_default("HOME", "/home/user").unwrap();
+
+        assert_eq!(env.get("HOME"), None);
+        assert_eq!(env.to_vec(), ["PATH=/bin", "EMPTY=", "USER=root"]);
+
+        assert_eq!(env.set("", "x"), Err(EnvError::InvalidKey("".into())));
+        assert_eq!(env.set("A=B", "x"), Err(EnvError::InvalidKey("A=B".into())));
+    }
+
+    #[test]
+    fn expand() {
+        let env = EnvMap::parse(["PATH=/usr/bin", "GOPATH=/go"]).unwrap();
+
+        assert_eq!(env.expand("$GOPATH/bin:$PATH"), "/go/bin:/usr/bin");
+        assert_eq!(env.expand("${GOPATH}_x"), "/go_x");
+        assert_eq!(env.expand("$MISSING-$"), "-$");
+        assert_eq!(env.expand("${GOPATH"), "${GOPATH");
+    }
+
+    #[test]
+    fn lex() {
+        use buildkit_rs_util::shell::Lex;
+
+        let env = EnvMap::parse(["GOPATH=/go"]).unwrap();
+        assert_eq!(
+            Lex::default()
+                .process_word("'$GOPATH' ${GOPATH:-x}/bin ${CGO:-0}", &env)
+                .unwrap(),
+            "$GOPATH /go/bin 0"
+        );
+    }
+
+    #[test]
+    fn merge() {
+        let mut base = EnvMap::parse(["PATH=/usr/bin", "GOPATH=/go", "DEBUG=1"]).unwrap();
+        let mut env = EnvMap::parse(["GOPATH=/src", "CGO_ENABLED=0"]).unwrap();
+        env.unset("DEBUG");
+
+        base.merge(&env);
+        assert_eq!(
+            base.to_vec(),
+            ["PATH=/usr/bin", "GOPATH=/src", "CGO_ENABLED=0"]
+        );
+    }
+}
@@ -1,20 +1,27 @@
 use std::{borrow::Cow, sync::Arc};
 
-use buildkit_rs_proto::pb::{self, op::Op as OpEnum, ExecOp, Meta, NetMode, Op, SecurityMode};
+use buildkit_rs_proto::pb::{self, op::Op as OpEnum, ExecOp, Meta, Op};
+use buildkit_rs_util::system::{default_path_env, OsFamily};
+use camino::Utf8Path;
 
 use crate::{
+    ops::source::image::ImageConfig,
+    platform::Platform,
     serialize::{
         id::OperationId,
         node::{Context, Node, Operation},
     },
-    utils::{OperationOutput, OutputIdx},
-    MultiBorrowedOutput, MultiOwnedOutput, OpMetadataBuilder,
+    utils::OutputIdx,
+    MultiOutput, OpMetadataBuilder, Output, OutputError,
 };
 
 use super::metadata::OpMetadata;
 
+pub mod env;
 pub mod mount;
 
+use env::{EnvError, EnvMap};
+
 /*
 type ExecOp struct {
     proxyEnv    *ProxyEnv
@@ -29,13 +36,16 @@ type ExecOp struct {
 */
 
 #[derive(Debug, Clone)]
-pub struct Exec<'a> {
+pub struct Exec {
     pub(crate) id: OperationId,
     pub(crate) metadata: OpMetadata,
 
     // pub proxy_env: Option<ProxyEnv>,
     pub context: Option<ExecContext>,
-    pub mounts: Vec<mount::Mount<'a>>,
+    pub mounts: Vec<mount::Mount>,
+    pub platform: Option<Platform>,
+    pub network: NetMode,
+    pub security: SecurityMode,
     // pub base: Option<State>,
     // pub constraints: Constraints,
     // pub is_validated: bool,
@@ -43,13 +53,16 @@ pub struct Exec<'a> {
     // pub ssh: Vec<SSHInfo>,
 }
 
-impl Exec<'static> {
+impl Exec {
     fn empty() -> Self {
         Self {
             id: OperationId::new(),
             metadata: OpMetadata::new(),
             context: None,
             mounts: vec![],
+            platform: None,
+            network: NetMode::default(),
+            security: SecurityMode::default(),
         }
     }
 
@@ -85,40 +98,138 @@ impl Exec<'static> {
             ..Self::empty()
         }
     }
-}
 
-impl<'a> Exec<'a> {
-    pub fn with_mount(mut self, mount: mount::Mount<'a>) -> Self {
+    pub fn with_mount(mut self, mount: mount::Mount) -> Self {
         self.mounts.push(mount);
         self
     }
 
-    pub fn with_env(mut self, env: Vec<String>) -> Self {
-        self.context = Some(self.context.unwrap().with_env(env));
+    /// Replace the env, it is merged on top of the root image's env
+    pub fn with_env(mut self, env: EnvMap) -> Self {
+        self.context.get_or_insert_with(Default::default).env = env;
+        self
+    }
+
+    /// Set an environment variable, fails if `key` is not a valid variable
+    /// name, see [`EnvMap::set`]
+    pub fn with_env_var(
+        mut self,
+        key: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Result<Self, EnvError> {
+        self.context
+            .get_or_insert_with(Default::default)
+            .env
+            .set(key, value)?;
+        Ok(self)
+    }
+
+    /// Unset an environment variable, including one set by the root image
+    pub fn without_env_var(mut self, key: impl Into<String>) -> Self {
+        self.context
+            .get_or_insert_with(Default::default)
+            .env
+            .unset(key);
         self
     }
 
+    /// The env set on the exec, without the env of the root image
+    pub fn env(&self) -> Option<&EnvMap> {
+        self.context.as_ref().map(|context| &context.env)
+    }
+
     pub fn with_cwd(mut self, cwd: String) -> Self {
-        self.context = Some(self.context.unwrap().with_cwd(cwd));
+        self.context.get_or_insert_with(Default::default).cwd = Some(cwd.into());
+        self
+    }
+
+    pub fn with_user(mut self, user: String) -> Self {
+        self.context.get_or_insert_with(Default::default).user = Some(user.into());
+        self
+    }
+
+    /// Set the platform of the exec, defaults to the platform of the root
+    /// mount's image config or the platform of the definition
+    pub fn with_platform(mut self, platform: Platform) -> Self {
+        self.platform = Some(platform);
+        self
+    }
+
+    /// Set the network of the exec, the host network needs the
+    /// `network.host` entitlement
+    pub fn with_network(mut self, network: NetMode) -> Self {
+        self.network = network;
+        self
+    }
+
+    /// Set the security mode of the exec, running insecure needs the
+    /// `security.insecure` entitlement
+    pub fn with_security(mut self, security: SecurityMode) -> Self {
+        self.security = security;
         self
     }
+
+    /// The output of the mount at `/`
+    pub fn root(self: &Arc<Self>) -> Result<Output, OutputError> {
+        self.mount_output("/")
+    }
+
+    /// The output of the writable mount at `dest`
+    pub fn mount_output(self: &Arc<Self>, dest: impl AsRef<str>) -> Result<Output, OutputError> {
+        let dest = Utf8Path::new(dest.as_ref());
+        match self.mount_outputs().find(|(mount, _)| mount.dest() == dest) {
+            Some((_, Some(index))) => Ok(Output::new(self.clone(), index)),
+            Some((_, None)) => Err(OutputError::NoOutput(dest.to_string())),
+            None => Err(OutputError::NoMount(dest.to_string())),
+        }
+    }
+
+    /// Each mount with its output index, writable mounts are numbered in the
+    /// order they were added
+    fn mount_outputs(&self) -> impl Iterator<Item = (&mount::Mount, Option<OutputIdx>)> {
+        let mut next = 0;
+        self.mounts.iter().map(move |mount| {
+            let output = mount.has_output().then(|| {
+                next += 1;
+                OutputIdx(next - 1)
+            });
+            (mount, output)
+        })
+    }
+
+    /// The image config of the input mounted at `/`, if any
+    fn root_config(&self) -> Option<&ImageConfig> {
+        self.mounts
+            .iter()
+            .find(|mount| mount.is_root())
+            .and_then(|mount| mount.input())
+            .and_then(|input| input.operation().image_config(OutputIdx(input.index())))
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct ExecContext {
     pub args: Vec<String>,
-    pub env: Vec<String>,
-    pub cwd: Cow<'static, str>,
-    pub user: Cow<'static, str>,
+    pub env: EnvMap,
+    /// Defaults to the root image's working directory or `/`
+    pub cwd: Option<Cow<'static, str>>,
+    /// Defaults to the root image's user or `root`
+    pub user: Option<Cow<'static, str>>,
+}
+
+impl Default for ExecContext {
+    fn default() -> Self {
+        Self::new(vec![])
+    }
 }
 
 impl ExecContext {
     pub fn new(args: Vec<String>) -> Self {
         Self {
             args,
-            env: vec![],
-            cwd: "/".into(),
-            user: "root".into(),
+            env: EnvMap::new(),
+            cwd: None,
+            user: None,
         }
     }
 
@@ -127,33 +238,95 @@ impl ExecContext {
         self
     }
 
-    pub fn with_env(mut self, env: Vec<String>) -> Self {
+    pub fn with_env(mut self, env: EnvMap) -> Self {
         self.env = env;
         self
     }
 
     pub fn with_cwd(mut self, cwd: String) -> Self {
-        self.cwd = cwd.into();
+        self.cwd = Some(cwd.into());
         self
     }
 
     pub fn with_user(mut self, user: String) -> Self {
-        self.user = user.into();
+        self.user = Some(user.into());
         self
     }
 }
 
-impl Operation for Exec<'_> {
+/// The network an exec runs in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NetMode {
+    /// The network of the worker, isolated from the host
+    #[default]
+    Sandbox,
+    Host,
+    None,
+}
+
+impl From<NetMode> for pb::NetMode {
+    fn from(mode: NetMode) -> Self {
+        match mode {
+            NetMode::Sandbox => Self::Unset,
+            NetMode::Host => Self::Host,
+            NetMode::None => Self::None,
+        }
+    }
+}
+
+/// The privileges an exec runs with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SecurityMode {
+    #[default]
+    Sandbox,
+    /// Privileged, with all capabilities and devices of the host
+    Insecure,
+}
+
+impl From<SecurityMode> for pb::SecurityMode {
+    fn from(mode: SecurityMode) -> Self {
+        match mode {
+            SecurityMode::Sandbox => Self::Sandbox,
+            SecurityMode::Insecure => Self::Insecure,
+        }
+    }
+}
+
+/// Overlay `env` on top of the image's env and add the default `PATH` for
+/// the OS family if neither sets one
+fn merge_env(config: Option<&ImageConfig>, env: &EnvMap, os_family: OsFamily) -> Vec<String> {
+    let mut merged = EnvMap::new();
+    for var in config.map(|c| c.env.as_slice()).unwrap_or_default() {
+        let (key, value) = var.split_once('=').unwrap_or((var, ""));
+        // Skip malformed variables from the image instead of failing the build
+        let _ = merged.set(key, value);
+    }
+
+    merged.merge(env);
+    let _ = merged.set_default("PATH", default_path_env(os_family));
+    merged.to_vec()
+}
+
+impl Operation for Exec {
     fn id(&self) -> &OperationId {
         &self.id
     }
 
+    /// Only the output of the root mount carries the root image config
+    fn image_config(&self, index: OutputIdx) -> Option<&ImageConfig> {
+        let (_, root_index) = self.mount_outputs().find(|(mount, _)| mount.is_root())?;
+        if root_index?.0 != index.0 {
+            return None;
+        }
+        self.root_config()
+    }
+
     fn serialize(&self, ctx: &mut Context) -> Option<Node> {
         let mut mounts: Vec<pb::Mount> = vec![];
         let mut inputs: Vec<pb::Input> = vec![];
 
         let mut input_index = 0;
-        for mount in &self.mounts {
+        for (mount, output) in self.mount_outputs() {
             let input_index = if let Some(input) = mount.input() {
                 let current_index = input_index;
                 let node = ctx.register(input.operation()).unwrap();
@@ -170,22 +343,40 @@ impl Operation for Exec<'_> {
                 -1
             };
 
-            mounts.push(mount.to_pb(input_index));
+            mounts.push(mount.to_pb(input_index, output));
         }
 
+        let config = self.root_config();
+        let platform = self
+            .platform
+            .as_ref()
+            .or_else(|| config.and_then(|c| c.platform.as_ref()))
+            .or_else(|| ctx.platform());
+        let os_family = platform.map_or(OsFamily::Unix, Platform::os_family);
+
         let meta = self.context.as_ref().map(|ctx| Meta {
             args: ctx.args.clone(),
-            env: ctx.env.clone(),
-            cwd: ctx.cwd.clone().into_owned(),
-            user: ctx.user.clone().into_owned(),
+            env: merge_env(config, &ctx.env, os_family),
+            cwd: ctx
+                .cwd
+                .clone()
+                .map(Cow::into_owned)
+                .or_else(|| config.and_then(|c| c.working_dir.clone()))
+                .unwrap_or_else(|| "/".into()),
+            user: ctx
+                .user
+                .clone()
+                .map(Cow::into_owned)
+                .or_else(|| config.and_then(|c| c.user.clone()))
+                .unwrap_or_else(|| "root".into()),
             ..Default::default()
         });
 
         let exec_op = ExecOp {
             meta,
             mounts,
-            network: NetMode::Unset.into(),
-            security: SecurityMode::Sandbox.into(),
+            network: pb::NetMode::from(self.network).into(),
+            security: pb::SecurityMode::from(self.security).into(),
             secretenv: vec![],
         };
 
@@ -193,6 +384,7 @@ impl Operation for Exec<'_> {
             Op {
                 op: Some(OpEnum::Exec(exec_op)),
                 inputs,
+                platform: platform.map(Platform::to_pb),
                 ..Default::default()
             },
             self.metadata.clone().into(),
@@ -200,7 +392,7 @@ impl Operation for Exec<'_> {
     }
 }
 
-impl OpMetadataBuilder for Exec<'_> {
+impl OpMetadataBuilder for Exec {
     fn metadata(&self) -> &OpMetadata {
         &self.metadata
     }
@@ -210,16 +402,177 @@ impl OpMetadataBuilder for Exec<'_> {
     }
 }
 
-impl<'a, 'b: 'a> MultiBorrowedOutput<'b> for Exec<'b> {
-    fn output(&'b self, index: u32) -> OperationOutput<'b> {
-        // TODO: check if the requested index available.
-        OperationOutput::borrowed(self, OutputIdx(index))
+impl MultiOutput for Arc<Exec> {
+    fn output(&self, index: u32) -> Result<Output, OutputError> {
+        let count = self.mounts.iter().filter(|m| m.has_output()).count() as u32;
+        if index >= count {
+            return Err(OutputError::OutOfRange { index, count });
+        }
+        Ok(Output::new(self.clone(), OutputIdx(index)))
     }
 }
 
-impl<'a> MultiOwnedOutput<'a> for Arc<Exec<'a>> {
-    fn output(&self, index: u32) -> OperationOutput<'a> {
-        // TODO: check if the requested index available.
-        OperationOutput::owned(self.clone(), OutputIdx(index))
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{Image, Mount, SingleOutput};
+
+    fn exec_meta(exec: &Exec) -> (Meta, Option<pb::Platform>) {
+        use prost::Message;
+
+        let node = exec.serialize(&mut Context::new()).unwrap();
+        let op = Op::decode(node.bytes.as_slice()).unwrap();
+        match op.op {
+            Some(OpEnum::Exec(exec)) => (exec.meta.unwrap(), op.platform),
+            _ => panic!("not an exec op"),
+        }
+    }
+
+    #[test]
+    fn defaults_from_root_image_config() {
+        let image = Arc::new(Image::new("golang:1.20").with_config(ImageConfig {
+            env: vec![
+                "PATH=/usr/local/go/bin:/usr/bin".into(),
+                "GOPATH=/go".into(),
+            ],
+            working_dir: Some("/go".into()),
+            user: Some("nobody".into()),
+            platform: Some(Platform::LINUX_ARM64),
+        }));
+
+        let exec = Arc::new(Exec::shlex("go build").with_mount(Mount::layer(image.output(), "/")));
+        let (meta, platform) = exec_meta(&exec);
+        assert_eq!(meta.env, ["PATH=/usr/local/go/bin:/usr/bin", "GOPATH=/go"]);
+        assert_eq!(meta.cwd, "/go");
+        assert_eq!(meta.user, "nobody");
+        assert_eq!(platform, Some(Platform::LINUX_ARM64.to_pb()));
+
+        let exec = Arc::new(
+            Exec::shlex("go build")
+                .with_env_var("GOPATH", "/src")
+                .and_then(|exec| exec.with_env_var("CGO_ENABLED", "0"))
+                .unwrap()
+                .with_cwd("/src".into())
+                .with_mount(Mount::layer(image.output(), "/")),
+        );
+        let (meta, _) = exec_meta(&exec);
+        assert_eq!(
+            meta.env,
+            [
+                "PATH=/usr/local/go/bin:/usr/bin",
+                "GOPATH=/src",
+                "CGO_ENABLED=0"
+            ]
+        );
+        assert_eq!(meta.cwd, "/src");
+        assert_eq!(meta.user, "nobody");
+
+        let err = Exec::shlex("go build")
+            .with_env_var("A=B", "x")
+            .unwrap_err();
+        assert_eq!(err, EnvError::InvalidKey("A=B".into()));
+    }
+
+    #[test]
+    fn defaults_without_image_config() {
+        let image = Arc::new(Image::new("alpine:latest"));
+        let exec = Arc::new(Exec::shlex("ls").with_mount(Mount::layer(image.output(), "/")));
+        let (meta, platform) = exec_meta(&exec);
+        assert_eq!(
+            meta.env,
+            ["PATH=/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin"]
+        );
+        assert_eq!(meta.cwd, "/");
+        assert_eq!(meta.user, "root");
+        assert_eq!(platform, None);
+    }
+
+    #[test]
+    fn config_of_root_output_only() {
+        let image = Arc::new(Image::new("golang:1.20").with_config(ImageConfig {
+            working_dir: Some("/go".into()),
+            user: Some("nobody".into()),
+            ..Default::default()
+        }));
+        let build = Arc::new(
+            Exec::shlex("go build")
+                .with_mount(Mount::layer(image.output(), "/"))
+                .with_mount(Mount::scratch("/out")),
+        );
+
+        let exec = Arc::new(Exec::shlex("ls").with_mount(Mount::layer(build.root().unwrap(), "/")));
+        let (meta, _) = exec_meta(&exec);
+        assert_eq!((meta.cwd.as_str(), meta.user.as_str()), ("/go", "nobody"));
+
+        let exec = Arc::new(
+            Exec::shlex("ls").with_mount(Mount::layer(build.mount_output("/out").unwrap(), "/")),
+        );
+        let (meta, _) = exec_meta(&exec);
+        assert_eq!((meta.cwd.as_str(), meta.user.as_str()), ("/", "root"));
+    }
+
+    #[test]
+    fn unset_path() {
+        let image = Arc::new(Image::new("alpine:latest").with_config(ImageConfig {
+            env: vec!["PATH=/usr/bin".into(), "HOME=/root".into()],
+            ..Default::default()
+        }));
+        let exec = Arc::new(
+            Exec::shlex("ls")
+                .without_env_var("PATH")
+                .with_mount(Mount::layer(image.output(), "/")),
+        );
+        let (meta, _) = exec_meta(&exec);
+        assert_eq!(meta.env, ["HOME=/root"]);
+    }
+
+    #[test]
+    fn default_path_for_os_family() {
+        let image = Arc::new(Image::new("golang:1.20").with_config(ImageConfig {
+            env: vec!["GOPATH=/go".into(), "DEBUG=1".into()],
+            ..Default::default()
+        }));
+
+        let exec = Arc::new(
+            Exec::shlex("go build")
+                .with_platform(Platform::WINDOWS)
+                .without_env_var("DEBUG")
+                .with_mount(Mount::layer(image.output(), "/")),
+        );
+        let (meta, _) = exec_meta(&exec);
+        assert_eq!(
+            meta.env,
+            ["GOPATH=/go", "PATH=c:\\Windows\\System32;c:\\Windows"]
+        );
+    }
+
+    #[test]
+    fn network_and_security() {
+        use prost::Message;
+
+        let exec_op = |exec: Exec| {
+            let node = exec.serialize(&mut Context::new()).unwrap();
+            match Op::decode(node.bytes.as_slice()).unwrap().op {
+                Some(OpEnum::Exec(exec)) => exec,
+                _ => panic!("not an exec op"),
+            }
+        };
+
+        let exec = exec_op(Exec::shlex("ls"));
+        assert_eq!(exec.network(), pb::NetMode::Unset);
+        assert_eq!(exec.security(), pb::SecurityMode::Sandbox);
+
+        let exec = exec_op(
+            Exec::shlex("ls")
+                .with_network(NetMode::Host)
+                .with_security(SecurityMode::Insecure),
+        );
+        assert_eq!(exec.network(), pb::NetMode::Host);
+        assert_eq!(exec.security(), pb::SecurityMode::Insecure);
+
+        let exec = exec_op(Exec::shlex("ls").with_network(NetMode::None));
+        assert_eq!(exec.network(), pb::NetMode::None);
     }
 }
@@ -2,9 +2,9 @@ use buildkit_rs_proto::pb::{
     CacheOpt, CacheSharingOpt, Mount as PbMount, MountType as PbMountType, SecretOpt, SshOpt,
     TmpfsOpt,
 };
-use camino::Utf8PathBuf;
+use camino::{Utf8Path, Utf8PathBuf};
 
-use crate::utils::{OperationOutput, OutputIdx};
+use crate::{ops::output::Output, utils::OutputIdx};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum CacheSharingMode {
@@ -24,15 +24,22 @@ impl From<CacheSharingMode> for CacheSharingOpt {
     }
 }
 
+impl From<CacheSharingOpt> for CacheSharingMode {
+    fn from(opt: CacheSharingOpt) -> Self {
+        match opt {
+            CacheSharingOpt::Shared => Self::Shared,
+            CacheSharingOpt::Private => Self::Private,
+            CacheSharingOpt::Locked => Self::Locked,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
-pub enum MountType<'a> {
-    Scratch {
-        output: OutputIdx,
-    },
+pub enum MountType {
+    Scratch,
     Layer {
-        input: OperationOutput<'a>,
-        /// Readonly if None
-        output: Option<OutputIdx>,
+        input: Output,
+        readonly: bool,
     },
     Tmpfs {
         size: i64,
@@ -58,45 +65,41 @@ pub enum MountType<'a> {
 }
 
 #[derive(Debug, Clone)]
-pub struct Mount<'a> {
+pub struct Mount {
     dest: Utf8PathBuf,
-    mount_type: MountType<'a>,
+    mount_type: MountType,
     /// Selector for layer
     selector: Option<String>,
 }
 
-impl Mount<'_> {
-    pub fn scratch(dest: impl Into<Utf8PathBuf>, output: impl Into<OutputIdx>) -> Mount<'static> {
+impl Mount {
+    /// An empty writable mount, it has an output
+    pub fn scratch(dest: impl Into<Utf8PathBuf>) -> Mount {
         Mount {
             dest: dest.into(),
-            mount_type: MountType::Scratch {
-                output: output.into(),
-            },
+            mount_type: MountType::Scratch,
             selector: None,
         }
     }
 
-    pub fn layer(
-        input: OperationOutput<'_>,
-        dest: impl Into<Utf8PathBuf>,
-        output: impl Into<OutputIdx>,
-    ) -> Mount<'_> {
+    /// A writable mount of `input`, it has an output
+    pub fn layer(input: Output, dest: impl Into<Utf8PathBuf>) -> Mount {
         Mount {
             dest: dest.into(),
             mount_type: MountType::Layer {
                 input,
-                output: Some(output.into()),
+                readonly: false,
             },
             selector: None,
         }
     }
 
-    pub fn layer_readonly(input: OperationOutput<'_>, dest: impl Into<Utf8PathBuf>) -> Mount<'_> {
+    pub fn layer_readonly(input: Output, dest: impl Into<Utf8PathBuf>) -> Mount {
         Mount {
             dest: dest.into(),
             mount_type: MountType::Layer {
                 input,
-                output: None,
+                readonly: true,
             },
             selector: None,
         }
@@ -106,7 +109,7 @@ impl Mount<'_> {
         dest: impl Into<Utf8PathBuf>,
         id: impl Into<String>,
         sharing: CacheSharingMode,
-    ) -> Mount<'static> {
+    ) -> Mount {
         Mount {
             dest: dest.into(),
             mount_type: MountType::Cache {
@@ -124,7 +127,7 @@ impl Mount<'_> {
         gid: u32,
         mode: u32,
         optional: bool,
-    ) -> Mount<'static> {
+    ) -> Mount {
         Mount {
             dest: dest.into(),
             mount_type: MountType::Secret {
@@ -138,28 +141,74 @@ impl Mount<'_> {
         }
     }
 
+    /// An in-memory filesystem, a `size` of 0 means no limit
+    pub fn tmpfs(dest: impl Into<Utf8PathBuf>, size: i64) -> Mount {
+        Mount {
+            dest: dest.into(),
+            mount_type: MountType::Tmpfs { size },
+            selector: None,
+        }
+    }
+
+    /// A socket forwarded from the ssh agent of the client
+    pub fn ssh(
+        dest: impl Into<Utf8PathBuf>,
+        id: impl Into<String>,
+        uid: u32,
+        gid: u32,
+        mode: u32,
+        optional: bool,
+    ) -> Mount {
+        Mount {
+            dest: dest.into(),
+            mount_type: MountType::Ssh {
+                id: id.into(),
+                uid,
+                gid,
+                mode,
+                optional,
+            },
+            selector: None,
+        }
+    }
+
     pub fn with_selector(mut self, selector: impl Into<String>) -> Self {
         self.selector = Some(selector.into());
         self
     }
 
-    pub(crate) fn input(&self) -> Option<&OperationOutput> {
+    pub fn dest(&self) -> &Utf8Path {
+        &self.dest
+    }
+
+    /// Whether the mount produces an output, only writable scratch and layer
+    /// mounts do
+    pub fn has_output(&self) -> bool {
+        matches!(
+            self.mount_type,
+            MountType::Scratch
+                | MountType::Layer {
+                    readonly: false,
+                    ..
+                }
+        )
+    }
+
+    pub(crate) fn is_root(&self) -> bool {
+        self.dest == "/"
+    }
+
+    pub(crate) fn input(&self) -> Option<&Output> {
         match &self.mount_type {
             MountType::Layer { input, .. } => Some(input),
             _ => None,
         }
     }
 
-    pub(crate) fn to_pb(&self, input: i64) -> PbMount {
+    pub(crate) fn to_pb(&self, input: i64, output: Option<OutputIdx>) -> PbMount {
         PbMount {
             input,
-
-            output: match &self.mount_type {
-                MountType::Scratch { output } => Some(output.into()),
-                MountType::Layer { output, .. } => output.map(|o| o.into()),
-                _ => None,
-            }
-            .unwrap_or(-1),
+            output: output.map_or(-1, Into::into),
 
             selector: self.selector.clone().unwrap_or_default(),
             dest: self.dest.clone().into(),
@@ -167,13 +216,10 @@ impl Mount<'_> {
             // TODO: support result_id
             result_id: "".into(),
 
-            readonly: match &self.mount_type {
-                MountType::Layer { output, .. } => output.is_none(),
-                _ => false,
-            },
+            readonly: matches!(self.mount_type, MountType::Layer { readonly: true, .. }),
 
             mount_type: match self.mount_type {
-                MountType::Layer { .. } | MountType::Scratch { .. } => PbMountType::Bind,
+                MountType::Layer { .. } | MountType::Scratch => PbMountType::Bind,
                 MountType::Tmpfs { .. } => PbMountType::Tmpfs,
                 MountType::Cache { .. } => PbMountType::Cache,
                 MountType::Secret { .. } => PbMountType::Secret,
@@ -1,46 +1,114 @@
+use buildkit_rs_proto::pb::FileActionCopy;
 use camino::Utf8PathBuf;
 
-use crate::utils::OperationOutput;
+use crate::ops::output::Output;
 
-use super::FileAction;
+use super::{FileAction, Owner};
 
-#[derive(Debug)]
-pub(crate) struct Copy<'a> {
+/// Copy a path of another output onto the filesystem
+#[derive(Debug, Clone)]
+pub struct Copy {
+    pub(super) src_input: Output,
     src_path: Utf8PathBuf,
-    src_input: OperationOutput<'a>,
-    dst_path: Utf8PathBuf,
-    dest_input: OperationOutput<'a>,
-    // owner: Option<ChownOpt>,
-    // mode: i32,
-    // follow_symlink: bool,
-    // dir_copy_contents: bool,
-    // attempt_unpack_docker_compatibility: bool,
-    // create_dest_path: bool,
-    // allow_wildcard: bool,
-    // allow_empty_wildcard: bool,
-    // timestamp: i64,
-    // include_patterns: Vec<String>,
-    // exclude_patterns: Vec<String>,
+    dest_path: Utf8PathBuf,
+    owner: Option<Owner>,
+    mode: Option<u32>,
+    follow_symlink: bool,
+    dir_copy_contents: bool,
+    attempt_unpack: bool,
+    create_dest_path: bool,
+    allow_wildcard: bool,
+    allow_empty_wildcard: bool,
 }
 
-impl<'a> Copy<'a> {
+impl Copy {
+    /// Copy `src_path` of `src_input` to `dest_path`, a destination ending
+    /// with `/` is a directory to copy into
     pub fn new(
+        src_input: Output,
         src_path: impl Into<Utf8PathBuf>,
-        src_input: OperationOutput<'a>,
-        dst_path: impl Into<Utf8PathBuf>,
-        dest_input: OperationOutput<'a>,
+        dest_path: impl Into<Utf8PathBuf>,
     ) -> Self {
         Self {
-            src_path: src_path.into(),
             src_input,
-            dst_path: dst_path.into(),
-            dest_input,
+            src_path: src_path.into(),
+            dest_path: dest_path.into(),
+            owner: None,
+            mode: None,
+            follow_symlink: false,
+            dir_copy_contents: false,
+            attempt_unpack: false,
+            create_dest_path: false,
+            allow_wildcard: false,
+            allow_empty_wildcard: false,
+        }
+    }
+
+    /// Change the owner of the copied files, they keep theirs otherwise
+    pub fn with_owner(mut self, owner: Owner) -> Self {
+        self.owner = Some(owner);
+        self
+    }
+
+    /// Change the permission bits of the copied files
+    pub fn with_mode(mut self, mode: u32) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// Copy the target of a symlink source instead of the symlink
+    pub fn with_follow_symlink(mut self, follow: bool) -> Self {
+        self.follow_symlink = follow;
+        self
+    }
+
+    /// Copy the contents of a source directory instead of the directory
+    pub fn with_dir_copy_contents(mut self, contents: bool) -> Self {
+        self.dir_copy_contents = contents;
+        self
+    }
+
+    /// Extract a source that is an archive, like `ADD` of a Dockerfile
+    pub fn with_attempt_unpack(mut self, unpack: bool) -> Self {
+        self.attempt_unpack = unpack;
+        self
+    }
+
+    /// Create the missing parent directories of the destination
+    pub fn with_create_dest_path(mut self, create: bool) -> Self {
+        self.create_dest_path = create;
+        self
+    }
+
+    /// Match the source as a pattern, `allow_empty` does not fail when
+    /// nothing matches
+    pub fn with_allow_wildcard(mut self, allow: bool, allow_empty: bool) -> Self {
+        self.allow_wildcard = allow;
+        self.allow_empty_wildcard = allow_empty;
+        self
+    }
+
+    pub(super) fn to_pb(&self, input: i64) -> FileActionCopy {
+        FileActionCopy {
+            src: self.src_path.to_string(),
+            dest: self.dest_path.to_string(),
+            owner: self.owner.as_ref().map(|owner| owner.to_pb(input)),
+            mode: self.mode.map_or(-1, |mode| mode as i32),
+            follow_symlink: self.follow_symlink,
+            dir_copy_contents: self.dir_copy_contents,
+            attempt_unpack_docker_compatibility: self.attempt_unpack,
+            create_dest_path: self.create_dest_path,
+            allow_wildcard: self.allow_wildcard,
+            allow_empty_wildcard: self.allow_empty_wildcard,
+            timestamp: -1,
+            include_patterns: vec![],
+            exclude_patterns: vec![],
         }
     }
 }
 
-impl<'a> From<Copy<'a>> for FileAction<'a> {
-    fn from(copy: Copy<'a>) -> Self {
+impl From<Copy> for FileAction {
+    fn from(copy: Copy) -> Self {
         Self::Copy(copy)
     }
 }
@@ -1,32 +1,56 @@
+use buildkit_rs_proto::pb::FileActionMkDir;
 use camino::Utf8PathBuf;
 
-use crate::utils::OperationOutput;
+use super::{FileAction, Owner};
 
-use super::FileAction;
-
-#[derive(Debug)]
-pub(crate) struct Mkdir<'a> {
+/// Create a directory, with mode 0755 unless set
+#[derive(Debug, Clone)]
+pub struct Mkdir {
     path: Utf8PathBuf,
-    input: OperationOutput<'a>,
-
+    mode: u32,
     make_parents: bool,
-    // owner: Option<ChownOpt>,
-    // mode: i32,
-    // timestamp: i64,
+    owner: Option<Owner>,
 }
 
-impl<'a> Mkdir<'a> {
-    pub fn new(path: impl Into<Utf8PathBuf>, input: OperationOutput<'a>) -> Self {
+impl Mkdir {
+    pub fn new(path: impl Into<Utf8PathBuf>) -> Self {
         Self {
             path: path.into(),
-            input,
+            mode: 0o755,
             make_parents: false,
+            owner: None,
+        }
+    }
+
+    pub fn with_mode(mut self, mode: u32) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Create the missing parents as well, like `mkdir -p`
+    pub fn with_make_parents(mut self, make_parents: bool) -> Self {
+        self.make_parents = make_parents;
+        self
+    }
+
+    pub fn with_owner(mut self, owner: Owner) -> Self {
+        self.owner = Some(owner);
+        self
+    }
+
+    pub(super) fn to_pb(&self, input: i64) -> FileActionMkDir {
+        FileActionMkDir {
+            path: self.path.to_string(),
+            mode: self.mode as i32,
+            make_parents: self.make_parents,
+            owner: self.owner.as_ref().map(|owner| owner.to_pb(input)),
+            timestamp: -1,
         }
     }
 }
 
-impl<'a> From<Mkdir<'a>> for FileAction<'a> {
-    fn from(mkdir: Mkdir<'a>) -> Self {
+impl From<Mkdir> for FileAction {
+    fn from(mkdir: Mkdir) -> Self {
         Self::Mkdir(mkdir)
     }
 }
@@ -0,0 +1,50 @@
+use buildkit_rs_proto::pb::FileActionMkFile;
+use camino::Utf8PathBuf;
+
+use super::{FileAction, Owner};
+
+/// Create a file with the given contents, with mode 0644 unless set
+#[derive(Debug, Clone)]
+pub struct MkFile {
+    path: Utf8PathBuf,
+    data: Vec<u8>,
+    mode: u32,
+    owner: Option<Owner>,
+}
+
+impl MkFile {
+    pub fn new(path: impl Into<Utf8PathBuf>, data: impl Into<Vec<u8>>) -> Self {
+        Self {
+            path: path.into(),
+            data: data.into(),
+            mode: 0o644,
+            owner: None,
+        }
+    }
+
+    pub fn with_mode(mut self, mode: u32) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn with_owner(mut self, owner: Owner) -> Self {
+        self.owner = Some(owner);
+        self
+    }
+
+    pub(super) fn to_pb(&self, input: i64) -> FileActionMkFile {
+        FileActionMkFile {
+            path: self.path.to_string(),
+            mode: self.mode as i32,
+            data: self.data.clone(),
+            owner: self.owner.as_ref().map(|owner| owner.to_pb(input)),
+            timestamp: -1,
+        }
+    }
+}
+
+impl From<MkFile> for FileAction {
+    fn from(mkfile: MkFile) -> Self {
+        Self::MkFile(mkfile)
+    }
+}
@@ -1,88 +1,227 @@
 mod copy;
 mod mkdir;
+mod mkfile;
 
 use std::sync::Arc;
 
-use buildkit_rs_proto::pb::{op::Op as OpEnum, FileOp, Op};
-use copy::Copy;
-use mkdir::Mkdir;
+use buildkit_rs_proto::pb::{
+    self, file_action::Action, op::Op as OpEnum, user_opt::User as PbUser, FileOp, Op,
+};
+
+pub use copy::Copy;
+pub use mkdir::Mkdir;
+pub use mkfile::MkFile;
 
 use crate::{
+    platform::Platform,
     serialize::{
         id::OperationId,
         node::{Context, Node, Operation},
     },
-    utils::{OperationOutput, OutputIdx},
-    MultiBorrowedOutput, MultiOwnedOutput, OpMetadataBuilder,
+    utils::OutputIdx,
+    OpMetadataBuilder, Output, SingleOutput,
 };
 
-use super::metadata::OpMetadata;
+use super::metadata::{cap::CapID, OpMetadata};
 
-#[derive(Debug)]
-enum FileAction<'a> {
-    Copy(Copy<'a>),
-    Mkdir(Mkdir<'a>),
+#[derive(Debug, Clone)]
+pub enum FileAction {
+    Copy(Copy),
+    Mkdir(Mkdir),
+    MkFile(MkFile),
 }
 
-#[derive(Debug)]
-struct FileActions<'a> {
+/// A list of file actions, each applied to the result of the previous one.
+///
+/// File actions run in the daemon and do not need an image, the output is
+/// the result of the last action.
+#[derive(Debug, Clone)]
+pub struct FileActions {
     id: OperationId,
     metadata: OpMetadata,
 
-    actions: Vec<FileAction<'a>>,
+    base: Option<Output>,
+    actions: Vec<FileAction>,
+    platform: Option<Platform>,
 }
 
-impl FileActions<'_> {
-    pub fn new() -> Self {
+impl FileActions {
+    /// Apply the actions to `base`, or to an empty filesystem for `None`
+    pub fn new(base: Option<Output>) -> Self {
         Self {
             id: OperationId::new(),
             metadata: OpMetadata::new(),
+            base,
             actions: Vec::new(),
+            platform: None,
         }
     }
-}
 
-impl<'a> FileActions<'a> {
-    pub fn with_action(mut self, action: impl Into<FileAction<'a>>) -> Self {
+    pub fn with_action(mut self, action: impl Into<FileAction>) -> Self {
         self.actions.push(action.into());
         self
     }
+
+    /// Set the platform of the op, defaults to the platform of the definition
+    pub fn with_platform(mut self, platform: Platform) -> Self {
+        self.platform = Some(platform);
+        self
+    }
 }
 
-impl<'a, 'b: 'a> MultiBorrowedOutput<'b> for FileActions<'b> {
-    fn output(&'b self, index: u32) -> OperationOutput<'b> {
-        // TODO: check if the requested index available.
-        OperationOutput::borrowed(self, OutputIdx(index))
+impl SingleOutput for Arc<FileActions> {
+    fn output(&self) -> Output {
+        Output::new(self.clone(), OutputIdx(0))
     }
 }
 
-impl<'a> MultiOwnedOutput<'a> for Arc<FileActions<'a>> {
-    fn output(&self, index: u32) -> OperationOutput<'a> {
-        // TODO: check if the requested index available.
-        OperationOutput::owned(self.clone(), OutputIdx(index))
+/// The owner of the files created by an action
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Owner {
+    user: OwnerId,
+    group: Option<OwnerId>,
+}
+
+/// A user or group, names are looked up in `/etc/passwd` and `/etc/group`
+/// of the filesystem the action is applied to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OwnerId {
+    Id(u32),
+    Name(String),
+}
+
+impl Owner {
+    /// Without a group, the group id is the user id
+    pub fn new(user: OwnerId) -> Self {
+        Self { user, group: None }
+    }
+
+    pub fn with_group(mut self, group: OwnerId) -> Self {
+        self.group = Some(group);
+        self
+    }
+
+    /// Parse `user[:group]`, like `--chown` of a Dockerfile, numbers are ids
+    pub fn parse(chown: &str) -> Self {
+        match chown.split_once(':') {
+            Some((user, group)) => {
+                Self::new(OwnerId::parse(user)).with_group(OwnerId::parse(group))
+            }
+            None => Self::new(OwnerId::parse(chown)),
+        }
+    }
+
+    fn to_pb(&self, input: i64) -> pb::ChownOpt {
+        pb::ChownOpt {
+            user: Some(self.user.to_pb(input)),
+            group: self.group.as_ref().map(|group| group.to_pb(input)),
+        }
     }
 }
 
-impl Operation for FileActions<'_> {
+impl OwnerId {
+    /// A number is an id, anything else a name
+    pub fn parse(s: &str) -> Self {
+        match s.parse() {
+            Ok(id) => Self::Id(id),
+            Err(_) => Self::Name(s.into()),
+        }
+    }
+
+    fn to_pb(&self, input: i64) -> pb::UserOpt {
+        let user = match self {
+            Self::Id(id) => PbUser::ById(*id),
+            Self::Name(name) => PbUser::ByName(pb::NamedUserOpt {
+                name: name.clone(),
+                input,
+            }),
+        };
+        pb::UserOpt { user: Some(user) }
+    }
+}
+
+impl Operation for FileActions {
     fn id(&self) -> &OperationId {
         &self.id
     }
 
-    fn serialize(&self, _cx: &mut Context) -> Option<Node> {
-        let actions = vec![];
+    fn serialize(&self, ctx: &mut Context) -> Option<Node> {
+        let mut inputs: Vec<pb::Input> = vec![];
+        let mut input_index = |output: &Output, ctx: &mut Context| -> Option<i64> {
+            let input = pb::Input {
+                digest: ctx.register(output.operation())?.digest.clone(),
+                index: output.output().into(),
+            };
+            let index = match inputs.iter().position(|i| *i == input) {
+                Some(index) => index,
+                None => {
+                    inputs.push(input);
+                    inputs.len() - 1
+                }
+            };
+            Some(index as i64)
+        };
+
+        let base = match &self.base {
+            Some(base) => input_index(base, ctx)?,
+            None => -1,
+        };
+        let secondary = self
+            .actions
+            .iter()
+            .map(|action| match action {
+                FileAction::Copy(copy) => input_index(&copy.src_input, ctx),
+                _ => Some(-1),
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        // Actions after the first one are applied to the result of the
+        // previous action, which is referenced after the inputs
+        let input_count = inputs.len() as i64;
+        let last = self.actions.len().saturating_sub(1);
+        let actions = self
+            .actions
+            .iter()
+            .zip(secondary)
+            .enumerate()
+            .map(|(idx, (action, secondary_input))| {
+                let input = match idx {
+                    0 => base,
+                    idx => input_count + idx as i64 - 1,
+                };
+                pb::FileAction {
+                    input,
+                    secondary_input,
+                    output: if idx == last { 0 } else { -1 },
+                    action: Some(match action {
+                        FileAction::Copy(copy) => Action::Copy(copy.to_pb(input)),
+                        FileAction::Mkdir(mkdir) => Action::Mkdir(mkdir.to_pb(input)),
+                        FileAction::MkFile(mkfile) => Action::Mkfile(mkfile.to_pb(input)),
+                    }),
+                }
+            })
+            .collect();
+
+        let mut metadata = self.metadata.clone();
+        metadata.add_cap(CapID::FILE_BASE);
 
         Some(Node::new(
             Op {
                 op: Some(OpEnum::File(FileOp { actions })),
-
+                inputs,
+                platform: self
+                    .platform
+                    .as_ref()
+                    .or(ctx.platform())
+                    .map(Platform::to_pb),
                 ..Default::default()
             },
-            self.metadata.clone().into(),
+            metadata.into(),
         ))
     }
 }
 
-impl OpMetadataBuilder for FileActions<'_> {
+impl OpMetadataBuilder for FileActions {
     fn metadata(&self) -> &OpMetadata {
         &self.metadata
     }
@@ -91,3 +230,74 @@ impl OpMetadataBuilder for FileActions<'_> {
         &mut self.metadata
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+
+    use super::*;
+    use crate::{Definition, Image, Local};
+
+    #[test]
+    fn chained_actions() {
+        let image = Arc::new(Image::new("alpine:latest"));
+        let context = Arc::new(Local::new("context".into()));
+        let file = Arc::new(
+            FileActions::new(Some(image.output()))
+                .with_action(Mkdir::new("/app").with_make_parents(true))
+                .with_action(
+                    Copy::new(context.output(), "/src", "/app/")
+                        .with_owner(Owner::parse("app:1000"))
+                        .with_mode(0o755),
+                )
+                .with_action(MkFile::new("/app/VERSION", "1.0")),
+        );
+
+        let def = Definition::new(file.output());
+        assert_eq!(def.validate(), Ok(()));
+
+        let def = def.into_pb();
+        let op = pb::Op::decode(def.def[2].as_slice()).unwrap();
+        assert_eq!(op.inputs.len(), 2);
+        let Some(OpEnum::File(file)) = op.op else {
+            panic!("not a file op");
+        };
+
+        let io = file
+            .actions
+            .iter()
+            .map(|a| (a.input, a.secondary_input, a.output))
+            .collect::<Vec<_>>();
+        assert_eq!(io, [(0, -1, -1), (2, 1, -1), (3, -1, 0)]);
+
+        let Some(Action::Copy(copy)) = &file.actions[1].action else {
+            panic!("not a copy");
+        };
+        assert_eq!(copy.mode, 0o755);
+        assert_eq!(copy.timestamp, -1);
+        let owner = copy.owner.as_ref().unwrap();
+        assert_eq!(
+            owner.user.as_ref().unwrap().user,
+            Some(PbUser::ByName(pb::NamedUserOpt {
+                name: "app".into(),
+                input: 2,
+            }))
+        );
+        assert_eq!(owner.group.as_ref().unwrap().user, Some(PbUser::ById(1000)));
+    }
+
+    #[test]
+    fn scratch_base() {
+        let file = Arc::new(FileActions::new(None).with_action(MkFile::new("/a", "a")));
+        let def = Definition::new(file.output());
+        assert_eq!(def.validate(), Ok(()));
+
+        let op = pb::Op::decode(def.into_pb().def[0].as_slice()).unwrap();
+        assert!(op.inputs.is_empty());
+        let Some(OpEnum::File(file)) = op.op else {
+            panic!("not a file op");
+        };
+        assert_eq!(file.actions[0].input, -1);
+        assert_eq!(file.actions[0].output, 0);
+    }
+}
@@ -36,6 +36,8 @@ impl Attr {
     pub const EXCLUDE_PATTERNS: Attr = Attr::new("local.excludepatterns");
     /// `local.sharedkeyhint`
     pub const SHARED_KEY_HINT: Attr = Attr::new("local.sharedkeyhint");
+    /// `local.differ`
+    pub const LOCAL_DIFFER: Attr = Attr::new("local.differ");
 
     /// `llbbuild.filename`
     pub const LLB_DEFINITION_FILENAME: Attr = Attr::new("llbbuild.filename");
@@ -5,17 +5,24 @@ use std::collections::HashMap;
 
 use attr::Attr;
 use buildkit_rs_proto::pb;
+use cap::CapID;
 
 #[derive(Debug, Clone, Default)]
 pub struct OpMetadata {
     pub ignore_cache: bool,
     pub description: HashMap<Attr, String>,
+    pub caps: HashMap<CapID, bool>,
 }
 
 impl OpMetadata {
     pub fn new() -> Self {
         Self::default()
     }
+
+    /// Mark a capability as required by the op
+    pub fn add_cap(&mut self, cap: CapID) {
+        self.caps.insert(cap, true);
+    }
 }
 
 impl From<OpMetadata> for pb::OpMetadata {
@@ -27,7 +34,7 @@ impl From<OpMetadata> for pb::OpMetadata {
                 .into_iter()
                 .map(|(k, v)| (k.into(), v))
                 .collect(),
-            caps: HashMap::new(),
+            caps: val.caps.into_iter().map(|(k, v)| (k.into(), v)).collect(),
             export_cache: None,
             progress_group: None,
         }
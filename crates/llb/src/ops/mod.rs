@@ -1,5 +1,5 @@
 pub(crate) mod exec;
+pub(crate) mod file;
 pub(crate) mod metadata;
 pub(crate) mod output;
 pub(crate) mod source;
-mod file;
@@ -1,25 +1,154 @@
-use crate::utils::OperationOutput;
+use std::sync::Arc;
 
-pub trait MultiBorrowedOutput<'a> {
-    fn output(&'a self, number: u32) -> OperationOutput<'a>;
+use thiserror::Error;
+
+use crate::{serialize::node::Operation, utils::OutputIdx};
+
+/// A handle to one of the outputs of an operation.
+///
+/// The operation is shared behind an [`Arc`], so an output is cheap to clone,
+/// `Send + Sync` and not tied to the lifetime of the operation. This makes it
+/// possible to build a graph in a helper function, store it in a struct or
+/// pass it across threads and async tasks. Each operation is only serialized
+/// once, no matter how many outputs reference it.
+#[derive(Debug, Clone)]
+pub struct Output {
+    op: Arc<dyn Operation>,
+    index: OutputIdx,
 }
 
-pub trait MultiBorrowedLastOutput<'a> {
-    fn last_output(&'a self) -> Option<OperationOutput<'a>>;
+impl Output {
+    pub(crate) fn new(op: Arc<dyn Operation>, index: OutputIdx) -> Self {
+        Self { op, index }
+    }
+
+    pub(crate) fn operation(&self) -> &dyn Operation {
+        self.op.as_ref()
+    }
+
+    pub(crate) fn output(&self) -> OutputIdx {
+        self.index
+    }
+
+    /// The index of the output within its operation
+    pub fn index(&self) -> u32 {
+        self.index.0
+    }
 }
 
-pub trait MultiOwnedOutput<'a> {
-    fn output(&self, number: u32) -> OperationOutput<'a>;
+/// Operations with a single output, such as sources
+pub trait SingleOutput {
+    fn output(&self) -> Output;
 }
 
-pub trait MultiOwnedLastOutput<'a> {
-    fn last_output(&self) -> Option<OperationOutput<'a>>;
+/// Operations with an output for each writable mount or action
+pub trait MultiOutput {
+    /// The output at `index`, outputs are numbered in the order of the
+    /// writable mounts or actions
+    fn output(&self, index: u32) -> Result<Output, OutputError>;
 }
 
-pub trait SingleBorrowedOutput<'a> {
-    fn output(&'a self) -> OperationOutput<'a>;
+/// The error type for requesting an output an operation does not have
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum OutputError {
+    #[error("output {index} does not exist, the operation has {count} outputs")]
+    OutOfRange { index: u32, count: u32 },
+
+    #[error("no mount at {0}")]
+    NoMount(String),
+
+    #[error("the mount at {0} does not have an output")]
+    NoOutput(String),
 }
 
-pub trait SingleOwnedOutput<'a> {
-    fn output(&self) -> OperationOutput<'a>;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CacheSharingMode, Definition, Exec, Image, Mount};
+
+    fn assert_send_sync<T: Send + Sync + 'static>() {}
+
+    /// Build a graph in a helper, it can outlive the ops it was built from
+    fn build(name: &str) -> Output {
+        let image = Arc::new(Image::new(name));
+        let exec = Arc::new(Exec::shlex("ls").with_mount(Mount::layer(image.output(), "/")));
+        exec.root().unwrap()
+    }
+
+    #[test]
+    fn output_across_threads() {
+        assert_send_sync::<Output>();
+
+        let output = std::thread::spawn(|| build("alpine:latest"))
+            .join()
+            .unwrap();
+        let shared = output.clone();
+        let def = std::thread::spawn(move || Definition::new(shared).into_pb())
+            .join()
+            .unwrap();
+
+        assert_eq!(def.def.len(), 3);
+        assert_eq!(output.index(), 0);
+    }
+
+    #[test]
+    fn shared_op_is_serialized_once() {
+        let image = Arc::new(Image::new("alpine:latest"));
+        let first = Arc::new(Exec::shlex("ls").with_mount(Mount::layer(image.output(), "/")));
+        let second = Arc::new(
+            Exec::shlex("ls")
+                .with_mount(Mount::layer(first.root().unwrap(), "/"))
+                .with_mount(Mount::layer_readonly(image.output(), "/image")),
+        );
+
+        // image, first, second and the final op
+        assert_eq!(
+            Definition::new(second.root().unwrap()).into_pb().def.len(),
+            4
+        );
+    }
+
+    #[test]
+    fn outputs_follow_writable_mounts() {
+        let image = Arc::new(Image::new("alpine:latest"));
+        let exec = Arc::new(
+            Exec::shlex("make")
+                .with_mount(Mount::layer_readonly(image.output(), "/src"))
+                .with_mount(Mount::layer(image.output(), "/"))
+                .with_mount(Mount::cache("/cache", "cache", CacheSharingMode::Shared))
+                .with_mount(Mount::scratch("/out")),
+        );
+
+        assert_eq!(exec.root().unwrap().index(), 0);
+        assert_eq!(exec.mount_output("/out/").unwrap().index(), 1);
+        assert_eq!(exec.output(1).unwrap().index(), 1);
+
+        assert_eq!(
+            exec.output(2).unwrap_err(),
+            OutputError::OutOfRange { index: 2, count: 2 }
+        );
+        assert_eq!(
+            exec.mount_output("/src").unwrap_err(),
+            OutputError::NoOutput("/src".into())
+        );
+        assert_eq!(
+            exec.mount_output("/cache").unwrap_err(),
+            OutputError::NoOutput("/cache".into())
+        );
+        assert_eq!(
+            exec.mount_output("/tmp").unwrap_err(),
+            OutputError::NoMount("/tmp".into())
+        );
+
+        let readonly =
+            Arc::new(Exec::shlex("ls").with_mount(Mount::layer_readonly(image.output(), "/")));
+        assert_eq!(
+            readonly.root().unwrap_err(),
+            OutputError::NoOutput("/".into())
+        );
+        assert_eq!(
+            readonly.output(0).unwrap_err(),
+            OutputError::OutOfRange { index: 0, count: 0 }
+        );
+    }
 }
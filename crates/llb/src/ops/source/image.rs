@@ -1,18 +1,19 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, future::Future, pin::Pin, sync::Arc};
 
 use buildkit_rs_proto::pb::{self, op::Op as OpEnum, Op};
 use buildkit_rs_reference::Reference;
 
 use crate::{
     ops::{
-        metadata::{attr::Attr, OpMetadata, OpMetadataBuilder},
-        output::{SingleBorrowedOutput, SingleOwnedOutput},
+        metadata::{attr::Attr, cap::CapID, OpMetadata, OpMetadataBuilder},
+        output::{Output, SingleOutput},
     },
+    platform::Platform,
     serialize::{
         id::OperationId,
         node::{Context, Node, Operation},
     },
-    utils::{OperationOutput, OutputIdx}, platform::Platform,
+    utils::OutputIdx,
 };
 
 #[derive(Debug, Clone, Copy, Default)]
@@ -33,6 +34,116 @@ impl ResolveMode {
     }
 }
 
+/// How the image is recorded in the build history and disk usage
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RecordType {
+    #[default]
+    Regular,
+    /// Used internally by the build, e.g. a frontend helper image
+    Internal,
+    /// The image of a frontend
+    Frontend,
+}
+
+impl RecordType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RecordType::Regular => "regular",
+            RecordType::Internal => "internal",
+            RecordType::Frontend => "frontend",
+        }
+    }
+}
+
+/// The parts of an image config that are used as defaults for execs that
+/// run on top of the image
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImageConfig {
+    /// The environment variables in `KEY=VALUE` form
+    pub env: Vec<String>,
+    /// The working directory, `WORKDIR` in a Dockerfile
+    pub working_dir: Option<String>,
+    /// The user, `USER` in a Dockerfile
+    pub user: Option<String>,
+    /// The platform the image was built for
+    pub platform: Option<Platform>,
+}
+
+impl ImageConfig {
+    /// Parse the relevant fields from an OCI image config JSON document
+    pub fn from_json(bytes: &[u8]) -> Result<Self, serde_json::Error> {
+        let value: serde_json::Value = serde_json::from_slice(bytes)?;
+
+        let str_field = |v: &serde_json::Value, key: &str| {
+            v.get(key)
+                .and_then(|v| v.as_str())
+                .filter(|v| !v.is_empty())
+                .map(String::from)
+        };
+
+        let config = value.get("config").unwrap_or(&serde_json::Value::Null);
+
+        let env = config
+            .get("Env")
+            .and_then(|env| env.as_array())
+            .map(|env| {
+                env.iter()
+                    .filter_map(|v| v.as_str())
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let platform = match (str_field(&value, "os"), str_field(&value, "architecture")) {
+            (Some(os), Some(architecture)) => {
+                let mut platform = Platform::from_parts(
+                    &os,
+                    &architecture,
+                    str_field(&value, "variant").as_deref(),
+                );
+                if let Some(os_version) = str_field(&value, "os.version") {
+                    platform = platform.with_os_version(os_version);
+                }
+                if let Some(features) = value.get("os.features").and_then(|f| f.as_array()) {
+                    platform =
+                        platform.with_os_features(features.iter().filter_map(|f| f.as_str()));
+                }
+                Some(platform)
+            }
+            _ => None,
+        };
+
+        Ok(Self {
+            env,
+            working_dir: str_field(config, "WorkingDir"),
+            user: str_field(config, "User"),
+            platform,
+        })
+    }
+}
+
+/// The result of resolving an image config
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedImageConfig {
+    /// The digest of the resolved manifest
+    pub digest: String,
+    pub config: ImageConfig,
+}
+
+/// Resolves the config of an image, see [`Image::resolve`]
+///
+/// Based on the `ImageMetaResolver` interface in the Go client
+pub trait ImageMetaResolver {
+    type Error;
+
+    fn resolve_image_config<'a>(
+        &'a mut self,
+        reference: &'a Reference,
+        platform: Option<&'a Platform>,
+        resolve_mode: ResolveMode,
+    ) -> Pin<Box<dyn Future<Output = Result<ResolvedImageConfig, Self::Error>> + Send + 'a>>;
+}
+
 #[derive(Debug, Clone)]
 pub struct Image {
     id: OperationId,
@@ -41,41 +152,76 @@ pub struct Image {
 
     reference: Reference,
     resolve_mode: Option<ResolveMode>,
+    record_type: Option<RecordType>,
+    layer_limit: Option<u32>,
+    config: Option<ImageConfig>,
 }
 
 impl Image {
+    /// Create an image from a familiar name such as `alpine:latest`
+    ///
+    /// # Panics
+    ///
+    /// Panics if `name` is not a valid reference, see [`Image::try_new`]
     pub fn new(name: impl AsRef<str>) -> Self {
-        let reference = Reference::parse_normalized_named(name.as_ref()).unwrap();
+        Self::try_new(name).unwrap()
+    }
 
-        Self {
-            id: OperationId::new(),
-            metadata: OpMetadata::new(),
-            platform: None,
-            reference,
-            resolve_mode: None,
-        }
+    pub fn try_new(name: impl AsRef<str>) -> Result<Self, buildkit_rs_reference::Error> {
+        Reference::parse_normalized_named(name.as_ref()).map(Self::from_reference)
     }
 
+    /// Create an image from a reference that is not normalized
+    ///
+    /// # Panics
+    ///
+    /// Panics if `name` is not a valid reference, see [`Image::try_local`]
     pub fn local(name: impl AsRef<str>) -> Self {
-        Self {
-            id: OperationId::new(),
-            metadata: OpMetadata::new(),
-            platform: None,
-            reference: Reference::parse(name.as_ref()).unwrap(),
-            resolve_mode: None,
-        }
+        Self::try_local(name).unwrap()
+    }
+
+    pub fn try_local(name: impl AsRef<str>) -> Result<Self, buildkit_rs_reference::Error> {
+        Reference::parse(name.as_ref()).map(Self::from_reference)
     }
 
-    pub fn reference(reference: Reference) -> Self {
+    pub fn from_reference(reference: Reference) -> Self {
         Self {
             id: OperationId::new(),
             metadata: OpMetadata::new(),
             platform: None,
             reference,
             resolve_mode: None,
+            record_type: None,
+            layer_limit: None,
+            config: None,
         }
     }
 
+    /// The reference of the image, including the digest if it is pinned
+    pub fn reference(&self) -> &Reference {
+        &self.reference
+    }
+
+    /// Pin the image to `digest`, this fails if it is not a valid digest
+    pub fn with_digest(
+        mut self,
+        digest: impl Into<String>,
+    ) -> Result<Self, buildkit_rs_reference::Error> {
+        self.reference = self.reference.with_digest(digest)?;
+        Ok(self)
+    }
+
+    pub fn with_record_type(mut self, record_type: RecordType) -> Self {
+        self.record_type = Some(record_type);
+        self
+    }
+
+    /// Only use the first `limit` layers of the image
+    pub fn with_layer_limit(mut self, limit: u32) -> Self {
+        self.layer_limit = Some(limit);
+        self
+    }
+
     pub fn with_resolve_mode(mut self, mode: ResolveMode) -> Self {
         self.resolve_mode = Some(mode);
         self
@@ -85,6 +231,55 @@ impl Image {
         self.platform = Some(platform);
         self
     }
+
+    /// Set the image config used as defaults for execs that use this image
+    /// as their root mount
+    pub fn with_config(mut self, config: ImageConfig) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    pub fn config(&self) -> Option<&ImageConfig> {
+        self.config.as_ref()
+    }
+
+    /// Resolve the image config with `resolver`, so its env, working
+    /// directory, user and platform are used as defaults by execs
+    pub async fn resolve<R: ImageMetaResolver>(
+        mut self,
+        resolver: &mut R,
+    ) -> Result<Self, R::Error> {
+        let resolved = resolver
+            .resolve_image_config(
+                &self.reference,
+                self.platform.as_ref(),
+                self.resolve_mode.unwrap_or_default(),
+            )
+            .await?;
+
+        self.config = Some(resolved.config);
+        Ok(self)
+    }
+
+    /// Resolve the image with `resolver` and pin it to the digest of the
+    /// resolved manifest, so a floating tag always produces the same image
+    pub async fn pin<R>(mut self, resolver: &mut R) -> Result<Self, R::Error>
+    where
+        R: ImageMetaResolver,
+        R::Error: From<buildkit_rs_reference::Error>,
+    {
+        let resolved = resolver
+            .resolve_image_config(
+                &self.reference,
+                self.platform.as_ref(),
+                self.resolve_mode.unwrap_or_default(),
+            )
+            .await?;
+
+        self.reference = self.reference.with_digest(resolved.digest)?;
+        self.config = Some(resolved.config);
+        Ok(self)
+    }
 }
 
 impl Operation for Image {
@@ -92,11 +287,35 @@ impl Operation for Image {
         &self.id
     }
 
-    fn serialize(&self, _: &mut Context) -> Option<Node> {
+    fn image_config(&self, _index: OutputIdx) -> Option<&ImageConfig> {
+        self.config.as_ref()
+    }
+
+    fn serialize(&self, ctx: &mut Context) -> Option<Node> {
         let mut attrs = HashMap::default();
+        let mut metadata = self.metadata.clone();
+        metadata.add_cap(CapID::SOURCE_IMAGE);
+
+        // Like the Go client, only forcing a pull needs the cap as older
+        // daemons ignore the attribute otherwise
+        match self.resolve_mode.unwrap_or_default() {
+            ResolveMode::Default => {}
+            mode @ ResolveMode::Pull => {
+                attrs.insert(Attr::IMAGE_RESOLVE_MODE.into(), mode.as_str().into());
+                metadata.add_cap(CapID::SOURCE_IMAGE_RESOLVE_MODE);
+            }
+            mode @ ResolveMode::Local => {
+                attrs.insert(Attr::IMAGE_RESOLVE_MODE.into(), mode.as_str().into());
+            }
+        }
+
+        if let Some(record_type) = self.record_type {
+            attrs.insert(Attr::IMAGE_RECORD_TYPE.into(), record_type.as_str().into());
+        }
 
-        if let Some(ref mode) = self.resolve_mode {
-            attrs.insert(Attr::IMAGE_RESOLVE_MODE.into(), mode.as_str().into());
+        if let Some(limit) = self.layer_limit {
+            attrs.insert(Attr::IMAGE_LAYER_LIMIT.into(), limit.to_string());
+            metadata.add_cap(CapID::SOURCE_IMAGE_LAYER_LIMIT);
         }
 
         Some(Node::new(
@@ -107,11 +326,11 @@ impl Operation for Image {
                     attrs,
                 })),
 
-                platform: self.platform.as_ref().map(|p| p.to_pb()),
+                platform: self.platform.as_ref().or(ctx.platform()).map(|p| p.to_pb()),
 
                 ..Default::default()
             },
-            self.metadata.clone().into(),
+            metadata.into(),
         ))
     }
 }
@@ -126,14 +345,138 @@ impl OpMetadataBuilder for Image {
     }
 }
 
-impl<'a> SingleBorrowedOutput<'a> for Image {
-    fn output(&'a self) -> OperationOutput<'a> {
-        OperationOutput::borrowed(self, OutputIdx(0))
+impl SingleOutput for Arc<Image> {
+    fn output(&self) -> Output {
+        Output::new(self.clone(), OutputIdx(0))
     }
 }
 
-impl SingleOwnedOutput<'static> for Arc<Image> {
-    fn output(&self) -> OperationOutput<'static> {
-        OperationOutput::owned(self.clone(), OutputIdx(0))
+#[cfg(test)]
+mod tests {
+    use buildkit_rs_util::test_util::block_on;
+
+    use super::*;
+    use crate::check_op;
+
+    const DIGEST: &str = "sha256:86e0e091d0da6bde2456dbb48306f3956bbeb2eae1b5b9a43045843f69fe4aaa";
+
+    struct StaticResolver;
+
+    impl ImageMetaResolver for StaticResolver {
+        type Error = buildkit_rs_reference::Error;
+
+        fn resolve_image_config<'a>(
+            &'a mut self,
+            _reference: &'a Reference,
+            _platform: Option<&'a Platform>,
+            _resolve_mode: ResolveMode,
+        ) -> Pin<Box<dyn Future<Output = Result<ResolvedImageConfig, Self::Error>> + Send + 'a>>
+        {
+            Box::pin(async {
+                Ok(ResolvedImageConfig {
+                    digest: DIGEST.into(),
+                    config: ImageConfig::default(),
+                })
+            })
+        }
+    }
+
+    #[test]
+    fn invalid_reference() {
+        assert!(Image::try_new("alpine:latest").is_ok());
+        assert!(Image::try_new("Alpine").is_err());
+        assert!(Image::try_local("").is_err());
+        assert!(Image::new("alpine").with_digest("latest").is_err());
+    }
+
+    #[test]
+    fn image_attrs() {
+        let image = Image::new("alpine:latest")
+            .with_resolve_mode(ResolveMode::Pull)
+            .with_record_type(RecordType::Internal)
+            .with_layer_limit(2);
+
+        check_op!(
+            image,
+            |op| OpEnum::Source(pb::SourceOp {
+                identifier: "docker-image://docker.io/library/alpine:latest".into(),
+                attrs: crate::utils::test::to_map(vec![
+                    ("image.resolvemode", "pull"),
+                    ("image.recordtype", "internal"),
+                    ("image.layerlimit", "2"),
+                ]),
+            }),
+            |caps| vec![
+                "source.image",
+                "source.image.layerlimit",
+                "source.image.resolvemode"
+            ],
+        );
+    }
+
+    #[test]
+    fn resolve_mode_cap() {
+        check_op!(
+            Image::new("alpine:latest").with_resolve_mode(ResolveMode::Local),
+            |op| OpEnum::Source(pb::SourceOp {
+                identifier: "docker-image://docker.io/library/alpine:latest".into(),
+                attrs: crate::utils::test::to_map(vec![("image.resolvemode", "local")]),
+            }),
+            |caps| vec!["source.image"],
+        );
+
+        check_op!(
+            Image::new("alpine:latest").with_resolve_mode(ResolveMode::Default),
+            |op| OpEnum::Source(pb::SourceOp {
+                identifier: "docker-image://docker.io/library/alpine:latest".into(),
+                attrs: Default::default(),
+            }),
+            |caps| vec!["source.image"],
+        );
+    }
+
+    #[test]
+    fn pin_image() {
+        let image = Image::new("alpine:latest");
+        let pinned = block_on(image.pin(&mut StaticResolver)).unwrap();
+
+        assert_eq!(
+            pinned.reference().to_string(),
+            format!("docker.io/library/alpine:latest@{DIGEST}")
+        );
+        assert_eq!(pinned.config(), Some(&ImageConfig::default()));
+    }
+
+    #[test]
+    fn image_config_from_json() {
+        let json = br#"{
+            "architecture": "arm64",
+            "os": "linux",
+            "variant": "v8",
+            "config": {
+                "Env": ["PATH=/usr/local/go/bin:/usr/bin", "GOLANG_VERSION=1.20"],
+                "WorkingDir": "/go",
+                "User": ""
+            },
+            "rootfs": {"type": "layers", "diff_ids": []}
+        }"#;
+
+        assert_eq!(
+            ImageConfig::from_json(json).unwrap(),
+            ImageConfig {
+                env: vec![
+                    "PATH=/usr/local/go/bin:/usr/bin".into(),
+                    "GOLANG_VERSION=1.20".into()
+                ],
+                working_dir: Some("/go".into()),
+                user: None,
+                platform: Some(Platform::LINUX_ARM64),
+            }
+        );
+
+        assert_eq!(
+            ImageConfig::from_json(b"{}").unwrap(),
+            ImageConfig::default()
+        );
     }
 }
@@ -4,16 +4,35 @@ use buildkit_rs_proto::pb::{self, op::Op as OpEnum, Op};
 
 use crate::{
     ops::{
-        metadata::{attr::Attr, OpMetadata, OpMetadataBuilder},
-        output::{SingleBorrowedOutput, SingleOwnedOutput},
+        metadata::{attr::Attr, cap::CapID, OpMetadata, OpMetadataBuilder},
+        output::{Output, SingleOutput},
     },
     serialize::{
         id::OperationId,
         node::{Context, Node, Operation},
     },
-    utils::{OperationOutput, OutputIdx},
+    utils::OutputIdx,
 };
 
+/// How buildkit decides which files changed since the last transfer of a
+/// local source
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LocalDiffer {
+    /// Transfer every file
+    None,
+    /// Compare file metadata, such as the size and modification time
+    Metadata,
+}
+
+impl LocalDiffer {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LocalDiffer::None => "none",
+            LocalDiffer::Metadata => "metadata",
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Local {
     id: OperationId,
@@ -23,6 +42,12 @@ pub struct Local {
 
     exclude: Vec<String>,
     include: Vec<String>,
+    follow_paths: Vec<String>,
+
+    session_id: Option<String>,
+    unique_id: Option<String>,
+    shared_key_hint: Option<String>,
+    differ: Option<(LocalDiffer, bool)>,
 }
 
 impl Local {
@@ -33,6 +58,11 @@ impl Local {
             name,
             exclude: Vec::new(),
             include: Vec::new(),
+            follow_paths: Vec::new(),
+            session_id: None,
+            unique_id: None,
+            shared_key_hint: None,
+            differ: None,
         }
     }
 
@@ -68,6 +98,53 @@ impl Local {
         self.exclude.push(exclude.as_ref().into());
         self
     }
+
+    /// Paths that are always transferred, along with the targets of any
+    /// symlinks on the way to them
+    pub fn with_follow_paths<I, S>(mut self, follow_paths: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.follow_paths = follow_paths
+            .into_iter()
+            .map(|s| s.as_ref().into())
+            .collect();
+        self
+    }
+
+    pub fn with_follow_path(mut self, follow_path: impl AsRef<str>) -> Self {
+        self.follow_paths.push(follow_path.as_ref().into());
+        self
+    }
+
+    /// Only read the files from the session with this ID, instead of any
+    /// session attached to the build
+    pub fn with_session_id(mut self, session_id: impl Into<String>) -> Self {
+        self.session_id = Some(session_id.into());
+        self
+    }
+
+    /// Make the source unique, so it is never deduplicated with an identical
+    /// local source from another build
+    pub fn with_unique_id(mut self, unique_id: impl Into<String>) -> Self {
+        self.unique_id = Some(unique_id.into());
+        self
+    }
+
+    /// A key used to find the files of a previous transfer, so only the
+    /// changes have to be sent across runs
+    pub fn with_shared_key_hint(mut self, shared_key_hint: impl Into<String>) -> Self {
+        self.shared_key_hint = Some(shared_key_hint.into());
+        self
+    }
+
+    /// Set how changed files are detected, if `required` is set the build
+    /// fails on daemons that don't support choosing the differ
+    pub fn with_differ(mut self, differ: LocalDiffer, required: bool) -> Self {
+        self.differ = Some((differ, required));
+        self
+    }
 }
 
 impl Operation for Local {
@@ -75,14 +152,19 @@ impl Operation for Local {
         &self.id
     }
 
-    fn serialize(&self, _: &mut Context) -> Option<Node> {
+    fn serialize(&self, ctx: &mut Context) -> Option<Node> {
         let mut attrs = HashMap::default();
+        let mut metadata = self.metadata.clone();
+        metadata.add_cap(CapID::SOURCE_LOCAL);
 
-        if !self.exclude.is_empty() {
-            attrs.insert(
-                Attr::EXCLUDE_PATTERNS.into(),
-                serde_json::to_string(&self.exclude).unwrap(),
-            );
+        if let Some(session_id) = &self.session_id {
+            attrs.insert(Attr::LOCAL_SESSION_ID.into(), session_id.clone());
+            metadata.add_cap(CapID::SOURCE_LOCAL_SESSION_ID);
+        }
+
+        if let Some(unique_id) = &self.unique_id {
+            attrs.insert(Attr::LOCAL_UNIQUE_ID.into(), unique_id.clone());
+            metadata.add_cap(CapID::SOURCE_LOCAL_UNIQUE);
         }
 
         if !self.include.is_empty() {
@@ -90,6 +172,35 @@ impl Operation for Local {
                 Attr::INCLUDE_PATTERNS.into(),
                 serde_json::to_string(&self.include).unwrap(),
             );
+            metadata.add_cap(CapID::SOURCE_LOCAL_INCLUDE_PATTERNS);
+        }
+
+        if !self.follow_paths.is_empty() {
+            attrs.insert(
+                Attr::FOLLOW_PATHS.into(),
+                serde_json::to_string(&self.follow_paths).unwrap(),
+            );
+            metadata.add_cap(CapID::SOURCE_LOCAL_FOLLOW_PATHS);
+        }
+
+        if !self.exclude.is_empty() {
+            attrs.insert(
+                Attr::EXCLUDE_PATTERNS.into(),
+                serde_json::to_string(&self.exclude).unwrap(),
+            );
+            metadata.add_cap(CapID::SOURCE_LOCAL_EXCLUDE_PATTERNS);
+        }
+
+        if let Some(shared_key_hint) = &self.shared_key_hint {
+            attrs.insert(Attr::SHARED_KEY_HINT.into(), shared_key_hint.clone());
+            metadata.add_cap(CapID::SOURCE_LOCAL_SHARED_KEY_HINT);
+        }
+
+        if let Some((differ, required)) = self.differ {
+            attrs.insert(Attr::LOCAL_DIFFER.into(), differ.as_str().into());
+            if required {
+                metadata.add_cap(CapID::SOURCE_LOCAL_DIFFER);
+            }
         }
 
         Some(Node::new(
@@ -99,9 +210,11 @@ impl Operation for Local {
                     attrs,
                 })),
 
+                platform: ctx.platform().map(|p| p.to_pb()),
+
                 ..Default::default()
             },
-            self.metadata.clone().into(),
+            metadata.into(),
         ))
     }
 }
@@ -116,14 +229,61 @@ impl OpMetadataBuilder for Local {
     }
 }
 
-impl<'a> SingleBorrowedOutput<'a> for Local {
-    fn output(&'a self) -> OperationOutput<'a> {
-        OperationOutput::borrowed(self, OutputIdx(0))
+impl SingleOutput for Arc<Local> {
+    fn output(&self) -> Output {
+        Output::new(self.clone(), OutputIdx(0))
     }
 }
 
-impl SingleOwnedOutput<'static> for Arc<Local> {
-    fn output(&self) -> OperationOutput<'static> {
-        OperationOutput::owned(self.clone(), OutputIdx(0))
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::check_op;
+
+    #[test]
+    fn local_attrs() {
+        let local = Local::new("context".into())
+            .with_session_id("session")
+            .with_unique_id("unique")
+            .with_include("src/**")
+            .with_follow_path("Dockerfile")
+            .with_exclude("target")
+            .with_shared_key_hint("context")
+            .with_differ(LocalDiffer::Metadata, true);
+
+        check_op!(
+            local,
+            |op| OpEnum::Source(pb::SourceOp {
+                identifier: "local://context".into(),
+                attrs: crate::utils::test::to_map(vec![
+                    ("local.session", "session"),
+                    ("local.unique", "unique"),
+                    ("local.includepattern", r#"["src/**"]"#),
+                    ("local.followpaths", r#"["Dockerfile"]"#),
+                    ("local.excludepatterns", r#"["target"]"#),
+                    ("local.sharedkeyhint", "context"),
+                    ("local.differ", "metadata"),
+                ]),
+            }),
+            |caps| vec![
+                "source.local",
+                "source.local.differ",
+                "source.local.excludepatterns",
+                "source.local.followpaths",
+                "source.local.includepatterns",
+                "source.local.sessionid",
+                "source.local.sharedkeyhint",
+                "source.local.unique",
+            ],
+        );
+    }
+
+    #[test]
+    fn optional_differ() {
+        check_op!(
+            Local::new("context".into()).with_differ(LocalDiffer::None, false),
+            |caps| vec!["source.local"],
+        );
     }
 }
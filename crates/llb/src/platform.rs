@@ -1,5 +1,30 @@
 use buildkit_rs_proto::pb;
+use buildkit_rs_util::system::OsFamily;
 use std::{borrow::Cow, fmt};
+use thiserror::Error;
+
+/// The error type for parsing a platform specifier
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum PlatformError {
+    /// The specifier is empty
+    #[error("invalid platform specifier: empty")]
+    Empty,
+
+    /// A component of the specifier contains invalid characters
+    #[error("invalid platform specifier {specifier:?}: invalid component {component:?}")]
+    InvalidComponent {
+        specifier: String,
+        component: String,
+    },
+
+    /// A single component specifier is neither a known OS nor architecture
+    #[error("invalid platform specifier {0:?}: unknown operating system or architecture")]
+    UnknownOsOrArch(String),
+
+    /// The specifier has more than three components
+    #[error("invalid platform specifier {0:?}: too many components")]
+    TooManyComponents(String),
+}
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Platform {
@@ -9,6 +34,105 @@ pub struct Platform {
     pub os: Cow<'static, str>,
     /// The variant of the architecture
     pub variant: Option<Cow<'static, str>>,
+    /// The version of the operating system, mostly used for windows
+    pub os_version: Option<Cow<'static, str>>,
+    /// The features of the operating system required by an image
+    pub os_features: Vec<String>,
+}
+
+const KNOWN_OS: &[&str] = &[
+    "aix",
+    "android",
+    "darwin",
+    "dragonfly",
+    "freebsd",
+    "hurd",
+    "illumos",
+    "ios",
+    "js",
+    "linux",
+    "nacl",
+    "netbsd",
+    "openbsd",
+    "plan9",
+    "solaris",
+    "windows",
+    "zos",
+];
+
+const KNOWN_ARCH: &[&str] = &[
+    "386",
+    "amd64",
+    "amd64p32",
+    "arm",
+    "armbe",
+    "arm64",
+    "arm64be",
+    "ppc64",
+    "ppc64le",
+    "loong64",
+    "mips",
+    "mipsle",
+    "mips64",
+    "mips64le",
+    "mips64p32",
+    "mips64p32le",
+    "ppc",
+    "riscv",
+    "riscv64",
+    "s390",
+    "s390x",
+    "sparc",
+    "sparc64",
+    "wasm",
+];
+
+fn normalize_os(os: &str) -> String {
+    let os = os.to_ascii_lowercase();
+    match os.as_str() {
+        "macos" => "darwin".into(),
+        _ => os,
+    }
+}
+
+/// Normalize an architecture and variant to the canonical OCI values, based
+/// on `normalizeArch` from containerd's `platforms` package
+fn normalize_arch(arch: &str, variant: Option<&str>) -> (String, Option<String>) {
+    let arch = arch.to_ascii_lowercase();
+    let variant = variant
+        .map(str::to_ascii_lowercase)
+        .filter(|v| !v.is_empty());
+
+    match (arch.as_str(), variant.as_deref()) {
+        ("i386" | "x86", _) => ("386".into(), None),
+        ("x86_64" | "x86-64" | "amd64", Some("v1") | None) => ("amd64".into(), None),
+        ("x86_64" | "x86-64" | "amd64", _) => ("amd64".into(), variant),
+        ("aarch64" | "arm64", Some("8" | "v8") | None) => ("arm64".into(), None),
+        ("aarch64" | "arm64", _) => ("arm64".into(), variant),
+        ("powerpc64", _) => ("ppc64".into(), variant),
+        ("powerpc64le", _) => ("ppc64le".into(), variant),
+        ("loongarch64", _) => ("loong64".into(), variant),
+        ("armhf", _) => ("arm".into(), Some("v7".into())),
+        ("armel", _) => ("arm".into(), Some("v6".into())),
+        ("arm", Some("7") | None) => ("arm".into(), Some("v7".into())),
+        ("arm", Some(v @ ("5" | "6" | "8"))) => ("arm".into(), Some(format!("v{v}"))),
+        _ => (arch, variant),
+    }
+}
+
+fn is_known_os(os: &str) -> bool {
+    KNOWN_OS.contains(&os)
+}
+
+fn is_known_arch(arch: &str) -> bool {
+    KNOWN_ARCH.contains(&arch)
+}
+
+fn is_valid_component(component: &str) -> bool {
+    !component.is_empty()
+        && component
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
 }
 
 impl Platform {
@@ -22,6 +146,8 @@ impl Platform {
                 Some(variant) => Some(Cow::Borrowed(variant)),
                 None => None,
             },
+            os_version: None,
+            os_features: Vec::new(),
         }
     }
 
@@ -36,7 +162,69 @@ impl Platform {
     pub const DARWIN: Platform = Platform::new("darwin", "amd64", None);
     pub const WINDOWS: Platform = Platform::new("windows", "amd64", None);
 
-    pub(crate) fn to_pb(&self) -> pb::Platform {
+    /// The platform of the machine this code is running on
+    pub fn host() -> Self {
+        let variant = cfg!(target_arch = "arm").then_some("v7");
+        // Rust has one name for both endiannesses of 64-bit PowerPC
+        let arch = match std::env::consts::ARCH {
+            "powerpc64" if cfg!(target_endian = "little") => "powerpc64le",
+            arch => arch,
+        };
+        Self::from_parts(std::env::consts::OS, arch, variant)
+    }
+
+    /// Create a normalized platform from its parts
+    pub fn from_parts(os: &str, arch: &str, variant: Option<&str>) -> Self {
+        let (architecture, variant) = normalize_arch(arch, variant);
+
+        Self {
+            architecture: architecture.into(),
+            os: normalize_os(os).into(),
+            variant: variant.map(Into::into),
+            os_version: None,
+            os_features: Vec::new(),
+        }
+    }
+
+    pub fn with_os_version(mut self, os_version: impl Into<String>) -> Self {
+        self.os_version = Some(os_version.into().into());
+        self
+    }
+
+    pub fn with_os_features<I, S>(mut self, features: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.os_features = features.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Normalize the OS and architecture aliases, such as `x86_64` to `amd64`
+    /// and `armhf` to `arm/v7`
+    pub fn normalize(&self) -> Self {
+        Self {
+            os_version: self.os_version.clone(),
+            os_features: self.os_features.clone(),
+            ..Self::from_parts(&self.os, &self.architecture, self.variant.as_deref())
+        }
+    }
+
+    /// The OS family, which decides defaults such as the `PATH`
+    pub fn os_family(&self) -> OsFamily {
+        match self.os.as_ref() {
+            "windows" => OsFamily::Windows,
+            _ => OsFamily::Unix,
+        }
+    }
+
+    /// Create a matcher that only matches this platform and the platforms
+    /// it can run
+    pub fn matcher(&self) -> PlatformMatcher {
+        PlatformMatcher::new(self)
+    }
+
+    pub fn to_pb(&self) -> pb::Platform {
         pb::Platform {
             architecture: self.architecture.clone().into_owned(),
             os: self.os.clone().into_owned(),
@@ -45,7 +233,12 @@ impl Platform {
                 .as_ref()
                 .map(|v| v.clone().into_owned())
                 .unwrap_or_default(),
-            ..Default::default()
+            os_version: self
+                .os_version
+                .as_ref()
+                .map(|v| v.clone().into_owned())
+                .unwrap_or_default(),
+            os_features: self.os_features.clone(),
         }
     }
 }
@@ -56,32 +249,193 @@ impl fmt::Display for Platform {
             architecture,
             os,
             variant,
+            os_version,
+            ..
         } = self;
 
-        write!(f, "{os}/{architecture}")?;
+        write!(f, "{os}")?;
+        if let Some(os_version) = os_version {
+            write!(f, "({os_version})")?;
+        }
+        write!(f, "/{architecture}")?;
         if let Some(variant) = variant {
-            write!(f, "-{variant}")?;
+            write!(f, "/{variant}")?;
         }
         Ok(())
     }
 }
 
 impl std::str::FromStr for Platform {
-    type Err = String;
+    type Err = PlatformError;
 
+    /// Parse a platform specifier in the `os[(version)]/arch/variant` format,
+    /// based on `Parse` from containerd's `platforms` package.
+    ///
+    /// A single component is interpreted as either an OS or architecture, the
+    /// other part is filled in from the host platform.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut parts = s.splitn(2, '/');
-        let os = parts.next().unwrap();
-        let mut parts = parts.next().unwrap().splitn(2, '-');
-        let architecture = parts.next().unwrap();
-        let variant = parts.next();
-
-        Ok(Self {
-            architecture: Cow::Owned(architecture.to_owned()),
-            os: Cow::Owned(os.to_owned()),
-            variant: variant.map(|v| Cow::Owned(v.to_owned())),
+        if s.is_empty() {
+            return Err(PlatformError::Empty);
+        }
+
+        let (os_part, rest) = match s.split_once('/') {
+            Some((os, rest)) => (os, Some(rest)),
+            None => (s, None),
+        };
+
+        let (os_part, os_version) = match os_part.split_once('(') {
+            Some((os, version)) => match version.strip_suffix(')') {
+                Some(version) if !version.contains(['(', ')']) => (os, Some(version)),
+                _ => {
+                    return Err(PlatformError::InvalidComponent {
+                        specifier: s.into(),
+                        component: os_part.into(),
+                    })
+                }
+            },
+            None => (os_part, None),
+        };
+
+        let mut parts = vec![os_part];
+        parts.extend(rest.into_iter().flat_map(|rest| rest.split('/')));
+
+        if let Some(component) = parts.iter().find(|part| !is_valid_component(part)) {
+            return Err(PlatformError::InvalidComponent {
+                specifier: s.into(),
+                component: (*component).into(),
+            });
+        }
+
+        let mut platform = match parts[..] {
+            [os_or_arch] => {
+                let host = Platform::host();
+                if is_known_os(&normalize_os(os_or_arch)) {
+                    Platform::from_parts(os_or_arch, &host.architecture, host.variant.as_deref())
+                } else if is_known_arch(&normalize_arch(os_or_arch, None).0) {
+                    Platform::from_parts(&host.os, os_or_arch, None)
+                } else {
+                    return Err(PlatformError::UnknownOsOrArch(s.into()));
+                }
+            }
+            [os, arch] => Platform::from_parts(os, arch, None),
+            [os, arch, variant] => Platform::from_parts(os, arch, Some(variant)),
+            _ => return Err(PlatformError::TooManyComponents(s.into())),
+        };
+
+        platform.os_version = os_version.map(|v| v.to_owned().into());
+        Ok(platform)
+    }
+}
+
+/// Matches platforms that can run on a given platform, based on the `Only`
+/// matcher from containerd's `platforms` package.
+///
+/// For example `linux/arm64` can also run `linux/arm/v7` images and
+/// `linux/amd64` can also run `linux/386` images.
+#[derive(Debug, Clone)]
+pub struct PlatformMatcher {
+    /// The compatible platforms, ordered from most to least preferred
+    vector: Vec<Platform>,
+}
+
+impl PlatformMatcher {
+    pub fn new(platform: &Platform) -> Self {
+        let platform = platform.normalize();
+        let mut vector = vec![];
+        Self::push_vector(&mut vector, platform);
+        Self { vector }
+    }
+
+    fn push_vector(vector: &mut Vec<Platform>, platform: Platform) {
+        let variant_version = platform
+            .variant
+            .as_deref()
+            .and_then(|v| v.strip_prefix('v'))
+            .and_then(|v| v.parse::<u32>().ok());
+
+        let with_arch = |arch: &str, variant: Option<String>| Platform {
+            architecture: arch.to_owned().into(),
+            variant: variant.map(Into::into),
+            ..platform.clone()
+        };
+
+        let mut compatible = vec![];
+        match platform.architecture.as_ref() {
+            "amd64" => {
+                if let Some(version) = variant_version {
+                    compatible.extend(
+                        (2..version)
+                            .rev()
+                            .map(|v| with_arch("amd64", Some(format!("v{v}")))),
+                    );
+                    compatible.push(with_arch("amd64", None));
+                }
+                compatible.push(with_arch("386", None));
+            }
+            "arm" => {
+                if let Some(version) = variant_version {
+                    compatible.extend(
+                        (5..version)
+                            .rev()
+                            .map(|v| with_arch("arm", Some(format!("v{v}")))),
+                    );
+                }
+            }
+            "arm64" => {
+                let variant = platform.variant.as_deref().unwrap_or("v8").to_owned();
+                vector.push(platform.clone());
+                Self::push_vector(vector, with_arch("arm", Some(variant)));
+                return;
+            }
+            _ => {}
+        }
+
+        vector.push(platform);
+        vector.extend(compatible);
+    }
+
+    /// The preference of `platform`, lower is better, or `None` if it does
+    /// not match
+    pub fn rank(&self, platform: &Platform) -> Option<usize> {
+        let platform = platform.normalize();
+
+        self.vector.iter().position(|candidate| {
+            candidate.os == platform.os
+                && candidate.architecture == platform.architecture
+                && candidate.variant == platform.variant
+                && match (&candidate.os_version, &platform.os_version) {
+                    (Some(wanted), Some(version)) => {
+                        os_version_prefix(wanted) == os_version_prefix(version)
+                    }
+                    _ => true,
+                }
         })
     }
+
+    pub fn matches(&self, platform: &Platform) -> bool {
+        self.rank(platform).is_some()
+    }
+
+    /// Pick the best matching platform, for example from the entries of a
+    /// manifest list
+    pub fn best_match<'a, I>(&self, platforms: I) -> Option<&'a Platform>
+    where
+        I: IntoIterator<Item = &'a Platform>,
+    {
+        platforms
+            .into_iter()
+            .filter_map(|platform| self.rank(platform).map(|rank| (rank, platform)))
+            .min_by_key(|(rank, _)| *rank)
+            .map(|(_, platform)| platform)
+    }
+}
+
+/// The `major.minor.build` prefix of a windows OS version
+fn os_version_prefix(version: &str) -> &str {
+    match version.match_indices('.').nth(2) {
+        Some((idx, _)) => &version[..idx],
+        None => version,
+    }
 }
 
 #[cfg(test)]
@@ -91,9 +445,9 @@ mod tests {
     #[test]
     fn platform_display() {
         assert_eq!(Platform::LINUX_AMD64.to_string(), "linux/amd64");
-        assert_eq!(Platform::LINUX_ARMHF.to_string(), "linux/arm-v7");
-        assert_eq!(Platform::LINUX_ARM.to_string(), "linux/arm-v7");
-        assert_eq!(Platform::LINUX_ARMEL.to_string(), "linux/arm-v6");
+        assert_eq!(Platform::LINUX_ARMHF.to_string(), "linux/arm/v7");
+        assert_eq!(Platform::LINUX_ARM.to_string(), "linux/arm/v7");
+        assert_eq!(Platform::LINUX_ARMEL.to_string(), "linux/arm/v6");
         assert_eq!(Platform::LINUX_ARM64.to_string(), "linux/arm64");
         assert_eq!(Platform::LINUX_S390X.to_string(), "linux/s390x");
         assert_eq!(Platform::LINUX_PPC64.to_string(), "linux/ppc64");
@@ -101,4 +455,133 @@ mod tests {
         assert_eq!(Platform::DARWIN.to_string(), "darwin/amd64");
         assert_eq!(Platform::WINDOWS.to_string(), "windows/amd64");
     }
+
+    #[test]
+    fn platform_parse() {
+        let parse = |s: &str| s.parse::<Platform>();
+
+        assert_eq!(parse("linux/amd64"), Ok(Platform::LINUX_AMD64));
+        assert_eq!(parse("linux/x86_64"), Ok(Platform::LINUX_AMD64));
+        assert_eq!(parse("Linux/AMD64"), Ok(Platform::LINUX_AMD64));
+        assert_eq!(parse("linux/aarch64"), Ok(Platform::LINUX_ARM64));
+        assert_eq!(parse("linux/arm64/v8"), Ok(Platform::LINUX_ARM64));
+        assert_eq!(parse("linux/armhf"), Ok(Platform::LINUX_ARMHF));
+        assert_eq!(parse("linux/arm"), Ok(Platform::LINUX_ARM));
+        assert_eq!(parse("linux/arm/6"), Ok(Platform::LINUX_ARMEL));
+        assert_eq!(parse("linux/armel"), Ok(Platform::LINUX_ARMEL));
+        assert_eq!(parse("linux/x86"), Ok(Platform::new("linux", "386", None)));
+        assert_eq!(parse("linux/powerpc64"), Ok(Platform::LINUX_PPC64));
+        assert_eq!(parse("linux/powerpc64le"), Ok(Platform::LINUX_PPC64LE));
+        assert_eq!(
+            parse("linux/loongarch64"),
+            Ok(Platform::new("linux", "loong64", None))
+        );
+        assert_eq!(
+            parse("macos/arm64"),
+            Ok(Platform::new("darwin", "arm64", None))
+        );
+        assert_eq!(
+            parse("windows(10.0.17763)/amd64"),
+            Ok(Platform::WINDOWS.with_os_version("10.0.17763"))
+        );
+        assert_eq!(
+            parse("linux").map(|p| p.architecture),
+            Ok(Platform::host().architecture)
+        );
+        assert_eq!(parse("arm64").map(|p| p.os), Ok(Platform::host().os));
+    }
+
+    #[test]
+    fn platform_parse_errors() {
+        let parse = |s: &str| s.parse::<Platform>();
+
+        assert_eq!(parse(""), Err(PlatformError::Empty));
+        assert_eq!(
+            parse("foobar"),
+            Err(PlatformError::UnknownOsOrArch("foobar".into()))
+        );
+        assert_eq!(
+            parse("linux/amd64/v2/extra"),
+            Err(PlatformError::TooManyComponents(
+                "linux/amd64/v2/extra".into()
+            ))
+        );
+        assert_eq!(
+            parse("linux//amd64"),
+            Err(PlatformError::InvalidComponent {
+                specifier: "linux//amd64".into(),
+                component: "".into()
+            })
+        );
+        assert!(matches!(
+            parse("linux/amd 64"),
+            Err(PlatformError::InvalidComponent { .. })
+        ));
+        assert!(matches!(
+            parse("windows(10/amd64"),
+            Err(PlatformError::InvalidComponent { .. })
+        ));
+    }
+
+    #[test]
+    fn platform_display_roundtrip() {
+        for s in [
+            "linux/amd64",
+            "linux/arm/v7",
+            "linux/arm64",
+            "linux/amd64/v3",
+            "windows(10.0.17763)/amd64",
+        ] {
+            assert_eq!(s.parse::<Platform>().unwrap().to_string(), s);
+        }
+    }
+
+    #[test]
+    fn platform_matcher() {
+        let matcher = Platform::LINUX_ARM64.matcher();
+        assert!(matcher.matches(&Platform::LINUX_ARM64));
+        assert!(matcher.matches(&Platform::new("linux", "aarch64", Some("v8"))));
+        assert!(matcher.matches(&Platform::LINUX_ARM));
+        assert!(matcher.matches(&Platform::LINUX_ARMEL));
+        assert!(!matcher.matches(&Platform::LINUX_AMD64));
+        assert!(!matcher.matches(&Platform::new("darwin", "arm64", None)));
+
+        let matcher = "linux/amd64/v3".parse::<Platform>().unwrap().matcher();
+        assert!(matcher.matches(&"linux/amd64/v2".parse().unwrap()));
+        assert!(matcher.matches(&Platform::LINUX_AMD64));
+        assert!(matcher.matches(&Platform::new("linux", "386", None)));
+        assert!(!matcher.matches(&"linux/amd64/v4".parse().unwrap()));
+    }
+
+    #[test]
+    fn platform_best_match() {
+        let manifests = [
+            Platform::LINUX_ARMEL,
+            Platform::LINUX_AMD64,
+            Platform::LINUX_ARM,
+            Platform::new("linux", "386", None),
+        ];
+
+        assert_eq!(
+            Platform::LINUX_ARM64.matcher().best_match(&manifests),
+            Some(&Platform::LINUX_ARM)
+        );
+        assert_eq!(
+            Platform::LINUX_AMD64.matcher().best_match(&manifests),
+            Some(&Platform::LINUX_AMD64)
+        );
+        assert_eq!(Platform::LINUX_S390X.matcher().best_match(&manifests), None);
+
+        let windows = [
+            Platform::WINDOWS.with_os_version("10.0.17763.1234"),
+            Platform::WINDOWS.with_os_version("10.0.20348.100"),
+        ];
+        assert_eq!(
+            Platform::WINDOWS
+                .with_os_version("10.0.20348.5")
+                .matcher()
+                .best_match(&windows),
+            Some(&windows[1])
+        );
+    }
 }
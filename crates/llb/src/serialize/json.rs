@@ -0,0 +1,208 @@
+//! The JSON form of a definition, close to the output of `buildctl debug dump-llb`.
+//!
+//! A definition is a list of ops in dependency order, the last op is the
+//! terminal op that references the result:
+//!
+//! ```json
+//! [
+//!   {
+//!     "Op": { "op": { "source": { "identifier": "docker-image://docker.io/library/alpine:latest" } } },
+//!     "Digest": "sha256:...",
+//!     "OpMetadata": { "caps": { "source.image": true } }
+//!   }
+//! ]
+//! ```
+//!
+//! Fields with a default value are left out. When reading a definition the
+//! `Digest` of an op is only used to resolve the inputs of other ops, the
+//! digests are computed again, so the JSON can be edited by hand.
+
+use buildkit_rs_proto::pb;
+use prost::Message;
+use serde::{de::Error as _, ser::Error as _, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+
+use super::Definition;
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct JsonOp {
+    op: pb::Op,
+    #[serde(default)]
+    digest: String,
+    #[serde(default)]
+    op_metadata: pb::OpMetadata,
+}
+
+impl Definition {
+    /// Serialize to the JSON form, see [`Serialize`]
+    pub fn to_json(&self) -> String {
+        // Serializing protobuf messages to a string can not fail
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    /// Deserialize from the JSON form, see [`Deserialize`]
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+}
+
+impl Serialize for Definition {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Self::json_ops(&self.into_pb())
+            .map_err(S::Error::custom)?
+            .serialize(serializer)
+    }
+}
+
+impl Definition {
+    /// The ops of an encoded definition in the JSON form, without validating
+    /// it. Collected in an array they can be read with [`Definition::from_json`].
+    pub fn json_ops(def: &pb::Definition) -> Result<Vec<Value>, prost::DecodeError> {
+        def.def
+            .iter()
+            .map(|bytes| {
+                let digest = super::node::digest(bytes);
+                let op = JsonOp {
+                    op: pb::Op::decode(bytes.as_slice())?,
+                    op_metadata: def.metadata.get(&digest).cloned().unwrap_or_default(),
+                    digest,
+                };
+
+                // The maps of `Value` are sorted, which keeps the output stable
+                let mut value = serde_json::to_value(op).unwrap();
+                strip_defaults(&mut value);
+                Ok(value)
+            })
+            .collect()
+    }
+}
+
+impl<'de> Deserialize<'de> for Definition {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let ops = Vec::<JsonOp>::deserialize(deserializer)?
+            .into_iter()
+            .map(|op| (op.digest, op.op, op.op_metadata))
+            .collect();
+
+        Self::from_ops(ops).map_err(D::Error::custom)
+    }
+}
+
+/// Remove the fields that are set to their default value.
+///
+/// Zeros are kept, as the variant of a oneof can be a number, and so are
+/// empty objects, as an empty message is not the same as a missing one.
+fn strip_defaults(value: &mut Value) {
+    match value {
+        Value::Array(values) => values.iter_mut().for_each(strip_defaults),
+        Value::Object(fields) => {
+            fields.retain(|_, value| match value {
+                Value::Null | Value::Bool(false) => false,
+                Value::String(s) => !s.is_empty(),
+                Value::Array(values) => !values.is_empty(),
+                _ => true,
+            });
+            fields.values_mut().for_each(strip_defaults);
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::{Exec, Image, Local, Mount, SingleOutput, ValidationError};
+
+    fn definition() -> Definition {
+        let image = Arc::new(Image::new("alpine:latest"));
+        let local = Arc::new(Local::new("context".into()).with_excludes(["target"]));
+        let exec = Arc::new(
+            Exec::shlex("cargo build")
+                .with_env_var("CARGO_HOME", "/cargo")
+                .unwrap()
+                .with_mount(Mount::layer(image.output(), "/"))
+                .with_mount(Mount::layer_readonly(local.output(), "/src")),
+        );
+        Definition::new(exec.root().unwrap())
+    }
+
+    #[test]
+    fn round_trip() {
+        let def = definition();
+        let json = def.to_json();
+
+        let parsed = Definition::from_json(&json).unwrap();
+        assert_eq!(parsed.into_pb(), def.into_pb());
+        assert_eq!(parsed.to_json(), json);
+    }
+
+    #[test]
+    fn from_pb() {
+        let def = definition().into_pb();
+        assert_eq!(Definition::from_pb(&def).unwrap().into_pb(), def);
+    }
+
+    #[test]
+    fn format() {
+        let image = Arc::new(Image::new("alpine:latest"));
+        let value = serde_json::to_value(Definition::new(image.output())).unwrap();
+
+        let ops = value.as_array().unwrap();
+        assert_eq!(ops.len(), 2);
+        assert_eq!(
+            ops[0]["Op"],
+            json!({
+                "op": {
+                    "source": {
+                        "attrs": {},
+                        "identifier": "docker-image://docker.io/library/alpine:latest"
+                    }
+                }
+            })
+        );
+        assert_eq!(
+            ops[0]["OpMetadata"]["caps"],
+            json!({ "source.image": true })
+        );
+        assert_eq!(ops[1]["Op"]["inputs"][0]["digest"], ops[0]["Digest"]);
+    }
+
+    #[test]
+    fn hand_edited() {
+        let mut value = serde_json::to_value(definition()).unwrap();
+
+        // Change the image without updating any digest
+        value[0]["Op"]["op"]["source"]["identifier"] =
+            json!("docker-image://docker.io/library/debian:latest");
+        let edited: Definition = serde_json::from_value(value.clone()).unwrap();
+
+        let json = edited.to_json();
+        assert!(json.contains("debian:latest"));
+        assert_eq!(edited.validate(), Ok(()));
+
+        // Point the exec at an op that does not exist
+        value[2]["Op"]["inputs"][0]["digest"] = json!("sha256:missing");
+        let err = serde_json::from_value::<Definition>(value.clone()).unwrap_err();
+        assert!(err.to_string().contains("sha256:missing"));
+
+        // Make the exec its own input
+        value[2]["Op"]["inputs"][0]["digest"] = value[2]["Digest"].clone();
+        let err = serde_json::from_value::<Definition>(value).unwrap_err();
+        assert!(err.to_string().ends_with("depends on itself"));
+    }
+
+    #[test]
+    fn invalid_terminal() {
+        let image = json!({ "Op": { "op": { "source": { "identifier": "local://context" } } } });
+        let err = serde_json::from_value::<Definition>(json!([image])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            ValidationError::InvalidTerminal.to_string()
+        );
+    }
+}
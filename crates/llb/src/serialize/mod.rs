@@ -1,34 +1,49 @@
 pub mod id;
+mod json;
 pub mod node;
+mod raw;
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
 use buildkit_rs_proto::pb;
 use prost::Message;
 
-use crate::utils::OperationOutput;
+use crate::{
+    ops::output::Output,
+    platform::Platform,
+    utils::OutputIdx,
+    validate::{validate, ValidationError},
+};
 
-use self::node::{Context, Node};
+use self::{
+    node::{digest, Context, Node},
+    raw::RawOp,
+};
 
 struct Constraints;
 
 #[derive(Debug)]
-pub struct Definition<'a> {
-    input: OperationOutput<'a>,
+pub struct Definition {
+    input: Output,
     ignore_cache: bool,
+    platform: Option<Platform>,
 }
 
-impl<'a> Definition<'a> {
-    pub fn new(input: OperationOutput<'a>) -> Self {
+impl Definition {
+    pub fn new(input: Output) -> Self {
         Self {
             input,
             ignore_cache: false,
+            platform: None,
         }
     }
-}
 
-impl Definition<'_> {
     /// Convert to the protobuf representation
     pub fn into_pb(&self) -> pb::Definition {
-        let mut ctx = Context::new();
+        let mut ctx = Context::new().with_platform(self.platform.clone());
 
         let final_node_iter = std::iter::once(self.serialize(&mut ctx).unwrap());
 
@@ -61,11 +76,118 @@ impl Definition<'_> {
         }
     }
 
+    /// Convert from the protobuf representation.
+    ///
+    /// The ops that are not reachable from the result are dropped, the
+    /// source locations are not kept.
+    pub fn from_pb(def: &pb::Definition) -> Result<Self, ValidationError> {
+        let ops = def
+            .def
+            .iter()
+            .enumerate()
+            .map(|(position, bytes)| {
+                let op = pb::Op::decode(bytes.as_slice())
+                    .map_err(|source| ValidationError::Decode { position, source })?;
+                let digest = digest(bytes);
+                let metadata = def.metadata.get(&digest).cloned().unwrap_or_default();
+                Ok((digest, op, metadata))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Self::from_ops(ops)
+    }
+
+    /// Build a definition from ops identified by their digest, the last op
+    /// must be the terminal op that references the result
+    fn from_ops(mut ops: Vec<(String, pb::Op, pb::OpMetadata)>) -> Result<Self, ValidationError> {
+        let (terminal_digest, terminal, _) = ops.pop().ok_or(ValidationError::Empty)?;
+        let [result] = terminal.inputs.as_slice() else {
+            return Err(ValidationError::InvalidTerminal);
+        };
+        if terminal.op.is_some() {
+            return Err(ValidationError::InvalidTerminal);
+        }
+
+        struct Graph {
+            ops: Vec<(String, pb::Op, pb::OpMetadata)>,
+            positions: HashMap<String, usize>,
+            built: Vec<Option<Arc<RawOp>>>,
+            visiting: HashSet<usize>,
+        }
+
+        impl Graph {
+            fn output(&mut self, from: &str, input: &pb::Input) -> Result<Output, ValidationError> {
+                let Some(&idx) = self.positions.get(&input.digest) else {
+                    return Err(ValidationError::MissingInput {
+                        digest: from.into(),
+                        input: input.digest.clone(),
+                    });
+                };
+                let index = u32::try_from(input.index).map_err(|_| {
+                    ValidationError::OutputIndexOutOfRange {
+                        digest: from.into(),
+                        input: input.digest.clone(),
+                        index: input.index,
+                    }
+                })?;
+
+                Ok(Output::new(self.build(idx)?, OutputIdx(index)))
+            }
+
+            fn build(&mut self, idx: usize) -> Result<Arc<RawOp>, ValidationError> {
+                if let Some(op) = &self.built[idx] {
+                    return Ok(op.clone());
+                }
+                if !self.visiting.insert(idx) {
+                    return Err(ValidationError::Cycle {
+                        digest: self.ops[idx].0.clone(),
+                    });
+                }
+
+                let (digest, op, metadata) = self.ops[idx].clone();
+                let inputs = op
+                    .inputs
+                    .iter()
+                    .map(|input| self.output(&digest, input))
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                let op = Arc::new(RawOp::new(op, inputs, metadata));
+                self.visiting.remove(&idx);
+                self.built[idx] = Some(op.clone());
+                Ok(op)
+            }
+        }
+
+        let mut graph = Graph {
+            positions: ops
+                .iter()
+                .enumerate()
+                .map(|(idx, (digest, _, _))| (digest.clone(), idx))
+                .collect(),
+            built: vec![None; ops.len()],
+            visiting: HashSet::new(),
+            ops,
+        };
+
+        Ok(Self::new(graph.output(&terminal_digest, result)?))
+    }
+
+    /// Check the structure of the definition, see [`validate`](crate::validate)
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        validate(&self.into_pb())
+    }
+
     pub fn with_ignore_cache(mut self, ignore_cache: bool) -> Self {
         self.ignore_cache = ignore_cache;
         self
     }
 
+    /// Set the platform of all operations that do not set one explicitly
+    pub fn with_platform(mut self, platform: Platform) -> Self {
+        self.platform = Some(platform);
+        self
+    }
+
     fn serialize(&self, ctx: &mut Context) -> Option<Node> {
         let final_op = pb::Op {
             inputs: vec![pb::Input {
@@ -86,20 +208,6 @@ impl Definition<'_> {
         buf
     }
 
-    // Convert from the protobuf representation
-    // fn from_definition(x: &pb::Definition) -> Self {
-    //     let mut metadata = HashMap::new();
-    //     for (k, v) in &x.metadata {
-    //         metadata.insert(k.clone(), v.clone());
-    //     }
-    //     Self {
-    //         def: x.def.clone(),
-    //         metadata,
-    //         source: x.source.clone(),
-    //         constraints: None,
-    //     }
-    // }
-
     // fn head(&self) -> Option<Digest> {
     //     if self.def.is_empty() {
     //         return None;
@@ -119,3 +227,79 @@ impl Definition<'_> {
     //     Some(pop.inputs[0].digest.clone())
     // }
 }
+
+/// The same graph built once for each of a list of platforms
+#[derive(Debug, Clone, Default)]
+pub struct MultiPlatformDefinition {
+    definitions: Vec<(Platform, pb::Definition)>,
+}
+
+impl MultiPlatformDefinition {
+    /// Call `build` for each platform and serialize the resulting definitions,
+    /// the platform is also used for all operations that do not set one
+    pub fn new<I, F>(platforms: I, mut build: F) -> Self
+    where
+        I: IntoIterator<Item = Platform>,
+        F: FnMut(&Platform) -> Definition,
+    {
+        let definitions = platforms
+            .into_iter()
+            .map(|platform| {
+                let definition = build(&platform).with_platform(platform.clone()).into_pb();
+                (platform, definition)
+            })
+            .collect();
+
+        Self { definitions }
+    }
+
+    pub fn platforms(&self) -> impl Iterator<Item = &Platform> {
+        self.definitions.iter().map(|(platform, _)| platform)
+    }
+
+    pub fn definitions(&self) -> impl Iterator<Item = (&Platform, &pb::Definition)> {
+        self.definitions
+            .iter()
+            .map(|(platform, def)| (platform, def))
+    }
+
+    pub fn into_definitions(self) -> Vec<(Platform, pb::Definition)> {
+        self.definitions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{Exec, Image, Mount, SingleOutput};
+
+    #[test]
+    fn multi_platform_definition() {
+        let def =
+            MultiPlatformDefinition::new([Platform::LINUX_AMD64, Platform::LINUX_ARM64], |_| {
+                let image = Arc::new(Image::new("alpine:latest"));
+                let exec =
+                    Arc::new(Exec::shlex("uname -m").with_mount(Mount::layer(image.output(), "/")));
+                Definition::new(exec.root().unwrap())
+            });
+
+        let platforms = def.platforms().cloned().collect::<Vec<_>>();
+        assert_eq!(platforms, [Platform::LINUX_AMD64, Platform::LINUX_ARM64]);
+
+        for (platform, def) in def.definitions() {
+            let ops = def
+                .def
+                .iter()
+                .map(|bytes| pb::Op::decode(bytes.as_slice()).unwrap())
+                .collect::<Vec<_>>();
+
+            // The image and exec use the platform, the final op has none
+            assert_eq!(ops.len(), 3);
+            assert_eq!(ops[0].platform, Some(platform.to_pb()));
+            assert_eq!(ops[1].platform, Some(platform.to_pb()));
+            assert_eq!(ops[2].platform, None);
+        }
+    }
+}
@@ -3,19 +3,29 @@ use prost::Message;
 use sha2::{Digest, Sha256};
 use std::{collections::BTreeMap, fmt::Debug};
 
-use crate::sourcemap::SourceLocation;
+use crate::{
+    ops::source::image::ImageConfig, platform::Platform, sourcemap::SourceLocation,
+    utils::OutputIdx,
+};
 
 use super::id::OperationId;
 
 pub(crate) trait Operation: Debug + Send + Sync {
     fn id(&self) -> &OperationId;
 
+    /// The image config of the output at `index`, inherited by execs that
+    /// mount it as their root
+    fn image_config(&self, _index: OutputIdx) -> Option<&ImageConfig> {
+        None
+    }
+
     fn serialize(&self, cx: &mut Context) -> Option<Node>;
 }
 
 #[derive(Default)]
 pub struct Context {
     inner: BTreeMap<u64, Node>,
+    platform: Option<Platform>,
 }
 
 impl Context {
@@ -23,6 +33,16 @@ impl Context {
         Self::default()
     }
 
+    pub(crate) fn with_platform(mut self, platform: Option<Platform>) -> Self {
+        self.platform = platform;
+        self
+    }
+
+    /// The platform used by operations that do not set one explicitly
+    pub(crate) fn platform(&self) -> Option<&Platform> {
+        self.platform.as_ref()
+    }
+
     #[allow(clippy::map_entry)]
     pub(crate) fn register<'a>(&'a mut self, op: &dyn Operation) -> Option<&'a Node> {
         let id = **op.id();
@@ -45,7 +65,8 @@ impl Context {
     }
 }
 
-fn digest(bytes: &[u8]) -> String {
+/// The digest of an encoded op, as used to reference it in a definition
+pub fn digest(bytes: &[u8]) -> String {
     let mut hasher = Sha256::new();
     hasher.update(bytes);
     let digest_bytes = hasher.finalize();
@@ -0,0 +1,51 @@
+use buildkit_rs_proto::pb;
+
+use crate::ops::output::Output;
+
+use super::{
+    id::OperationId,
+    node::{Context, Node, Operation},
+};
+
+/// An operation that was decoded from an existing definition.
+///
+/// The digests of the inputs are replaced with the digests of the serialized
+/// input operations, so a graph that was edited by hand gets consistent
+/// digests again.
+#[derive(Debug)]
+pub(crate) struct RawOp {
+    id: OperationId,
+    op: pb::Op,
+    inputs: Vec<Output>,
+    metadata: pb::OpMetadata,
+}
+
+impl RawOp {
+    /// `inputs` must have the same length as the inputs of `op`
+    pub(crate) fn new(op: pb::Op, inputs: Vec<Output>, metadata: pb::OpMetadata) -> Self {
+        debug_assert_eq!(op.inputs.len(), inputs.len());
+
+        Self {
+            id: OperationId::new(),
+            op,
+            inputs,
+            metadata,
+        }
+    }
+}
+
+impl Operation for RawOp {
+    fn id(&self) -> &OperationId {
+        &self.id
+    }
+
+    fn serialize(&self, cx: &mut Context) -> Option<Node> {
+        let mut op = self.op.clone();
+        for (input, output) in op.inputs.iter_mut().zip(&self.inputs) {
+            input.digest = cx.register(output.operation())?.digest.clone();
+            input.index = output.output().into();
+        }
+
+        Some(Node::new(op, self.metadata.clone()))
+    }
+}
@@ -0,0 +1,719 @@
+use std::collections::HashMap;
+
+use buildkit_rs_proto::{
+    moby::buildkit::v1::sourcepolicy as spb,
+    pb::{self, op::Op as OpEnum},
+};
+use prost::Message;
+use regex::Regex;
+use thiserror::Error;
+
+use crate::serialize::node::digest;
+
+/// The maximum number of times a single source can be converted, this
+/// prevents rules that convert back and forth from looping forever
+const MAX_CONVERSIONS: usize = 20;
+
+/// The error type for evaluating a source policy
+#[derive(Debug, Clone, Error)]
+pub enum SourcePolicyError {
+    /// One or more sources are denied by the policy
+    #[error("{} source(s) denied by policy: {}", .0.len(), .0.iter().map(|v| v.identifier.as_str()).collect::<Vec<_>>().join(", "))]
+    Denied(Vec<PolicyViolation>),
+
+    /// A selector or attribute constraint is not a valid regex
+    #[error("invalid regex {pattern:?} in source policy: {source}")]
+    InvalidRegex {
+        pattern: String,
+        #[source]
+        source: regex::Error,
+    },
+
+    /// A source keeps being converted by the policy
+    #[error("source {0:?} was converted more than {MAX_CONVERSIONS} times")]
+    TooManyConversions(String),
+
+    /// One of the marshaled ops could not be decoded
+    #[error("failed to decode op: {0}")]
+    Decode(#[from] prost::DecodeError),
+}
+
+/// A source that is denied by a policy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PolicyViolation {
+    /// The digest of the source op in the definition
+    pub digest: String,
+    /// The identifier of the source after conversions
+    pub identifier: String,
+    /// The index of the last deny rule that matched the source
+    pub rule: usize,
+}
+
+/// How the identifier of a selector is matched
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MatchType {
+    /// `*` matches any sequence of characters and `?` matches a single
+    /// character, both can be referenced as `$1`, `$2`... in a conversion
+    #[default]
+    Wildcard,
+    /// The identifier must match exactly
+    Exact,
+    /// The identifier is a regex, groups can be referenced in a conversion
+    Regex,
+}
+
+impl From<MatchType> for spb::MatchType {
+    fn from(match_type: MatchType) -> Self {
+        match match_type {
+            MatchType::Wildcard => Self::Wildcard,
+            MatchType::Exact => Self::Exact,
+            MatchType::Regex => Self::Regex,
+        }
+    }
+}
+
+/// A condition on an attribute of a source op
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AttrConstraint {
+    Equal { key: String, value: String },
+    NotEqual { key: String, value: String },
+    Matches { key: String, pattern: String },
+}
+
+impl AttrConstraint {
+    fn to_pb(&self) -> spb::AttrConstraint {
+        let (key, value, condition) = match self {
+            AttrConstraint::Equal { key, value } => (key, value, spb::AttrMatch::Equal),
+            AttrConstraint::NotEqual { key, value } => (key, value, spb::AttrMatch::Notequal),
+            AttrConstraint::Matches { key, pattern } => (key, pattern, spb::AttrMatch::Matches),
+        };
+
+        spb::AttrConstraint {
+            key: key.clone(),
+            value: value.clone(),
+            condition: condition.into(),
+        }
+    }
+}
+
+/// Selects the sources a rule applies to, based on their identifier
+/// (e.g. `docker-image://docker.io/library/alpine:latest`) and attributes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Selector {
+    identifier: String,
+    match_type: MatchType,
+    constraints: Vec<AttrConstraint>,
+}
+
+impl Selector {
+    pub fn new(identifier: impl Into<String>, match_type: MatchType) -> Self {
+        Self {
+            identifier: identifier.into(),
+            match_type,
+            constraints: vec![],
+        }
+    }
+
+    pub fn wildcard(identifier: impl Into<String>) -> Self {
+        Self::new(identifier, MatchType::Wildcard)
+    }
+
+    pub fn exact(identifier: impl Into<String>) -> Self {
+        Self::new(identifier, MatchType::Exact)
+    }
+
+    pub fn regex(identifier: impl Into<String>) -> Self {
+        Self::new(identifier, MatchType::Regex)
+    }
+
+    pub fn with_constraint(mut self, constraint: AttrConstraint) -> Self {
+        self.constraints.push(constraint);
+        self
+    }
+
+    pub fn with_attr_equal(self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.with_constraint(AttrConstraint::Equal {
+            key: key.into(),
+            value: value.into(),
+        })
+    }
+
+    pub fn with_attr_not_equal(self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.with_constraint(AttrConstraint::NotEqual {
+            key: key.into(),
+            value: value.into(),
+        })
+    }
+
+    pub fn with_attr_matches(self, key: impl Into<String>, pattern: impl Into<String>) -> Self {
+        self.with_constraint(AttrConstraint::Matches {
+            key: key.into(),
+            pattern: pattern.into(),
+        })
+    }
+
+    fn to_pb(&self) -> spb::Selector {
+        spb::Selector {
+            identifier: self.identifier.clone(),
+            match_type: spb::MatchType::from(self.match_type).into(),
+            constraints: self.constraints.iter().map(AttrConstraint::to_pb).collect(),
+        }
+    }
+}
+
+/// The changes a convert rule makes to a matching source
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Update {
+    identifier: Option<String>,
+    attrs: HashMap<String, String>,
+}
+
+impl Update {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replace the identifier, wildcard and regex groups can be referenced
+    /// with `$1` or `${1}`
+    pub fn with_identifier(mut self, identifier: impl Into<String>) -> Self {
+        self.identifier = Some(identifier.into());
+        self
+    }
+
+    pub fn with_attr(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.attrs.insert(key.into(), value.into());
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Action {
+    Allow,
+    Deny,
+    Convert(Update),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rule {
+    action: Action,
+    selector: Selector,
+}
+
+/// A source policy, which allows, denies or converts the sources of a build.
+///
+/// Rules are evaluated in order and the last allow or deny rule that matches
+/// a source decides if it is allowed. Sources are allowed by default.
+///
+/// Based on the `sourcepolicy` package in buildkit.
+///
+/// ```
+/// use buildkit_rs_llb::{SourcePolicy, Selector, Update};
+///
+/// let policy = SourcePolicy::new()
+///     .deny(Selector::wildcard("docker-image://*"))
+///     .allow(Selector::wildcard("docker-image://registry.example.com/*"))
+///     .convert(
+///         Selector::exact("docker-image://docker.io/library/alpine:latest"),
+///         Update::new().with_identifier("docker-image://registry.example.com/alpine:3.18"),
+///     );
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourcePolicy {
+    rules: Vec<Rule>,
+}
+
+impl SourcePolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn allow(mut self, selector: Selector) -> Self {
+        self.rules.push(Rule {
+            action: Action::Allow,
+            selector,
+        });
+        self
+    }
+
+    pub fn deny(mut self, selector: Selector) -> Self {
+        self.rules.push(Rule {
+            action: Action::Deny,
+            selector,
+        });
+        self
+    }
+
+    pub fn convert(mut self, selector: Selector, update: Update) -> Self {
+        self.rules.push(Rule {
+            action: Action::Convert(update),
+            selector,
+        });
+        self
+    }
+
+    pub fn to_pb(&self) -> spb::Policy {
+        spb::Policy {
+            version: 1,
+            rules: self
+                .rules
+                .iter()
+                .map(|rule| {
+                    let (action, updates) = match &rule.action {
+                        Action::Allow => (spb::PolicyAction::Allow, None),
+                        Action::Deny => (spb::PolicyAction::Deny, None),
+                        Action::Convert(update) => (
+                            spb::PolicyAction::Convert,
+                            Some(spb::Update {
+                                identifier: update.identifier.clone().unwrap_or_default(),
+                                attrs: update.attrs.clone(),
+                            }),
+                        ),
+                    };
+
+                    spb::Rule {
+                        action: action.into(),
+                        selector: Some(rule.selector.to_pb()),
+                        updates,
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    /// Apply the policy to a definition, returning the definition with all
+    /// conversions applied or every source that is denied
+    pub fn evaluate(&self, def: &pb::Definition) -> Result<pb::Definition, SourcePolicyError> {
+        let rules = self
+            .rules
+            .iter()
+            .map(CompiledRule::new)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut ops = def
+            .def
+            .iter()
+            .map(|bytes| Ok((digest(bytes), pb::Op::decode(bytes.as_slice())?)))
+            .collect::<Result<Vec<_>, SourcePolicyError>>()?;
+
+        let mut violations = vec![];
+        let mut mutated = false;
+
+        for (digest, op) in &mut ops {
+            let Some(OpEnum::Source(source)) = &mut op.op else {
+                continue;
+            };
+
+            let mut conversions = 0;
+            loop {
+                match evaluate_source(&rules, source) {
+                    Evaluation::Allowed => break,
+                    Evaluation::Denied(rule) => {
+                        violations.push(PolicyViolation {
+                            digest: digest.clone(),
+                            identifier: source.identifier.clone(),
+                            rule,
+                        });
+                        break;
+                    }
+                    Evaluation::Converted => {
+                        mutated = true;
+                        conversions += 1;
+                        if conversions > MAX_CONVERSIONS {
+                            return Err(SourcePolicyError::TooManyConversions(
+                                source.identifier.clone(),
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+
+        if !violations.is_empty() {
+            return Err(SourcePolicyError::Denied(violations));
+        }
+
+        if !mutated {
+            return Ok(def.clone());
+        }
+
+        Ok(rewrite(def, ops))
+    }
+}
+
+struct CompiledRule<'a> {
+    rule: &'a Rule,
+    identifier: Option<Regex>,
+    constraints: Vec<Option<Regex>>,
+}
+
+impl<'a> CompiledRule<'a> {
+    fn new(rule: &'a Rule) -> Result<Self, SourcePolicyError> {
+        let compile = |pattern: &str| {
+            Regex::new(pattern).map_err(|source| SourcePolicyError::InvalidRegex {
+                pattern: pattern.into(),
+                source,
+            })
+        };
+
+        let identifier = match rule.selector.match_type {
+            MatchType::Exact => None,
+            MatchType::Wildcard => Some(compile(&wildcard_to_regex(&rule.selector.identifier))?),
+            MatchType::Regex => Some(compile(&rule.selector.identifier)?),
+        };
+
+        let constraints = rule
+            .selector
+            .constraints
+            .iter()
+            .map(|constraint| match constraint {
+                AttrConstraint::Matches { pattern, .. } => compile(pattern).map(Some),
+                _ => Ok(None),
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            rule,
+            identifier,
+            constraints,
+        })
+    }
+
+    fn matches(&self, source: &pb::SourceOp) -> bool {
+        let identifier_matches = match &self.identifier {
+            None => self.rule.selector.identifier == source.identifier,
+            Some(re) => re.is_match(&source.identifier),
+        };
+
+        identifier_matches
+            && self
+                .rule
+                .selector
+                .constraints
+                .iter()
+                .zip(&self.constraints)
+                .all(|(constraint, re)| {
+                    let attr = |key: &String| source.attrs.get(key).map(String::as_str);
+                    match constraint {
+                        AttrConstraint::Equal { key, value } => attr(key) == Some(value.as_str()),
+                        AttrConstraint::NotEqual { key, value } => {
+                            attr(key) != Some(value.as_str())
+                        }
+                        AttrConstraint::Matches { key, .. } => attr(key)
+                            .zip(re.as_ref())
+                            .is_some_and(|(attr, re)| re.is_match(attr)),
+                    }
+                })
+    }
+
+    /// Apply the update of a convert rule, returns whether the source changed
+    fn convert(&self, update: &Update, source: &mut pb::SourceOp) -> bool {
+        let mut changed = false;
+
+        if let Some(identifier) = &update.identifier {
+            let converted = match &self.identifier {
+                None => identifier.clone(),
+                Some(re) => re.replace_all(&source.identifier, identifier).into_owned(),
+            };
+
+            if converted != source.identifier {
+                source.identifier = converted;
+                changed = true;
+            }
+        }
+
+        for (key, value) in &update.attrs {
+            if source.attrs.get(key) != Some(value) {
+                source.attrs.insert(key.clone(), value.clone());
+                changed = true;
+            }
+        }
+
+        changed
+    }
+}
+
+enum Evaluation {
+    Allowed,
+    Denied(usize),
+    Converted,
+}
+
+fn evaluate_source(rules: &[CompiledRule], source: &mut pb::SourceOp) -> Evaluation {
+    let mut denied = None;
+
+    for (idx, rule) in rules.iter().enumerate() {
+        if !rule.matches(source) {
+            continue;
+        }
+
+        match &rule.rule.action {
+            Action::Allow => denied = None,
+            Action::Deny => denied = Some(idx),
+            Action::Convert(update) => {
+                if rule.convert(update, source) {
+                    return Evaluation::Converted;
+                }
+            }
+        }
+    }
+
+    match denied {
+        Some(idx) => Evaluation::Denied(idx),
+        None => Evaluation::Allowed,
+    }
+}
+
+/// Convert a wildcard pattern to an anchored regex, capturing each `*` and `?`
+fn wildcard_to_regex(wildcard: &str) -> String {
+    let mut re = String::from("^");
+    for c in wildcard.chars() {
+        match c {
+            '*' => re.push_str("(.*)"),
+            '?' => re.push_str("(.)"),
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+    re
+}
+
+/// Re-encode the ops, updating the digests of converted ops and every op
+/// that depends on them
+fn rewrite(def: &pb::Definition, ops: Vec<(String, pb::Op)>) -> pb::Definition {
+    let positions: HashMap<String, usize> = ops
+        .iter()
+        .enumerate()
+        .map(|(idx, (digest, _))| (digest.clone(), idx))
+        .collect();
+
+    fn encode(
+        idx: usize,
+        ops: &[(String, pb::Op)],
+        positions: &HashMap<String, usize>,
+        encoded: &mut Vec<Option<(String, Vec<u8>)>>,
+    ) -> String {
+        if let Some((digest, _)) = &encoded[idx] {
+            return digest.clone();
+        }
+
+        let mut op = ops[idx].1.clone();
+        for input in &mut op.inputs {
+            if let Some(&input_idx) = positions.get(&input.digest) {
+                input.digest = encode(input_idx, ops, positions, encoded);
+            }
+        }
+
+        let bytes = op.encode_to_vec();
+        let new_digest = digest(&bytes);
+        encoded[idx] = Some((new_digest.clone(), bytes));
+        new_digest
+    }
+
+    let mut encoded = vec![None; ops.len()];
+    for idx in 0..ops.len() {
+        encode(idx, &ops, &positions, &mut encoded);
+    }
+
+    // Every op is encoded above, so each slot is filled
+    let (digests, bytes): (Vec<_>, Vec<_>) = encoded.into_iter().flatten().unzip();
+    let renamed: HashMap<&str, &str> = ops
+        .iter()
+        .zip(&digests)
+        .map(|((old, _), new)| (old.as_str(), new.as_str()))
+        .collect();
+    let rename = |digest: &String| {
+        renamed
+            .get(digest.as_str())
+            .map_or_else(|| digest.clone(), |new| (*new).to_owned())
+    };
+
+    let mut source = def.source.clone();
+    if let Some(source) = &mut source {
+        source.locations = source
+            .locations
+            .drain()
+            .map(|(digest, locations)| (rename(&digest), locations))
+            .collect();
+    }
+
+    pb::Definition {
+        def: bytes,
+        metadata: def
+            .metadata
+            .iter()
+            .map(|(digest, metadata)| (rename(digest), metadata.clone()))
+            .collect(),
+        source,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{Definition, Exec, Image, Local, Mount, SingleOutput};
+
+    fn sources(def: &pb::Definition) -> Vec<String> {
+        def.def
+            .iter()
+            .filter_map(|bytes| match pb::Op::decode(bytes.as_slice()).unwrap().op {
+                Some(OpEnum::Source(source)) => Some(source.identifier),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn definition() -> pb::Definition {
+        let image = Arc::new(Image::new("alpine:latest"));
+        let local = Arc::new(Local::new("context".into()));
+        let exec = Arc::new(
+            Exec::shlex("ls")
+                .with_mount(Mount::layer(image.output(), "/"))
+                .with_mount(Mount::layer_readonly(local.output(), "/src")),
+        );
+        Definition::new(exec.root().unwrap()).into_pb()
+    }
+
+    #[test]
+    fn wildcard_regex() {
+        assert_eq!(
+            wildcard_to_regex("docker-image://*"),
+            r"^docker\-image://(.*)$"
+        );
+        assert_eq!(wildcard_to_regex("a?b.c"), r"^a(.)b\.c$");
+    }
+
+    #[test]
+    fn allow_by_default() {
+        let def = definition();
+        assert_eq!(SourcePolicy::new().evaluate(&def).unwrap(), def);
+    }
+
+    #[test]
+    fn deny_unapproved_registries() {
+        let def = definition();
+
+        let policy = SourcePolicy::new()
+            .deny(Selector::wildcard("docker-image://*"))
+            .allow(Selector::wildcard("docker-image://registry.example.com/*"));
+
+        match policy.evaluate(&def) {
+            Err(SourcePolicyError::Denied(violations)) => {
+                assert_eq!(violations.len(), 1);
+                assert_eq!(
+                    violations[0].identifier,
+                    "docker-image://docker.io/library/alpine:latest"
+                );
+                assert_eq!(violations[0].rule, 0);
+            }
+            res => panic!("expected denied, got {res:?}"),
+        }
+
+        let policy = policy.allow(Selector::wildcard("docker-image://docker.io/library/*"));
+        assert!(policy.evaluate(&def).is_ok());
+    }
+
+    #[test]
+    fn convert_to_digest() {
+        let def = definition();
+        let pinned = "docker-image://docker.io/library/alpine:latest@sha256:82d1e9d7ed48a7523bdebc18cf6290bdb97b82302a8a9c27d4fe885949ea94d1";
+
+        let policy = SourcePolicy::new()
+            .convert(
+                Selector::exact("docker-image://docker.io/library/alpine:latest"),
+                Update::new().with_identifier(pinned),
+            )
+            // Deny any image that is not pinned after conversion
+            .deny(Selector::regex("^docker-image://[^@]*$"));
+
+        let converted = policy.evaluate(&def).unwrap();
+        assert_eq!(sources(&converted), [pinned, "local://context"]);
+
+        // The digests of the converted op and its dependents change
+        assert_eq!(crate::validate(&converted), Ok(()));
+        assert_eq!(converted.metadata.len(), def.metadata.len());
+        assert_ne!(converted.def, def.def);
+        for digest in converted.metadata.keys() {
+            assert!(converted
+                .def
+                .iter()
+                .any(|bytes| &super::digest(bytes) == digest));
+        }
+    }
+
+    #[test]
+    fn convert_with_captures_and_attrs() {
+        let def = definition();
+
+        let policy = SourcePolicy::new().convert(
+            Selector::wildcard("docker-image://docker.io/library/*:latest"),
+            Update::new()
+                .with_identifier("docker-image://mirror.example.com/$1:stable")
+                .with_attr("image.resolvemode", "pull"),
+        );
+
+        let converted = policy.evaluate(&def).unwrap();
+        assert_eq!(
+            sources(&converted),
+            [
+                "docker-image://mirror.example.com/alpine:stable",
+                "local://context"
+            ]
+        );
+    }
+
+    #[test]
+    fn attr_constraints() {
+        let policy = SourcePolicy::new()
+            .deny(Selector::wildcard("local://*").with_attr_not_equal("local.sharedkeyhint", "ok"));
+        assert!(matches!(
+            policy.evaluate(&definition()),
+            Err(SourcePolicyError::Denied(_))
+        ));
+
+        let policy = SourcePolicy::new().deny(Selector::regex("(").with_attr_matches("key", ".*"));
+        assert!(matches!(
+            policy.evaluate(&definition()),
+            Err(SourcePolicyError::InvalidRegex { .. })
+        ));
+    }
+
+    #[test]
+    fn conversion_loop() {
+        let policy = SourcePolicy::new()
+            .convert(
+                Selector::exact("local://context"),
+                Update::new().with_identifier("local://other"),
+            )
+            .convert(
+                Selector::exact("local://other"),
+                Update::new().with_identifier("local://context"),
+            );
+
+        assert!(matches!(
+            policy.evaluate(&definition()),
+            Err(SourcePolicyError::TooManyConversions(_))
+        ));
+    }
+
+    #[test]
+    fn policy_to_pb() {
+        let policy = SourcePolicy::new()
+            .deny(Selector::wildcard("docker-image://*"))
+            .convert(
+                Selector::regex("^local://(.*)$").with_attr_equal("a", "b"),
+                Update::new().with_attr("c", "d"),
+            );
+
+        let pb = policy.to_pb();
+        assert_eq!(pb.version, 1);
+        assert_eq!(pb.rules.len(), 2);
+        assert_eq!(pb.rules[0].action(), spb::PolicyAction::Deny);
+        assert_eq!(pb.rules[1].action(), spb::PolicyAction::Convert);
+        let selector = pb.rules[1].selector.as_ref().unwrap();
+        assert_eq!(selector.match_type(), spb::MatchType::Regex);
+        assert_eq!(selector.constraints[0].condition(), spb::AttrMatch::Equal);
+        assert_eq!(pb.rules[1].updates.as_ref().unwrap().attrs["c"], "d");
+    }
+}
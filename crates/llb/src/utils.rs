@@ -1,53 +1,9 @@
-use std::sync::Arc;
-
-use crate::serialize::node::Operation;
-
 #[derive(Copy, Clone, Debug)]
 pub struct OutputIdx(pub u32);
 
 #[derive(Copy, Clone, Debug)]
 pub struct OwnOutputIdx(pub u32);
 
-#[derive(Debug, Clone)]
-pub struct OperationOutput<'a> {
-    kind: OperationOutputKind<'a>,
-}
-
-#[derive(Debug, Clone)]
-enum OperationOutputKind<'a> {
-    Owned(Arc<dyn Operation + 'a>, OutputIdx),
-    Borrowed(&'a dyn Operation, OutputIdx),
-}
-
-impl<'a> OperationOutput<'a> {
-    pub(crate) fn owned(op: Arc<dyn Operation + 'a>, idx: OutputIdx) -> Self {
-        Self {
-            kind: OperationOutputKind::Owned(op, idx),
-        }
-    }
-
-    pub(crate) fn borrowed(op: &'a dyn Operation, idx: OutputIdx) -> Self {
-        Self {
-            kind: OperationOutputKind::Borrowed(op, idx),
-        }
-    }
-
-    pub(crate) fn operation(&self) -> &dyn Operation {
-        match self.kind {
-            OperationOutputKind::Owned(ref op, ..) => op.as_ref(),
-            OperationOutputKind::Borrowed(op, ..) => op,
-        }
-    }
-
-    pub(crate) fn output(&self) -> OutputIdx {
-        match self.kind {
-            OperationOutputKind::Owned(_, output) | OperationOutputKind::Borrowed(_, output) => {
-                output
-            }
-        }
-    }
-}
-
 impl From<OutputIdx> for i64 {
     fn from(val: OutputIdx) -> Self {
         val.0.into()
@@ -105,12 +61,12 @@ pub mod test {
         ($op:expr, $(|$name:ident| $value:expr,)*) => ($crate::check_op!($op, $(|$name| $value),*));
         ($op:expr, $(|$name:ident| $value:expr),*) => {{
             #[allow(unused_imports)]
-            use $crate::serialization::{Context, Operation};
+            use $crate::serialize::node::{Context, Operation};
 
             let mut context = Context::default();
             let serialized = $op.serialize(&mut context).unwrap();
 
-            $(crate::check_op_property!(serialized, context, $name, $value));*
+            $($crate::check_op_property!(serialized, context, $name, $value));*
         }};
     }
 
@@ -167,13 +123,13 @@ pub mod test {
                 .collect::<Vec<_>>();
 
             caps.sort();
-            assert_eq!(caps, crate::utils::test::to_vec($value));
+            assert_eq!(caps, $crate::utils::test::to_vec($value));
         }};
 
         ($serialized:expr, $context:expr, description, $value:expr) => {
             assert_eq!(
                 $serialized.metadata.description,
-                crate::utils::test::to_map($value),
+                $crate::utils::test::to_map($value),
             );
         };
 
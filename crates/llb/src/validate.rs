@@ -0,0 +1,373 @@
+use std::collections::{HashMap, HashSet};
+
+use buildkit_rs_proto::pb::{self, op::Op as OpEnum, MountType};
+use prost::Message;
+use thiserror::Error;
+
+use crate::{serialize::node::digest, CacheSharingMode};
+
+/// The error type for a structurally invalid LLB definition
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ValidationError {
+    /// The definition does not contain any ops
+    #[error("definition does not contain any operations")]
+    Empty,
+
+    /// One of the marshaled ops could not be decoded
+    #[error("failed to decode op at position {position}: {source}")]
+    Decode {
+        position: usize,
+        #[source]
+        source: prost::DecodeError,
+    },
+
+    /// An op references an input digest that is not part of the definition
+    #[error("op {digest} references unknown input {input}")]
+    MissingInput { digest: String, input: String },
+
+    /// The last op is not a terminal op with a single input and no operation
+    #[error("the last op of the definition must only reference the result")]
+    InvalidTerminal,
+
+    /// An op depends on itself through its inputs, which is only possible
+    /// when reading a definition whose digests were edited by hand, see
+    /// [`Definition::from_json`](crate::Definition::from_json)
+    #[error("op {digest} depends on itself")]
+    Cycle { digest: String },
+
+    /// An op references an output index that its input does not produce
+    #[error("op {digest} references output {index} of {input} which does not exist")]
+    OutputIndexOutOfRange {
+        digest: String,
+        input: String,
+        index: i64,
+    },
+
+    /// An exec has no arguments to run
+    #[error("exec {digest} has no arguments")]
+    EmptyArgs { digest: String },
+
+    /// An exec does not have a mount at `/`
+    #[error("exec {digest} does not have a root mount")]
+    MissingRootMount { digest: String },
+
+    /// A mount destination is relative
+    #[error("exec {digest} has a mount with a relative destination {dest:?}")]
+    MountDestNotAbsolute { digest: String, dest: String },
+
+    /// Two mounts of the same exec share a destination
+    #[error("exec {digest} has multiple mounts at {dest:?}")]
+    DuplicateMountDest { digest: String, dest: String },
+
+    /// A mount references an input index the exec does not have
+    #[error("exec {digest} has a mount at {dest:?} referencing missing input {input}")]
+    MountInputOutOfRange {
+        digest: String,
+        dest: String,
+        input: i64,
+    },
+
+    /// The same cache ID is mounted with different sharing modes
+    #[error("cache {id:?} is mounted with conflicting sharing modes {first:?} and {second:?}")]
+    ConflictingCacheSharing {
+        id: String,
+        first: CacheSharingMode,
+        second: CacheSharingMode,
+    },
+}
+
+/// Validate the structure of a definition before it is sent to buildkit.
+///
+/// This catches mistakes that would otherwise only be reported by the
+/// solver, such as referencing an output that does not exist or an exec
+/// without a root mount.
+pub fn validate(def: &pb::Definition) -> Result<(), ValidationError> {
+    if def.def.is_empty() {
+        return Err(ValidationError::Empty);
+    }
+
+    let ops = def
+        .def
+        .iter()
+        .enumerate()
+        .map(|(position, bytes)| {
+            pb::Op::decode(bytes.as_slice())
+                .map(|op| (digest(bytes), op))
+                .map_err(|source| ValidationError::Decode { position, source })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let by_digest: HashMap<&str, &pb::Op> = ops.iter().map(|(d, op)| (d.as_str(), op)).collect();
+    let mut caches: HashMap<&str, CacheSharingMode> = HashMap::new();
+
+    for (digest, op) in &ops {
+        for input in &op.inputs {
+            let Some(input_op) = by_digest.get(input.digest.as_str()) else {
+                return Err(ValidationError::MissingInput {
+                    digest: digest.clone(),
+                    input: input.digest.clone(),
+                });
+            };
+
+            if !has_output(input_op, input.index) {
+                return Err(ValidationError::OutputIndexOutOfRange {
+                    digest: digest.clone(),
+                    input: input.digest.clone(),
+                    index: input.index,
+                });
+            }
+        }
+
+        if let Some(OpEnum::Exec(exec)) = &op.op {
+            validate_exec(digest, exec, op.inputs.len(), &mut caches)?;
+        }
+    }
+
+    // Ops reference their inputs by digest, which can't form a cycle, but the
+    // result is only found through the terminal op
+    let (_, terminal) = ops.last().expect("definition is not empty");
+    if terminal.op.is_some() || terminal.inputs.len() != 1 {
+        return Err(ValidationError::InvalidTerminal);
+    }
+
+    Ok(())
+}
+
+fn has_output(op: &pb::Op, index: i64) -> bool {
+    match &op.op {
+        Some(OpEnum::Exec(exec)) => exec
+            .mounts
+            .iter()
+            .any(|m| m.output >= 0 && m.output == index),
+        Some(OpEnum::File(file)) => file
+            .actions
+            .iter()
+            .any(|a| a.output >= 0 && a.output == index),
+        Some(_) => index == 0,
+        None => false,
+    }
+}
+
+fn validate_exec<'a>(
+    digest: &str,
+    exec: &'a pb::ExecOp,
+    input_count: usize,
+    caches: &mut HashMap<&'a str, CacheSharingMode>,
+) -> Result<(), ValidationError> {
+    if exec.meta.as_ref().is_none_or(|meta| meta.args.is_empty()) {
+        return Err(ValidationError::EmptyArgs {
+            digest: digest.into(),
+        });
+    }
+
+    let mut dests = HashSet::new();
+    for mount in &exec.mounts {
+        if !mount.dest.starts_with('/') {
+            return Err(ValidationError::MountDestNotAbsolute {
+                digest: digest.into(),
+                dest: mount.dest.clone(),
+            });
+        }
+
+        if !dests.insert(clean_path(&mount.dest)) {
+            return Err(ValidationError::DuplicateMountDest {
+                digest: digest.into(),
+                dest: mount.dest.clone(),
+            });
+        }
+
+        if mount.input >= 0 && mount.input as usize >= input_count {
+            return Err(ValidationError::MountInputOutOfRange {
+                digest: digest.into(),
+                dest: mount.dest.clone(),
+                input: mount.input,
+            });
+        }
+
+        if let (MountType::Cache, Some(cache)) = (mount.mount_type(), &mount.cache_opt) {
+            let sharing = cache.sharing().into();
+            match caches.get(cache.id.as_str()) {
+                Some(&first) if first != sharing => {
+                    return Err(ValidationError::ConflictingCacheSharing {
+                        id: cache.id.clone(),
+                        first,
+                        second: sharing,
+                    });
+                }
+                Some(_) => {}
+                None => {
+                    caches.insert(&cache.id, sharing);
+                }
+            }
+        }
+    }
+
+    if !dests.contains("/") {
+        return Err(ValidationError::MissingRootMount {
+            digest: digest.into(),
+        });
+    }
+
+    Ok(())
+}
+
+/// The lexically cleaned form of an absolute path, like Go's `path.Clean`
+fn clean_path(path: &str) -> String {
+    let mut parts = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    format!("/{}", parts.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{Definition, Exec, Image, Mount, SingleOutput};
+
+    #[test]
+    fn valid_exec() {
+        let image = Arc::new(Image::new("alpine:latest"));
+        let exec = Arc::new(
+            Exec::shlex("echo hello")
+                .with_mount(Mount::layer(image.output(), "/"))
+                .with_mount(Mount::cache("/cache", "cache", CacheSharingMode::Locked)),
+        );
+
+        assert_eq!(Definition::new(exec.root().unwrap()).validate(), Ok(()));
+    }
+
+    #[test]
+    fn output_index_out_of_range() {
+        let image = Arc::new(Image::new("alpine:latest"));
+        let exec =
+            Arc::new(Exec::shlex("echo hello").with_mount(Mount::layer(image.output(), "/")));
+        let mut def = Definition::new(exec.root().unwrap()).into_pb();
+
+        // Point the terminal op at an output the exec does not have
+        let mut terminal = pb::Op::decode(def.def.pop().unwrap().as_slice()).unwrap();
+        terminal.inputs[0].index = 1;
+        def.def.push(terminal.encode_to_vec());
+
+        assert!(matches!(
+            validate(&def),
+            Err(ValidationError::OutputIndexOutOfRange { index: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn invalid_terminal() {
+        let image = Arc::new(Image::new("alpine:latest"));
+        let mut def = Definition::new(image.output()).into_pb();
+        assert_eq!(validate(&def), Ok(()));
+
+        // Without the terminal op, the last op is the image source
+        def.def.pop();
+        assert_eq!(validate(&def), Err(ValidationError::InvalidTerminal));
+    }
+
+    #[test]
+    fn missing_root_mount() {
+        let exec = Arc::new(Exec::shlex("echo hello").with_mount(Mount::scratch("/out")));
+
+        assert!(matches!(
+            Definition::new(exec.mount_output("/out").unwrap()).validate(),
+            Err(ValidationError::MissingRootMount { .. })
+        ));
+    }
+
+    #[test]
+    fn empty_args() {
+        let image = Arc::new(Image::new("alpine:latest"));
+        let exec =
+            Arc::new(Exec::new(Vec::<String>::new()).with_mount(Mount::layer(image.output(), "/")));
+
+        assert!(matches!(
+            Definition::new(exec.root().unwrap()).validate(),
+            Err(ValidationError::EmptyArgs { .. })
+        ));
+    }
+
+    #[test]
+    fn invalid_mount_dest() {
+        let image = Arc::new(Image::new("alpine:latest"));
+        let exec = Arc::new(
+            Exec::shlex("echo hello")
+                .with_mount(Mount::layer(image.output(), "/"))
+                .with_mount(Mount::scratch("out")),
+        );
+
+        assert!(matches!(
+            Definition::new(exec.root().unwrap()).validate(),
+            Err(ValidationError::MountDestNotAbsolute { .. })
+        ));
+
+        let exec = Arc::new(
+            Exec::shlex("echo hello")
+                .with_mount(Mount::layer(image.output(), "/"))
+                .with_mount(Mount::scratch("/out"))
+                .with_mount(Mount::scratch("/out/")),
+        );
+
+        assert!(matches!(
+            Definition::new(exec.root().unwrap()).validate(),
+            Err(ValidationError::DuplicateMountDest { .. })
+        ));
+    }
+
+    #[test]
+    fn conflicting_cache_sharing() {
+        let image = Arc::new(Image::new("alpine:latest"));
+        let first = Arc::new(
+            Exec::shlex("echo hello")
+                .with_mount(Mount::layer(image.output(), "/"))
+                .with_mount(Mount::cache("/cache", "cache", CacheSharingMode::Shared)),
+        );
+        let second = Arc::new(
+            Exec::shlex("echo world")
+                .with_mount(Mount::layer(first.root().unwrap(), "/"))
+                .with_mount(Mount::cache("/cache", "cache", CacheSharingMode::Private)),
+        );
+
+        assert_eq!(
+            Definition::new(second.root().unwrap()).validate(),
+            Err(ValidationError::ConflictingCacheSharing {
+                id: "cache".into(),
+                first: CacheSharingMode::Shared,
+                second: CacheSharingMode::Private,
+            })
+        );
+    }
+
+    #[test]
+    fn missing_input() {
+        let def = pb::Definition {
+            def: vec![pb::Op {
+                inputs: vec![pb::Input {
+                    digest: "sha256:abc".into(),
+                    index: 0,
+                }],
+                ..Default::default()
+            }
+            .encode_to_vec()],
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            validate(&def),
+            Err(ValidationError::MissingInput { .. })
+        ));
+        assert_eq!(
+            validate(&pb::Definition::default()),
+            Err(ValidationError::Empty)
+        );
+    }
+}
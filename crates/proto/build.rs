@@ -13,8 +13,17 @@ fn main() -> Result<()> {
         format!("{BUILDKIT_DIR}/api/services/control/control.proto"),
     ];
 
+    // The LLB ops can be converted to and from JSON for debugging and golden files
     tonic_build::configure()
         .build_server(false)
+        .message_attribute(
+            ".pb",
+            "#[derive(serde::Serialize, serde::Deserialize)] #[serde(default, rename_all = \"camelCase\")]",
+        )
+        .enum_attribute(
+            ".pb",
+            "#[derive(serde::Serialize, serde::Deserialize)] #[serde(rename_all = \"camelCase\")]",
+        )
         .compile(&protos, &includes)?;
 
     for (session_type, pkg_name) in [
@@ -37,5 +46,12 @@ fn main() -> Result<()> {
         std::fs::rename(src, dest).unwrap();
     }
 
+    // Served by the session for the local cache backend, containerd imports
+    // `gogoproto/gogo.proto` relative to the gogo repository
+    tonic_build::configure().build_client(false).compile(
+        &["vendor/github.com/containerd/containerd/api/services/content/v1/content.proto"],
+        &["vendor", "vendor/github.com/gogo/protobuf"],
+    )?;
+
     Ok(())
 }
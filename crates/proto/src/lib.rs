@@ -15,6 +15,19 @@ pub mod google {
     }
 }
 
+pub mod containerd {
+    pub mod services {
+        pub mod content {
+            pub mod v1 {
+                include!(concat!(
+                    env!("OUT_DIR"),
+                    "/containerd.services.content.v1.rs"
+                ));
+            }
+        }
+    }
+}
+
 pub mod moby {
     pub mod buildkit {
         pub mod v1 {
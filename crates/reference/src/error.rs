@@ -24,4 +24,8 @@ pub enum Error {
     /// The name matches the identifier pattern and is therefore not allowed
     #[error("invalid repository name, cannot specify 64-byte hexadecimal strings")]
     NameIdentifier,
+
+    /// The digest is not in the `algorithm:encoded` form
+    #[error("invalid digest format")]
+    InvalidDigestFormat,
 }
@@ -1,6 +1,8 @@
 use crate::{
     consts::{DEFAULT_DOMAIN, LEGACY_DEFAULT_DOMAIN, NAME_TOTAL_LENGTH_MAX, OFFICIAL_REPO_PREFIX},
-    regex::{ANCHORED_IDENTIFIER_REGEXP, ANCHORED_NAME_REGEXP, REFERENCE_REGEX},
+    regex::{
+        ANCHORED_DIGEST_REGEXP, ANCHORED_IDENTIFIER_REGEXP, ANCHORED_NAME_REGEXP, REFERENCE_REGEX,
+    },
     Error,
 };
 use std::{borrow::Cow, cmp::Ordering, fmt};
@@ -35,7 +37,7 @@ impl Repository {
     /// Normalizes the path of the repository if it is an official repository
     ///
     /// (i.e. `library/foo` -> `foo`)
-    pub fn normalized_path(&self) -> Option<Cow<str>> {
+    pub fn normalized_path(&self) -> Option<Cow<'_, str>> {
         let path = self.path.as_deref()?;
         if matches!(
             self.domain.as_deref(),
@@ -95,9 +97,9 @@ impl Reference {
                 return Err(Error::NameEmpty);
             }
             if REFERENCE_REGEX.captures(&s.to_lowercase()).is_some() {
-                return Err(Error::NameContainsUppercase)
+                return Err(Error::NameContainsUppercase);
             }
-            return Err(Error::InvalidReferenceFormat)
+            return Err(Error::InvalidReferenceFormat);
         };
 
         if matches.get(0).unwrap().as_str().len() > NAME_TOTAL_LENGTH_MAX {
@@ -111,7 +113,7 @@ impl Reference {
                 .as_str(),
         );
         let Some(name_match) = name_match else {
-            return Err(Error::InvalidReferenceFormat)
+            return Err(Error::InvalidReferenceFormat);
         };
 
         let repo = match name_match.get(1) {
@@ -163,7 +165,7 @@ impl Reference {
     }
 
     /// Returns the path of the reference, normalized if it is an official repository
-    pub fn path(&self) -> Option<Cow<str>> {
+    pub fn path(&self) -> Option<Cow<'_, str>> {
         self.repository.normalized_path()
     }
 
@@ -177,6 +179,17 @@ impl Reference {
         self.digest.as_deref()
     }
 
+    /// Pin the reference to `digest`, keeping the tag so the reference stays readable
+    pub fn with_digest(mut self, digest: impl Into<String>) -> Result<Self, Error> {
+        let digest = digest.into();
+        if !ANCHORED_DIGEST_REGEXP.is_match(&digest) {
+            return Err(Error::InvalidDigestFormat);
+        }
+
+        self.digest = Some(digest);
+        Ok(self)
+    }
+
     /// `rank_ord` returns a [Ordering] based on the following rules preferring higher
     /// information references, then by the lexicographical ordering of the reference string:
     ///
@@ -404,4 +417,21 @@ mod test {
             "docker.io/library/busybox:latest@sha256:86e0e091d0da6bde2456dbb48306f3956bbeb2eae1b5b9a43045843f69fe4aaa"
         );
     }
+
+    #[test]
+    fn test_with_digest() {
+        let digest = "sha256:86e0e091d0da6bde2456dbb48306f3956bbeb2eae1b5b9a43045843f69fe4aaa";
+        let pinned = Reference::parse_normalized_named("busybox:latest")
+            .unwrap()
+            .with_digest(digest)
+            .unwrap();
+
+        assert_eq!(pinned.tag(), Some("latest"));
+        assert_eq!(pinned.digest(), Some(digest));
+
+        assert!(matches!(
+            pinned.with_digest("latest"),
+            Err(Error::InvalidDigestFormat)
+        ));
+    }
 }
@@ -1,2 +1,5 @@
 pub mod oci;
+pub mod shell;
 pub mod system;
+#[cfg(feature = "test-util")]
+pub mod test_util;
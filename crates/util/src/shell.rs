@@ -0,0 +1,744 @@
+//! Shell-word expansion with the semantics of the `shell.Lex` type in the
+//! Dockerfile frontend of BuildKit.
+//!
+//! Supported are single and double quotes, escapes, `$VAR`, `${VAR}` and the
+//! `${VAR:-default}`, `${VAR:+alt}`, `${VAR:?message}` modifiers (also without
+//! the colon), the `${VAR#pattern}`, `${VAR##pattern}`, `${VAR%pattern}` and
+//! `${VAR%%pattern}` trims and the `${VAR/pattern/replacement}` and
+//! `${VAR//pattern/replacement}` substitutions.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+};
+
+/// A source of variables for expansion
+pub trait EnvGetter {
+    /// The value of a variable, `None` if it is not set
+    fn get_env(&self, key: &str) -> Option<&str>;
+}
+
+impl<T: EnvGetter + ?Sized> EnvGetter for &T {
+    fn get_env(&self, key: &str) -> Option<&str> {
+        (**self).get_env(key)
+    }
+}
+
+impl EnvGetter for HashMap<String, String> {
+    fn get_env(&self, key: &str) -> Option<&str> {
+        self.get(key).map(String::as_str)
+    }
+}
+
+impl EnvGetter for BTreeMap<String, String> {
+    fn get_env(&self, key: &str) -> Option<&str> {
+        self.get(key).map(String::as_str)
+    }
+}
+
+/// Variables in the `KEY=VALUE` form, the last one wins
+impl EnvGetter for [String] {
+    fn get_env(&self, key: &str) -> Option<&str> {
+        self.iter()
+            .rev()
+            .filter_map(|var| var.split_once('='))
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| v)
+    }
+}
+
+impl EnvGetter for Vec<String> {
+    fn get_env(&self, key: &str) -> Option<&str> {
+        self.as_slice().get_env(key)
+    }
+}
+
+/// The error type for expanding a word
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ShellError {
+    /// A quote is not closed
+    UnterminatedQuote(char),
+    /// A `${` is not closed
+    MissingBrace,
+    /// A `${}` substitution without a valid name
+    BadSubstitution,
+    /// The modifier of a `${}` substitution is not supported
+    UnsupportedModifier(String),
+    /// A variable required by `${VAR?message}` is not set
+    Required { name: String, message: String },
+}
+
+impl std::error::Error for ShellError {}
+
+impl fmt::Display for ShellError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShellError::UnterminatedQuote(quote) => {
+                let kind = match quote {
+                    '\'' => "single-quote",
+                    _ => "double-quote",
+                };
+                write!(
+                    f,
+                    "unexpected end of statement while looking for matching {kind}"
+                )
+            }
+            ShellError::MissingBrace => write!(f, "syntax error: missing '}}'"),
+            ShellError::BadSubstitution => write!(f, "syntax error: bad substitution"),
+            ShellError::UnsupportedModifier(modifier) => {
+                write!(f, "unsupported modifier ({modifier}) in substitution")
+            }
+            ShellError::Required { name, message } => write!(f, "{name}: {message}"),
+        }
+    }
+}
+
+/// Expands shell words, see the [module documentation](self)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lex {
+    escape: char,
+    raw_quotes: bool,
+    raw_escapes: bool,
+    skip_unset_env: bool,
+}
+
+impl Default for Lex {
+    fn default() -> Self {
+        Self::new('\\')
+    }
+}
+
+impl Lex {
+    /// A lexer with the given escape character, `\` or `` ` `` in a
+    /// Dockerfile
+    pub fn new(escape: char) -> Self {
+        Self {
+            escape,
+            raw_quotes: false,
+            raw_escapes: false,
+            skip_unset_env: false,
+        }
+    }
+
+    /// Keep the quotes in the result, used for heredocs
+    pub fn with_raw_quotes(mut self, raw_quotes: bool) -> Self {
+        self.raw_quotes = raw_quotes;
+        self
+    }
+
+    /// Keep the escape characters in the result
+    pub fn with_raw_escapes(mut self, raw_escapes: bool) -> Self {
+        self.raw_escapes = raw_escapes;
+        self
+    }
+
+    /// Leave variables that are not set as they were written instead of
+    /// expanding them to an empty string
+    pub fn with_skip_unset_env(mut self, skip_unset_env: bool) -> Self {
+        self.skip_unset_env = skip_unset_env;
+        self
+    }
+
+    /// Expand a word, whitespace is kept as it is
+    pub fn process_word<E>(&self, word: &str, env: &E) -> Result<String, ShellError>
+    where
+        E: EnvGetter + ?Sized,
+    {
+        Ok(Scanner::new(self, word, env).process(&[])?.0)
+    }
+
+    /// Expand a word and split the result into words on unquoted whitespace,
+    /// including whitespace in the values of unquoted variables
+    pub fn process_words<E>(&self, word: &str, env: &E) -> Result<Vec<String>, ShellError>
+    where
+        E: EnvGetter + ?Sized,
+    {
+        Ok(Scanner::new(self, word, env).process(&[])?.1.finish())
+    }
+}
+
+#[derive(Default)]
+struct Words {
+    words: Vec<String>,
+    word: String,
+    in_word: bool,
+}
+
+impl Words {
+    fn add_char(&mut self, c: char) {
+        if !c.is_whitespace() {
+            self.add_raw_char(c);
+        } else if self.in_word {
+            self.words.push(std::mem::take(&mut self.word));
+            self.in_word = false;
+        }
+    }
+
+    fn add_raw_char(&mut self, c: char) {
+        self.word.push(c);
+        self.in_word = true;
+    }
+
+    fn add_str(&mut self, s: &str) {
+        s.chars().for_each(|c| self.add_char(c));
+    }
+
+    fn add_raw_str(&mut self, s: &str) {
+        s.chars().for_each(|c| self.add_raw_char(c));
+    }
+
+    fn finish(mut self) -> Vec<String> {
+        if self.in_word {
+            self.words.push(self.word);
+        }
+        self.words
+    }
+}
+
+struct Scanner<'a, E: ?Sized> {
+    lex: &'a Lex,
+    chars: Vec<char>,
+    pos: usize,
+    env: &'a E,
+}
+
+impl<'a, E: EnvGetter + ?Sized> Scanner<'a, E> {
+    fn new(lex: &'a Lex, word: &str, env: &'a E) -> Self {
+        Self {
+            lex,
+            chars: word.chars().collect(),
+            pos: 0,
+            env,
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        self.pos += c.is_some() as usize;
+        c
+    }
+
+    /// Process until the end or one of the `stop` characters, which is
+    /// consumed
+    fn process(&mut self, stop: &[char]) -> Result<(String, Words), ShellError> {
+        self.process_with(stop, self.lex.raw_escapes)
+    }
+
+    fn process_with(
+        &mut self,
+        stop: &[char],
+        raw_escapes: bool,
+    ) -> Result<(String, Words), ShellError> {
+        let mut result = String::new();
+        let mut words = Words::default();
+
+        while let Some(c) = self.peek() {
+            if stop.contains(&c) {
+                self.next();
+                return Ok((result, words));
+            }
+
+            match c {
+                '\'' => {
+                    let quoted = self.single_quote()?;
+                    result.push_str(&quoted);
+                    words.add_raw_str(&quoted);
+                }
+                '"' => {
+                    let quoted = self.double_quote()?;
+                    result.push_str(&quoted);
+                    words.add_raw_str(&quoted);
+                }
+                '$' => {
+                    let value = self.dollar()?;
+                    result.push_str(&value);
+                    words.add_str(&value);
+                }
+                c if c == self.lex.escape => {
+                    self.next();
+                    let Some(escaped) = self.next() else {
+                        break;
+                    };
+                    if raw_escapes {
+                        result.push(c);
+                        words.add_raw_char(c);
+                    }
+                    result.push(escaped);
+                    words.add_raw_char(escaped);
+                }
+                c => {
+                    self.next();
+                    result.push(c);
+                    words.add_char(c);
+                }
+            }
+        }
+
+        if stop.is_empty() {
+            Ok((result, words))
+        } else {
+            Err(ShellError::MissingBrace)
+        }
+    }
+
+    fn single_quote(&mut self) -> Result<String, ShellError> {
+        let mut result = String::new();
+        let quote = self.next().unwrap();
+        if self.lex.raw_quotes {
+            result.push(quote);
+        }
+
+        loop {
+            match self.next() {
+                None => return Err(ShellError::UnterminatedQuote(quote)),
+                Some(c) if c == quote => break,
+                Some(c) => result.push(c),
+            }
+        }
+
+        if self.lex.raw_quotes {
+            result.push(quote);
+        }
+        Ok(result)
+    }
+
+    fn double_quote(&mut self) -> Result<String, ShellError> {
+        let mut result = String::new();
+        let quote = self.next().unwrap();
+        if self.lex.raw_quotes {
+            result.push(quote);
+        }
+
+        loop {
+            match self.peek() {
+                None => return Err(ShellError::UnterminatedQuote(quote)),
+                Some(c) if c == quote => {
+                    self.next();
+                    break;
+                }
+                Some('$') => result.push_str(&self.dollar()?),
+                Some(c) if c == self.lex.escape => {
+                    self.next();
+                    // Only a few characters can be escaped, other escapes are
+                    // kept as they are
+                    match self.peek() {
+                        Some(next) if next == '"' || next == '$' || next == c => {
+                            self.next();
+                            if self.lex.raw_escapes {
+                                result.push(c);
+                            }
+                            result.push(next);
+                        }
+                        _ => result.push(c),
+                    }
+                }
+                Some(c) => {
+                    self.next();
+                    result.push(c);
+                }
+            }
+        }
+
+        if self.lex.raw_quotes {
+            result.push(quote);
+        }
+        Ok(result)
+    }
+
+    /// A name, a number or a special parameter such as `$@`
+    fn name(&mut self) -> String {
+        let mut name = String::new();
+        match self.peek() {
+            Some(c) if c.is_ascii_digit() => {
+                while let Some(c) = self.peek().filter(char::is_ascii_digit) {
+                    name.push(c);
+                    self.next();
+                }
+            }
+            Some(c @ ('@' | '*' | '#' | '?' | '-' | '$' | '!' | '0')) => {
+                self.next();
+                name.push(c);
+            }
+            _ => {
+                while let Some(c) = self.peek().filter(|c| c.is_alphanumeric() || *c == '_') {
+                    name.push(c);
+                    self.next();
+                }
+            }
+        }
+        name
+    }
+
+    fn dollar(&mut self) -> Result<String, ShellError> {
+        self.next();
+
+        if self.peek() != Some('{') {
+            let name = self.name();
+            if name.is_empty() {
+                return Ok("$".into());
+            }
+            return Ok(match self.env.get_env(&name) {
+                Some(value) => value.into(),
+                None if self.lex.skip_unset_env => format!("${name}"),
+                None => String::new(),
+            });
+        }
+
+        self.next();
+        match self.peek() {
+            None => return Err(ShellError::MissingBrace),
+            Some('{' | '}' | ':') => return Err(ShellError::BadSubstitution),
+            Some(_) => {}
+        }
+
+        let name = self.name();
+        let value = self.env.get_env(&name).map(String::from);
+        let Some(mut modifier) = self.next() else {
+            return Err(ShellError::MissingBrace);
+        };
+
+        if modifier == '}' {
+            return Ok(match value {
+                Some(value) => value,
+                None if self.lex.skip_unset_env => format!("${{{name}}}"),
+                None => String::new(),
+            });
+        }
+
+        let mut chs = modifier.to_string();
+        let null_is_unset = modifier == ':';
+        if null_is_unset {
+            modifier = self.next().ok_or(ShellError::MissingBrace)?;
+            chs.push(modifier);
+        }
+
+        match modifier {
+            '-' | '+' | '?' => {
+                let (word, _) = self.process(&['}'])?;
+                let set = match &value {
+                    Some(value) => !(null_is_unset && value.is_empty()),
+                    None => false,
+                };
+
+                match (modifier, value) {
+                    (_, None) if self.lex.skip_unset_env => Ok(format!("${{{name}{chs}{word}}}")),
+                    ('-', value) => Ok(if set { value.unwrap() } else { word }),
+                    ('+', _) => Ok(if set { word } else { String::new() }),
+                    (_, value) if set => Ok(value.unwrap()),
+                    (_, value) => Err(ShellError::Required {
+                        message: match (word.is_empty(), value) {
+                            (false, _) => word,
+                            (true, None) => "is not allowed to be unset".into(),
+                            (true, Some(_)) => "is not allowed to be empty".into(),
+                        },
+                        name,
+                    }),
+                }
+            }
+            '#' | '%' if !null_is_unset => {
+                let longest = self.peek() == Some(modifier);
+                if longest {
+                    self.next();
+                    chs.push(modifier);
+                }
+
+                let (pattern, _) = self.process_with(&['}'], true)?;
+                let Some(value) = value else {
+                    return Ok(match self.lex.skip_unset_env {
+                        true => format!("${{{name}{chs}{pattern}}}"),
+                        false => String::new(),
+                    });
+                };
+
+                let pattern = pattern.chars().collect::<Vec<_>>();
+                Ok(trim(
+                    &value,
+                    &pattern,
+                    self.lex.escape,
+                    modifier == '#',
+                    longest,
+                ))
+            }
+            '/' if !null_is_unset => {
+                let all = self.peek() == Some('/');
+                if all {
+                    self.next();
+                    chs.push('/');
+                }
+
+                // The replacement is optional, `${VAR/pattern}` removes the match
+                let (pattern, _) = self.process_with(&['/', '}'], true)?;
+                let replacement = match self.chars[self.pos - 1] {
+                    '/' => Some(self.process(&['}'])?.0),
+                    _ => None,
+                };
+
+                let Some(value) = value else {
+                    return Ok(match (self.lex.skip_unset_env, replacement) {
+                        (true, Some(replacement)) => {
+                            format!("${{{name}{chs}{pattern}/{replacement}}}")
+                        }
+                        (true, None) => format!("${{{name}{chs}{pattern}}}"),
+                        (false, _) => String::new(),
+                    });
+                };
+                let replacement = replacement.unwrap_or_default();
+
+                let pattern = pattern.chars().collect::<Vec<_>>();
+                Ok(replace(
+                    &value,
+                    &pattern,
+                    &replacement,
+                    self.lex.escape,
+                    all,
+                ))
+            }
+            _ => Err(ShellError::UnsupportedModifier(chs)),
+        }
+    }
+}
+
+/// Remove the shortest or longest prefix or suffix matching `pattern`
+fn trim(value: &str, pattern: &[char], escape: char, prefix: bool, longest: bool) -> String {
+    let chars = value.chars().collect::<Vec<_>>();
+    let len = chars.len();
+
+    let mut ends: Box<dyn Iterator<Item = usize>> = match longest {
+        true => Box::new((0..=len).rev()),
+        false => Box::new(0..=len),
+    };
+    let found = match prefix {
+        true => ends.find(|&end| glob_match(pattern, &chars[..end], escape)),
+        false => ends
+            .map(|n| len - n)
+            .find(|&start| glob_match(pattern, &chars[start..], escape)),
+    };
+
+    match (found, prefix) {
+        (Some(end), true) => chars[end..].iter().collect(),
+        (Some(start), false) => chars[..start].iter().collect(),
+        (None, _) => value.into(),
+    }
+}
+
+/// Replace the first or all longest matches of `pattern`
+fn replace(value: &str, pattern: &[char], replacement: &str, escape: char, all: bool) -> String {
+    if pattern.is_empty() {
+        return value.into();
+    }
+
+    let chars = value.chars().collect::<Vec<_>>();
+    let mut result = String::new();
+    let mut start = 0;
+    while start < chars.len() {
+        let matched = (start + 1..=chars.len())
+            .rev()
+            .find(|&end| glob_match(pattern, &chars[start..end], escape));
+
+        match matched {
+            Some(end) => {
+                result.push_str(replacement);
+                start = end;
+                if !all {
+                    result.extend(&chars[start..]);
+                    return result;
+                }
+            }
+            None => {
+                result.push(chars[start]);
+                start += 1;
+            }
+        }
+    }
+    result
+}
+
+/// Match a shell pattern with `*`, `?` and `[...]` against the whole input
+fn glob_match(pattern: &[char], input: &[char], escape: char) -> bool {
+    match pattern.split_first() {
+        None => input.is_empty(),
+        Some(('*', rest)) => (0..=input.len()).any(|skip| glob_match(rest, &input[skip..], escape)),
+        Some(('?', rest)) => !input.is_empty() && glob_match(rest, &input[1..], escape),
+        Some(('[', rest)) => {
+            let Some((&c, input_rest)) = input.split_first() else {
+                return false;
+            };
+            match match_class(rest, c, escape) {
+                Some((true, rest)) => glob_match(rest, input_rest, escape),
+                Some((false, _)) => false,
+                // An unclosed bracket is matched literally
+                None => c == '[' && glob_match(rest, input_rest, escape),
+            }
+        }
+        Some((&c, rest)) => {
+            let (c, rest) = match rest.split_first() {
+                Some((&escaped, rest)) if c == escape => (escaped, rest),
+                _ => (c, rest),
+            };
+            input.first() == Some(&c) && glob_match(rest, &input[1..], escape)
+        }
+    }
+}
+
+/// Match a character class after the `[`, returns whether it matched and the
+/// pattern after the `]`
+fn match_class(pattern: &[char], c: char, escape: char) -> Option<(bool, &[char])> {
+    let (negate, mut pattern) = match pattern.split_first() {
+        Some(('!' | '^', rest)) => (true, rest),
+        _ => (false, pattern),
+    };
+
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let (&start, rest) = pattern.split_first()?;
+        if start == ']' && !first {
+            return Some((matched != negate, rest));
+        }
+        first = false;
+
+        let (start, rest) = match rest.split_first() {
+            Some((&escaped, rest)) if start == escape => (escaped, rest),
+            _ => (start, rest),
+        };
+        pattern = rest;
+
+        match pattern {
+            ['-', end, rest @ ..] if *end != ']' => {
+                matched |= (start..=*end).contains(&c);
+                pattern = rest;
+            }
+            _ => matched |= start == c,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env() -> Vec<String> {
+        ["PWD=/home", "EMPTY=", "WORDS=a  b", "FILE=archive.tar.gz"]
+            .map(String::from)
+            .to_vec()
+    }
+
+    fn word(word: &str) -> Result<String, ShellError> {
+        Lex::default().process_word(word, &env())
+    }
+
+    #[test]
+    fn quotes_and_escapes() {
+        assert_eq!(
+            word(r#"he'l"l'o "$PWD" '$PWD'"#).unwrap(),
+            r#"hel"lo /home $PWD"#
+        );
+        assert_eq!(word(r"\$PWD \\ \a").unwrap(), r"$PWD \ a");
+        assert_eq!(word(r#""\$PWD \a \" \\""#).unwrap(), r#"$PWD \a " \"#);
+        assert_eq!(word("trailing\\").unwrap(), "trailing");
+        assert_eq!(
+            Lex::new('`').process_word("`$PWD C:\\dir", &env()).unwrap(),
+            "$PWD C:\\dir"
+        );
+        assert_eq!(
+            Lex::default()
+                .with_raw_quotes(true)
+                .process_word(r#"'$PWD' "$PWD""#, &env())
+                .unwrap(),
+            r#"'$PWD' "/home""#
+        );
+
+        assert_eq!(word("'open"), Err(ShellError::UnterminatedQuote('\'')));
+        assert_eq!(word("\"open"), Err(ShellError::UnterminatedQuote('"')));
+    }
+
+    #[test]
+    fn variables() {
+        assert_eq!(
+            word("$PWD/${PWD}x $MISSING.$ $").unwrap(),
+            "/home//homex .$ $"
+        );
+        assert_eq!(word("$1 $@").unwrap(), " ");
+        assert_eq!(
+            Lex::default()
+                .with_skip_unset_env(true)
+                .process_word("$MISSING ${MISSING} ${MISSING:-x}", &env())
+                .unwrap(),
+            "$MISSING ${MISSING} ${MISSING:-x}"
+        );
+        assert_eq!(
+            Lex::default()
+                .with_skip_unset_env(true)
+                .process_word("${MISSING/a/b} ${MISSING//a/b} ${MISSING/a}", &env())
+                .unwrap(),
+            "${MISSING/a/b} ${MISSING//a/b} ${MISSING/a}"
+        );
+
+        assert_eq!(word("${PWD"), Err(ShellError::MissingBrace));
+        assert_eq!(word("${}"), Err(ShellError::BadSubstitution));
+        assert_eq!(
+            word("${PWD^}"),
+            Err(ShellError::UnsupportedModifier("^".into()))
+        );
+    }
+
+    #[test]
+    fn modifiers() {
+        assert_eq!(word("${MISSING:-$PWD}").unwrap(), "/home");
+        assert_eq!(
+            word("${EMPTY:-default} ${EMPTY-default}").unwrap(),
+            "default "
+        );
+        assert_eq!(
+            word("${PWD:+alt} ${EMPTY:+alt} ${EMPTY+alt}").unwrap(),
+            "alt  alt"
+        );
+        assert_eq!(word("${MISSING:-'a }'}").unwrap(), "a }");
+        assert_eq!(word("${PWD:?}").unwrap(), "/home");
+
+        assert_eq!(
+            word("${MISSING?}").unwrap_err().to_string(),
+            "MISSING: is not allowed to be unset"
+        );
+        assert_eq!(
+            word("${EMPTY:?must be set}"),
+            Err(ShellError::Required {
+                name: "EMPTY".into(),
+                message: "must be set".into()
+            })
+        );
+    }
+
+    #[test]
+    fn patterns() {
+        assert_eq!(word("${FILE#*.}").unwrap(), "tar.gz");
+        assert_eq!(word("${FILE##*.}").unwrap(), "gz");
+        assert_eq!(word("${FILE%.*}").unwrap(), "archive.tar");
+        assert_eq!(word("${FILE%%.*}").unwrap(), "archive");
+        assert_eq!(word("${FILE#[a-c]?}").unwrap(), "chive.tar.gz");
+        assert_eq!(word(r"${FILE%\.gz}").unwrap(), "archive.tar");
+        assert_eq!(word("${FILE/./-}").unwrap(), "archive-tar.gz");
+        assert_eq!(word("${FILE//./-}").unwrap(), "archive-tar-gz");
+        assert_eq!(word("${FILE/a*./x}").unwrap(), "xgz");
+        assert_eq!(word("${FILE/tar}").unwrap(), "archive..gz");
+        assert_eq!(
+            word("${FILE:#x}"),
+            Err(ShellError::UnsupportedModifier(":#".into()))
+        );
+    }
+
+    #[test]
+    fn words() {
+        let lex = Lex::default();
+        assert_eq!(
+            lex.process_words(r#"a "b c"  $WORDS "$WORDS" '' d\ e"#, &env())
+                .unwrap(),
+            ["a", "b c", "a", "b", "a  b", "d e"]
+        );
+        assert!(lex.process_words("  ", &env()).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,13 @@
+//! Helpers for the tests of the other crates
+
+use std::future::Future;
+use std::task::{Context, Poll, Waker};
+
+/// Poll a future that never waits to completion, panics if it is pending
+pub fn block_on<F: Future>(fut: F) -> F::Output {
+    let mut fut = std::pin::pin!(fut);
+    match fut.as_mut().poll(&mut Context::from_waker(Waker::noop())) {
+        Poll::Ready(output) => output,
+        Poll::Pending => panic!("future is pending"),
+    }
+}
@@ -5,6 +5,7 @@
 //! want to use only a subset of the SDK.
 
 pub use buildkit_rs_client as client;
+pub use buildkit_rs_dockerfile as dockerfile;
 pub use buildkit_rs_llb as llb;
 pub use buildkit_rs_proto as proto;
 pub use buildkit_rs_reference as reference;